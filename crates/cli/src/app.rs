@@ -8,18 +8,22 @@ use std::{
 
 use anyhow::{Context, Result};
 use candle_core::{DType, Tensor};
-use deepseek_ocr_config::{AppConfig, LocalFileSystem};
+use deepseek_ocr_config::{AppConfig, ConfigOverrides, InferenceSettings, LocalFileSystem};
 use deepseek_ocr_core::{
+    document::{DocumentResult, JsonlSink},
     inference::{
-        build_prompt_tokens, compute_image_embeddings, normalize_text, prepare_vision_inputs,
-        render_prompt,
+        FinishReason, PartialUtf8, StreamingDetokenizer, build_prompt_tokens,
+        compute_image_embeddings, decode_without_partial_utf8, finish_trimmed_output,
+        image_token_ids, normalize_text, prepare_vision_inputs_with_stats, render_prompt,
+        tail_is_partial_utf8, trim_to_structural_boundary,
     },
-    model::{DeepseekOcrModel, GenerateOptions},
-    runtime::{default_dtype_for_device, prepare_device_and_dtype},
+    model::{DeepseekOcrModel, GenerateOptions, LoadOptions, LruPrefixCache, PrefixCache},
+    runtime::{default_dtype_for_device, prepare_device_and_dtype, seed_device},
+    vision::{PageBreakOptions, detect_page_breaks_with, load_image},
 };
 use image::DynamicImage;
 use tokenizers::Tokenizer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
     args::Args,
@@ -34,24 +38,39 @@ pub fn run(args: Args) -> Result<()> {
 
     let prompt_raw = load_prompt(&args)?;
 
-    let fs = LocalFileSystem::new("deepseek-ocr");
-    let (mut app_config, descriptor) = AppConfig::load_or_init(&fs, args.config.as_deref())?;
+    let config_fs = LocalFileSystem::new("deepseek-ocr");
+    let (mut app_config, descriptor) = AppConfig::load_or_init(&config_fs, args.config.as_deref())?;
+    app_config += ConfigOverrides::from_env()?;
     app_config += &args;
+    let fs = app_config.model_file_system(&config_fs)?;
     app_config.normalise(&fs)?;
     let resources = app_config.active_model_resources(&fs)?;
 
     info!(
         "Using configuration {} (active model `{}`)",
-        descriptor.location.display_with(&fs)?,
+        descriptor.location.display_with(&config_fs)?,
         app_config.models.active
     );
 
     let config_path = ensure_config_file(&fs, &resources.config)?;
-    let tokenizer_path = ensure_tokenizer_file(&fs, &resources.tokenizer)?;
+    let tokenizer_path = ensure_tokenizer_file(&fs, &resources)?;
     let weights_path = prepare_weights_path(&fs, &resources.weights)?;
 
-    let (device, maybe_precision) =
-        prepare_device_and_dtype(app_config.inference.device, app_config.inference.precision)?;
+    let model_settings = app_config.active_inference_settings()?;
+    let (device, maybe_precision) = prepare_device_and_dtype(
+        model_settings.device,
+        model_settings.device_index,
+        model_settings.precision,
+    )
+    .with_context(|| {
+        format!(
+            "device {:?} for model `{}` is not available",
+            model_settings.device, app_config.models.active
+        )
+    })?;
+    if let Some(seed) = model_settings.seed {
+        seed_device(&device, seed)?;
+    }
     let dtype = maybe_precision.unwrap_or_else(|| default_dtype_for_device(&device));
 
     info!(
@@ -63,11 +82,29 @@ pub fn run(args: Args) -> Result<()> {
     );
 
     let load_start = Instant::now();
-    let model = DeepseekOcrModel::load(
+    let load_options = LoadOptions {
+        quantize: app_config.inference.quantize,
+        language_overrides: app_config.model_config_overrides.clone(),
+        preprocess_device: app_config.inference.preprocess_device,
+        weight_key_remap: app_config.inference.weight_key_remap.clone(),
+        lora_adapters: app_config.inference.lora_adapters.clone(),
+        debug_crops_dir: app_config.inference.debug_crops_dir.clone(),
+        aux_loss: app_config.inference.aux_loss,
+        flash_nan_check: app_config.inference.flash_nan_check,
+        early_exit: app_config.inference.early_exit(),
+        prefill_chunk_size: app_config.inference.prefill_chunk_size,
+        kv_max_seq_len: app_config.inference.kv_max_seq_len,
+        kv_eviction: app_config.inference.kv_eviction,
+        kv_compact_stride: app_config.inference.kv_compact_stride,
+        max_tiles: Some(app_config.inference.max_tiles),
+        normalization: app_config.inference.normalization,
+    };
+    let model = DeepseekOcrModel::load_with_options(
         Some(&config_path),
         Some(&weights_path),
         device.clone(),
         dtype,
+        &load_options,
     )
     .context("failed to load DeepSeek-OCR model")?;
     info!(
@@ -95,34 +132,170 @@ pub fn run(args: Args) -> Result<()> {
     let images: Vec<DynamicImage> = args
         .images
         .iter()
-        .map(|path| {
-            image::open(path).with_context(|| format!("failed to open image at {}", path.display()))
-        })
+        .map(|path| load_image(path, app_config.inference.apply_exif_orientation))
         .collect::<Result<Vec<_>>>()?;
 
-    let owned_inputs = prepare_vision_inputs(
-        &model,
-        &images,
-        app_config.inference.base_size,
-        app_config.inference.image_size,
-        app_config.inference.crop_mode,
+    let mut sink = match args.output_jsonl.as_deref() {
+        Some(path) => {
+            let (sink, done) = JsonlSink::open(path, args.resume)?;
+            if done > 0 {
+                info!("Resuming: {done} result(s) already in {}", path.display());
+            }
+            Some((sink, done))
+        }
+        None => None,
+    };
+    let already_done = sink.as_ref().map_or(0, |(_, done)| *done);
+    let mut emit = |document: &DocumentResult| -> Result<()> {
+        match sink.as_mut() {
+            Some((sink, _)) => sink.write(document),
+            None => Ok(()),
+        }
+    };
+
+    // Pays off when several sections share a long instruction before their image.
+    let prefix_store = app_config
+        .inference
+        .prefix_cache_entries
+        .map(LruPrefixCache::new);
+    let prefix_cache = prefix_store.as_ref().map(|store| PrefixCache {
+        store,
+        model_id: &app_config.models.active,
+        template: &app_config.inference.template,
+    });
+
+    let normalized = if app_config.inference.split_pages {
+        anyhow::ensure!(
+            images.len() == 1,
+            "--split-pages expects exactly one image (got {})",
+            images.len()
+        );
+        let page_options = PageBreakOptions {
+            min_gap_height: app_config.inference.page_break_min_gap,
+            threshold: app_config.inference.page_break_threshold,
+            ..PageBreakOptions::default()
+        };
+        let slices = detect_page_breaks_with(&images[0], &page_options);
+        let total = slices.len();
+        info!("Split input image into {total} page section(s)");
+        let mut sections = Vec::with_capacity(total);
+        for (idx, slice) in slices.into_iter().enumerate() {
+            if idx < already_done {
+                continue;
+            }
+            info!(
+                "Recognizing section {}/{total} (rows {}..{})",
+                idx + 1,
+                slice.top,
+                slice.top + slice.height
+            );
+            if idx > already_done {
+                let mut stdout = io::stdout();
+                let _ = write!(stdout, "\n\n");
+                let _ = stdout.flush();
+            }
+            let (section, document) = recognize(
+                &model,
+                &tokenizer,
+                &app_config.inference,
+                &prompt_with_template,
+                &[slice.image],
+                prefix_cache,
+                args.return_prompt_token_ids,
+            )?;
+            emit(&document)?;
+            sections.push(section);
+        }
+        if app_config.inference.detect_empty_output {
+            sections.retain(|section| !section.is_empty());
+        }
+        sections.join("\n\n")
+    } else if already_done > 0 {
+        info!("Output already recorded; nothing left to recognize");
+        String::new()
+    } else {
+        let (normalized, document) = recognize(
+            &model,
+            &tokenizer,
+            &app_config.inference,
+            &prompt_with_template,
+            &images,
+            prefix_cache,
+            args.return_prompt_token_ids,
+        )?;
+        emit(&document)?;
+        normalized
+    };
+    info!("Final output:\n{normalized}");
+    if let Some(stats) = model.language_model().aux_loss_stats() {
+        info!(
+            "MoE aux loss: last {:.6}, mean {:.6} over {} forwards",
+            stats.last, stats.mean, stats.forwards
+        );
+    }
+    if let Some(stats) = model.language_model().early_exit_stats() {
+        info!(
+            "Early exit: {} of {} decode steps exited, {:.2} layers per step on average",
+            stats.exits, stats.steps, stats.mean_layers
+        );
+    }
+
+    if let Some(session) = bench_session {
+        let report = session.finalize()?;
+        bench::print_summary(&report);
+    }
+
+    Ok(())
+}
+
+/// Run one prompt through vision preprocessing and generation, returning the normalized text and
+/// its document envelope, which carries the prompt token ids with `return_prompt_token_ids`.
+fn recognize(
+    model: &DeepseekOcrModel,
+    tokenizer: &Tokenizer,
+    settings: &InferenceSettings,
+    prompt: &str,
+    images: &[DynamicImage],
+    prefix_cache: Option<PrefixCache<'_>>,
+    return_prompt_token_ids: bool,
+) -> Result<(String, DocumentResult)> {
+    let (owned_inputs, preprocess_stats) = prepare_vision_inputs_with_stats(
+        model,
+        images,
+        settings.base_size,
+        settings.image_size,
+        settings.crop_mode,
+        settings.strip_aspect_threshold,
+        &settings.preprocess_pipeline(),
     )?;
-    let embeddings = compute_image_embeddings(&model, &owned_inputs)?;
+    for (idx, stats) in preprocess_stats.iter().enumerate() {
+        if let Some(angle) = stats.deskew_degrees {
+            info!("Image {}: corrected skew of {angle:.2}°", idx + 1);
+        }
+    }
+    let embeddings = compute_image_embeddings(model, &owned_inputs)?;
 
     let (input_ids_vec, mask_vec) = build_prompt_tokens(
-        &tokenizer,
-        &prompt_with_template,
+        tokenizer,
+        prompt,
         &embeddings,
         &owned_inputs,
-        app_config.inference.base_size,
-        app_config.inference.image_size,
-        app_config.inference.crop_mode,
+        settings.base_size,
+        settings.image_size,
+        settings.crop_mode,
     )?;
 
+    let image_tokens = mask_vec.iter().filter(|&&b| b != 0).count();
     info!(
         "Prompt prepared: {} tokens ({} image tokens)",
         input_ids_vec.len(),
-        mask_vec.iter().filter(|&&b| b != 0).count()
+        image_tokens
+    );
+    let max_new_tokens = settings.max_new_tokens.resolve(
+        input_ids_vec.len(),
+        image_tokens,
+        model.language_model().config().max_position_embeddings,
+        settings.max_new_tokens_ceiling,
     );
 
     let input_ids = Tensor::from_vec(
@@ -134,20 +307,44 @@ pub fn run(args: Args) -> Result<()> {
     let mask_tensor = Tensor::from_vec(mask_vec.clone(), (1, mask_vec.len()), model.device())?
         .to_dtype(DType::U8)?;
 
-    let mut options = GenerateOptions::new(app_config.inference.max_new_tokens);
+    let mut options = GenerateOptions::new(max_new_tokens);
     options.images_seq_mask = Some(&mask_tensor);
     if !embeddings.is_empty() {
         options.image_embeddings = Some(embeddings.as_slice());
     }
     options.eos_token_id = model.language_model().config().eos_token_id;
-    options.use_cache = app_config.inference.use_cache;
+    let banned_token_ids = if settings.ban_image_tokens {
+        image_token_ids(tokenizer)
+    } else {
+        Vec::new()
+    };
+    options.banned_token_ids = &banned_token_ids;
+    let sampler = settings.sampling().sampler();
+    options.sampler = sampler.as_ref();
+    options.repetition_penalty = settings.repetition_penalty()?;
+    options.no_repeat_ngram_size = settings.no_repeat_ngram_size();
+    let beam_search = settings.beam_search();
+    options.beam_search = beam_search;
+    options.use_cache = settings.use_cache;
+    options.prefix_cache = prefix_cache;
 
     let tokenizer_for_stream = tokenizer.clone();
+    let decode_for_stream =
+        move |ids: &[u32]| tokenizer_for_stream.decode(ids, true).unwrap_or_default();
     let progress_state = Rc::new(RefCell::new(0usize));
     let stream_state = Rc::clone(&progress_state);
+    let detokenizer = Rc::new(RefCell::new(StreamingDetokenizer::new()));
+    let stream_detokenizer = Rc::clone(&detokenizer);
     let stdout = Rc::new(RefCell::new(io::stdout()));
     let stdout_handle = Rc::clone(&stdout);
-    let progress_callback = move |count: usize, ids: &[i64]| {
+    let write_stream = move |text: &str| {
+        if !text.is_empty() {
+            let mut handle = stdout_handle.borrow_mut();
+            let _ = write!(handle, "{text}");
+            let _ = handle.flush();
+        }
+    };
+    let progress_callback = |count: usize, ids: &[i64]| {
         let mut last = stream_state.borrow_mut();
         if count <= *last {
             return;
@@ -156,50 +353,97 @@ pub fn run(args: Args) -> Result<()> {
             .iter()
             .filter_map(|&id| u32::try_from(id).ok())
             .collect();
-        if !new_tokens.is_empty() {
-            if let Ok(decoded) = tokenizer_for_stream.decode(&new_tokens, true) {
-                if !decoded.is_empty() {
-                    let mut handle = stdout_handle.borrow_mut();
-                    let _ = write!(handle, "{}", decoded);
-                    let _ = handle.flush();
-                }
-            }
-        }
+        // The detokenizer holds back a split multibyte character until the rest of its bytes
+        // arrive.
+        write_stream(
+            &stream_detokenizer
+                .borrow_mut()
+                .push(&new_tokens, &decode_for_stream),
+        );
         *last = count;
     };
     options.progress_callback = Some(&progress_callback);
+    let extend_for_utf8 = |ids: &[i64]| {
+        tail_is_partial_utf8(ids, |tail| tokenizer.decode(tail, true).unwrap_or_default())
+    };
+    if settings.partial_utf8 == PartialUtf8::Complete {
+        options.extend_while = Some(&extend_for_utf8);
+    }
+    let stop = settings.stop_criteria();
+    let stop_when =
+        |ids: &[i64]| stop.matches(ids, |ids| tokenizer.decode(ids, false).unwrap_or_default());
+    if !stop.is_empty() {
+        options.stop_when = Some(&stop_when);
+    }
 
     info!(
-        "Starting generation with requested budget {} tokens",
-        app_config.inference.max_new_tokens
+        "Starting generation with requested budget {max_new_tokens} tokens ({})",
+        settings.max_new_tokens
     );
     info!("--- Generation start ---");
     let gen_start = Instant::now();
     let generated = model.generate(&input_ids, options)?;
-    let elapsed = gen_start.elapsed();
-    info!("--- Generation done in {:.2?} ---", elapsed);
-
     let generated_tokens = generated
         .to_vec2::<i64>()?
         .into_iter()
         .next()
         .unwrap_or_default();
-    let decoded = tokenizer
-        .decode(
-            &generated_tokens
-                .iter()
-                .filter_map(|&id| u32::try_from(id).ok())
-                .collect::<Vec<_>>(),
-            true,
-        )
-        .unwrap_or_default();
-    let normalized = normalize_text(&decoded);
-    info!("Final output:\n{normalized}");
-
-    if let Some(session) = bench_session {
-        let report = session.finalize()?;
-        bench::print_summary(&report);
+    if beam_search.is_some() {
+        // Beam search only settles on its output once every hypothesis has finished, so nothing
+        // was streamed while it decoded.
+        progress_callback(generated_tokens.len(), &generated_tokens);
     }
+    write_stream(&detokenizer.borrow_mut().finish(&decode_for_stream));
+    let elapsed = gen_start.elapsed();
+    info!("--- Generation done in {:.2?} ---", elapsed);
 
-    Ok(())
+    let generated_tokens = stop.strip_tokens(&generated_tokens).to_vec();
+    let decoded = decode_without_partial_utf8(
+        &generated_tokens
+            .iter()
+            .filter_map(|&id| u32::try_from(id).ok())
+            .collect::<Vec<_>>(),
+        |ids| tokenizer.decode(ids, true).unwrap_or_default(),
+    );
+    let decoded = stop.truncate(&decoded).to_string();
+    let (decoded, truncated) =
+        if settings.structure_aware_stop && generated_tokens.len() >= max_new_tokens {
+            trim_to_structural_boundary(&decoded)
+        } else {
+            (decoded, false)
+        };
+    if truncated {
+        warn!(
+            "Token budget ran out mid-structure; final output trimmed to the last complete block"
+        );
+    }
+    let (normalized, finish_reason) = finish_trimmed_output(
+        normalize_text(&decoded),
+        settings.detect_empty_output,
+        truncated,
+    );
+    if finish_reason == FinishReason::Empty {
+        info!("Output is blank; returning empty text");
+    }
+    let mut document = DocumentResult::from_output(
+        &normalized,
+        input_ids_vec.len(),
+        generated_tokens.len(),
+        finish_reason,
+    );
+    document.usage.deskew_degrees = preprocess_stats
+        .iter()
+        .filter_map(|stats| stats.deskew_degrees)
+        .collect();
+    document.usage.prompt_token_ids = return_prompt_token_ids.then_some(input_ids_vec);
+    if settings.raw_output {
+        let ids: Vec<u32> = generated_tokens
+            .iter()
+            .filter_map(|&id| u32::try_from(id).ok())
+            .collect();
+        let raw = tokenizer.decode(&ids, false).unwrap_or_default();
+        info!("Raw output:\n{raw}");
+        document.raw_output = Some(raw);
+    }
+    Ok((normalized, document))
 }
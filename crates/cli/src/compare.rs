@@ -0,0 +1,131 @@
+use anyhow::{Result, ensure};
+use deepseek_ocr_config::{AppConfig, ConfigOverrides, LocalFileSystem};
+use deepseek_ocr_core::{
+    inference::{CompareSettings, recognize_compare, render_prompt},
+    model::LoadOptions,
+    runtime::prepare_device_and_dtype,
+    vision::load_image,
+};
+use tokenizers::Tokenizer;
+use tracing::info;
+
+use crate::{
+    args::{Args, CompareArgs},
+    prompt::load_prompt,
+    resources::{ensure_config_file, ensure_tokenizer_file, prepare_weights_path},
+};
+
+pub fn run(args: &Args, compare_args: &CompareArgs) -> Result<()> {
+    ensure!(
+        args.images.len() == 1,
+        "compare expects exactly one --image (got {})",
+        args.images.len()
+    );
+    let prompt_raw = load_prompt(args)?;
+
+    let config_fs = LocalFileSystem::new("deepseek-ocr");
+    let (mut app_config, descriptor) = AppConfig::load_or_init(&config_fs, args.config.as_deref())?;
+    app_config += ConfigOverrides::from_env()?;
+    app_config += args;
+    let fs = app_config.model_file_system(&config_fs)?;
+    app_config.normalise(&fs)?;
+    let resources = app_config.active_model_resources(&fs)?;
+    info!(
+        "Using configuration {} (active model `{}`)",
+        descriptor.location.display_with(&config_fs)?,
+        app_config.models.active
+    );
+
+    let config_path = ensure_config_file(&fs, &resources.config)?;
+    let tokenizer_path = ensure_tokenizer_file(&fs, &resources)?;
+    let weights_path = prepare_weights_path(&fs, &resources.weights)?;
+    let model_settings = app_config.active_inference_settings()?;
+    let (device, _) =
+        prepare_device_and_dtype(model_settings.device, model_settings.device_index, None)?;
+    let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|err| {
+        anyhow::anyhow!(
+            "failed to load tokenizer from {}: {err}",
+            tokenizer_path.display()
+        )
+    })?;
+
+    let prompt = render_prompt(&app_config.inference.template, "", &prompt_raw)?;
+    let image = load_image(&args.images[0], app_config.inference.apply_exif_orientation)?;
+    let settings = CompareSettings {
+        config_path: Some(&config_path),
+        weights_path: Some(&weights_path),
+        device,
+        load_options: LoadOptions {
+            quantize: app_config.inference.quantize,
+            language_overrides: app_config.model_config_overrides.clone(),
+            preprocess_device: app_config.inference.preprocess_device,
+            weight_key_remap: app_config.inference.weight_key_remap.clone(),
+            lora_adapters: app_config.inference.lora_adapters.clone(),
+            debug_crops_dir: app_config.inference.debug_crops_dir.clone(),
+            aux_loss: app_config.inference.aux_loss,
+            flash_nan_check: app_config.inference.flash_nan_check,
+            early_exit: app_config.inference.early_exit(),
+            prefill_chunk_size: app_config.inference.prefill_chunk_size,
+            kv_max_seq_len: app_config.inference.kv_max_seq_len,
+            kv_eviction: app_config.inference.kv_eviction,
+            kv_compact_stride: app_config.inference.kv_compact_stride,
+            max_tiles: Some(app_config.inference.max_tiles),
+            normalization: app_config.inference.normalization,
+        },
+        tokenizer: &tokenizer,
+        prompt: &prompt,
+        base_size: app_config.inference.base_size,
+        image_size: app_config.inference.image_size,
+        crop_mode: app_config.inference.crop_mode,
+        strip_aspect_threshold: app_config.inference.strip_aspect_threshold,
+        preprocess: app_config.inference.preprocess_pipeline(),
+        max_new_tokens: app_config.inference.max_new_tokens,
+        max_new_tokens_ceiling: app_config.inference.max_new_tokens_ceiling,
+        return_prompt_token_ids: compare_args.print_token_ids,
+        ban_image_tokens: app_config.inference.ban_image_tokens,
+        progress: None,
+    };
+    let results = recognize_compare(&image, &compare_args.precisions, &settings)?;
+
+    let Some((reference_precision, reference)) = results.first() else {
+        return Ok(());
+    };
+    if let Some(prompt_ids) = &reference.prompt_token_ids {
+        println!("=== prompt ({} tokens) ===", prompt_ids.len());
+        println!("{prompt_ids:?}");
+    }
+    for (precision, result) in &results {
+        println!(
+            "=== {precision:?} ({} tokens, {:.2?}) ===",
+            result.token_ids.len(),
+            result.elapsed
+        );
+        println!("{}", result.text);
+        if compare_args.print_token_ids {
+            println!("token ids: {:?}", result.token_ids);
+        }
+    }
+    for (precision, result) in results.iter().skip(1) {
+        let shared = reference
+            .token_ids
+            .iter()
+            .zip(&result.token_ids)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if result.token_ids == reference.token_ids {
+            println!("{precision:?} matches {reference_precision:?} token for token");
+        } else {
+            println!(
+                "{precision:?} diverges from {reference_precision:?} at token {shared} ({} vs {} tokens; text {})",
+                result.token_ids.len(),
+                reference.token_ids.len(),
+                if result.text == reference.text {
+                    "identical"
+                } else {
+                    "differs"
+                }
+            );
+        }
+    }
+    Ok(())
+}
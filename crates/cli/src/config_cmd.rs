@@ -0,0 +1,51 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use deepseek_ocr_config::AppConfig;
+
+use crate::args::{ConfigArgs, ConfigCommand};
+
+pub fn run(config_args: &ConfigArgs) -> Result<()> {
+    match &config_args.command {
+        ConfigCommand::Diff { a, b } => {
+            let expected = AppConfig::load_from_path(a)?;
+            let actual = AppConfig::load_from_path(b)?;
+            let diffs = expected.diff(&actual)?;
+            if diffs.is_empty() {
+                println!("No differences between {} and {}", a.display(), b.display());
+            }
+            for diff in &diffs {
+                println!("{diff}");
+            }
+            Ok(())
+        }
+        ConfigCommand::Repair { path, dry_run } => {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("failed to read configuration from {}", path.display()))?;
+            let repair = AppConfig::repair(&contents)
+                .with_context(|| format!("failed to repair configuration at {}", path.display()))?;
+            for field in &repair.unknown_fields {
+                eprintln!("warning: unknown setting `{field}` is ignored when loading; kept as-is");
+            }
+            if repair.fixes.is_empty() {
+                println!("No repairs needed for {}", path.display());
+                return Ok(());
+            }
+            for fix in &repair.fixes {
+                println!("{fix}");
+            }
+            if *dry_run {
+                println!("Dry run: {} was not modified", path.display());
+                return Ok(());
+            }
+            fs::write(path, repair.to_toml_string()?)
+                .with_context(|| format!("failed to write configuration to {}", path.display()))?;
+            println!(
+                "Wrote {} with {} repair(s)",
+                path.display(),
+                repair.fixes.len()
+            );
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::Result;
+use deepseek_ocr_config::{AppConfig, ConfigOverrides, LocalFileSystem};
+use deepseek_ocr_core::{
+    config::load_ocr_config,
+    runtime::{DeviceKind, Precision, auto_device_kind, system_memory},
+};
+use tracing::info;
+
+use crate::{
+    args::{Args, EstimateArgs},
+    resources::ensure_config_file,
+};
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+pub fn run(args: &Args, estimate_args: &EstimateArgs) -> Result<()> {
+    let config_fs = LocalFileSystem::new("deepseek-ocr");
+    let (mut app_config, descriptor) = AppConfig::load_or_init(&config_fs, args.config.as_deref())?;
+    app_config += ConfigOverrides::from_env()?;
+    app_config += args;
+    let fs = app_config.model_file_system(&config_fs)?;
+    app_config.normalise(&fs)?;
+    let resources = app_config.active_model_resources(&fs)?;
+    info!(
+        "Using configuration {} (active model `{}`)",
+        descriptor.location.display_with(&config_fs)?,
+        app_config.models.active
+    );
+
+    let config_path = ensure_config_file(&fs, &resources.config)?;
+    let language = load_ocr_config(Some(&config_path))?.resolved_language_config()?;
+
+    let model_settings = app_config.active_inference_settings()?;
+    let device = match model_settings.device {
+        DeviceKind::Auto => auto_device_kind(),
+        device => device,
+    };
+    let precision = model_settings.precision.unwrap_or(match device {
+        DeviceKind::Cpu => Precision::F32,
+        DeviceKind::Metal | DeviceKind::Cuda | DeviceKind::Auto => Precision::F16,
+    });
+    let max_cache_len = estimate_args.max_cache_len.unwrap_or_else(|| {
+        model_settings
+            .kv_max_seq_len
+            .map_or(language.max_position_embeddings, |bound| {
+                bound.min(language.max_position_embeddings)
+            })
+    });
+    let max_num_seqs = estimate_args
+        .max_num_seqs
+        .or(app_config.inference.max_num_seqs)
+        .unwrap_or(1);
+    let quantize = model_settings.quantize;
+    let estimate =
+        language.estimated_memory_quantized(precision, max_cache_len, max_num_seqs, quantize);
+
+    println!(
+        "Memory estimate for `{}` (device={device:?}, precision={precision:?}, quantize={quantize:?}, max_cache_len={max_cache_len}, max_num_seqs={max_num_seqs})",
+        app_config.models.active
+    );
+    println!("  weights:     {}", format_gib(estimate.weights_bytes));
+    println!("  activations: {}", format_gib(estimate.activation_bytes));
+    println!("  kv cache:    {}", format_gib(estimate.kv_cache_bytes));
+    println!("  total:       {}", format_gib(estimate.total_bytes()));
+    println!("  (language decoder only; vision encoders add a few hundred MiB)");
+
+    match available_memory_bytes(device) {
+        Some(available) => {
+            println!("  available:   {}", format_gib(available));
+            if estimate.total_bytes() > available {
+                println!("  -> estimate exceeds available memory; the load is likely to OOM");
+            } else {
+                println!(
+                    "  -> fits with {} to spare",
+                    format_gib(available - estimate.total_bytes())
+                );
+            }
+        }
+        None => println!("  available:   unknown for {device:?}"),
+    }
+    Ok(())
+}
+
+fn format_gib(bytes: u64) -> String {
+    format!("{:.2} GiB", bytes as f64 / GIB)
+}
+
+/// Available system memory. Device memory for GPUs is not queried.
+fn available_memory_bytes(device: DeviceKind) -> Option<u64> {
+    if !matches!(device, DeviceKind::Cpu) {
+        return None;
+    }
+    system_memory().map(|memory| memory.free_bytes)
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    slice,
+};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use deepseek_ocr_config::{
+    AppConfig, ConfigOverrides, LocalFileSystem, ModelFileSystem, ModelResources, Namespace,
+    ResourceLocation, VirtualFileSystem, VirtualPath,
+};
+use deepseek_ocr_core::{
+    config::load_ocr_config,
+    model::{shared_mmaped_safetensors, weight_files},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokenizers::Tokenizer;
+use tracing::{info, warn};
+
+use crate::{
+    args::Args,
+    resources::{ensure_config_file, ensure_tokenizer_file, physical_path, prepare_weights_path},
+};
+
+/// Name of the checksum manifest in the cache namespace.
+const MANIFEST_FILE: &str = "fetch-manifest.json";
+
+/// Download every missing resource of every model in the registry, then check each file loads.
+/// Files already on disk are only verified, so running it again does no network work: a file
+/// whose SHA-256 matches the manifest recorded by an earlier run is not loaded again, and one
+/// that no longer matches is reported as corrupted.
+pub fn run(args: &Args) -> Result<()> {
+    let config_fs = LocalFileSystem::new("deepseek-ocr");
+    let (mut app_config, descriptor) = AppConfig::load_or_init(&config_fs, args.config.as_deref())?;
+    app_config += ConfigOverrides::from_env()?;
+    app_config += args;
+    let fs = app_config.model_file_system(&config_fs)?;
+    let mut invalid = app_config.normalise_per_model(&fs)?;
+    info!(
+        "Using configuration {}",
+        descriptor.location.display_with(&config_fs)?
+    );
+
+    let manifest_path = config_fs.with_physical_path(
+        &VirtualPath::new(Namespace::Cache, vec![MANIFEST_FILE.into()]),
+        |path| Ok(path.to_path_buf()),
+    )?;
+    let mut manifest = FetchManifest::load(&manifest_path)?;
+    let mut failed = Vec::new();
+    for model_id in app_config.models.entries.keys() {
+        let result = match invalid.remove(model_id) {
+            Some(err) => Err(err),
+            None => app_config
+                .model_resources(&fs, model_id)
+                .and_then(|resources| fetch_model(&fs, &resources, &mut manifest)),
+        };
+        match result {
+            Ok(status) => println!("{model_id}: ok ({status})"),
+            Err(err) => {
+                println!("{model_id}: failed: {err:#}");
+                failed.push(model_id.as_str());
+            }
+        }
+    }
+    if let Err(err) = manifest.save(&manifest_path) {
+        warn!("could not record checksums: {err:#}");
+    }
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} models could not be fetched: {}",
+            failed.len(),
+            app_config.models.entries.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn fetch_model(
+    fs: &ModelFileSystem,
+    resources: &ModelResources,
+    manifest: &mut FetchManifest,
+) -> Result<String> {
+    let config_state = presence(fs, &resources.config)?;
+    let config_path = ensure_config_file(fs, &resources.config)?;
+    let config_state = manifest.verify(config_state, slice::from_ref(&config_path), || {
+        load_ocr_config(Some(&config_path))?;
+        Ok(String::new())
+    })?;
+
+    let tokenizer_state = presence(fs, &resources.tokenizer)?;
+    let tokenizer_path = ensure_tokenizer_file(fs, resources)?;
+    let tokenizer_state =
+        manifest.verify(tokenizer_state, slice::from_ref(&tokenizer_path), || {
+            Tokenizer::from_file(&tokenizer_path).map_err(|err| {
+                anyhow!(
+                    "failed to load tokenizer from {}: {err}",
+                    tokenizer_path.display()
+                )
+            })?;
+            Ok(String::new())
+        })?;
+
+    let weights_state = presence(fs, &resources.weights)?;
+    let weights_path = prepare_weights_path(fs, &resources.weights)?;
+    let weights_state = manifest.verify(weights_state, &weight_files(&weights_path)?, || {
+        let tensors = count_tensors(&weights_path)?;
+        Ok(format!(" with {tensors} tensors"))
+    })?;
+
+    Ok(format!(
+        "config {config_state}, tokenizer {tokenizer_state}, weights {weights_state}"
+    ))
+}
+
+fn presence(fs: &ModelFileSystem, location: &ResourceLocation) -> Result<&'static str> {
+    Ok(if physical_path(fs, location)?.is_file() {
+        "present"
+    } else {
+        "downloaded"
+    })
+}
+
+/// Map the weights and read the safetensors header, which catches truncated downloads.
+fn count_tensors(path: &Path) -> Result<usize> {
+    let tensors = shared_mmaped_safetensors(path)?;
+    let count = tensors.tensors().len();
+    ensure!(
+        count > 0,
+        "weights at {} contain no tensors",
+        path.display()
+    );
+    Ok(count)
+}
+
+/// SHA-256 of every file `fetch` has checked, keyed by canonical path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FetchManifest {
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl FetchManifest {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read(path)
+            .with_context(|| format!("failed to read checksum manifest {}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("failed to parse checksum manifest {}", path.display()))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        let contents = serde_json::to_vec_pretty(self)?;
+        std::fs::write(path, contents)
+            .with_context(|| format!("failed to write checksum manifest {}", path.display()))
+    }
+
+    /// Compare `files` against their recorded checksums. When all of them match, the files are
+    /// reported as verified without running `load`; otherwise `load` checks them and their
+    /// checksums are recorded. A recorded checksum that no longer matches is an error, unless
+    /// the files were just downloaded to replace a deleted copy.
+    fn verify(
+        &mut self,
+        state: &str,
+        files: &[PathBuf],
+        load: impl FnOnce() -> Result<String>,
+    ) -> Result<String> {
+        let mut digests = Vec::with_capacity(files.len());
+        let downloaded = state == "downloaded";
+        let mut all_recorded = !downloaded;
+        for file in files {
+            let key = std::fs::canonicalize(file)
+                .with_context(|| format!("failed to resolve {}", file.display()))?;
+            let digest = sha256_file(&key)?;
+            match self.files.get(&key).filter(|_| !downloaded) {
+                Some(recorded) if *recorded == digest => {}
+                Some(recorded) => bail!(
+                    "{} does not match the checksum recorded when it was fetched (expected \
+                     {recorded}, found {digest}); delete it to download it again",
+                    key.display()
+                ),
+                None => all_recorded = false,
+            }
+            digests.push((key, digest));
+        }
+        if all_recorded {
+            return Ok("verified".to_string());
+        }
+        let detail = load()?;
+        self.files.extend(digests);
+        Ok(format!("{state}{detail}"))
+    }
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
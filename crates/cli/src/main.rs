@@ -1,11 +1,15 @@
 mod app;
 mod args;
 mod bench;
+mod compare;
+mod config_cmd;
+mod estimate;
+mod fetch;
 mod logging;
 mod prompt;
 mod resources;
 
-use crate::args::Args;
+use crate::args::{Args, Command};
 use anyhow::Result;
 use clap::Parser;
 use tracing::error;
@@ -21,5 +25,11 @@ fn main() {
 
 fn try_run() -> Result<()> {
     let args = Args::parse();
-    app::run(args)
+    match &args.command {
+        Some(Command::Estimate(estimate_args)) => estimate::run(&args, estimate_args),
+        Some(Command::Compare(compare_args)) => compare::run(&args, compare_args),
+        Some(Command::Config(config_args)) => config_cmd::run(config_args),
+        Some(Command::Fetch) => fetch::run(&args),
+        None => app::run(args),
+    }
 }
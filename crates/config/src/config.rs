@@ -1,32 +1,92 @@
 use std::{
-    collections::BTreeMap,
-    fs,
+    collections::{BTreeMap, BTreeSet},
+    env, fmt, fs,
     ops::AddAssign,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use anyhow::{Context, Result, anyhow};
-use deepseek_ocr_core::runtime::{DeviceKind, Precision};
-use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use clap::ValueEnum;
+use deepseek_ocr_core::{
+    config::LanguageConfigOverrides,
+    conversation::{get_prompt_template, prompt_template_names},
+    inference::{MaxNewTokens, PartialUtf8, StopCriteria},
+    model::{BeamSearch, MAX_CROP_TILES, MIN_CROP_TILES, Normalization, WeightKeyRemap},
+    runtime::{DeviceKind, Precision, PreprocessDevice, UtilizationOf},
+    transformer::{
+        cache::CacheEviction,
+        decoder::EarlyExit,
+        lora::LoraAdapter,
+        sampling::{RepetitionPenalty, SamplingParams},
+        weights::WeightQuant,
+    },
+    vision::{
+        BinarizeMethod, BuiltinPreprocessor, PageBreakOptions, PreprocessOptions,
+        PreprocessPipeline,
+    },
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use toml_edit::{DocumentMut, InlineTable, Item, Table, TableLike};
+use tracing::warn;
 
-use crate::fs::{VirtualFileSystem, VirtualPath};
+use crate::{
+    fs::{LocalFileSystem, ModelFileSystem, VirtualFileSystem, VirtualPath},
+    hub::{self, HubFiles},
+};
 
 const DEFAULT_MODEL_ID: &str = "deepseek-ocr";
 
+/// Schema version written to new configuration files. Bump it together with a new entry in
+/// [`MIGRATIONS`] whenever a released field is renamed or moved.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Upgrade steps for older configuration files: entry `n` turns a version `n` document into
+/// version `n + 1` and reports whether it had to move or rewrite any setting.
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [migrate_v0];
+
+type Migration = fn(&mut JsonMap<String, JsonValue>) -> bool;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct AppConfig {
+    /// Schema version of the file; older files are migrated when loaded, see [`CONFIG_VERSION`].
+    pub version: u32,
     pub models: ModelRegistry,
     pub inference: InferenceSettings,
     pub server: ServerSettings,
+    /// Language-model config fields merged over the checkpoint's own `config.json` at load time.
+    #[serde(skip_serializing_if = "LanguageConfigOverrides::is_empty")]
+    pub model_config_overrides: LanguageConfigOverrides,
+    /// Named inference presets: each holds any subset of the `inference` settings and is merged
+    /// over them when selected with `--profile <name>`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, toml::Table>,
+    /// Profile selected by the overrides, checked by [`AppConfig::normalise`].
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// Dotted paths of the settings in the loaded file that no field recognises, e.g. a
+    /// misspelled `inference.max_new_toknes`. They are ignored with a warning.
+    #[serde(skip)]
+    pub unknown_fields: Vec<String>,
+    /// Make [`AppConfig::normalise`] reject `unknown_fields` instead of ignoring them.
+    #[serde(skip)]
+    pub strict_config: bool,
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             models: ModelRegistry::default(),
             inference: InferenceSettings::default(),
             server: ServerSettings::default(),
+            model_config_overrides: LanguageConfigOverrides::default(),
+            profiles: BTreeMap::new(),
+            profile: None,
+            unknown_fields: Vec::new(),
+            strict_config: false,
         }
     }
 }
@@ -35,6 +95,11 @@ impl Default for AppConfig {
 #[serde(default)]
 pub struct ModelRegistry {
     pub active: String,
+    /// Entry the server loads instead when the active model fails to load (e.g. out of memory).
+    pub fallback: Option<String>,
+    /// Base URL model files are downloaded from into a local mirror instead of being read from
+    /// the cache directory (servers and CLIs built with the `http` feature). `None` stays local.
+    pub remote_url: Option<String>,
     pub entries: BTreeMap<String, ModelEntry>,
 }
 
@@ -44,42 +109,156 @@ impl Default for ModelRegistry {
         entries.insert(DEFAULT_MODEL_ID.to_string(), ModelEntry::default());
         Self {
             active: DEFAULT_MODEL_ID.to_string(),
+            fallback: None,
+            remote_url: None,
             entries,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ModelEntry {
     pub config: Option<PathBuf>,
     pub tokenizer: Option<PathBuf>,
     pub weights: Option<PathBuf>,
-}
-
-impl Default for ModelEntry {
-    fn default() -> Self {
-        Self {
-            config: None,
-            tokenizer: None,
-            weights: None,
-        }
-    }
+    /// Hugging Face Hub repo (e.g. `deepseek-ai/DeepSeek-OCR`) that provides whichever of
+    /// `config`, `tokenizer` and `weights` are unset, downloaded into the model's cache dir on
+    /// first use.
+    pub hf_repo: Option<String>,
+    /// Branch, tag or commit of `hf_repo`; `main` when unset.
+    pub revision: Option<String>,
+    /// Device for this model, overriding `inference.device`.
+    pub device: Option<DeviceKind>,
+    /// Precision for this model, overriding `inference.precision`.
+    pub precision: Option<Precision>,
+    /// Entry whose fields this one takes wherever it leaves them unset, e.g. a variant that shares
+    /// another entry's weights but brings its own tokenizer.
+    pub inherits: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct InferenceSettings {
     pub device: DeviceKind,
+    /// Which GPU to run on when `device` is CUDA or Metal (or `auto` picks one); `0` is the first.
+    pub device_index: usize,
     pub precision: Option<Precision>,
+    /// Quantize the decoder's linear layers in memory as they load. Checkpoints are always read
+    /// from safetensors; GGUF files are not supported.
+    pub quantize: Option<WeightQuant>,
+    /// Name of the registered prompt template the user prompt is rendered with.
     pub template: String,
     pub base_size: u32,
     pub image_size: u32,
     pub crop_mode: bool,
-    pub max_new_tokens: usize,
+    /// Cut images whose long side is at least this many times the short side into a strip of
+    /// square crops instead of the closest crop grid. Must be greater than 1. `None` keeps grid
+    /// tiling for every image.
+    pub strip_aspect_threshold: Option<f32>,
+    /// Most crops per image in crop mode. The crop grid is the `columns x rows` layout within
+    /// this budget whose aspect ratio is closest to the image's, so raising it lets very wide or
+    /// tall pages keep full-resolution crops at the cost of more vision tokens.
+    pub max_tiles: u32,
+    /// Per-channel `mean` and `std` (RGB, on pixel values scaled to `[0, 1]`) the global view and
+    /// crops are normalised with. Defaults to DeepSeek-OCR's `[0.5, 0.5, 0.5]` for both; change
+    /// it only for fine-tunes trained with different preprocessing.
+    pub normalization: Normalization,
+    /// Built-in preprocessors applied to each image, in order, before tiling. Empty by default.
+    pub preprocess: Vec<BuiltinPreprocessor>,
+    /// Largest skew in degrees the `deskew` preprocessor corrects.
+    pub deskew_max_angle: f32,
+    /// Threshold selection used by the `binarize` preprocessor.
+    pub binarize_method: BinarizeMethod,
+    /// Build image tensors on the compute device or on the CPU followed by one transfer.
+    pub preprocess_device: PreprocessDevice,
+    /// Write every crop the vision encoders see to this directory, for debugging preprocessing.
+    pub debug_crops_dir: Option<PathBuf>,
+    /// Compute the MoE load-balancing loss on every forward for routing diagnostics.
+    pub aux_loss: bool,
+    /// Check flash-attention outputs for NaNs and re-run the affected layer with eager attention.
+    /// Costs a device sync per layer; has no effect without flash attention.
+    pub flash_nan_check: bool,
+    /// Experimental: on decode steps, check the next-token entropy after this many layers and
+    /// skip the remaining layers' MLPs when it is below `early_exit_entropy`. `None` is off.
+    pub early_exit_layer: Option<usize>,
+    /// Entropy threshold in nats for `early_exit_layer`.
+    pub early_exit_entropy: f32,
+    /// Prefill prompts in blocks of this many tokens, appending each to the KV cache before the
+    /// next. Smaller blocks lower peak memory on long prompts; `None` prefills in one pass.
+    pub prefill_chunk_size: Option<usize>,
+    /// Keep the KV cache of up to this many distinct prompt prefixes (the text before the first
+    /// image) in memory and reuse it on later prompts. `None` disables prefix caching.
+    pub prefix_cache_entries: Option<usize>,
+    /// Cap every sequence's KV cache at this many positions, evicting by `kv_eviction` beyond
+    /// it, so very long pages decode in bounded memory. `None` keeps every position.
+    pub kv_max_seq_len: Option<usize>,
+    /// Which positions a capped KV cache keeps: `drop-oldest`, or `sink:N` to also keep the first
+    /// `N` positions.
+    pub kv_eviction: CacheEviction,
+    /// Positions a capped KV cache leaves free below `kv_max_seq_len` after each eviction, so it
+    /// is rebuilt once every this many tokens plus one. `None` frees half the window after the
+    /// attention sink.
+    pub kv_compact_stride: Option<usize>,
+    /// Tensor-name prefix rewrites for checkpoints that name their weights differently from the
+    /// upstream release.
+    pub weight_key_remap: Vec<WeightKeyRemap>,
+    /// LoRA adapters applied over the decoder's projections at load time, e.g. a task-specific
+    /// fine-tune selected through a profile.
+    pub lora_adapters: Vec<LoraAdapter>,
+    /// Generation budget; `auto` sizes it per request from the image token count.
+    pub max_new_tokens: MaxNewTokens,
+    /// Largest budget `max_new_tokens = "auto"` may pick.
+    pub max_new_tokens_ceiling: usize,
+    /// Sampling temperature; `0` decodes greedily.
+    pub temperature: f64,
+    /// Nucleus sampling cutoff in `(0, 1]`, applied when `temperature` is above zero.
+    pub top_p: Option<f64>,
+    /// Seed for the sampler RNG and the compute device's RNG. With a seed, runs with the same
+    /// image, prompt and settings produce the same tokens on the CPU; `None` seeds from the OS.
+    pub seed: Option<u64>,
+    /// Decode with beam search over this many hypotheses; `1` keeps greedy or sampled decoding.
+    /// Beam search neither samples nor streams, so it needs `temperature = 0`.
+    pub num_beams: usize,
+    /// Beam hypotheses are ranked by log-probability divided by `length^length_penalty`; above
+    /// `1.0` favours longer outputs.
+    pub length_penalty: f32,
+    /// Divide the positive logits of already generated tokens by this (and multiply negative
+    /// ones), discouraging loops; `1.0` leaves them unchanged.
+    pub repetition_penalty: f32,
+    /// Never generate a token that would repeat an n-gram of this many tokens; `0` disables.
+    pub no_repeat_ngram_size: usize,
     pub use_cache: bool,
+    /// Rotate/flip input images according to their EXIF orientation before preprocessing.
+    pub apply_exif_orientation: bool,
+    /// When the token budget runs out mid-table or mid-list, trim the output back to the last
+    /// complete Markdown structure instead of returning a half-written row, and report a
+    /// `truncated` finish reason. Needs a structured `template` such as `markdown`.
+    pub structure_aware_stop: bool,
+    /// Replace output that is only whitespace or placeholder tokens (typical for blank pages)
+    /// with an empty string, reported with an `empty` finish reason.
+    pub detect_empty_output: bool,
+    /// Never decode the image placeholder token, which carries no text. Turn off for fine-tunes
+    /// that emit it on purpose.
+    pub ban_image_tokens: bool,
+    /// Stop decoding as soon as the output contains one of these strings, which is cut from the
+    /// returned text. Empty by default.
+    pub stop_sequences: Vec<String>,
+    /// Also return the decoded output with special tokens and grounding markup intact, for
+    /// debugging layout parsing.
+    pub raw_output: bool,
+    /// How to handle a multibyte character cut in half by `max_new_tokens`.
+    pub partial_utf8: PartialUtf8,
+    /// Split tall single-image inputs at whitespace gaps and OCR each page-like section separately.
+    pub split_pages: bool,
+    /// Minimum height in pixels of a blank band treated as a page break.
+    pub page_break_min_gap: u32,
+    /// Maximum fraction of dark pixels in a row that still counts as blank.
+    pub page_break_threshold: f32,
     /// Fraction of GPU memory to use for model + cache (0.0 - 1.0)
     pub gpu_memory_utilization: Option<f32>,
+    /// Whether `gpu_memory_utilization` applies to total or currently free device memory.
+    pub gpu_memory_utilization_of: UtilizationOf,
     /// Maximum number of concurrent sequences/batches
     pub max_num_seqs: Option<usize>,
 }
@@ -88,25 +267,261 @@ impl Default for InferenceSettings {
     fn default() -> Self {
         Self {
             device: DeviceKind::Cpu,
+            device_index: 0,
             precision: None,
+            quantize: None,
             template: "plain".to_string(),
             base_size: 1024,
             image_size: 640,
             crop_mode: true,
-            max_new_tokens: 512,
+            strip_aspect_threshold: None,
+            max_tiles: MAX_CROP_TILES,
+            normalization: Normalization::DEEPSEEK_OCR,
+            preprocess: Vec::new(),
+            deskew_max_angle: PreprocessOptions::default().deskew_max_angle,
+            binarize_method: BinarizeMethod::default(),
+            preprocess_device: PreprocessDevice::default(),
+            debug_crops_dir: None,
+            aux_loss: false,
+            flash_nan_check: false,
+            early_exit_layer: None,
+            early_exit_entropy: 0.5,
+            prefill_chunk_size: None,
+            prefix_cache_entries: None,
+            kv_max_seq_len: None,
+            kv_eviction: CacheEviction::default(),
+            kv_compact_stride: None,
+            weight_key_remap: Vec::new(),
+            lora_adapters: Vec::new(),
+            max_new_tokens: MaxNewTokens::default(),
+            max_new_tokens_ceiling: 8192,
+            temperature: 0.0,
+            top_p: None,
+            seed: None,
+            num_beams: 1,
+            length_penalty: 1.0,
+            repetition_penalty: 1.0,
+            no_repeat_ngram_size: 0,
             use_cache: true,
+            apply_exif_orientation: true,
+            structure_aware_stop: false,
+            detect_empty_output: false,
+            ban_image_tokens: true,
+            stop_sequences: Vec::new(),
+            raw_output: false,
+            partial_utf8: PartialUtf8::Drop,
+            split_pages: false,
+            page_break_min_gap: PageBreakOptions::default().min_gap_height,
+            page_break_threshold: PageBreakOptions::default().threshold,
             gpu_memory_utilization: None,
+            gpu_memory_utilization_of: UtilizationOf::default(),
             max_num_seqs: None,
         }
     }
 }
 
+impl InferenceSettings {
+    /// Reject values the model cannot run with, naming the offending field, so they fail at
+    /// startup instead of deep inside the vision or decode code.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.base_size > 0,
+            "inference.base_size must be greater than 0"
+        );
+        ensure!(
+            self.image_size > 0,
+            "inference.image_size must be greater than 0"
+        );
+        ensure!(
+            get_prompt_template(&self.template).is_some(),
+            "inference.template `{}` is not a registered prompt template (known: {})",
+            self.template,
+            prompt_template_names().join(", ")
+        );
+        ensure!(
+            !self.structure_aware_stop
+                || get_prompt_template(&self.template).is_some_and(|t| t.structured_output()),
+            "inference.structure_aware_stop only applies to structured templates such as \
+             `markdown`; `{}` produces plain text",
+            self.template
+        );
+        if self.crop_mode {
+            // Crops are cut at `image_size` and the global view at `base_size`; a crop larger
+            // than the global view is not a layout the vision encoders were trained on.
+            ensure!(
+                self.image_size <= self.base_size,
+                "inference.image_size ({}) must not exceed inference.base_size ({}) when crop_mode is on",
+                self.image_size,
+                self.base_size
+            );
+            ensure!(
+                self.max_tiles >= MIN_CROP_TILES,
+                "inference.max_tiles must be at least {MIN_CROP_TILES}, got {}",
+                self.max_tiles
+            );
+        }
+        if let Some(threshold) = self.strip_aspect_threshold {
+            // Every image is at least 1:1, so a threshold of 1 or less would strip-tile all of
+            // them, squares included.
+            ensure!(
+                threshold.is_finite() && threshold > 1.0,
+                "inference.strip_aspect_threshold must be a number greater than 1, got {threshold}"
+            );
+        }
+        self.normalization
+            .validate()
+            .context("invalid inference.normalization")?;
+        ensure!(
+            self.max_new_tokens != MaxNewTokens::Fixed(0),
+            "inference.max_new_tokens must be greater than 0"
+        );
+        ensure!(
+            self.temperature.is_finite() && self.temperature >= 0.0,
+            "inference.temperature must be a non-negative number, got {}",
+            self.temperature
+        );
+        if let Some(top_p) = self.top_p {
+            ensure!(
+                top_p > 0.0 && top_p <= 1.0,
+                "inference.top_p must be in (0, 1], got {top_p}"
+            );
+        }
+        ensure!(
+            self.num_beams >= 1,
+            "inference.num_beams must be at least 1"
+        );
+        ensure!(
+            self.length_penalty.is_finite(),
+            "inference.length_penalty must be a finite number, got {}",
+            self.length_penalty
+        );
+        ensure!(
+            self.repetition_penalty.is_finite() && self.repetition_penalty > 0.0,
+            "inference.repetition_penalty must be a positive number, got {}",
+            self.repetition_penalty
+        );
+        ensure!(
+            self.num_beams == 1 || self.temperature == 0.0,
+            "inference.num_beams ({}) cannot be combined with sampling; set inference.temperature \
+             to 0",
+            self.num_beams
+        );
+        if let Some(max_seq_len) = self.kv_max_seq_len {
+            ensure!(
+                self.kv_eviction.kept_head() < max_seq_len,
+                "inference.kv_eviction `{}` leaves no room for recent positions within \
+                 inference.kv_max_seq_len ({max_seq_len})",
+                self.kv_eviction
+            );
+        }
+        if let Some(stride) = self.kv_compact_stride {
+            ensure!(stride > 0, "inference.kv_compact_stride must be at least 1");
+            if let Some(max_seq_len) = self.kv_max_seq_len {
+                let window = max_seq_len - self.kv_eviction.kept_head();
+                ensure!(
+                    stride < window,
+                    "inference.kv_compact_stride ({stride}) must be below the {window} positions \
+                     inference.kv_max_seq_len keeps outside the attention sink"
+                );
+            }
+        }
+        if let Some(utilization) = self.gpu_memory_utilization {
+            ensure!(
+                (0.0..=1.0).contains(&utilization),
+                "inference.gpu_memory_utilization must be between 0.0 and 1.0, got {utilization}"
+            );
+        }
+        Ok(())
+    }
+
+    /// These settings with the keys of profile `name` written over them.
+    fn with_profile(&self, name: &str, profile: &toml::Table) -> Result<Self> {
+        let mut merged =
+            toml::Table::try_from(self).context("failed to serialize inference settings")?;
+        // Keys the settings do not serialize (unset options) are taken from the profile as is.
+        merge_written_values("", profile, &mut merged, &mut Vec::new());
+        toml::Value::Table(merged)
+            .try_into()
+            .with_context(|| format!("profile `{name}` does not match the inference settings"))
+    }
+
+    /// Sampling parameters for generation; greedy unless `temperature` is above zero.
+    pub fn sampling(&self) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            seed: self.seed,
+        }
+    }
+
+    /// Beam search settings, when `num_beams` is above one.
+    pub fn beam_search(&self) -> Option<BeamSearch> {
+        (self.num_beams > 1)
+            .then(|| BeamSearch::new(self.num_beams).with_length_penalty(self.length_penalty))
+    }
+
+    /// Repetition penalty for generation, when `repetition_penalty` is not `1.0`.
+    pub fn repetition_penalty(&self) -> Result<Option<RepetitionPenalty>> {
+        if self.repetition_penalty == 1.0 {
+            return Ok(None);
+        }
+        RepetitionPenalty::new(self.repetition_penalty)
+            .map(Some)
+            .context("invalid inference.repetition_penalty")
+    }
+
+    /// N-gram size generation never repeats, when `no_repeat_ngram_size` is above zero.
+    pub fn no_repeat_ngram_size(&self) -> Option<usize> {
+        (self.no_repeat_ngram_size > 0).then_some(self.no_repeat_ngram_size)
+    }
+
+    /// Stop criteria for the configured `stop_sequences`.
+    pub fn stop_criteria(&self) -> StopCriteria {
+        StopCriteria::new().with_strings(self.stop_sequences.iter().cloned())
+    }
+
+    /// Build the configured chain of built-in preprocessors.
+    pub fn preprocess_pipeline(&self) -> PreprocessPipeline {
+        let options = PreprocessOptions {
+            deskew_max_angle: self.deskew_max_angle,
+            binarize_method: self.binarize_method,
+        };
+        PreprocessPipeline::from_builtins_with(&self.preprocess, &options)
+    }
+
+    /// Early-exit settings for the decoder, when `early_exit_layer` is set.
+    pub fn early_exit(&self) -> Option<EarlyExit> {
+        self.early_exit_layer.map(|layer| EarlyExit {
+            layer,
+            max_entropy: self.early_exit_entropy,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ServerSettings {
     pub host: String,
     pub port: u16,
+    /// Port for the gRPC service (servers built with the `grpc` feature). `None` leaves it off.
+    pub grpc_port: Option<u16>,
     pub model_id: String,
+    /// Consecutive inference failures within `breaker_window_secs` that mark the server unready
+    /// and reject new work. `None` disables the circuit breaker.
+    pub breaker_threshold: Option<u32>,
+    /// Window for counting failures, and how long the breaker stays open before letting a trial
+    /// request through.
+    pub breaker_window_secs: u64,
+    /// Reload the model from disk when the breaker trips.
+    pub breaker_reload: bool,
+    /// Generation requests allowed to wait for one of the `max_num_seqs` slots; further
+    /// requests are rejected with 503. `None` lets the queue grow without bound.
+    pub max_queued_requests: Option<usize>,
+    /// Streamed tokens coalesced into one event. `1` sends an event per decode step.
+    pub stream_flush_tokens: usize,
+    /// Send the buffered tokens once this many milliseconds have passed since the last event,
+    /// even if fewer than `stream_flush_tokens` are waiting. `None` flushes on the count alone.
+    pub stream_flush_interval_ms: Option<u64>,
 }
 
 impl Default for ServerSettings {
@@ -114,7 +529,14 @@ impl Default for ServerSettings {
         Self {
             host: "0.0.0.0".to_string(),
             port: 8000,
+            grpc_port: None,
             model_id: DEFAULT_MODEL_ID.to_string(),
+            breaker_threshold: None,
+            breaker_window_secs: 60,
+            breaker_reload: false,
+            max_queued_requests: None,
+            stream_flush_tokens: 1,
+            stream_flush_interval_ms: None,
         }
     }
 }
@@ -134,20 +556,289 @@ impl ResourceLocation {
             ResourceLocation::Physical(path) => Ok(path.display().to_string()),
         }
     }
+
+    /// `name` in the same directory as this location, on the same backend.
+    pub fn sibling(&self, name: &str) -> Option<ResourceLocation> {
+        match self {
+            ResourceLocation::Virtual(path) => {
+                let (_, dir) = path.segments().split_last()?;
+                let mut segments = dir.to_vec();
+                segments.push(name.to_string());
+                Some(ResourceLocation::Virtual(VirtualPath::new(
+                    path.namespace(),
+                    segments,
+                )))
+            }
+            ResourceLocation::Physical(path) => path
+                .parent()
+                .map(|dir| ResourceLocation::Physical(dir.join(name))),
+        }
+    }
+
+    /// Whether a file exists here: looked up through `fs` for virtual paths, on disk for
+    /// physical ones.
+    pub fn exists(&self, fs: &impl VirtualFileSystem) -> Result<bool> {
+        match self {
+            ResourceLocation::Virtual(path) => fs.exists(path),
+            ResourceLocation::Physical(path) => Ok(path.is_file()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ModelResources {
     pub config: ResourceLocation,
     pub tokenizer: ResourceLocation,
+    pub tokenizer_source: TokenizerSource,
     pub weights: ResourceLocation,
 }
 
+/// Where the active model's tokenizer path came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizerSource {
+    /// Set explicitly in the model entry or on the command line.
+    Configured,
+    /// `tokenizer.json` found next to the weights, as in a HuggingFace model directory.
+    WeightsDir,
+    /// The managed per-model path, downloaded on first use.
+    Default,
+}
+
+impl TokenizerSource {
+    pub fn describe(self) -> &'static str {
+        match self {
+            TokenizerSource::Configured => "configured",
+            TokenizerSource::WeightsDir => "found next to weights",
+            TokenizerSource::Default => "default location",
+        }
+    }
+}
+
+/// One setting that differs between two configurations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigFieldDiff {
+    /// Dotted TOML path of the setting, e.g. `inference.max_new_tokens`.
+    pub path: String,
+    /// Value in the first configuration, `None` when unset there.
+    pub old: Option<String>,
+    /// Value in the second configuration, `None` when unset there.
+    pub new: Option<String>,
+}
+
+impl fmt::Display for ConfigFieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "(unset)".into());
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.path,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+/// One change made by [`AppConfig::repair`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigRepairFix {
+    pub diff: ConfigFieldDiff,
+    /// Why the value was changed.
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigRepairFix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.diff, self.reason)
+    }
+}
+
+/// Outcome of [`AppConfig::repair`].
+#[derive(Debug, Clone)]
+pub struct ConfigRepair {
+    /// The repaired configuration.
+    pub config: AppConfig,
+    /// Every change made, in the order it was applied.
+    pub fixes: Vec<ConfigRepairFix>,
+    /// Dotted paths of keys the configuration does not know. They are ignored when loading, and
+    /// kept as-is in [`ConfigRepair::to_toml_string`].
+    pub unknown_fields: Vec<String>,
+    document: DocumentMut,
+}
+
+impl ConfigRepair {
+    /// The repaired file contents: the original file with only the repaired keys changed, so
+    /// comments, key order and formatting elsewhere survive.
+    pub fn to_toml_string(&self) -> Result<String> {
+        Ok(self.document.to_string())
+    }
+}
+
 pub struct ConfigDescriptor {
     pub location: ResourceLocation,
+    /// Format the file was read in, and is written back in by [`save_config`].
+    pub format: ConfigFormat,
+}
+
+/// On-disk configuration syntax, chosen by file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConfigFormat {
+    #[default]
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// `.yaml`/`.yml` and `.json` (any case) select YAML and JSON; anything else is TOML.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, contents: &str) -> Result<T> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(contents)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+            ConfigFormat::Json => serde_json::from_str(contents)?,
+        })
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::to_string_pretty(value)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+            ConfigFormat::Json => {
+                let mut json = serde_json::to_string_pretty(value)?;
+                json.push('\n');
+                json
+            }
+        })
+    }
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigFormat::Toml => "TOML",
+            ConfigFormat::Yaml => "YAML",
+            ConfigFormat::Json => "JSON",
+        })
+    }
 }
 
 impl AppConfig {
+    /// Read a configuration file without creating it or touching the managed directories. Older
+    /// schema versions are migrated in memory only. The format follows the extension (see
+    /// [`ConfigFormat::from_path`]).
+    pub fn load_from_path(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read configuration from {}", path.display()))?;
+        let format = ConfigFormat::from_path(path);
+        let (config, _) = AppConfig::parse_versioned(format, &contents).with_context(|| {
+            format!(
+                "failed to parse {format} configuration at {}",
+                path.display()
+            )
+        })?;
+        Ok(config)
+    }
+
+    /// Parse `contents`, upgrading documents written by an older schema version first. Also
+    /// returns the upgraded document when a migration changed a setting, so the caller can write
+    /// it back; a file that only lacks the newer `version` stamp is left for the next load to
+    /// upgrade in memory again, keeping its comments and layout.
+    /// Keys the schema does not know are kept in that document, recorded in
+    /// [`AppConfig::unknown_fields`] and logged as warnings.
+    pub fn parse_versioned(format: ConfigFormat, contents: &str) -> Result<(Self, Option<String>)> {
+        let mut document: JsonValue = format.parse(contents)?;
+        let migrated = migrate_document(&mut document)?;
+        let mut config: AppConfig = serde_json::from_value(document.clone())?;
+        config.unknown_fields = unknown_settings(&document, &config)?;
+        for field in &config.unknown_fields {
+            warn!("unknown setting `{field}` in the configuration is ignored");
+        }
+        let rewritten = if migrated {
+            Some(format.serialize(&document)?)
+        } else {
+            None
+        };
+        Ok((config, rewritten))
+    }
+
+    /// List every setting whose value differs between `self` and `other`, in path order. Values
+    /// are compared leaf by leaf; lists count as a single value.
+    pub fn diff(&self, other: &AppConfig) -> Result<Vec<ConfigFieldDiff>> {
+        let old = toml::Value::try_from(self).context("failed to serialize configuration")?;
+        let new = toml::Value::try_from(other).context("failed to serialize configuration")?;
+        let mut diffs = Vec::new();
+        diff_values("", Some(&old), Some(&new), &mut diffs);
+        Ok(diffs)
+    }
+
+    /// Parse `contents` leniently and fix the mistakes hand-edited files commonly contain:
+    /// wrongly cased device and precision names, out-of-range `gpu_memory_utilization` and
+    /// `max_num_seqs`, and a registry without an entry for the active model.
+    ///
+    /// Older schema versions are migrated first: an unversioned file only gains the `version`
+    /// stamp, while a migration that changes settings rewrites the whole document. Fails if the
+    /// file is not TOML, was written by a newer version, or still does not match the schema
+    /// afterwards.
+    pub fn repair(contents: &str) -> Result<ConfigRepair> {
+        let mut document: DocumentMut = contents
+            .parse()
+            .context("configuration is not valid TOML")?;
+        let mut migrated: JsonValue = ConfigFormat::Toml.parse(contents)?;
+        if migrate_document(&mut migrated)? {
+            document = ConfigFormat::Toml.serialize(&migrated)?.parse()?;
+        } else if document.get("version").is_none() {
+            document.insert("version", toml_edit::value(i64::from(CONFIG_VERSION)));
+        }
+        let mut fixes = Vec::new();
+        repair_values(&mut document, &mut fixes);
+        let written: JsonValue = ConfigFormat::Toml.parse(&document.to_string())?;
+        let mut config: AppConfig = serde_json::from_value(written.clone())
+            .context("configuration does not match the schema even after repair")?;
+
+        let known: BTreeSet<String> = config.models.entries.keys().cloned().collect();
+        config.normalise_registry();
+        for model_id in config
+            .models
+            .entries
+            .keys()
+            .filter(|id| !known.contains(*id))
+        {
+            let reason = if *model_id == config.models.active {
+                "`models.active` has no entry"
+            } else {
+                "`models.entries` is empty"
+            };
+            insert_model_entry(&mut document, model_id);
+            fixes.push(ConfigRepairFix {
+                diff: ConfigFieldDiff {
+                    path: format!("models.entries.{model_id}"),
+                    old: None,
+                    new: Some("{}".into()),
+                },
+                reason: reason.into(),
+            });
+        }
+
+        let unknown_fields = unknown_settings(&written, &config)?;
+        Ok(ConfigRepair {
+            config,
+            fixes,
+            unknown_fields,
+            document,
+        })
+    }
+
     pub fn load_or_init(
         fs: &impl VirtualFileSystem,
         override_path: Option<&Path>,
@@ -171,6 +862,90 @@ impl AppConfig {
     }
 
     pub fn normalise(&mut self, fs: &impl VirtualFileSystem) -> Result<()> {
+        match self.normalise_per_model(fs)?.into_values().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`Self::normalise`], but a model entry that cannot be resolved (a broken `inherits`
+    /// chain, an unusable model dir) does not fail the whole configuration. Such errors are
+    /// returned by model id instead, for commands that report on every model, such as `fetch`.
+    pub fn normalise_per_model(
+        &mut self,
+        fs: &impl VirtualFileSystem,
+    ) -> Result<BTreeMap<String, anyhow::Error>> {
+        ensure!(
+            !self.strict_config || self.unknown_fields.is_empty(),
+            "unknown settings in the configuration: {}",
+            self.unknown_fields
+                .iter()
+                .map(|field| format!("`{field}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        self.normalise_registry();
+        self.validate_fallback()?;
+        let mut failed = self.resolve_inheritance();
+        self.validate_profiles()?;
+        self.inference.validate()?;
+        for (model_id, entry) in self.models.entries.iter_mut() {
+            if failed.contains_key(model_id) {
+                continue;
+            }
+            if let Err(err) = entry.normalise(fs, model_id) {
+                failed.insert(model_id.clone(), err);
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Check that `models.fallback` names another entry of the registry.
+    fn validate_fallback(&self) -> Result<()> {
+        let Some(fallback) = self.models.fallback.as_deref() else {
+            return Ok(());
+        };
+        ensure!(
+            self.models.entries.contains_key(fallback),
+            "models.fallback `{fallback}` is not in models.entries"
+        );
+        ensure!(
+            fallback != self.models.active,
+            "models.fallback `{fallback}` is the active model; name a different entry"
+        );
+        Ok(())
+    }
+
+    /// Check that the selected profile exists and that every profile merges into valid
+    /// inference settings.
+    fn validate_profiles(&self) -> Result<()> {
+        if let Some(name) = self.profile.as_deref() {
+            ensure!(
+                self.profiles.contains_key(name),
+                "profile `{name}` is not defined in `profiles`"
+            );
+        }
+        for (name, profile) in &self.profiles {
+            self.inference
+                .with_profile(name, profile)?
+                .validate()
+                .with_context(|| format!("invalid profile `{name}`"))?;
+        }
+        Ok(())
+    }
+
+    /// Merge profile `name` over the inference settings.
+    pub fn apply_profile(&mut self, name: &str) -> Result<()> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| anyhow!("profile `{name}` is not defined"))?;
+        self.inference = self.inference.with_profile(name, profile)?;
+        Ok(())
+    }
+
+    /// Make sure the registry has an entry for the active model.
+    fn normalise_registry(&mut self) {
         if self.models.entries.is_empty() {
             self.models
                 .entries
@@ -181,20 +956,103 @@ impl AppConfig {
                 .entries
                 .insert(self.models.active.clone(), ModelEntry::default());
         }
+    }
 
-        for (model_id, entry) in self.models.entries.iter_mut() {
-            entry.normalise(fs, model_id)?;
+    /// Fill each entry's unset fields from the entries it `inherits`, nearest first. Entries
+    /// whose chain is broken are left as they are and returned with the error.
+    fn resolve_inheritance(&mut self) -> BTreeMap<String, anyhow::Error> {
+        let entries = &self.models.entries;
+        let mut resolved = Vec::new();
+        let mut failed = BTreeMap::new();
+        for (model_id, entry) in entries {
+            match Self::inherited_entry(entries, model_id, entry) {
+                Ok(merged) => resolved.push((model_id.clone(), merged)),
+                Err(err) => {
+                    failed.insert(model_id.clone(), err);
+                }
+            }
+        }
+        self.models.entries.extend(resolved);
+        failed
+    }
+
+    fn inherited_entry(
+        entries: &BTreeMap<String, ModelEntry>,
+        model_id: &str,
+        entry: &ModelEntry,
+    ) -> Result<ModelEntry> {
+        let mut merged = entry.clone();
+        let mut chain = vec![model_id];
+        let mut parent_id = entry.inherits.as_deref();
+        while let Some(id) = parent_id {
+            if chain.contains(&id) {
+                chain.push(id);
+                bail!("model inheritance cycle: {}", chain.join(" -> "));
+            }
+            let parent = entries.get(id).ok_or_else(|| {
+                anyhow!(
+                    "model `{}` inherits from `{id}`, which is not in the configuration",
+                    chain[chain.len() - 1]
+                )
+            })?;
+            merged.inherit_from(parent);
+            chain.push(id);
+            parent_id = parent.inherits.as_deref();
+        }
+        Ok(merged)
+    }
+
+    /// Backend the model files are resolved through: `local` itself, or a mirror of
+    /// `models.remote_url` under its cache directory.
+    pub fn model_file_system(&self, local: &LocalFileSystem) -> Result<ModelFileSystem> {
+        match self.models.remote_url.as_deref() {
+            None => Ok(ModelFileSystem::Local(local.clone())),
+            #[cfg(feature = "http")]
+            Some(url) => {
+                let mirror = local.cache_root().join("remote");
+                Ok(ModelFileSystem::Http(crate::fs::HttpFileSystem::new(
+                    url, mirror,
+                )?))
+            }
+            #[cfg(not(feature = "http"))]
+            Some(url) => {
+                bail!("models.remote_url `{url}` requires a build with the `http` feature")
+            }
         }
-        Ok(())
     }
 
     pub fn active_model_resources(&self, fs: &impl VirtualFileSystem) -> Result<ModelResources> {
         self.model_resources(fs, &self.models.active)
     }
 
+    /// Inference settings for the active model, with its per-entry device/precision applied.
+    pub fn active_inference_settings(&self) -> Result<InferenceSettings> {
+        self.model_inference_settings(&self.models.active)
+    }
+
+    /// Inference settings for `model_id`: the global `inference` section with the entry's
+    /// device/precision applied.
+    pub fn model_inference_settings(&self, model_id: &str) -> Result<InferenceSettings> {
+        let entry = self
+            .models
+            .entries
+            .get(model_id)
+            .ok_or_else(|| anyhow!("model `{model_id}` not found in configuration"))?;
+        let mut settings = self.inference.clone();
+        if let Some(device) = entry.device {
+            settings.device = device;
+        }
+        if entry.precision.is_some() {
+            settings.precision = entry.precision;
+        }
+        Ok(settings)
+    }
+
+    /// Where the files of `model_id` live, after downloading any it takes from its `hf_repo`
+    /// that are still missing. Only models resolved here are ever fetched.
     pub fn model_resources(
         &self,
-        _fs: &impl VirtualFileSystem,
+        fs: &impl VirtualFileSystem,
         model_id: &str,
     ) -> Result<ModelResources> {
         let entry = self
@@ -202,10 +1060,20 @@ impl AppConfig {
             .entries
             .get(model_id)
             .ok_or_else(|| anyhow!("model `{model_id}` not found in configuration"))?;
-        Ok(entry.resolved(model_id))
+        entry.fetch_missing(fs, model_id)?;
+        entry.resolved(fs, model_id)
     }
 
     pub fn apply_overrides(&mut self, overrides: &ConfigOverrides) {
+        if let Some(strict) = overrides.strict_config {
+            self.strict_config = strict;
+        }
+        // The profile goes first so the overrides below win over it. A missing or malformed
+        // profile is left for `normalise` to report.
+        if let Some(profile) = overrides.profile.as_ref() {
+            self.profile = Some(profile.clone());
+            let _ = self.apply_profile(profile);
+        }
         if let Some(model_id) = overrides.model_id.as_ref() {
             self.models.active = model_id.clone();
             self.models
@@ -213,6 +1081,12 @@ impl AppConfig {
                 .entry(model_id.clone())
                 .or_insert_with(ModelEntry::default);
         }
+        if let Some(fallback) = overrides.fallback_model.as_ref() {
+            self.models.fallback = Some(fallback.clone());
+        }
+        if let Some(url) = overrides.remote_url.as_ref() {
+            self.models.remote_url = Some(url.clone());
+        }
 
         if let Some(entry) = self.models.entries.get_mut(&self.models.active) {
             if let Some(path) = overrides.model_config.as_ref() {
@@ -224,14 +1098,27 @@ impl AppConfig {
             if let Some(path) = overrides.weights.as_ref() {
                 entry.weights = Some(path.clone());
             }
+            // Command-line device/precision win over the entry's own placement.
+            if overrides.inference.device.is_some() {
+                entry.device = overrides.inference.device;
+            }
+            if overrides.inference.precision.is_some() {
+                entry.precision = overrides.inference.precision;
+            }
         }
 
         if let Some(device) = overrides.inference.device {
             self.inference.device = device;
         }
+        if let Some(index) = overrides.inference.device_index {
+            self.inference.device_index = index;
+        }
         if overrides.inference.precision.is_some() {
             self.inference.precision = overrides.inference.precision;
         }
+        if overrides.inference.quantize.is_some() {
+            self.inference.quantize = overrides.inference.quantize;
+        }
         if let Some(template) = overrides.inference.template.as_ref() {
             self.inference.template = template.clone();
         }
@@ -244,15 +1131,132 @@ impl AppConfig {
         if let Some(crop_mode) = overrides.inference.crop_mode {
             self.inference.crop_mode = crop_mode;
         }
+        if overrides.inference.strip_aspect_threshold.is_some() {
+            self.inference.strip_aspect_threshold = overrides.inference.strip_aspect_threshold;
+        }
+        if let Some(max_tiles) = overrides.inference.max_tiles {
+            self.inference.max_tiles = max_tiles;
+        }
+        if let Some(mean) = overrides.inference.normalization_mean {
+            self.inference.normalization.mean = mean;
+        }
+        if let Some(std) = overrides.inference.normalization_std {
+            self.inference.normalization.std = std;
+        }
+        if let Some(preprocess) = &overrides.inference.preprocess {
+            self.inference.preprocess = preprocess.clone();
+        }
+        if let Some(max_angle) = overrides.inference.deskew_max_angle {
+            self.inference.deskew_max_angle = max_angle;
+        }
+        if let Some(method) = overrides.inference.binarize_method {
+            self.inference.binarize_method = method;
+        }
+        if let Some(device) = overrides.inference.preprocess_device {
+            self.inference.preprocess_device = device;
+        }
+        if overrides.inference.debug_crops_dir.is_some() {
+            self.inference.debug_crops_dir = overrides.inference.debug_crops_dir.clone();
+        }
+        if let Some(aux_loss) = overrides.inference.aux_loss {
+            self.inference.aux_loss = aux_loss;
+        }
+        if let Some(flash_nan_check) = overrides.inference.flash_nan_check {
+            self.inference.flash_nan_check = flash_nan_check;
+        }
+        if overrides.inference.early_exit_layer.is_some() {
+            self.inference.early_exit_layer = overrides.inference.early_exit_layer;
+        }
+        if let Some(entropy) = overrides.inference.early_exit_entropy {
+            self.inference.early_exit_entropy = entropy;
+        }
+        if overrides.inference.prefill_chunk_size.is_some() {
+            self.inference.prefill_chunk_size = overrides.inference.prefill_chunk_size;
+        }
+        if overrides.inference.prefix_cache_entries.is_some() {
+            self.inference.prefix_cache_entries = overrides.inference.prefix_cache_entries;
+        }
+        if overrides.inference.kv_max_seq_len.is_some() {
+            self.inference.kv_max_seq_len = overrides.inference.kv_max_seq_len;
+        }
+        if let Some(eviction) = overrides.inference.kv_eviction {
+            self.inference.kv_eviction = eviction;
+        }
+        if overrides.inference.kv_compact_stride.is_some() {
+            self.inference.kv_compact_stride = overrides.inference.kv_compact_stride;
+        }
+        if let Some(remap) = overrides.inference.weight_key_remap.as_ref() {
+            self.inference.weight_key_remap = remap.clone();
+        }
+        if let Some(adapters) = overrides.inference.lora_adapters.as_ref() {
+            self.inference.lora_adapters = adapters.clone();
+        }
         if let Some(max_new_tokens) = overrides.inference.max_new_tokens {
             self.inference.max_new_tokens = max_new_tokens;
         }
+        if let Some(ceiling) = overrides.inference.max_new_tokens_ceiling {
+            self.inference.max_new_tokens_ceiling = ceiling;
+        }
+        if let Some(temperature) = overrides.inference.temperature {
+            self.inference.temperature = temperature;
+        }
+        if overrides.inference.top_p.is_some() {
+            self.inference.top_p = overrides.inference.top_p;
+        }
+        if overrides.inference.seed.is_some() {
+            self.inference.seed = overrides.inference.seed;
+        }
+        if let Some(num_beams) = overrides.inference.num_beams {
+            self.inference.num_beams = num_beams;
+        }
+        if let Some(length_penalty) = overrides.inference.length_penalty {
+            self.inference.length_penalty = length_penalty;
+        }
+        if let Some(repetition_penalty) = overrides.inference.repetition_penalty {
+            self.inference.repetition_penalty = repetition_penalty;
+        }
+        if let Some(no_repeat_ngram_size) = overrides.inference.no_repeat_ngram_size {
+            self.inference.no_repeat_ngram_size = no_repeat_ngram_size;
+        }
         if let Some(use_cache) = overrides.inference.use_cache {
             self.inference.use_cache = use_cache;
         }
+        if let Some(apply) = overrides.inference.apply_exif_orientation {
+            self.inference.apply_exif_orientation = apply;
+        }
+        if let Some(structure_aware_stop) = overrides.inference.structure_aware_stop {
+            self.inference.structure_aware_stop = structure_aware_stop;
+        }
+        if let Some(detect_empty_output) = overrides.inference.detect_empty_output {
+            self.inference.detect_empty_output = detect_empty_output;
+        }
+        if let Some(ban_image_tokens) = overrides.inference.ban_image_tokens {
+            self.inference.ban_image_tokens = ban_image_tokens;
+        }
+        if let Some(stop_sequences) = &overrides.inference.stop_sequences {
+            self.inference.stop_sequences = stop_sequences.clone();
+        }
+        if let Some(raw_output) = overrides.inference.raw_output {
+            self.inference.raw_output = raw_output;
+        }
+        if let Some(partial_utf8) = overrides.inference.partial_utf8 {
+            self.inference.partial_utf8 = partial_utf8;
+        }
+        if let Some(split_pages) = overrides.inference.split_pages {
+            self.inference.split_pages = split_pages;
+        }
+        if let Some(min_gap) = overrides.inference.page_break_min_gap {
+            self.inference.page_break_min_gap = min_gap;
+        }
+        if let Some(threshold) = overrides.inference.page_break_threshold {
+            self.inference.page_break_threshold = threshold;
+        }
         if overrides.inference.gpu_memory_utilization.is_some() {
             self.inference.gpu_memory_utilization = overrides.inference.gpu_memory_utilization;
         }
+        if let Some(of) = overrides.inference.gpu_memory_utilization_of {
+            self.inference.gpu_memory_utilization_of = of;
+        }
         if overrides.inference.max_num_seqs.is_some() {
             self.inference.max_num_seqs = overrides.inference.max_num_seqs;
         }
@@ -262,13 +1266,46 @@ impl AppConfig {
         if let Some(port) = overrides.server.port {
             self.server.port = port;
         }
+        if overrides.server.grpc_port.is_some() {
+            self.server.grpc_port = overrides.server.grpc_port;
+        }
         if let Some(model_id) = overrides.server.model_id.as_ref() {
             self.server.model_id = model_id.clone();
         }
+        if overrides.server.breaker_threshold.is_some() {
+            self.server.breaker_threshold = overrides.server.breaker_threshold;
+        }
+        if let Some(window) = overrides.server.breaker_window_secs {
+            self.server.breaker_window_secs = window;
+        }
+        if let Some(reload) = overrides.server.breaker_reload {
+            self.server.breaker_reload = reload;
+        }
+        if overrides.server.max_queued_requests.is_some() {
+            self.server.max_queued_requests = overrides.server.max_queued_requests;
+        }
+        if let Some(tokens) = overrides.server.stream_flush_tokens {
+            self.server.stream_flush_tokens = tokens;
+        }
+        if overrides.server.stream_flush_interval_ms.is_some() {
+            self.server.stream_flush_interval_ms = overrides.server.stream_flush_interval_ms;
+        }
     }
 }
 
 impl ModelEntry {
+    fn inherit_from(&mut self, parent: &ModelEntry) {
+        self.config = self.config.take().or_else(|| parent.config.clone());
+        self.tokenizer = self.tokenizer.take().or_else(|| parent.tokenizer.clone());
+        self.weights = self.weights.take().or_else(|| parent.weights.clone());
+        if self.hf_repo.is_none() {
+            self.hf_repo = parent.hf_repo.clone();
+            self.revision = self.revision.take().or_else(|| parent.revision.clone());
+        }
+        self.device = self.device.or(parent.device);
+        self.precision = self.precision.or(parent.precision);
+    }
+
     fn normalise(&mut self, fs: &impl VirtualFileSystem, model_id: &str) -> Result<()> {
         let model_dir = VirtualPath::model_dir(model_id.to_string());
         fs.ensure_dir(&model_dir)?;
@@ -278,23 +1315,343 @@ impl ModelEntry {
         Ok(())
     }
 
-    fn resolved(&self, model_id: &str) -> ModelResources {
+    /// Download the files this entry takes from `hf_repo` that are not in its model dir yet.
+    fn fetch_missing(&self, fs: &impl VirtualFileSystem, model_id: &str) -> Result<()> {
+        if let Some(repo) = self.hf_repo.as_deref() {
+            hub::download_missing(
+                fs,
+                model_id,
+                repo,
+                self.revision.as_deref(),
+                HubFiles {
+                    config: self.config.is_none(),
+                    tokenizer: self.tokenizer.is_none(),
+                    weights: self.weights.is_none(),
+                },
+            )
+            .with_context(|| format!("failed to fetch model `{model_id}` from {repo}"))?;
+        }
+        Ok(())
+    }
+
+    fn resolved(&self, fs: &impl VirtualFileSystem, model_id: &str) -> Result<ModelResources> {
         let config = match &self.config {
             Some(path) => ResourceLocation::Physical(path.clone()),
             None => ResourceLocation::Virtual(VirtualPath::model_config(model_id.to_string())),
         };
-        let tokenizer = match &self.tokenizer {
-            Some(path) => ResourceLocation::Physical(path.clone()),
-            None => ResourceLocation::Virtual(VirtualPath::model_tokenizer(model_id.to_string())),
-        };
+        // Resolved first so a `tokenizer.json` beside the weights is found on whichever backend
+        // holds them.
         let weights = match &self.weights {
             Some(path) => ResourceLocation::Physical(path.clone()),
-            None => ResourceLocation::Virtual(VirtualPath::model_weights(model_id.to_string())),
+            None => {
+                // Sharded checkpoints keep an index instead of a single weights file.
+                let single = VirtualPath::model_weights(model_id.to_string());
+                let index = VirtualPath::model_weights_index(model_id.to_string());
+                if !fs.exists(&single)? && fs.exists(&index)? {
+                    ResourceLocation::Virtual(index)
+                } else {
+                    ResourceLocation::Virtual(single)
+                }
+            }
         };
-        ModelResources {
+        let (tokenizer, tokenizer_source) = match &self.tokenizer {
+            Some(path) => (
+                ResourceLocation::Physical(path.clone()),
+                TokenizerSource::Configured,
+            ),
+            None => match weights.sibling("tokenizer.json") {
+                Some(location) if location.exists(fs)? => (location, TokenizerSource::WeightsDir),
+                _ => (
+                    ResourceLocation::Virtual(VirtualPath::model_tokenizer(model_id.to_string())),
+                    TokenizerSource::Default,
+                ),
+            },
+        };
+        Ok(ModelResources {
             config,
             tokenizer,
+            tokenizer_source,
             weights,
+        })
+    }
+}
+
+fn diff_values(
+    path: &str,
+    old: Option<&toml::Value>,
+    new: Option<&toml::Value>,
+    diffs: &mut Vec<ConfigFieldDiff>,
+) {
+    if let (Some(toml::Value::Table(old)), Some(toml::Value::Table(new))) = (old, new) {
+        let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        for key in keys {
+            let child = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{path}.{key}")
+            };
+            diff_values(&child, old.get(key), new.get(key), diffs);
+        }
+    } else if old != new {
+        diffs.push(ConfigFieldDiff {
+            path: path.to_string(),
+            old: old.map(ToString::to_string),
+            new: new.map(ToString::to_string),
+        });
+    }
+}
+
+/// Value-level fixes applied before the document is deserialized, so they also cover values
+/// that would otherwise fail to parse. Values are replaced in place, keeping their comments.
+fn repair_values(document: &mut DocumentMut, fixes: &mut Vec<ConfigRepairFix>) {
+    if let Some(inference) = document
+        .get_mut("inference")
+        .and_then(Item::as_table_like_mut)
+    {
+        fix_case::<DeviceKind>(inference, "inference", "device", fixes);
+        fix_case::<Precision>(inference, "inference", "precision", fixes);
+        repair_utilization(inference, fixes);
+        if inference.get("max_num_seqs").and_then(Item::as_integer) == Some(0) {
+            unset(
+                inference,
+                "inference",
+                "max_num_seqs",
+                "must be greater than 0; unset for no limit",
+                fixes,
+            );
+        }
+    }
+    let Some(models) = document.get_mut("models").and_then(Item::as_table_like_mut) else {
+        return;
+    };
+    let Some(entries) = models.get_mut("entries").and_then(Item::as_table_like_mut) else {
+        return;
+    };
+    for (model_id, entry) in entries.iter_mut() {
+        let Some(entry) = entry.as_table_like_mut() else {
+            continue;
+        };
+        let path = format!("models.entries.{}", model_id.get());
+        fix_case::<DeviceKind>(entry, &path, "device", fixes);
+        fix_case::<Precision>(entry, &path, "precision", fixes);
+    }
+}
+
+/// Lowercase an enum name such as `CUDA` when only the lowercase spelling parses.
+fn fix_case<T: DeserializeOwned>(
+    table: &mut dyn TableLike,
+    path: &str,
+    key: &str,
+    fixes: &mut Vec<ConfigRepairFix>,
+) {
+    let Some(name) = table.get(key).and_then(Item::as_str) else {
+        return;
+    };
+    let lower = name.to_lowercase();
+    let parses = |name: &str| T::deserialize(toml::Value::String(name.to_owned())).is_ok();
+    if lower == name || parses(name) || !parses(&lower) {
+        return;
+    }
+    replace_value(table, path, key, lower.into(), "names are lowercase", fixes);
+}
+
+fn repair_utilization(inference: &mut dyn TableLike, fixes: &mut Vec<ConfigRepairFix>) {
+    const KEY: &str = "gpu_memory_utilization";
+    let utilization = match inference.get(KEY) {
+        Some(item) if item.is_float() => item.as_float().unwrap_or_default(),
+        Some(item) if item.is_integer() => item.as_integer().unwrap_or_default() as f64,
+        _ => return,
+    };
+    if utilization > 0.0 && utilization <= 1.0 {
+        return;
+    }
+    if utilization > 1.0 && utilization <= 100.0 {
+        replace_value(
+            inference,
+            "inference",
+            KEY,
+            (utilization / 100.0).into(),
+            "looks like a percentage; expected a fraction between 0.0 and 1.0",
+            fixes,
+        );
+    } else {
+        unset(
+            inference,
+            "inference",
+            KEY,
+            "must be between 0.0 and 1.0; unset to use the whole device",
+            fixes,
+        );
+    }
+}
+
+/// Swap the value of `key` for `new`, keeping the whitespace and comment around it.
+fn replace_value(
+    table: &mut dyn TableLike,
+    path: &str,
+    key: &str,
+    mut new: toml_edit::Value,
+    reason: &str,
+    fixes: &mut Vec<ConfigRepairFix>,
+) {
+    let Some(old) = table.get_mut(key).and_then(Item::as_value_mut) else {
+        return;
+    };
+    *new.decor_mut() = old.decor().clone();
+    let old = std::mem::replace(old, new.clone());
+    fixes.push(ConfigRepairFix {
+        diff: ConfigFieldDiff {
+            path: format!("{path}.{key}"),
+            old: Some(show_value(&old)),
+            new: Some(show_value(&new)),
+        },
+        reason: reason.into(),
+    });
+}
+
+fn unset(
+    table: &mut dyn TableLike,
+    path: &str,
+    key: &str,
+    reason: &str,
+    fixes: &mut Vec<ConfigRepairFix>,
+) {
+    let old = table.remove(key);
+    fixes.push(ConfigRepairFix {
+        diff: ConfigFieldDiff {
+            path: format!("{path}.{key}"),
+            old: old.as_ref().and_then(Item::as_value).map(show_value),
+            new: None,
+        },
+        reason: reason.into(),
+    });
+}
+
+/// A value as written in TOML, without its surrounding whitespace and comments.
+fn show_value(value: &toml_edit::Value) -> String {
+    value.clone().decorated("", "").to_string()
+}
+
+/// Add an empty `models.entries.<model_id>` table, inline when `models.entries` is written as an
+/// inline table.
+fn insert_model_entry(document: &mut DocumentMut, model_id: &str) {
+    let models = document.entry("models").or_insert_with(implicit_table);
+    if let Some(models) = models.as_table_mut() {
+        models.entry("entries").or_insert_with(implicit_table);
+    }
+    let entries = &mut document["models"]["entries"];
+    entries[model_id] = if entries.is_table() {
+        Item::Table(Table::new())
+    } else {
+        toml_edit::value(InlineTable::new())
+    };
+}
+
+/// A table that only gets a header of its own once it holds keys.
+fn implicit_table() -> Item {
+    let mut table = Table::new();
+    table.set_implicit(true);
+    Item::Table(table)
+}
+
+/// Lay the values written in `original` over the serialized configuration, so they keep their
+/// spelling (`0.9` rather than the `f32` round trip), and record the paths of keys the typed
+/// configuration dropped. Empty tables are copied without being reported, since empty sections
+/// such as `[model_config_overrides]` are simply skipped when serializing.
+fn merge_written_values(
+    path: &str,
+    original: &toml::Table,
+    repaired: &mut toml::Table,
+    unknown: &mut Vec<String>,
+) {
+    for (key, value) in original {
+        let child = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        match (value, repaired.get_mut(key)) {
+            (toml::Value::Table(original), Some(toml::Value::Table(repaired))) => {
+                merge_written_values(&child, original, repaired, unknown);
+            }
+            (value, Some(slot)) => *slot = value.clone(),
+            (value, None) => {
+                if !matches!(value, toml::Value::Table(table) if table.is_empty()) {
+                    unknown.push(child);
+                }
+                repaired.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Upgrade `document` to [`CONFIG_VERSION`] in place and report whether any setting changed
+/// beyond the stamped `version`. Files without a `version` key predate versioning and count as
+/// version 0; versions newer than this build are rejected rather than parsed with a schema that
+/// may mean something else.
+fn migrate_document(document: &mut JsonValue) -> Result<bool> {
+    let document = document
+        .as_object_mut()
+        .context("configuration must be a table of settings")?;
+    let version = match document.get("version") {
+        None => 0,
+        Some(value) => value
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .with_context(|| format!("`version` must be a non-negative integer, got {value}"))?,
+    };
+    ensure!(
+        version <= CONFIG_VERSION,
+        "configuration version {version} is newer than the supported version {CONFIG_VERSION}; \
+         upgrade deepseek-ocr to read it"
+    );
+    if version == CONFIG_VERSION {
+        return Ok(false);
+    }
+    let mut changed = false;
+    for migrate in &MIGRATIONS[version as usize..] {
+        changed |= migrate(document);
+    }
+    document.insert("version".into(), CONFIG_VERSION.into());
+    Ok(changed)
+}
+
+/// Unversioned files: every field added since then has a default, so the layout carries over
+/// unchanged and only the version is stamped.
+fn migrate_v0(_document: &mut JsonMap<String, JsonValue>) -> bool {
+    false
+}
+
+/// Dotted paths of the keys in `written` that were dropped when deserializing it into `config`.
+/// Profiles are checked against the inference settings they are merged over. Empty tables are
+/// not reported, matching [`merge_written_values`].
+fn unknown_settings(written: &JsonValue, config: &AppConfig) -> Result<Vec<String>> {
+    let mut known = serde_json::to_value(config)?;
+    let inference = known["inference"].clone();
+    if let Some(profiles) = known.get_mut("profiles").and_then(JsonValue::as_object_mut) {
+        for profile in profiles.values_mut() {
+            *profile = inference.clone();
+        }
+    }
+    let mut unknown = Vec::new();
+    collect_unknown("", written, &known, &mut unknown);
+    Ok(unknown)
+}
+
+fn collect_unknown(path: &str, written: &JsonValue, known: &JsonValue, unknown: &mut Vec<String>) {
+    let (Some(written), Some(known)) = (written.as_object(), known.as_object()) else {
+        return;
+    };
+    for (key, value) in written {
+        let child = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        match known.get(key) {
+            Some(known) => collect_unknown(&child, value, known, unknown),
+            None if value.as_object().is_some_and(JsonMap::is_empty) => {}
+            None => unknown.push(child),
         }
     }
 }
@@ -304,25 +1661,34 @@ fn load_virtual_config(fs: &impl VirtualFileSystem) -> Result<(AppConfig, Config
     if !fs.exists(&path)? {
         let mut cfg = AppConfig::default();
         cfg.normalise(fs)?;
-        let serialized = toml::to_string_pretty(&cfg)?;
+        let serialized = ConfigFormat::Toml.serialize(&cfg)?;
         fs.write(&path, serialized.as_bytes())?;
         return Ok((
             cfg,
             ConfigDescriptor {
                 location: ResourceLocation::Virtual(path),
+                format: ConfigFormat::Toml,
             },
         ));
     }
 
     let bytes = fs.read(&path)?;
     let contents = String::from_utf8(bytes).context("configuration file is not valid UTF-8")?;
-    let mut cfg: AppConfig =
-        toml::from_str(&contents).context("failed to parse configuration file")?;
+    let (mut cfg, migrated) = AppConfig::parse_versioned(ConfigFormat::Toml, &contents)
+        .context("failed to parse configuration file")?;
+    if let Some(migrated) = migrated {
+        // The migrated settings are already in use; failing to persist them only means the next
+        // load migrates again.
+        if let Err(err) = fs.write(&path, migrated.as_bytes()) {
+            warn!("failed to write the migrated configuration back: {err:#}");
+        }
+    }
     cfg.normalise(fs)?;
     Ok((
         cfg,
         ConfigDescriptor {
             location: ResourceLocation::Virtual(path),
+            format: ConfigFormat::Toml,
         },
     ))
 }
@@ -332,6 +1698,7 @@ fn load_physical_config(
     path: &Path,
 ) -> Result<(AppConfig, ConfigDescriptor)> {
     let path_buf = path.to_path_buf();
+    let format = ConfigFormat::from_path(path);
     if !path.exists() {
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)
@@ -339,26 +1706,41 @@ fn load_physical_config(
         }
         let mut cfg = AppConfig::default();
         cfg.normalise(fs)?;
-        let serialized = toml::to_string_pretty(&cfg)?;
+        let serialized = format.serialize(&cfg)?;
         fs::write(&path_buf, serialized)
             .with_context(|| format!("failed to write configuration to {}", path_buf.display()))?;
         return Ok((
             cfg,
             ConfigDescriptor {
                 location: ResourceLocation::Physical(path_buf),
+                format,
             },
         ));
     }
 
     let contents = fs::read_to_string(&path_buf)
         .with_context(|| format!("failed to read configuration from {}", path_buf.display()))?;
-    let mut cfg: AppConfig = toml::from_str(&contents)
-        .with_context(|| format!("failed to parse configuration at {}", path_buf.display()))?;
+    let (mut cfg, migrated) = AppConfig::parse_versioned(format, &contents).with_context(|| {
+        format!(
+            "failed to parse {format} configuration at {}",
+            path_buf.display()
+        )
+    })?;
+    if let Some(migrated) = migrated {
+        // A read-only file, e.g. one mounted into a container, is still usable as it is.
+        if let Err(err) = fs::write(&path_buf, migrated) {
+            warn!(
+                "failed to write the migrated configuration back to {}: {err}",
+                path_buf.display()
+            );
+        }
+    }
     cfg.normalise(fs)?;
     Ok((
         cfg,
         ConfigDescriptor {
             location: ResourceLocation::Physical(path_buf),
+            format,
         },
     ))
 }
@@ -367,6 +1749,13 @@ fn load_physical_config(
 pub struct ConfigOverrides {
     pub config_path: Option<PathBuf>,
     pub model_id: Option<String>,
+    /// Entry of `profiles` merged over the inference settings before the other overrides.
+    pub profile: Option<String>,
+    /// Fail instead of warning when the file has settings no field recognises.
+    pub strict_config: Option<bool>,
+    pub fallback_model: Option<String>,
+    /// Download model files from this base URL (see [`ModelRegistry::remote_url`]).
+    pub remote_url: Option<String>,
     pub model_config: Option<PathBuf>,
     pub tokenizer: Option<PathBuf>,
     pub weights: Option<PathBuf>,
@@ -377,14 +1766,55 @@ pub struct ConfigOverrides {
 #[derive(Debug, Default, Clone)]
 pub struct InferenceOverride {
     pub device: Option<DeviceKind>,
+    pub device_index: Option<usize>,
     pub precision: Option<Precision>,
+    pub quantize: Option<WeightQuant>,
     pub template: Option<String>,
     pub base_size: Option<u32>,
     pub image_size: Option<u32>,
     pub crop_mode: Option<bool>,
-    pub max_new_tokens: Option<usize>,
+    pub strip_aspect_threshold: Option<f32>,
+    pub max_tiles: Option<u32>,
+    pub normalization_mean: Option<[f32; 3]>,
+    pub normalization_std: Option<[f32; 3]>,
+    pub preprocess: Option<Vec<BuiltinPreprocessor>>,
+    pub deskew_max_angle: Option<f32>,
+    pub binarize_method: Option<BinarizeMethod>,
+    pub preprocess_device: Option<PreprocessDevice>,
+    pub debug_crops_dir: Option<PathBuf>,
+    pub aux_loss: Option<bool>,
+    pub flash_nan_check: Option<bool>,
+    pub early_exit_layer: Option<usize>,
+    pub early_exit_entropy: Option<f32>,
+    pub prefill_chunk_size: Option<usize>,
+    pub prefix_cache_entries: Option<usize>,
+    pub kv_max_seq_len: Option<usize>,
+    pub kv_eviction: Option<CacheEviction>,
+    pub kv_compact_stride: Option<usize>,
+    pub weight_key_remap: Option<Vec<WeightKeyRemap>>,
+    pub lora_adapters: Option<Vec<LoraAdapter>>,
+    pub max_new_tokens: Option<MaxNewTokens>,
+    pub max_new_tokens_ceiling: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub num_beams: Option<usize>,
+    pub length_penalty: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub no_repeat_ngram_size: Option<usize>,
     pub use_cache: Option<bool>,
+    pub apply_exif_orientation: Option<bool>,
+    pub structure_aware_stop: Option<bool>,
+    pub detect_empty_output: Option<bool>,
+    pub ban_image_tokens: Option<bool>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub raw_output: Option<bool>,
+    pub partial_utf8: Option<PartialUtf8>,
+    pub split_pages: Option<bool>,
+    pub page_break_min_gap: Option<u32>,
+    pub page_break_threshold: Option<f32>,
     pub gpu_memory_utilization: Option<f32>,
+    pub gpu_memory_utilization_of: Option<UtilizationOf>,
     pub max_num_seqs: Option<usize>,
 }
 
@@ -392,7 +1822,122 @@ pub struct InferenceOverride {
 pub struct ServerOverride {
     pub host: Option<String>,
     pub port: Option<u16>,
+    pub grpc_port: Option<u16>,
     pub model_id: Option<String>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_window_secs: Option<u64>,
+    pub breaker_reload: Option<bool>,
+    pub max_queued_requests: Option<usize>,
+    pub stream_flush_tokens: Option<usize>,
+    pub stream_flush_interval_ms: Option<u64>,
+}
+
+impl ConfigOverrides {
+    /// Overrides read from `DEEPSEEK_OCR_*` environment variables, for containers without a
+    /// mounted config file. Unset or empty variables leave the setting alone; a value that does
+    /// not parse is an error naming the variable. Only the settings below are read; the others,
+    /// including every list- and path-valued one, come from the file or command-line flags.
+    ///
+    /// | Variable | Setting |
+    /// | --- | --- |
+    /// | `DEEPSEEK_OCR_MODEL` | `models.active` |
+    /// | `DEEPSEEK_OCR_FALLBACK_MODEL` | `models.fallback` |
+    /// | `DEEPSEEK_OCR_REMOTE_URL` | `models.remote_url` |
+    /// | `DEEPSEEK_OCR_PROFILE` | profile from `profiles` |
+    /// | `DEEPSEEK_OCR_STRICT_CONFIG` | reject unknown settings |
+    /// | `DEEPSEEK_OCR_DEVICE` | `inference.device` |
+    /// | `DEEPSEEK_OCR_DEVICE_INDEX` | `inference.device_index` |
+    /// | `DEEPSEEK_OCR_PRECISION` | `inference.precision` |
+    /// | `DEEPSEEK_OCR_QUANTIZE` | `inference.quantize` |
+    /// | `DEEPSEEK_OCR_TEMPLATE` | `inference.template` |
+    /// | `DEEPSEEK_OCR_BASE_SIZE` | `inference.base_size` |
+    /// | `DEEPSEEK_OCR_IMAGE_SIZE` | `inference.image_size` |
+    /// | `DEEPSEEK_OCR_CROP_MODE` | `inference.crop_mode` |
+    /// | `DEEPSEEK_OCR_MAX_NEW_TOKENS` | `inference.max_new_tokens` |
+    /// | `DEEPSEEK_OCR_MAX_NEW_TOKENS_CEILING` | `inference.max_new_tokens_ceiling` |
+    /// | `DEEPSEEK_OCR_USE_CACHE` | `inference.use_cache` |
+    /// | `DEEPSEEK_OCR_KV_MAX_SEQ_LEN` | `inference.kv_max_seq_len` |
+    /// | `DEEPSEEK_OCR_KV_EVICTION` | `inference.kv_eviction` |
+    /// | `DEEPSEEK_OCR_MAX_NUM_SEQS` | `inference.max_num_seqs` |
+    /// | `DEEPSEEK_OCR_GPU_MEMORY_UTILIZATION` | `inference.gpu_memory_utilization` |
+    /// | `DEEPSEEK_OCR_PREPROCESS_DEVICE` | `inference.preprocess_device` |
+    /// | `DEEPSEEK_OCR_HOST` | `server.host` |
+    /// | `DEEPSEEK_OCR_PORT` | `server.port` |
+    /// | `DEEPSEEK_OCR_GRPC_PORT` | `server.grpc_port` |
+    /// | `DEEPSEEK_OCR_MAX_QUEUED_REQUESTS` | `server.max_queued_requests` |
+    pub fn from_env() -> Result<Self> {
+        let mut overrides = ConfigOverrides {
+            model_id: env_override("DEEPSEEK_OCR_MODEL", parse_from_str)?,
+            profile: env_override("DEEPSEEK_OCR_PROFILE", parse_from_str)?,
+            strict_config: env_override("DEEPSEEK_OCR_STRICT_CONFIG", parse_from_str)?,
+            fallback_model: env_override("DEEPSEEK_OCR_FALLBACK_MODEL", parse_from_str)?,
+            remote_url: env_override("DEEPSEEK_OCR_REMOTE_URL", parse_from_str)?,
+            ..ConfigOverrides::default()
+        };
+
+        let inference = &mut overrides.inference;
+        inference.device = env_override("DEEPSEEK_OCR_DEVICE", parse_value_enum)?;
+        inference.device_index = env_override("DEEPSEEK_OCR_DEVICE_INDEX", parse_from_str)?;
+        inference.precision = env_override("DEEPSEEK_OCR_PRECISION", parse_value_enum)?;
+        inference.quantize = env_override("DEEPSEEK_OCR_QUANTIZE", parse_value_enum)?;
+        inference.template = env_override("DEEPSEEK_OCR_TEMPLATE", parse_from_str)?;
+        inference.base_size = env_override("DEEPSEEK_OCR_BASE_SIZE", parse_from_str)?;
+        inference.image_size = env_override("DEEPSEEK_OCR_IMAGE_SIZE", parse_from_str)?;
+        inference.crop_mode = env_override("DEEPSEEK_OCR_CROP_MODE", parse_from_str)?;
+        inference.max_new_tokens = env_override("DEEPSEEK_OCR_MAX_NEW_TOKENS", parse_from_str)?;
+        inference.max_new_tokens_ceiling =
+            env_override("DEEPSEEK_OCR_MAX_NEW_TOKENS_CEILING", parse_from_str)?;
+        inference.use_cache = env_override("DEEPSEEK_OCR_USE_CACHE", parse_from_str)?;
+        inference.kv_max_seq_len = env_override("DEEPSEEK_OCR_KV_MAX_SEQ_LEN", parse_from_str)?;
+        inference.kv_eviction = env_override("DEEPSEEK_OCR_KV_EVICTION", parse_from_str)?;
+        inference.max_num_seqs = env_override("DEEPSEEK_OCR_MAX_NUM_SEQS", parse_from_str)?;
+        inference.gpu_memory_utilization =
+            env_override("DEEPSEEK_OCR_GPU_MEMORY_UTILIZATION", parse_from_str)?;
+        inference.preprocess_device =
+            env_override("DEEPSEEK_OCR_PREPROCESS_DEVICE", parse_value_enum)?;
+
+        let server = &mut overrides.server;
+        server.host = env_override("DEEPSEEK_OCR_HOST", parse_from_str)?;
+        server.port = env_override("DEEPSEEK_OCR_PORT", parse_from_str)?;
+        server.grpc_port = env_override("DEEPSEEK_OCR_GRPC_PORT", parse_from_str)?;
+        server.max_queued_requests =
+            env_override("DEEPSEEK_OCR_MAX_QUEUED_REQUESTS", parse_from_str)?;
+        Ok(overrides)
+    }
+}
+
+fn env_override<T>(name: &str, parse: impl FnOnce(&str) -> Result<T>) -> Result<Option<T>> {
+    let raw = match env::var(name) {
+        Ok(raw) => raw,
+        Err(env::VarError::NotPresent) => return Ok(None),
+        Err(env::VarError::NotUnicode(_)) => bail!("{name} is not valid UTF-8"),
+    };
+    let value = raw.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    parse(value)
+        .map(Some)
+        .with_context(|| format!("invalid value `{value}` for {name}"))
+}
+
+fn parse_from_str<T>(value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|err| anyhow!("{err}"))
+}
+
+fn parse_value_enum<T: ValueEnum>(value: &str) -> Result<T> {
+    T::from_str(value, true).map_err(|_| {
+        let expected: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|possible| possible.get_name().to_string())
+            .collect();
+        anyhow!("expected one of {}", expected.join(", "))
+    })
 }
 
 pub trait ConfigOverride {
@@ -422,7 +1967,7 @@ pub fn save_config(
     descriptor: &ConfigDescriptor,
     config: &AppConfig,
 ) -> Result<()> {
-    let serialized = toml::to_string_pretty(config)?;
+    let serialized = descriptor.format.serialize(config)?;
     match &descriptor.location {
         ResourceLocation::Virtual(path) => fs.write(path, serialized.as_bytes()),
         ResourceLocation::Physical(path) => fs::write(path, serialized)
@@ -1,9 +1,19 @@
 use std::{
+    collections::HashMap,
     fs,
     path::{Path, PathBuf},
+    sync::{
+        Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
+#[cfg(feature = "http")]
+use reqwest::{
+    StatusCode, Url,
+    blocking::{Client, Response},
+};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum Namespace {
@@ -65,6 +75,10 @@ impl VirtualPath {
     pub fn model_weights(model_id: impl Into<String>) -> Self {
         Self::model_dir(model_id).join("model.safetensors")
     }
+
+    pub fn model_weights_index(model_id: impl Into<String>) -> Self {
+        Self::model_dir(model_id).join(crate::hub::WEIGHTS_INDEX_FILE)
+    }
 }
 
 /// Abstraction over storage backends used by the application.
@@ -116,6 +130,10 @@ impl LocalFileSystem {
         &self.app_name
     }
 
+    pub fn cache_root(&self) -> &Path {
+        &self.cache_root
+    }
+
     fn resolve<'a>(&'a self, path: &VirtualPath) -> Result<PathBuf> {
         let root = match path.namespace() {
             Namespace::Config => &self.config_root,
@@ -179,6 +197,331 @@ impl VirtualFileSystem for LocalFileSystem {
     }
 }
 
+/// Read-only remote backend: paths resolve against a base URL and files are downloaded on first
+/// use into a local mirror, which also backs [`VirtualFileSystem::with_physical_path`] since
+/// weights are memory-mapped from a real file.
+///
+/// Both namespaces map onto the same URL tree (`{base}/models/<id>/config.json`,
+/// `{base}/config.toml`) but stay apart in the mirror. Writes, directory creation and removals
+/// only touch the mirror; nothing is ever uploaded.
+#[cfg(feature = "http")]
+#[derive(Debug, Clone)]
+pub struct HttpFileSystem {
+    base_url: Url,
+    mirror_root: PathBuf,
+    client: Client,
+}
+
+#[cfg(feature = "http")]
+impl HttpFileSystem {
+    pub fn new(base_url: &str, mirror_root: PathBuf) -> Result<Self> {
+        let base_url =
+            Url::parse(base_url).with_context(|| format!("invalid base URL `{base_url}`"))?;
+        if base_url.cannot_be_a_base() {
+            return Err(anyhow!("`{base_url}` cannot be used as a base URL"));
+        }
+        let client = Client::builder()
+            .timeout(None)
+            .build()
+            .context("failed to build HTTP client")?;
+        Ok(Self {
+            base_url,
+            mirror_root,
+            client,
+        })
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Remote location of `path`; segments are percent-encoded.
+    pub fn url(&self, path: &VirtualPath) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("base URL checked in HttpFileSystem::new")
+            .pop_if_empty()
+            .extend(path.segments());
+        url
+    }
+
+    fn mirror_path(&self, path: &VirtualPath) -> PathBuf {
+        let mut buf = self.mirror_root.join(match path.namespace() {
+            Namespace::Config => "config",
+            Namespace::Cache => "cache",
+        });
+        for segment in path.segments() {
+            buf.push(segment);
+        }
+        buf
+    }
+
+    /// Mirror `path` locally, downloading it unless a copy is already there.
+    fn fetch(&self, path: &VirtualPath) -> Result<PathBuf> {
+        let physical = self.mirror_path(path);
+        if physical.exists() {
+            return Ok(physical);
+        }
+        let url = self.url(path);
+        let mut response = self.get(&url)?;
+        if let Some(parent) = physical.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        // Download next to the target and rename, so an interrupted transfer is never mistaken
+        // for a complete mirror.
+        let mut partial = physical.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        let mut file = fs::File::create(&partial)
+            .with_context(|| format!("failed to create {}", partial.display()))?;
+        response
+            .copy_to(&mut file)
+            .with_context(|| format!("failed to download {url}"))?;
+        drop(file);
+        fs::rename(&partial, &physical)
+            .with_context(|| format!("failed to move download to {}", physical.display()))?;
+        Ok(physical)
+    }
+
+    fn get(&self, url: &Url) -> Result<Response> {
+        self.client
+            .get(url.clone())
+            .send()
+            .and_then(Response::error_for_status)
+            .with_context(|| format!("failed to fetch {url}"))
+    }
+}
+
+#[cfg(feature = "http")]
+impl VirtualFileSystem for HttpFileSystem {
+    fn read(&self, path: &VirtualPath) -> Result<Vec<u8>> {
+        let physical = self.fetch(path)?;
+        fs::read(&physical).with_context(|| format!("failed to read {}", physical.display()))
+    }
+
+    fn write(&self, path: &VirtualPath, contents: &[u8]) -> Result<()> {
+        let physical = self.mirror_path(path);
+        self.ensure_parent(path)?;
+        fs::write(&physical, contents)
+            .with_context(|| format!("failed to write {}", physical.display()))
+    }
+
+    fn exists(&self, path: &VirtualPath) -> Result<bool> {
+        if self.mirror_path(path).exists() {
+            return Ok(true);
+        }
+        let url = self.url(path);
+        let response = self
+            .client
+            .head(url.clone())
+            .send()
+            .with_context(|| format!("failed to query {url}"))?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(false),
+            status => Err(anyhow!("HEAD {url} returned {status}")),
+        }
+    }
+
+    fn ensure_dir(&self, path: &VirtualPath) -> Result<()> {
+        let physical = self.mirror_path(path);
+        fs::create_dir_all(&physical)
+            .with_context(|| format!("failed to create directory {}", physical.display()))
+    }
+
+    fn ensure_parent(&self, path: &VirtualPath) -> Result<()> {
+        let physical = self.mirror_path(path);
+        if let Some(parent) = physical.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &VirtualPath) -> Result<()> {
+        let physical = self.mirror_path(path);
+        if physical.exists() {
+            fs::remove_file(&physical)
+                .with_context(|| format!("failed to remove {}", physical.display()))?;
+        }
+        Ok(())
+    }
+
+    fn with_physical_path<F, T>(&self, path: &VirtualPath, func: F) -> Result<T>
+    where
+        F: FnOnce(&Path) -> Result<T>,
+    {
+        let physical = self.fetch(path)?;
+        func(&physical)
+    }
+}
+
+/// Backend model files are resolved through: the local cache, or a mirror of
+/// `models.remote_url` (see [`AppConfig::model_file_system`](crate::AppConfig::model_file_system)).
+#[derive(Debug, Clone)]
+pub enum ModelFileSystem {
+    Local(LocalFileSystem),
+    #[cfg(feature = "http")]
+    Http(HttpFileSystem),
+}
+
+impl VirtualFileSystem for ModelFileSystem {
+    fn read(&self, path: &VirtualPath) -> Result<Vec<u8>> {
+        match self {
+            Self::Local(fs) => fs.read(path),
+            #[cfg(feature = "http")]
+            Self::Http(fs) => fs.read(path),
+        }
+    }
+
+    fn write(&self, path: &VirtualPath, contents: &[u8]) -> Result<()> {
+        match self {
+            Self::Local(fs) => fs.write(path, contents),
+            #[cfg(feature = "http")]
+            Self::Http(fs) => fs.write(path, contents),
+        }
+    }
+
+    fn exists(&self, path: &VirtualPath) -> Result<bool> {
+        match self {
+            Self::Local(fs) => fs.exists(path),
+            #[cfg(feature = "http")]
+            Self::Http(fs) => fs.exists(path),
+        }
+    }
+
+    fn ensure_dir(&self, path: &VirtualPath) -> Result<()> {
+        match self {
+            Self::Local(fs) => fs.ensure_dir(path),
+            #[cfg(feature = "http")]
+            Self::Http(fs) => fs.ensure_dir(path),
+        }
+    }
+
+    fn ensure_parent(&self, path: &VirtualPath) -> Result<()> {
+        match self {
+            Self::Local(fs) => fs.ensure_parent(path),
+            #[cfg(feature = "http")]
+            Self::Http(fs) => fs.ensure_parent(path),
+        }
+    }
+
+    fn remove_file(&self, path: &VirtualPath) -> Result<()> {
+        match self {
+            Self::Local(fs) => fs.remove_file(path),
+            #[cfg(feature = "http")]
+            Self::Http(fs) => fs.remove_file(path),
+        }
+    }
+
+    fn with_physical_path<F, T>(&self, path: &VirtualPath, func: F) -> Result<T>
+    where
+        F: FnOnce(&Path) -> Result<T>,
+    {
+        match self {
+            Self::Local(fs) => fs.with_physical_path(path, func),
+            #[cfg(feature = "http")]
+            Self::Http(fs) => fs.with_physical_path(path, func),
+        }
+    }
+}
+
+/// Backend that keeps every file in memory, for exercising config loading without touching disk.
+///
+/// Directories are implicit: a path exists when it is a file or a prefix of one, and
+/// `ensure_dir`/`ensure_parent` do nothing. [`VirtualFileSystem::with_physical_path`] is the only
+/// operation that reaches the disk: it spills the file (if any) into a fresh temporary
+/// directory, stores back whatever the callback left there, and removes the directory again.
+#[derive(Debug, Default)]
+pub struct MemoryFileSystem {
+    files: Mutex<HashMap<VirtualPath, Vec<u8>>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed `path` with `contents`, e.g. a config file a test wants loaded.
+    pub fn with_file(self, path: VirtualPath, contents: impl Into<Vec<u8>>) -> Self {
+        self.files().insert(path, contents.into());
+        self
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<VirtualPath, Vec<u8>>> {
+        self.files.lock().expect("memory file system lock poisoned")
+    }
+}
+
+impl VirtualFileSystem for MemoryFileSystem {
+    fn read(&self, path: &VirtualPath) -> Result<Vec<u8>> {
+        self.files()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| anyhow!("no such file in memory: {}", path.segments().join("/")))
+    }
+
+    fn write(&self, path: &VirtualPath, contents: &[u8]) -> Result<()> {
+        self.files().insert(path.clone(), contents.to_vec());
+        Ok(())
+    }
+
+    fn exists(&self, path: &VirtualPath) -> Result<bool> {
+        Ok(self.files().keys().any(|file| {
+            file.namespace() == path.namespace() && file.segments().starts_with(path.segments())
+        }))
+    }
+
+    fn ensure_dir(&self, _path: &VirtualPath) -> Result<()> {
+        Ok(())
+    }
+
+    fn ensure_parent(&self, _path: &VirtualPath) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &VirtualPath) -> Result<()> {
+        self.files().remove(path);
+        Ok(())
+    }
+
+    fn with_physical_path<F, T>(&self, path: &VirtualPath, func: F) -> Result<T>
+    where
+        F: FnOnce(&Path) -> Result<T>,
+    {
+        static SPILLS: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "deepseek-ocr-memfs-{}-{}",
+            std::process::id(),
+            SPILLS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut physical = dir.clone();
+        for segment in path.segments() {
+            physical.push(segment);
+        }
+        let result = (|| {
+            if let Some(parent) = physical.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create directory {}", parent.display()))?;
+            }
+            if let Some(contents) = self.files().get(path) {
+                fs::write(&physical, contents)
+                    .with_context(|| format!("failed to spill {}", physical.display()))?;
+            }
+            let value = func(&physical)?;
+            if physical.is_file() {
+                let contents = fs::read(&physical)
+                    .with_context(|| format!("failed to read back {}", physical.display()))?;
+                self.files().insert(path.clone(), contents);
+            }
+            Ok(value)
+        })();
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+}
+
 fn default_config_dir(app_name: &str) -> PathBuf {
     dirs::config_dir()
         .unwrap_or_else(|| fallback_home(".config"))
@@ -0,0 +1,211 @@
+//! Hugging Face Hub downloads for model entries that name an `hf_repo`. The client itself is
+//! only built with the `hub` feature; without it, entries whose files are all present or
+//! configured still load.
+
+#[cfg(feature = "hub")]
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Component, Path},
+};
+
+#[cfg(feature = "hub")]
+use anyhow::Context;
+use anyhow::{Result, bail};
+#[cfg(feature = "hub")]
+use hf_hub::{Repo, RepoType, api::sync::ApiBuilder};
+#[cfg(feature = "hub")]
+use tracing::info;
+
+#[cfg(feature = "hub")]
+use crate::fs::Namespace;
+use crate::fs::{VirtualFileSystem, VirtualPath};
+
+#[cfg(feature = "hub")]
+const SINGLE_WEIGHTS_FILE: &str = "model.safetensors";
+pub(crate) const WEIGHTS_INDEX_FILE: &str = "model.safetensors.index.json";
+
+/// Which files of a model the Hub should provide; the rest are configured as physical paths.
+pub(crate) struct HubFiles {
+    pub config: bool,
+    pub tokenizer: bool,
+    pub weights: bool,
+}
+
+/// Download the requested files of `repo` at `revision` into the virtual dir of `model_id`,
+/// skipping any that are already there. Only contacts the Hub when something is missing.
+///
+/// Weights land as `model.safetensors` when the repo has a single file (or an index naming a
+/// single shard); otherwise the index and every shard it references are kept side by side.
+/// hf-hub's own cache lives under the `hub` directory of the cache namespace and files are
+/// hard-linked from it where the platform allows, copied otherwise.
+pub(crate) fn download_missing(
+    fs: &impl VirtualFileSystem,
+    model_id: &str,
+    repo: &str,
+    revision: Option<&str>,
+    files: HubFiles,
+) -> Result<()> {
+    let config = VirtualPath::model_config(model_id.to_string());
+    let tokenizer = VirtualPath::model_tokenizer(model_id.to_string());
+    let weights = VirtualPath::model_weights(model_id.to_string());
+    let index = VirtualPath::model_weights_index(model_id.to_string());
+    let fetch_config = files.config && !fs.exists(&config)?;
+    let fetch_tokenizer = files.tokenizer && !fs.exists(&tokenizer)?;
+    let fetch_weights = files.weights && !fs.exists(&weights)? && !fs.exists(&index)?;
+    if !(fetch_config || fetch_tokenizer || fetch_weights) {
+        return Ok(());
+    }
+    fetch_from_hub(
+        fs,
+        model_id,
+        repo,
+        revision,
+        HubFiles {
+            config: fetch_config,
+            tokenizer: fetch_tokenizer,
+            weights: fetch_weights,
+        },
+    )
+}
+
+#[cfg(not(feature = "hub"))]
+fn fetch_from_hub(
+    _fs: &impl VirtualFileSystem,
+    model_id: &str,
+    repo: &str,
+    _revision: Option<&str>,
+    _files: HubFiles,
+) -> Result<()> {
+    bail!(
+        "model `{model_id}` is missing files that would come from Hugging Face repo {repo}, but \
+         this build lacks the `hub` feature"
+    )
+}
+
+#[cfg(feature = "hub")]
+fn fetch_from_hub(
+    fs: &impl VirtualFileSystem,
+    model_id: &str,
+    repo: &str,
+    revision: Option<&str>,
+    files: HubFiles,
+) -> Result<()> {
+    let model_dir = VirtualPath::model_dir(model_id.to_string());
+    let config = VirtualPath::model_config(model_id.to_string());
+    let tokenizer = VirtualPath::model_tokenizer(model_id.to_string());
+    let weights = VirtualPath::model_weights(model_id.to_string());
+    let index = VirtualPath::model_weights_index(model_id.to_string());
+
+    let hub_cache = VirtualPath::new(Namespace::Cache, vec!["hub".into()]);
+    fs.ensure_dir(&hub_cache)?;
+    let api = fs.with_physical_path(&hub_cache, |cache_dir| {
+        ApiBuilder::from_env()
+            .with_cache_dir(cache_dir.to_path_buf())
+            .build()
+            .context("failed to initialise Hugging Face API client")
+    })?;
+    let revision = revision.unwrap_or("main");
+    let repo_handle = api.repo(Repo::with_revision(
+        repo.to_string(),
+        RepoType::Model,
+        revision.to_string(),
+    ));
+    let fetch = |remote: &str, target: &VirtualPath| -> Result<()> {
+        info!("Downloading {remote} from Hugging Face repo {repo}@{revision}");
+        let cached = repo_handle
+            .get(remote)
+            .with_context(|| format!("failed to download {remote} from {repo}@{revision}"))?;
+        place_file(fs, &cached, target)
+    };
+
+    if files.config {
+        fetch("config.json", &config)?;
+    }
+    if files.tokenizer {
+        fetch("tokenizer.json", &tokenizer)?;
+    }
+    if !files.weights {
+        return Ok(());
+    }
+    let listing = repo_handle
+        .info()
+        .with_context(|| format!("failed to list files of {repo}@{revision}"))?;
+    let available: BTreeSet<&str> = listing
+        .siblings
+        .iter()
+        .map(|sibling| sibling.rfilename.as_str())
+        .collect();
+    if available.contains(SINGLE_WEIGHTS_FILE) {
+        return fetch(SINGLE_WEIGHTS_FILE, &weights);
+    }
+    if !available.contains(WEIGHTS_INDEX_FILE) {
+        bail!("{repo}@{revision} has neither {SINGLE_WEIGHTS_FILE} nor {WEIGHTS_INDEX_FILE}");
+    }
+    let index_path = repo_handle
+        .get(WEIGHTS_INDEX_FILE)
+        .with_context(|| format!("failed to download {WEIGHTS_INDEX_FILE} from {repo}"))?;
+    let shards = index_shards(&index_path)?;
+    if let [shard] = shards.as_slice() {
+        return fetch(shard, &weights);
+    }
+    for shard in &shards {
+        fetch(shard, &model_dir.join(shard.as_str()))?;
+    }
+    place_file(fs, &index_path, &index)
+}
+
+/// Distinct shard file names referenced by a safetensors index, in sorted order. Names are
+/// joined onto the model dir, so anything but a plain relative path is rejected.
+#[cfg(feature = "hub")]
+fn index_shards(index_path: &Path) -> Result<Vec<String>> {
+    let contents =
+        fs::read(index_path).with_context(|| format!("failed to read {}", index_path.display()))?;
+    let index: serde_json::Value = serde_json::from_slice(&contents)
+        .with_context(|| format!("failed to parse {}", index_path.display()))?;
+    let Some(weight_map) = index.get("weight_map").and_then(|map| map.as_object()) else {
+        bail!("{} has no `weight_map`", index_path.display());
+    };
+    let shards: BTreeSet<String> = weight_map
+        .values()
+        .filter_map(|shard| shard.as_str().map(str::to_string))
+        .collect();
+    if shards.is_empty() {
+        bail!("{} references no shards", index_path.display());
+    }
+    for shard in &shards {
+        let plain = !shard.is_empty()
+            && Path::new(shard)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !plain {
+            bail!(
+                "{} references shard `{shard}` outside the model directory",
+                index_path.display()
+            );
+        }
+    }
+    Ok(shards.into_iter().collect())
+}
+
+/// Put the hf-hub cache entry `source` at `target`, preferring a hard link to save disk space.
+#[cfg(feature = "hub")]
+fn place_file(fs: &impl VirtualFileSystem, source: &Path, target: &VirtualPath) -> Result<()> {
+    // Snapshot entries are symlinks into the blob store; link the blob itself.
+    let source = fs::canonicalize(source)
+        .with_context(|| format!("failed to resolve {}", source.display()))?;
+    fs.ensure_parent(target)?;
+    fs.with_physical_path(target, |physical| {
+        if fs::hard_link(&source, physical).is_ok() {
+            return Ok(());
+        }
+        fs::copy(&source, physical).with_context(|| {
+            format!(
+                "failed to copy {} to {}",
+                source.display(),
+                physical.display()
+            )
+        })?;
+        Ok(())
+    })
+}
@@ -1,8 +1,14 @@
 pub mod config;
 pub mod fs;
+mod hub;
 
 pub use config::{
-    AppConfig, ConfigDescriptor, ConfigOverride, ConfigOverrides, InferenceSettings, ModelRegistry,
-    ModelResources, ResourceLocation, ServerSettings,
+    AppConfig, CONFIG_VERSION, ConfigDescriptor, ConfigFieldDiff, ConfigFormat, ConfigOverride,
+    ConfigOverrides, ConfigRepair, ConfigRepairFix, InferenceSettings, ModelRegistry,
+    ModelResources, ResourceLocation, ServerSettings, TokenizerSource,
+};
+#[cfg(feature = "http")]
+pub use fs::HttpFileSystem;
+pub use fs::{
+    LocalFileSystem, MemoryFileSystem, ModelFileSystem, Namespace, VirtualFileSystem, VirtualPath,
 };
-pub use fs::{LocalFileSystem, Namespace, VirtualFileSystem, VirtualPath};
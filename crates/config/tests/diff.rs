@@ -0,0 +1,58 @@
+use deepseek_ocr_config::{AppConfig, ConfigFieldDiff};
+
+fn changed(path: &str, old: Option<&str>, new: Option<&str>) -> ConfigFieldDiff {
+    ConfigFieldDiff {
+        path: path.into(),
+        old: old.map(Into::into),
+        new: new.map(Into::into),
+    }
+}
+
+#[test]
+fn identical_configs_have_no_diff() {
+    let config = AppConfig::default();
+    assert!(config.diff(&config.clone()).unwrap().is_empty());
+}
+
+#[test]
+fn diff_reports_dotted_paths_and_written_values() {
+    let old = AppConfig::default();
+    let mut new = old.clone();
+    new.inference.base_size = 1280;
+    new.inference.gpu_memory_utilization = Some(0.5);
+    new.server.port = 9000;
+    new.server.host = "127.0.0.1".into();
+
+    let diffs = old.diff(&new).unwrap();
+    assert_eq!(
+        diffs,
+        [
+            changed(
+                "inference.base_size",
+                Some(&old.inference.base_size.to_string()),
+                Some("1280")
+            ),
+            changed("inference.gpu_memory_utilization", None, Some("0.5")),
+            changed(
+                "server.host",
+                Some(&format!("\"{}\"", old.server.host)),
+                Some("\"127.0.0.1\"")
+            ),
+            changed(
+                "server.port",
+                Some(&old.server.port.to_string()),
+                Some("9000")
+            ),
+        ]
+    );
+    assert_eq!(
+        diffs[3].to_string(),
+        format!("server.port: {} -> 9000", old.server.port)
+    );
+
+    let reversed = new.diff(&old).unwrap();
+    assert_eq!(
+        reversed[1],
+        changed("inference.gpu_memory_utilization", Some("0.5"), None)
+    );
+}
@@ -0,0 +1,132 @@
+use std::{env, sync::Mutex};
+
+use deepseek_ocr_config::{
+    AppConfig, ConfigFormat, ConfigOverrides, MemoryFileSystem, config::ServerOverride,
+};
+use deepseek_ocr_core::{
+    inference::MaxNewTokens,
+    runtime::{DeviceKind, PreprocessDevice},
+    transformer::{cache::CacheEviction, weights::WeightQuant},
+};
+
+/// The environment is shared by every test in this binary.
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Run `f` with `vars` set, removing them again afterwards.
+fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+    let _guard = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (name, value) in vars {
+        // SAFETY: `ENV` keeps the tests in this binary from touching the environment at once.
+        unsafe { env::set_var(name, value) };
+    }
+    let result = f();
+    for (name, _) in vars {
+        // SAFETY: as above.
+        unsafe { env::remove_var(name) };
+    }
+    result
+}
+
+#[test]
+fn variables_parse_into_overrides() {
+    let overrides = with_env(
+        &[
+            ("DEEPSEEK_OCR_MODEL", "ocr-large"),
+            ("DEEPSEEK_OCR_FALLBACK_MODEL", "ocr-small"),
+            ("DEEPSEEK_OCR_DEVICE", "CPU"),
+            ("DEEPSEEK_OCR_QUANTIZE", "int8"),
+            ("DEEPSEEK_OCR_BASE_SIZE", " 1280 "),
+            ("DEEPSEEK_OCR_CROP_MODE", "false"),
+            ("DEEPSEEK_OCR_MAX_NEW_TOKENS", "auto"),
+            ("DEEPSEEK_OCR_KV_EVICTION", "sink:4"),
+            ("DEEPSEEK_OCR_PREPROCESS_DEVICE", "cpu"),
+            ("DEEPSEEK_OCR_MAX_QUEUED_REQUESTS", "16"),
+            ("DEEPSEEK_OCR_IMAGE_SIZE", ""),
+        ],
+        ConfigOverrides::from_env,
+    )
+    .unwrap();
+    assert_eq!(overrides.model_id.as_deref(), Some("ocr-large"));
+    assert_eq!(overrides.fallback_model.as_deref(), Some("ocr-small"));
+    assert!(matches!(overrides.inference.device, Some(DeviceKind::Cpu)));
+    assert_eq!(overrides.inference.quantize, Some(WeightQuant::Int8));
+    assert_eq!(overrides.inference.base_size, Some(1280));
+    assert_eq!(overrides.inference.crop_mode, Some(false));
+    assert_eq!(overrides.inference.max_new_tokens, Some(MaxNewTokens::Auto));
+    assert_eq!(
+        overrides.inference.kv_eviction,
+        Some(CacheEviction::AttentionSink { sink: 4 })
+    );
+    assert!(matches!(
+        overrides.inference.preprocess_device,
+        Some(PreprocessDevice::Cpu)
+    ));
+    assert_eq!(overrides.server.max_queued_requests, Some(16));
+    assert_eq!(
+        overrides.inference.image_size, None,
+        "empty variables are ignored"
+    );
+    assert_eq!(overrides.server.port, None);
+}
+
+#[test]
+fn invalid_values_name_the_variable() {
+    for (name, value, expected) in [
+        (
+            "DEEPSEEK_OCR_PORT",
+            "eighty",
+            "invalid value `eighty` for DEEPSEEK_OCR_PORT",
+        ),
+        (
+            "DEEPSEEK_OCR_CROP_MODE",
+            "yes",
+            "invalid value `yes` for DEEPSEEK_OCR_CROP_MODE",
+        ),
+        (
+            "DEEPSEEK_OCR_DEVICE",
+            "tpu",
+            "invalid value `tpu` for DEEPSEEK_OCR_DEVICE: expected one of",
+        ),
+    ] {
+        let err = with_env(&[(name, value)], ConfigOverrides::from_env).unwrap_err();
+        assert!(format!("{err:#}").starts_with(expected), "{err:#}");
+    }
+}
+
+#[test]
+fn flags_win_over_variables_which_win_over_the_file() {
+    let contents = r#"
+version = 1
+
+[inference]
+base_size = 1024
+image_size = 640
+
+[server]
+port = 8000
+"#;
+    let (mut config, _) = AppConfig::parse_versioned(ConfigFormat::Toml, contents).unwrap();
+    let env = with_env(
+        &[
+            ("DEEPSEEK_OCR_BASE_SIZE", "1280"),
+            ("DEEPSEEK_OCR_PORT", "9000"),
+        ],
+        ConfigOverrides::from_env,
+    )
+    .unwrap();
+    // The CLI and server apply their parsed flags after the environment, the same way.
+    let flags = ConfigOverrides {
+        server: ServerOverride {
+            port: Some(9100),
+            ..ServerOverride::default()
+        },
+        ..ConfigOverrides::default()
+    };
+    config += env;
+    config += flags;
+    config.normalise(&MemoryFileSystem::new()).unwrap();
+
+    assert_eq!(config.inference.base_size, 1280);
+    assert_eq!(config.inference.image_size, 640);
+    assert_eq!(config.server.port, 9100);
+}
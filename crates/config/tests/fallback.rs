@@ -0,0 +1,64 @@
+use deepseek_ocr_config::{AppConfig, ConfigFormat, MemoryFileSystem};
+
+fn normalise(contents: &str) -> anyhow::Result<AppConfig> {
+    let (mut config, _) = AppConfig::parse_versioned(ConfigFormat::Toml, contents)?;
+    config.normalise(&MemoryFileSystem::new())?;
+    Ok(config)
+}
+
+#[test]
+fn fallback_must_name_another_entry() {
+    let config = normalise(
+        r#"
+version = 1
+
+[models]
+active = "large"
+fallback = "small"
+
+[models.entries.large]
+
+[models.entries.small]
+device = "cpu"
+"#,
+    )
+    .expect("fallback entry exists");
+    assert_eq!(config.models.fallback.as_deref(), Some("small"));
+
+    let err = normalise(
+        r#"
+version = 1
+
+[models]
+active = "large"
+fallback = "smal"
+
+[models.entries.large]
+
+[models.entries.small]
+"#,
+    )
+    .unwrap_err();
+    assert_eq!(
+        format!("{err:#}"),
+        "models.fallback `smal` is not in models.entries"
+    );
+}
+
+#[test]
+fn fallback_cannot_be_the_active_model() {
+    let err = normalise(
+        r#"
+version = 1
+
+[models]
+active = "large"
+fallback = "large"
+"#,
+    )
+    .unwrap_err();
+    assert_eq!(
+        format!("{err:#}"),
+        "models.fallback `large` is the active model; name a different entry"
+    );
+}
@@ -0,0 +1,86 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use deepseek_ocr_config::{AppConfig, ConfigFormat, MemoryFileSystem, config::save_config};
+
+fn config_path(extension: &str) -> PathBuf {
+    static FILES: AtomicU64 = AtomicU64::new(0);
+    std::env::temp_dir()
+        .join(format!(
+            "deepseek-ocr-formats-{}-{}",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ))
+        .join(format!("config.{extension}"))
+}
+
+#[test]
+fn format_follows_the_file_extension() {
+    for (path, format) in [
+        ("config.yaml", ConfigFormat::Yaml),
+        ("config.yml", ConfigFormat::Yaml),
+        ("CONFIG.YML", ConfigFormat::Yaml),
+        ("config.json", ConfigFormat::Json),
+        ("config.Json", ConfigFormat::Json),
+        ("config.toml", ConfigFormat::Toml),
+        ("config.txt", ConfigFormat::Toml),
+        ("config", ConfigFormat::Toml),
+        ("settings.json.bak", ConfigFormat::Toml),
+    ] {
+        assert_eq!(ConfigFormat::from_path(Path::new(path)), format, "{path}");
+    }
+}
+
+#[test]
+fn yaml_and_json_configs_round_trip_through_save_and_load() {
+    let fs = MemoryFileSystem::new();
+    for (extension, format) in [("yaml", ConfigFormat::Yaml), ("json", ConfigFormat::Json)] {
+        let path = config_path(extension);
+        // A missing file is created with the defaults, in the format its extension names.
+        let (mut config, descriptor) = AppConfig::load_or_init(&fs, Some(&path)).unwrap();
+        assert_eq!(descriptor.format, format);
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _: serde_json::Value = format.parse(&written).unwrap();
+
+        config.inference.base_size = 1280;
+        config.inference.stop_sequences = vec!["</end>".into()];
+        config.server.port = 9000;
+        save_config(&fs, &descriptor, &config).unwrap();
+
+        let (loaded, reloaded) = AppConfig::load_or_init(&fs, Some(&path)).unwrap();
+        assert_eq!(reloaded.format, format);
+        assert_eq!(
+            ConfigFormat::Toml.serialize(&loaded).unwrap(),
+            ConfigFormat::Toml.serialize(&config).unwrap(),
+            "{format} round trip"
+        );
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn handwritten_yaml_and_json_configs_load() {
+    let fs = MemoryFileSystem::new();
+    for (extension, contents) in [
+        (
+            "yml",
+            "version: 1\ninference:\n  base_size: 640\n  crop_mode: false\nserver:\n  port: 8123\n",
+        ),
+        (
+            "json",
+            r#"{"version": 1, "inference": {"base_size": 640, "crop_mode": false}, "server": {"port": 8123}}"#,
+        ),
+    ] {
+        let path = config_path(extension);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        let (config, _) = AppConfig::load_or_init(&fs, Some(&path)).unwrap();
+        assert!(config.unknown_fields.is_empty(), "{extension}");
+        assert_eq!(config.inference.base_size, 640, "{extension}");
+        assert!(!config.inference.crop_mode, "{extension}");
+        assert_eq!(config.server.port, 8123, "{extension}");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+#![cfg(feature = "http")]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+};
+
+use deepseek_ocr_config::{
+    AppConfig, ConfigOverrides, HttpFileSystem, LocalFileSystem, ModelFileSystem,
+    VirtualFileSystem, VirtualPath,
+};
+
+const CONFIG_JSON: &str = r#"{"model_type": "deepseek_vl_v2"}"#;
+
+/// Serves `/models/ocr/config.json` and answers 404 for everything else, recording each
+/// request line (e.g. `GET /models/ocr/config.json`).
+fn serve() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind test server");
+    let base_url = format!("http://{}/", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&requests);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.expect("accept");
+            let mut reader = BufReader::new(stream.try_clone().expect("clone stream"));
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).expect("read request");
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header).expect("read header") <= 2 {
+                    break;
+                }
+            }
+            let mut parts = request_line.split_whitespace();
+            let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            log.lock().unwrap().push(format!("{method} {path}"));
+            let (status, body) = match path {
+                "/models/ocr/config.json" => ("200 OK", CONFIG_JSON),
+                _ => ("404 Not Found", ""),
+            };
+            let mut response = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            if method == "GET" {
+                response.push_str(body);
+            }
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    (base_url, requests)
+}
+
+fn mirror_dir() -> PathBuf {
+    static DIRS: AtomicU64 = AtomicU64::new(0);
+    std::env::temp_dir().join(format!(
+        "deepseek-ocr-httpfs-{}-{}",
+        std::process::id(),
+        DIRS.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+#[test]
+fn exists_asks_the_server_with_head() {
+    let (base_url, requests) = serve();
+    let mirror = mirror_dir();
+    let fs = HttpFileSystem::new(&base_url, mirror.clone()).unwrap();
+
+    assert!(fs.exists(&VirtualPath::model_config("ocr")).unwrap());
+    assert!(!fs.exists(&VirtualPath::model_weights("ocr")).unwrap());
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [
+            "HEAD /models/ocr/config.json",
+            "HEAD /models/ocr/model.safetensors"
+        ]
+    );
+    let _ = fs::remove_dir_all(mirror);
+}
+
+#[test]
+fn files_are_downloaded_once_into_the_mirror() {
+    let (base_url, requests) = serve();
+    let mirror = mirror_dir();
+    let fs = HttpFileSystem::new(&base_url, mirror.clone()).unwrap();
+    let path = VirtualPath::model_config("ocr");
+
+    assert_eq!(fs.read(&path).unwrap(), CONFIG_JSON.as_bytes());
+    let physical = fs
+        .with_physical_path(&path, |physical| Ok(physical.to_path_buf()))
+        .unwrap();
+    assert_eq!(physical, mirror.join("cache/models/ocr/config.json"));
+    assert!(!mirror.join("cache/models/ocr/config.json.part").exists());
+    assert!(fs.exists(&path).unwrap());
+    assert_eq!(fs.read(&path).unwrap(), CONFIG_JSON.as_bytes());
+    assert_eq!(
+        *requests.lock().unwrap(),
+        ["GET /models/ocr/config.json"],
+        "later reads and checks are served from the mirror"
+    );
+    let _ = fs::remove_dir_all(mirror);
+}
+
+#[test]
+fn missing_files_are_an_error_and_leave_no_mirror_copy() {
+    let (base_url, _) = serve();
+    let mirror = mirror_dir();
+    let fs = HttpFileSystem::new(&base_url, mirror.clone()).unwrap();
+    let path = VirtualPath::model_tokenizer("ocr");
+
+    let err = fs.read(&path).unwrap_err();
+    assert!(
+        format!("{err:#}").contains("/models/ocr/tokenizer.json"),
+        "{err:#}"
+    );
+    assert!(!mirror.join("cache/models/ocr/tokenizer.json").exists());
+    let _ = fs::remove_dir_all(mirror);
+}
+
+#[test]
+fn remote_url_selects_the_http_backend() {
+    let local = LocalFileSystem::with_directories("test", mirror_dir(), mirror_dir());
+    let mut config = AppConfig::default();
+    assert!(matches!(
+        config.model_file_system(&local).unwrap(),
+        ModelFileSystem::Local(_)
+    ));
+
+    config += ConfigOverrides {
+        remote_url: Some("http://models.example/ocr/".into()),
+        ..ConfigOverrides::default()
+    };
+    match config.model_file_system(&local).unwrap() {
+        ModelFileSystem::Http(fs) => {
+            assert_eq!(
+                fs.url(&VirtualPath::model_config("ocr")).as_str(),
+                "http://models.example/ocr/models/ocr/config.json"
+            );
+        }
+        other => panic!("expected the HTTP backend, got {other:?}"),
+    }
+}
@@ -0,0 +1,88 @@
+use deepseek_ocr_config::{AppConfig, MemoryFileSystem, VirtualFileSystem, VirtualPath};
+
+/// Every file of the entry is configured explicitly, so the Hub is never needed.
+const EXPLICIT_PATHS: &str = r#"
+version = 1
+
+[models]
+active = "remote"
+
+[models.entries.remote]
+hf_repo = "example/does-not-exist"
+config = "/models/remote/config.json"
+tokenizer = "/models/remote/tokenizer.json"
+weights = "/models/remote/model.safetensors"
+"#;
+
+const REPO_ONLY: &str = r#"
+version = 1
+
+[models]
+active = "remote"
+
+[models.entries.remote]
+hf_repo = "example/does-not-exist"
+"#;
+
+fn load(fs: &MemoryFileSystem) -> AppConfig {
+    AppConfig::load_or_init(fs, None).expect("config loads").0
+}
+
+#[test]
+fn explicit_paths_skip_hub_resolution() {
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), EXPLICIT_PATHS);
+    // Loading normalises the registry; the repo does not exist, so reaching the Hub would fail.
+    let config = load(&fs);
+    let resources = config.active_model_resources(&fs).unwrap();
+    assert_eq!(
+        resources.weights.display_with(&fs).unwrap(),
+        "/models/remote/model.safetensors"
+    );
+}
+
+#[test]
+fn files_already_in_the_model_dir_skip_hub_resolution() {
+    let fs = MemoryFileSystem::new()
+        .with_file(VirtualPath::config_file(), REPO_ONLY)
+        .with_file(VirtualPath::model_config("remote"), "{}")
+        .with_file(VirtualPath::model_tokenizer("remote"), "{}")
+        .with_file(VirtualPath::model_weights_index("remote"), "{}");
+    let config = load(&fs);
+    config.active_model_resources(&fs).unwrap();
+}
+
+/// The active entry is complete; the other one would need the Hub.
+const INACTIVE_REMOTE: &str = r#"
+version = 1
+
+[models]
+active = "local"
+
+[models.entries.local]
+config = "/models/local/config.json"
+tokenizer = "/models/local/tokenizer.json"
+weights = "/models/local/model.safetensors"
+
+[models.entries.remote]
+hf_repo = "example/does-not-exist"
+"#;
+
+#[test]
+fn inactive_entries_are_not_fetched() {
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), INACTIVE_REMOTE);
+    let config = load(&fs);
+    config.active_model_resources(&fs).unwrap();
+    assert!(!fs.exists(&VirtualPath::model_config("remote")).unwrap());
+}
+
+#[cfg(not(feature = "hub"))]
+#[test]
+fn missing_files_need_the_hub_feature() {
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), REPO_ONLY);
+    // Loading succeeds; the Hub is only needed once the model's files are resolved.
+    let config = load(&fs);
+    let Err(err) = config.active_model_resources(&fs) else {
+        panic!("resolving needs the Hub for the missing files");
+    };
+    assert!(format!("{err:#}").contains("`hub` feature"), "{err:#}");
+}
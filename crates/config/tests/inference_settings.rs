@@ -0,0 +1,223 @@
+use deepseek_ocr_config::{AppConfig, ConfigFormat, InferenceSettings};
+use deepseek_ocr_core::{inference::MaxNewTokens, transformer::cache::CacheEviction};
+
+#[test]
+fn kv_cache_bound_round_trips_and_is_validated() {
+    let contents = r#"
+version = 1
+
+[inference]
+kv_max_seq_len = 4096
+kv_eviction = "sink:4"
+"#;
+    let (config, _) = AppConfig::parse_versioned(ConfigFormat::Toml, contents).unwrap();
+    assert!(config.unknown_fields.is_empty());
+    assert_eq!(config.inference.kv_max_seq_len, Some(4096));
+    assert_eq!(
+        config.inference.kv_eviction,
+        CacheEviction::AttentionSink { sink: 4 }
+    );
+    config.inference.validate().unwrap();
+
+    let written = ConfigFormat::Toml.serialize(&config).unwrap();
+    assert!(written.contains(r#"kv_eviction = "sink:4""#), "{written}");
+
+    let mut inference = config.inference.clone();
+    inference.kv_max_seq_len = Some(4);
+    let err = inference.validate().unwrap_err();
+    assert!(err.to_string().contains("inference.kv_eviction"), "{err:#}");
+
+    let bad = contents.replace("sink:4", "newest");
+    assert!(AppConfig::parse_versioned(ConfigFormat::Toml, &bad).is_err());
+}
+
+#[test]
+fn kv_compact_stride_must_fit_in_the_window() {
+    let contents = r#"
+version = 1
+
+[inference]
+kv_max_seq_len = 4096
+kv_eviction = "sink:4"
+kv_compact_stride = 256
+"#;
+    let (config, _) = AppConfig::parse_versioned(ConfigFormat::Toml, contents).unwrap();
+    assert!(config.unknown_fields.is_empty());
+    assert_eq!(config.inference.kv_compact_stride, Some(256));
+    config.inference.validate().unwrap();
+
+    let mut inference = config.inference.clone();
+    inference.kv_compact_stride = Some(0);
+    let err = inference.validate().unwrap_err();
+    assert!(err.to_string().contains("at least 1"), "{err:#}");
+
+    // The window is the bound minus the four sink positions.
+    inference.kv_compact_stride = Some(4092);
+    let err = inference.validate().unwrap_err();
+    assert!(err.to_string().contains("4092 positions"), "{err:#}");
+    inference.kv_compact_stride = Some(4091);
+    inference.validate().unwrap();
+
+    // Without a bound the stride has nothing to compact and is only checked for zero.
+    inference.kv_max_seq_len = None;
+    inference.kv_compact_stride = Some(1 << 20);
+    inference.validate().unwrap();
+}
+
+#[test]
+fn structure_aware_stop_needs_a_structured_template() {
+    let mut inference = InferenceSettings {
+        structure_aware_stop: true,
+        ..InferenceSettings::default()
+    };
+    let err = inference.validate().unwrap_err();
+    assert!(
+        err.to_string().contains("inference.structure_aware_stop"),
+        "{err:#}"
+    );
+
+    inference.template = "markdown".into();
+    inference.validate().unwrap();
+}
+
+#[test]
+fn beam_search_is_configured_and_cannot_sample() {
+    let mut inference = InferenceSettings::default();
+    assert_eq!(inference.beam_search(), None);
+
+    inference.num_beams = 4;
+    inference.length_penalty = 1.5;
+    let beam = inference.beam_search().unwrap();
+    assert_eq!((beam.num_beams, beam.length_penalty), (4, 1.5));
+    inference.validate().unwrap();
+
+    inference.temperature = 0.7;
+    let err = inference.validate().unwrap_err();
+    assert!(err.to_string().contains("inference.num_beams"), "{err:#}");
+
+    inference.temperature = 0.0;
+    inference.num_beams = 0;
+    let err = inference.validate().unwrap_err();
+    assert!(err.to_string().contains("inference.num_beams"), "{err:#}");
+}
+
+#[test]
+fn repetition_controls_are_configured_and_validated() {
+    let mut inference = InferenceSettings::default();
+    assert_eq!(inference.repetition_penalty().unwrap(), None);
+    assert_eq!(inference.no_repeat_ngram_size(), None);
+
+    inference.repetition_penalty = 1.3;
+    inference.no_repeat_ngram_size = 3;
+    inference.validate().unwrap();
+    let penalty = inference.repetition_penalty().unwrap().unwrap();
+    assert_eq!(penalty.penalty(), 1.3);
+    assert_eq!(inference.no_repeat_ngram_size(), Some(3));
+
+    for penalty in [0.0, -1.0, f32::NAN] {
+        inference.repetition_penalty = penalty;
+        let err = inference.validate().unwrap_err();
+        assert!(
+            err.to_string().contains("inference.repetition_penalty"),
+            "{err:#}"
+        );
+    }
+}
+
+/// Default settings with one field changed must fail validation on that field.
+fn assert_rejected(inference: InferenceSettings, field: &str) {
+    let err = inference.validate().unwrap_err();
+    assert!(err.to_string().contains(field), "{err:#}");
+}
+
+#[test]
+fn zero_base_size_is_rejected() {
+    assert_rejected(
+        InferenceSettings {
+            base_size: 0,
+            ..InferenceSettings::default()
+        },
+        "inference.base_size",
+    );
+}
+
+#[test]
+fn zero_image_size_is_rejected() {
+    assert_rejected(
+        InferenceSettings {
+            image_size: 0,
+            ..InferenceSettings::default()
+        },
+        "inference.image_size",
+    );
+}
+
+#[test]
+fn zero_max_new_tokens_is_rejected() {
+    assert_rejected(
+        InferenceSettings {
+            max_new_tokens: MaxNewTokens::Fixed(0),
+            ..InferenceSettings::default()
+        },
+        "inference.max_new_tokens",
+    );
+}
+
+#[test]
+fn gpu_memory_utilization_must_be_a_fraction() {
+    for utilization in [-0.1, 1.5, f32::NAN] {
+        assert_rejected(
+            InferenceSettings {
+                gpu_memory_utilization: Some(utilization),
+                ..InferenceSettings::default()
+            },
+            "inference.gpu_memory_utilization",
+        );
+    }
+    for utilization in [0.0, 0.9, 1.0] {
+        InferenceSettings {
+            gpu_memory_utilization: Some(utilization),
+            ..InferenceSettings::default()
+        }
+        .validate()
+        .unwrap();
+    }
+}
+
+#[test]
+fn crops_larger_than_the_global_view_are_rejected_in_crop_mode() {
+    let inference = InferenceSettings {
+        crop_mode: true,
+        base_size: 640,
+        image_size: 1024,
+        ..InferenceSettings::default()
+    };
+    assert_rejected(inference.clone(), "inference.image_size");
+
+    // Without crops only the global view is encoded, so the sizes are independent.
+    InferenceSettings {
+        crop_mode: false,
+        ..inference
+    }
+    .validate()
+    .unwrap();
+}
+
+#[test]
+fn strip_aspect_threshold_must_exceed_one() {
+    for threshold in [1.0, 0.5, -3.0, f32::NAN, f32::INFINITY] {
+        assert_rejected(
+            InferenceSettings {
+                strip_aspect_threshold: Some(threshold),
+                ..InferenceSettings::default()
+            },
+            "inference.strip_aspect_threshold",
+        );
+    }
+    InferenceSettings {
+        strip_aspect_threshold: Some(8.0),
+        ..InferenceSettings::default()
+    }
+    .validate()
+    .unwrap();
+}
@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use deepseek_ocr_config::{AppConfig, ConfigFormat, MemoryFileSystem, VirtualPath};
+use deepseek_ocr_core::runtime::{DeviceKind, Precision};
+
+/// Parse without normalising, so each test resolves inheritance itself.
+fn load(contents: &str) -> (MemoryFileSystem, AppConfig) {
+    let (config, _) =
+        AppConfig::parse_versioned(ConfigFormat::Toml, contents).expect("config parses");
+    (MemoryFileSystem::new(), config)
+}
+
+#[test]
+fn entry_takes_unset_fields_from_its_parent() {
+    let (fs, mut config) = load(
+        r#"
+version = 1
+
+[models.entries.base]
+weights = "/models/base/model.safetensors"
+tokenizer = "/models/base/tokenizer.json"
+hf_repo = "example/base"
+revision = "v1"
+device = "cpu"
+
+[models.entries.variant]
+inherits = "base"
+tokenizer = "/models/variant/tokenizer.json"
+precision = "bf16"
+"#,
+    );
+    config.normalise(&fs).expect("inheritance resolves");
+    let variant = &config.models.entries["variant"];
+    assert_eq!(
+        variant.weights.as_deref(),
+        Some(Path::new("/models/base/model.safetensors"))
+    );
+    assert_eq!(
+        variant.tokenizer.as_deref(),
+        Some(Path::new("/models/variant/tokenizer.json")),
+        "fields the entry sets are kept"
+    );
+    assert_eq!(variant.hf_repo.as_deref(), Some("example/base"));
+    assert_eq!(
+        variant.revision.as_deref(),
+        Some("v1"),
+        "the revision comes with the repo"
+    );
+    assert!(matches!(variant.device, Some(DeviceKind::Cpu)));
+    assert!(matches!(variant.precision, Some(Precision::Bf16)));
+    assert!(config.models.entries["base"].precision.is_none());
+}
+
+#[test]
+fn revision_is_not_inherited_without_the_repo() {
+    let (fs, mut config) = load(
+        r#"
+version = 1
+
+[models.entries.base]
+hf_repo = "example/base"
+revision = "v1"
+
+[models.entries.fork]
+inherits = "base"
+hf_repo = "example/fork"
+"#,
+    );
+    config.normalise(&fs).unwrap();
+    let fork = &config.models.entries["fork"];
+    assert_eq!(fork.hf_repo.as_deref(), Some("example/fork"));
+    assert!(fork.revision.is_none());
+}
+
+#[test]
+fn nearest_ancestor_wins_along_a_chain() {
+    let (fs, mut config) = load(
+        r#"
+version = 1
+
+[models.entries.root]
+config = "/models/root/config.json"
+weights = "/models/root/model.safetensors"
+device = "cpu"
+
+[models.entries.middle]
+inherits = "root"
+weights = "/models/middle/model.safetensors"
+
+[models.entries.leaf]
+inherits = "middle"
+"#,
+    );
+    config.normalise(&fs).expect("chain resolves");
+    let leaf = &config.models.entries["leaf"];
+    assert_eq!(
+        leaf.weights.as_deref(),
+        Some(Path::new("/models/middle/model.safetensors"))
+    );
+    assert_eq!(
+        leaf.config.as_deref(),
+        Some(Path::new("/models/root/config.json"))
+    );
+    assert!(matches!(leaf.device, Some(DeviceKind::Cpu)));
+}
+
+const CYCLE: &str = r#"
+version = 1
+
+[models.entries.a]
+inherits = "b"
+
+[models.entries.b]
+inherits = "c"
+
+[models.entries.c]
+inherits = "a"
+
+[models.entries.standalone]
+weights = "/models/standalone/model.safetensors"
+"#;
+
+#[test]
+fn inheritance_cycle_fails_normalise() {
+    let (fs, mut config) = load(CYCLE);
+    let err = config.normalise(&fs).unwrap_err();
+    assert_eq!(
+        format!("{err:#}"),
+        "model inheritance cycle: a -> b -> c -> a"
+    );
+
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), CYCLE);
+    assert!(
+        AppConfig::load_or_init(&fs, None).is_err(),
+        "loading normalises too"
+    );
+}
+
+#[test]
+fn inheritance_errors_are_reported_per_model() {
+    let (fs, mut config) = load(&format!(
+        "{CYCLE}\n[models.entries.orphan]\ninherits = \"missing\"\n"
+    ));
+    let failed = config.normalise_per_model(&fs).unwrap();
+    assert_eq!(
+        failed.keys().map(String::as_str).collect::<Vec<_>>(),
+        ["a", "b", "c", "orphan"]
+    );
+    assert_eq!(
+        format!("{:#}", failed["b"]),
+        "model inheritance cycle: b -> c -> a -> b"
+    );
+    assert_eq!(
+        format!("{:#}", failed["orphan"]),
+        "model `orphan` inherits from `missing`, which is not in the configuration"
+    );
+    assert!(
+        !failed.contains_key("standalone"),
+        "entries outside the cycle still resolve"
+    );
+}
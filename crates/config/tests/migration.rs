@@ -0,0 +1,52 @@
+use deepseek_ocr_config::{
+    AppConfig, CONFIG_VERSION, ConfigFormat, MemoryFileSystem, VirtualFileSystem, VirtualPath,
+};
+
+const CONFIG_V0: &str = include_str!("fixtures/config_v0.toml");
+
+#[test]
+fn unversioned_config_is_migrated_in_memory() {
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), CONFIG_V0);
+    let (config, _) = AppConfig::load_or_init(&fs, None).expect("v0 config loads");
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert!(!config.inference.crop_mode);
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.inference.gpu_memory_utilization, Some(0.8));
+
+    let (_, rewritten) = AppConfig::parse_versioned(ConfigFormat::Toml, CONFIG_V0).unwrap();
+    assert!(
+        rewritten.is_none(),
+        "stamping the version alone does not rewrite the file"
+    );
+}
+
+// The v0 migration only stamps `version` and changes no setting, so these tests cover the path
+// that leaves the file alone. Writing a migrated file back only happens once a migration renames
+// or changes a setting, which no schema version does yet.
+#[test]
+fn commented_v0_config_keeps_its_comments() {
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), CONFIG_V0);
+    AppConfig::load_or_init(&fs, None).expect("v0 config loads");
+    let written = String::from_utf8(fs.read(&VirtualPath::config_file()).unwrap()).unwrap();
+    assert_eq!(written, CONFIG_V0);
+    assert!(written.contains("# leave room for the desktop"));
+}
+
+#[test]
+fn future_config_version_is_rejected() {
+    let contents = format!("version = {}\n{CONFIG_V0}", CONFIG_VERSION + 1);
+    let err = AppConfig::parse_versioned(ConfigFormat::Toml, &contents).unwrap_err();
+    assert!(
+        err.to_string().contains("newer than the supported version"),
+        "{err:#}"
+    );
+
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), contents.clone());
+    assert!(AppConfig::load_or_init(&fs, None).is_err());
+    let written = fs.read(&VirtualPath::config_file()).unwrap();
+    assert_eq!(
+        written,
+        contents.as_bytes(),
+        "a newer file is left untouched"
+    );
+}
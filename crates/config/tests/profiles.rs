@@ -0,0 +1,84 @@
+use deepseek_ocr_config::{
+    AppConfig, ConfigOverrides, MemoryFileSystem, VirtualPath, config::InferenceOverride,
+};
+
+const CONFIG: &str = r#"
+version = 1
+
+[inference]
+template = "plain"
+base_size = 1024
+image_size = 640
+crop_mode = true
+
+[profiles.fast]
+base_size = 512
+image_size = 512
+crop_mode = false
+"#;
+
+fn load(contents: &str) -> (MemoryFileSystem, anyhow::Result<AppConfig>) {
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), contents);
+    let config = AppConfig::load_or_init(&fs, None).map(|(config, _)| config);
+    (fs, config)
+}
+
+#[test]
+fn profile_merges_over_inference_and_cli_overrides_win() {
+    let (fs, config) = load(CONFIG);
+    let mut config = config.expect("config with a profile loads");
+    assert_eq!(
+        config.inference.base_size, 1024,
+        "profiles apply on request"
+    );
+
+    config += ConfigOverrides {
+        profile: Some("fast".into()),
+        inference: InferenceOverride {
+            image_size: Some(256),
+            ..InferenceOverride::default()
+        },
+        ..ConfigOverrides::default()
+    };
+    config.normalise(&fs).expect("selected profile is valid");
+    assert_eq!(config.inference.base_size, 512);
+    assert!(!config.inference.crop_mode);
+    assert_eq!(
+        config.inference.image_size, 256,
+        "the flag beats the profile"
+    );
+    assert_eq!(config.inference.template, "plain", "unset keys are kept");
+}
+
+#[test]
+fn missing_profile_fails_in_normalise() {
+    let (fs, config) = load(CONFIG);
+    let mut config = config.unwrap();
+    config += ConfigOverrides {
+        profile: Some("missing".into()),
+        ..ConfigOverrides::default()
+    };
+    let err = config.normalise(&fs).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("profile `missing` is not defined in `profiles`"),
+        "{err:#}"
+    );
+}
+
+#[test]
+fn malformed_profiles_are_rejected() {
+    let wrong_type = format!("{CONFIG}\n[profiles.broken]\nbase_size = \"large\"\n");
+    let err = load(&wrong_type).1.unwrap_err();
+    assert!(
+        format!("{err:#}").contains("profile `broken` does not match the inference settings"),
+        "{err:#}"
+    );
+
+    let invalid = format!("{CONFIG}\n[profiles.zero]\nbase_size = 0\n");
+    let err = load(&invalid).1.unwrap_err();
+    assert!(
+        format!("{err:#}").contains("invalid profile `zero`"),
+        "{err:#}"
+    );
+}
@@ -0,0 +1,53 @@
+use deepseek_ocr_config::{AppConfig, CONFIG_VERSION};
+
+const CONFIG_BROKEN: &str = include_str!("fixtures/config_broken.toml");
+const CONFIG_V0: &str = include_str!("fixtures/config_v0.toml");
+
+#[test]
+fn repair_fixes_values_and_keeps_comments_and_order() {
+    let repair = AppConfig::repair(CONFIG_BROKEN).expect("broken config repairs");
+    let fixed: Vec<String> = repair
+        .fixes
+        .iter()
+        .map(|fix| fix.diff.to_string())
+        .collect();
+    assert_eq!(
+        fixed,
+        [
+            "inference.gpu_memory_utilization: 90 -> 0.9",
+            "inference.max_num_seqs: 0 -> (unset)",
+            "models.entries.deepseek-ocr.device: \"CUDA\" -> \"cuda\"",
+            "models.entries.ocr-large: (unset) -> {}",
+        ]
+    );
+    assert_eq!(repair.unknown_fields, ["inference.custom_flag"]);
+    assert_eq!(repair.config.inference.gpu_memory_utilization, Some(0.9));
+    assert_eq!(repair.config.inference.max_num_seqs, None);
+
+    let written = repair.to_toml_string().unwrap();
+    let expected = CONFIG_BROKEN
+        .replace("\"CUDA\"", "\"cuda\"")
+        .replace("= 90 #", "= 0.9 #")
+        .replace("max_num_seqs = 0\n", "")
+        .replace(
+            "lowercase\n\n[inference]",
+            "lowercase\n\n[models.entries.ocr-large]\n\n[inference]",
+        );
+    assert_eq!(written, expected);
+
+    let reloaded = AppConfig::repair(&written).expect("repaired config parses");
+    assert!(reloaded.fixes.is_empty(), "{:?}", reloaded.fixes);
+}
+
+#[test]
+fn repairing_an_unversioned_config_only_stamps_the_version() {
+    let repair = AppConfig::repair(CONFIG_V0).expect("v0 config repairs");
+    assert!(repair.fixes.is_empty(), "{:?}", repair.fixes);
+    assert_eq!(repair.config.version, CONFIG_VERSION);
+    let written = repair.to_toml_string().unwrap();
+    assert!(written.contains("# leave room for the desktop"), "{written}");
+    assert_eq!(
+        written.replacen(&format!("version = {CONFIG_VERSION}\n"), "", 1),
+        CONFIG_V0
+    );
+}
@@ -0,0 +1,82 @@
+use std::fs;
+
+use deepseek_ocr_config::{
+    AppConfig, MemoryFileSystem, ResourceLocation, TokenizerSource, VirtualPath,
+};
+
+fn config_with_entry(entry: &str) -> String {
+    format!(
+        r#"
+version = 1
+
+[models]
+active = "local"
+
+[models.entries.local]
+{entry}
+"#
+    )
+}
+
+fn load(fs: &MemoryFileSystem) -> AppConfig {
+    AppConfig::load_or_init(fs, None).expect("config loads").0
+}
+
+#[test]
+fn tokenizer_beside_managed_weights_is_found_through_the_file_system() {
+    let fs = MemoryFileSystem::new()
+        .with_file(VirtualPath::config_file(), config_with_entry(""))
+        .with_file(VirtualPath::model_weights("local"), "")
+        .with_file(VirtualPath::model_tokenizer("local"), "{}");
+    let resources = load(&fs).active_model_resources(&fs).unwrap();
+    assert_eq!(resources.tokenizer_source, TokenizerSource::WeightsDir);
+    let ResourceLocation::Virtual(path) = &resources.tokenizer else {
+        panic!(
+            "expected a virtual tokenizer path, got {:?}",
+            resources.tokenizer
+        );
+    };
+    assert_eq!(*path, VirtualPath::model_tokenizer("local"));
+}
+
+#[test]
+fn missing_sibling_falls_back_to_the_default_location() {
+    let fs = MemoryFileSystem::new()
+        .with_file(VirtualPath::config_file(), config_with_entry(""))
+        .with_file(VirtualPath::model_weights("local"), "");
+    let resources = load(&fs).active_model_resources(&fs).unwrap();
+    assert_eq!(resources.tokenizer_source, TokenizerSource::Default);
+}
+
+#[test]
+fn tokenizer_beside_configured_weights_is_found_on_disk() {
+    let dir = std::env::temp_dir().join(format!("deepseek-ocr-tokenizer-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let weights = dir.join("model.safetensors");
+    let entry = format!("weights = {:?}", weights.display().to_string());
+    let memory =
+        MemoryFileSystem::new().with_file(VirtualPath::config_file(), config_with_entry(&entry));
+    let config = load(&memory);
+
+    let resources = config.active_model_resources(&memory).unwrap();
+    assert_eq!(resources.tokenizer_source, TokenizerSource::Default);
+
+    fs::write(dir.join("tokenizer.json"), "{}").unwrap();
+    let resources = config.active_model_resources(&memory).unwrap();
+    assert_eq!(resources.tokenizer_source, TokenizerSource::WeightsDir);
+    let ResourceLocation::Physical(path) = &resources.tokenizer else {
+        panic!(
+            "expected a physical tokenizer path, got {:?}",
+            resources.tokenizer
+        );
+    };
+    assert_eq!(*path, dir.join("tokenizer.json"));
+
+    // An explicit tokenizer wins over the sibling.
+    let entry = format!("{entry}\ntokenizer = \"/elsewhere/tokenizer.json\"");
+    let memory =
+        MemoryFileSystem::new().with_file(VirtualPath::config_file(), config_with_entry(&entry));
+    let resources = load(&memory).active_model_resources(&memory).unwrap();
+    assert_eq!(resources.tokenizer_source, TokenizerSource::Configured);
+    fs::remove_dir_all(&dir).unwrap();
+}
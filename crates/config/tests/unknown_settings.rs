@@ -0,0 +1,67 @@
+use deepseek_ocr_config::{
+    AppConfig, ConfigFormat, ConfigOverrides, MemoryFileSystem, VirtualPath,
+};
+
+const CONFIG: &str = r#"
+version = 1
+
+[inference]
+max_new_toknes = 2048
+crop_mode = false
+
+[inference.normalization]
+mean = [0.5, 0.5, 0.5]
+sdt = [0.5, 0.5, 0.5]
+
+[server]
+prot = 9000
+
+[profiles.fast]
+base_size = 512
+imgae_size = 512
+"#;
+
+#[test]
+fn unknown_settings_are_collected_by_path() {
+    let (config, _) = AppConfig::parse_versioned(ConfigFormat::Toml, CONFIG).unwrap();
+    assert_eq!(
+        config.unknown_fields,
+        [
+            "inference.max_new_toknes",
+            "inference.normalization.sdt",
+            "profiles.fast.imgae_size",
+            "server.prot",
+        ]
+    );
+    assert!(!config.inference.crop_mode);
+}
+
+#[test]
+fn written_defaults_have_no_unknown_settings() {
+    for format in [ConfigFormat::Toml, ConfigFormat::Yaml, ConfigFormat::Json] {
+        let contents = format.serialize(&AppConfig::default()).unwrap();
+        let (config, rewritten) = AppConfig::parse_versioned(format, &contents).unwrap();
+        assert!(
+            config.unknown_fields.is_empty(),
+            "{format}: {:?}",
+            config.unknown_fields
+        );
+        assert!(rewritten.is_none());
+    }
+}
+
+#[test]
+fn strict_config_rejects_unknown_settings() {
+    let fs = MemoryFileSystem::new().with_file(VirtualPath::config_file(), CONFIG);
+    let (mut config, _) = AppConfig::load_or_init(&fs, None).expect("unknown settings only warn");
+
+    config += ConfigOverrides {
+        strict_config: Some(true),
+        ..ConfigOverrides::default()
+    };
+    let err = config.normalise(&fs).unwrap_err();
+    assert!(
+        err.to_string().contains("`inference.max_new_toknes`"),
+        "{err:#}"
+    );
+}
@@ -83,6 +83,7 @@ impl BenchEvent {
 #[cfg(feature = "bench-metrics")]
 mod imp {
     use super::{BenchEvent, BenchField};
+    use candle_core::Device;
     use once_cell::sync::Lazy;
     use std::sync::{Arc, RwLock};
     use std::time::{Duration, Instant};
@@ -163,6 +164,15 @@ mod imp {
         fn record(&self, event: BenchEvent);
     }
 
+    pub fn sync(device: &Device) {
+        if current_recorder().is_none() {
+            return;
+        }
+        if let Err(err) = device.synchronize() {
+            tracing::warn!("device synchronize failed while timing: {err}");
+        }
+    }
+
     pub fn record_instant(stage: &'static str, fields: impl IntoIterator<Item = BenchField>) {
         if let Some(recorder) = current_recorder() {
             let event = BenchEvent {
@@ -178,6 +188,7 @@ mod imp {
 #[cfg(not(feature = "bench-metrics"))]
 mod imp {
     use super::{BenchEvent, BenchField};
+    use candle_core::Device;
     use std::sync::Arc;
 
     pub fn set_recorder(_recorder: Option<Arc<dyn BenchRecorder + Send + Sync>>) {}
@@ -202,7 +213,19 @@ mod imp {
         fn record(&self, _event: BenchEvent);
     }
 
+    pub fn sync(_device: &Device) {}
+
     pub fn record_instant(_stage: &'static str, _fields: impl IntoIterator<Item = BenchField>) {}
 }
 
 pub use imp::{BenchRecorder, Timer, record_instant, set_recorder};
+
+/// Block until `device` has finished its queued work, but only while benchmark timing is active.
+///
+/// CUDA and Metal launch kernels asynchronously, so a [`Timer`] closed right after a forward pass
+/// measures launch time rather than execution time. Call this at phase boundaries (before starting
+/// and before finishing a timer); without a recorder installed, or without the `bench-metrics`
+/// feature, it is a no-op and costs production runs nothing.
+pub fn sync(device: &candle_core::Device) {
+    imp::sync(device)
+}
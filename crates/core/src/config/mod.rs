@@ -4,11 +4,16 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, bail, ensure};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::{
+    runtime::{Precision, dtype_from_precision},
+    transformer::weights::{WeightQuant, should_use_moe},
+};
+
 static DEFAULT_CONFIG_PATHS: Lazy<[&str; 2]> =
     Lazy::new(|| ["DeepSeek-OCR/config.json", "config.json"]);
 
@@ -215,6 +220,311 @@ pub struct DeepseekV2Config {
     pub extra: BTreeMap<String, Value>,
 }
 
+/// Inference-time overrides merged over the checkpoint's [`DeepseekV2Config`] at load.
+///
+/// Only fields that leave weight shapes untouched are accepted, so an override can never make the
+/// checkpoint unloadable:
+/// - `rms_norm_eps`: epsilon of every RMSNorm in the decoder.
+/// - `rope_theta`: rotary embedding base; changes positional behaviour, not shapes.
+/// - `attn_implementation`: `eager`, `sdpa` or `flash_attention_2` (the latter requires the
+///   `flash-attn` feature and a CUDA device).
+/// - `max_position_embeddings`: longest sequence the rotary tables and cache sizing assume.
+/// - `rope_scaling`: a named [`RopeScalingPreset`], expanded into the config's `rope_scaling`
+///   object.
+///
+/// Unknown fields are rejected rather than ignored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanguageConfigOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rms_norm_eps: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rope_theta: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attn_implementation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_position_embeddings: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rope_scaling: Option<RopeScalingPreset>,
+}
+
+/// Named rotary-embedding scaling preset for running past the trained context length.
+///
+/// Written as `rope_scaling = { type = "ntk", factor = 4.0 }` under `[model_config_overrides]`.
+/// Every factor must be at least 1; raise `max_position_embeddings` alongside it so the rotary
+/// tables and cache sizing cover the longer context.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RopeScalingPreset {
+    /// Position interpolation: positions are divided by `factor`, so `factor` times the trained
+    /// context maps onto the trained range. Simple but blurs nearby positions; keep to 2-4x.
+    Linear { factor: f32 },
+    /// NTK-aware scaling: raises `rope_theta` by `factor^(d / (d - 2))` for a rotary dimension
+    /// `d`, stretching low frequencies while keeping high ones close to the original. Holds up
+    /// better than linear without fine-tuning; 2-8x.
+    Ntk { factor: f32 },
+    /// YaRN: interpolates only the frequencies whose wavelength exceeds the original context,
+    /// leaves the fast ones untouched, and sharpens attention by `0.1 * ln(factor) + 1`. Best of
+    /// the three at long range; 4-32x. `original_max_position_embeddings` defaults to the
+    /// checkpoint's `max_position_embeddings` before overrides.
+    Yarn {
+        factor: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        original_max_position_embeddings: Option<usize>,
+        #[serde(default = "default_yarn_beta_fast")]
+        beta_fast: f32,
+        #[serde(default = "default_yarn_beta_slow")]
+        beta_slow: f32,
+    },
+}
+
+impl RopeScalingPreset {
+    pub fn factor(&self) -> f32 {
+        match *self {
+            Self::Linear { factor } | Self::Ntk { factor } | Self::Yarn { factor, .. } => factor,
+        }
+    }
+
+    /// Reject factors below 1 and YaRN betas that do not bracket a frequency band.
+    pub fn validate(&self) -> Result<()> {
+        let factor = self.factor();
+        ensure!(
+            factor.is_finite() && factor >= 1.0,
+            "rope_scaling factor must be a finite number >= 1 (got {factor})"
+        );
+        if let Self::Yarn {
+            original_max_position_embeddings,
+            beta_fast,
+            beta_slow,
+            ..
+        } = *self
+        {
+            ensure!(
+                original_max_position_embeddings != Some(0),
+                "rope_scaling original_max_position_embeddings must be greater than zero"
+            );
+            ensure!(
+                beta_slow.is_finite() && beta_fast.is_finite() && 0.0 < beta_slow,
+                "rope_scaling betas must be positive finite numbers"
+            );
+            ensure!(
+                beta_fast > beta_slow,
+                "rope_scaling beta_fast ({beta_fast}) must exceed beta_slow ({beta_slow})"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_yarn_beta_fast() -> f32 {
+    32.0
+}
+
+fn default_yarn_beta_slow() -> f32 {
+    1.0
+}
+
+impl LanguageConfigOverrides {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+const ATTN_IMPLEMENTATIONS: [&str; 3] = ["eager", "sdpa", "flash_attention_2"];
+
+impl DeepseekV2Config {
+    /// Merge `overrides` into this config and validate the result.
+    pub fn apply_overrides(&mut self, overrides: &LanguageConfigOverrides) -> Result<()> {
+        if let Some(eps) = overrides.rms_norm_eps {
+            self.rms_norm_eps = eps;
+        }
+        if let Some(theta) = overrides.rope_theta {
+            self.rope_theta = theta;
+        }
+        if let Some(attn) = overrides.attn_implementation.as_ref() {
+            self.attn_implementation = Some(attn.clone());
+        }
+        if let Some(mut preset) = overrides.rope_scaling {
+            if let RopeScalingPreset::Yarn {
+                original_max_position_embeddings: original @ None,
+                ..
+            } = &mut preset
+            {
+                *original = Some(self.max_position_embeddings);
+            }
+            self.rope_scaling =
+                Some(serde_json::to_value(preset).context("failed to expand rope_scaling preset")?);
+        }
+        if let Some(max_positions) = overrides.max_position_embeddings {
+            self.max_position_embeddings = max_positions;
+        }
+        self.validate_overridable()
+            .context("invalid model config after applying overrides")
+    }
+
+    /// The `rope_scaling` object as a [`RopeScalingPreset`].
+    ///
+    /// Objects whose `type` is not one of the presets (e.g. vendor-specific variants) are ignored,
+    /// as they always have been; a recognised type with missing or malformed fields is an error.
+    pub fn rope_scaling_preset(&self) -> Result<Option<RopeScalingPreset>> {
+        let Some(value) = self.rope_scaling.as_ref() else {
+            return Ok(None);
+        };
+        let kind = value.get("type").and_then(Value::as_str);
+        if !matches!(kind, Some("linear" | "ntk" | "yarn")) {
+            return Ok(None);
+        }
+        serde_json::from_value(value.clone())
+            .map(Some)
+            .with_context(|| format!("invalid rope_scaling {value}"))
+    }
+
+    /// Sanity-check the fields [`LanguageConfigOverrides`] can touch.
+    pub fn validate_overridable(&self) -> Result<()> {
+        ensure!(
+            self.rms_norm_eps.is_finite() && self.rms_norm_eps > 0.0,
+            "rms_norm_eps must be a positive finite number (got {})",
+            self.rms_norm_eps
+        );
+        ensure!(
+            self.rope_theta.is_finite() && self.rope_theta > 0.0,
+            "rope_theta must be a positive finite number (got {})",
+            self.rope_theta
+        );
+        ensure!(
+            self.max_position_embeddings > 0,
+            "max_position_embeddings must be greater than zero"
+        );
+        if let Some(preset) = self.rope_scaling_preset()? {
+            preset.validate()?;
+        }
+        if let Some(attn) = self.attn_implementation.as_deref() {
+            ensure!(
+                ATTN_IMPLEMENTATIONS
+                    .iter()
+                    .any(|known| known.eq_ignore_ascii_case(attn)),
+                "unsupported attn_implementation `{attn}` (expected one of {})",
+                ATTN_IMPLEMENTATIONS.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Approximate memory footprint of the language decoder, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// Resident decoder weights (embeddings, attention, MLP/MoE, norms, LM head).
+    pub weights_bytes: u64,
+    /// Peak transient tensors for a single forward over `max_cache_len` tokens per sequence.
+    pub activation_bytes: u64,
+    /// Fully populated KV cache for every sequence.
+    pub kv_cache_bytes: u64,
+}
+
+impl MemoryEstimate {
+    pub fn total_bytes(&self) -> u64 {
+        self.weights_bytes + self.activation_bytes + self.kv_cache_bytes
+    }
+}
+
+impl DeepseekV2Config {
+    /// Estimate how much memory the decoder needs at `precision` before loading any weights.
+    ///
+    /// The vision encoders and projector are not described by this config and are not included.
+    /// Activations assume eager attention (full score matrices) and are an upper bound.
+    pub fn estimated_memory(
+        &self,
+        precision: Precision,
+        max_cache_len: usize,
+        max_num_seqs: usize,
+    ) -> MemoryEstimate {
+        self.estimated_memory_quantized(precision, max_cache_len, max_num_seqs, None)
+    }
+
+    /// Like [`Self::estimated_memory`], with the attention and MLP projections stored as `quant`
+    /// (see [`crate::model::LoadOptions::quantize`]). Embeddings, norms, router gates and the LM
+    /// head stay at `precision`, as they do when loading.
+    pub fn estimated_memory_quantized(
+        &self,
+        precision: Precision,
+        max_cache_len: usize,
+        max_num_seqs: usize,
+        quant: Option<WeightQuant>,
+    ) -> MemoryEstimate {
+        let elem = dtype_from_precision(precision).size_in_bytes() as u64;
+        let linear = |out_dim: u64, in_dim: u64| -> u64 {
+            quant
+                .and_then(|quant| quant.linear_bytes(out_dim as usize, in_dim as usize))
+                .unwrap_or(out_dim * in_dim * elem)
+        };
+        let hidden = self.hidden_size as u64;
+        let heads = self.num_attention_heads.max(1) as u64;
+        let head_dim = hidden / heads;
+        let kv_heads = self.num_key_value_heads.unwrap_or(self.num_attention_heads) as u64;
+        let v_head_dim = self
+            .v_head_dim
+            .filter(|&dim| dim > 0)
+            .map_or(head_dim, |dim| dim as u64);
+        let vocab = self.vocab_size as u64;
+
+        let attention = linear(heads * head_dim, hidden)
+            + linear(kv_heads * head_dim, hidden)
+            + linear(kv_heads * v_head_dim, hidden)
+            + linear(hidden, heads * v_head_dim);
+        let mlp_bytes =
+            |intermediate: u64| 2 * linear(intermediate, hidden) + linear(hidden, intermediate);
+        let dense_mlp = mlp_bytes(self.intermediate_size as u64);
+        let moe_intermediate = self.moe_intermediate_size.unwrap_or(0) as u64;
+        let routed = self.n_routed_experts.unwrap_or(0) as u64;
+        let shared = self.n_shared_experts.unwrap_or(0) as u64;
+        let shared_mlp = if shared > 0 {
+            mlp_bytes(moe_intermediate * shared)
+        } else {
+            0
+        };
+        let moe_mlp = routed * hidden * elem + routed * mlp_bytes(moe_intermediate) + shared_mlp;
+        let layer_bytes: u64 = (0..self.num_hidden_layers)
+            .map(|layer_idx| {
+                let mlp = if should_use_moe(self, layer_idx) {
+                    moe_mlp
+                } else {
+                    dense_mlp
+                };
+                attention + mlp + 2 * hidden * elem
+            })
+            .sum();
+        let head_params = if self.tie_word_embeddings {
+            0
+        } else {
+            vocab * hidden
+        };
+        let weights_bytes = (vocab * hidden + hidden + head_params) * elem + layer_bytes;
+
+        let seqs = max_num_seqs.max(1) as u64;
+        let tokens = max_cache_len as u64;
+        let kv_cache_bytes = self.num_hidden_layers as u64
+            * seqs
+            * tokens
+            * kv_heads
+            * (head_dim + v_head_dim)
+            * elem;
+
+        let widest_mlp = (self.intermediate_size as u64)
+            .max(moe_intermediate * shared.max(1))
+            .max(moe_intermediate * self.num_experts_per_tok.unwrap_or(0) as u64);
+        let per_token = 4 * hidden + 2 * widest_mlp + vocab;
+        let scores = heads * tokens * tokens;
+        let activation_bytes = seqs * (tokens * per_token + scores) * elem;
+
+        MemoryEstimate {
+            weights_bytes,
+            activation_bytes,
+            kv_cache_bytes,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProjectorConfig {
     #[serde(default)]
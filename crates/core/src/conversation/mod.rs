@@ -5,6 +5,13 @@ use std::{
 
 use once_cell::sync::Lazy;
 
+mod prompt;
+
+pub use prompt::{
+    GROUNDING_MARKER, IMAGE_PLACEHOLDER, InstructionTemplate, PromptTemplate, get_prompt_template,
+    prompt_template_names, register_prompt_template,
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SeparatorStyle {
     DeepSeek,
@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use once_cell::sync::Lazy;
+
+use super::{CONVERSATION_TEMPLATES, ConversationTemplate, get_conv_template};
+
+/// Placeholder the tokenizer expands into one image's vision tokens.
+pub const IMAGE_PLACEHOLDER: &str = "<image>";
+/// Marker asking the model to emit `<|ref|>`/`<|det|>` boxes alongside the text.
+pub const GROUNDING_MARKER: &str = "<|grounding|>";
+
+/// Turns a raw user prompt into the text handed to the tokenizer, including its `<image>`
+/// placeholders.
+pub trait PromptTemplate: Send + Sync {
+    fn render(&self, system_prompt: &str, user_prompt: &str) -> String;
+
+    /// Whether the model answers this template with structured Markdown (tables, lists), the
+    /// output `structure_aware_stop` trims back to a block boundary.
+    fn structured_output(&self) -> bool {
+        false
+    }
+}
+
+impl PromptTemplate for ConversationTemplate {
+    fn render(&self, system_prompt: &str, user_prompt: &str) -> String {
+        let mut template = self.clone();
+        template.set_system_message(system_prompt.to_owned());
+        template.reset_messages();
+        template.append_message("User", Some(user_prompt.to_owned()));
+        template.append_message("Assistant", None);
+        template.get_prompt()
+    }
+}
+
+/// Single-turn OCR instruction in the layout DeepSeek-OCR was trained on:
+/// `<image>\n[<|grounding|>]instruction`.
+///
+/// Placeholders already in the user prompt are kept as written, otherwise one `<image>` is
+/// prepended. A blank user prompt falls back to `default_instruction`. There is no system turn,
+/// so the system prompt is ignored.
+#[derive(Debug, Clone)]
+pub struct InstructionTemplate {
+    pub default_instruction: String,
+    pub grounding: bool,
+    /// Reported by [`PromptTemplate::structured_output`].
+    pub structured: bool,
+}
+
+impl InstructionTemplate {
+    pub fn new(default_instruction: impl Into<String>, grounding: bool) -> Self {
+        Self {
+            default_instruction: default_instruction.into(),
+            grounding,
+            structured: false,
+        }
+    }
+
+    /// Mark the instruction as one the model answers with structured Markdown.
+    pub fn with_structured_output(mut self) -> Self {
+        self.structured = true;
+        self
+    }
+}
+
+impl PromptTemplate for InstructionTemplate {
+    fn render(&self, _system_prompt: &str, user_prompt: &str) -> String {
+        let user_prompt = user_prompt.trim();
+        let (images, text) = match user_prompt.rfind(IMAGE_PLACEHOLDER) {
+            Some(pos) => user_prompt.split_at(pos + IMAGE_PLACEHOLDER.len()),
+            None => (IMAGE_PLACEHOLDER, user_prompt),
+        };
+        let mut text = text.trim_start();
+        let grounding = self.grounding && !text.starts_with(GROUNDING_MARKER);
+        if text.is_empty() || text == GROUNDING_MARKER {
+            text = &self.default_instruction;
+        }
+        let marker = if grounding { GROUNDING_MARKER } else { "" };
+        format!("{images}\n{marker}{text}")
+    }
+
+    fn structured_output(&self) -> bool {
+        self.structured
+    }
+}
+
+static PROMPT_TEMPLATES: Lazy<RwLock<BTreeMap<String, Arc<dyn PromptTemplate>>>> =
+    Lazy::new(|| {
+        let mut map: BTreeMap<String, Arc<dyn PromptTemplate>> = BTreeMap::new();
+        map.insert(
+            "markdown".into(),
+            Arc::new(
+                InstructionTemplate::new("Convert the document to markdown.", true)
+                    .with_structured_output(),
+            ),
+        );
+        map.insert(
+            "grounding".into(),
+            Arc::new(InstructionTemplate::new("OCR this image.", true)),
+        );
+        map.insert(
+            "free".into(),
+            Arc::new(InstructionTemplate::new("Free OCR.", false)),
+        );
+        RwLock::new(map)
+    });
+
+/// Register a prompt template under `name`, replacing any template already registered there.
+///
+/// Register custom templates before loading the configuration: unknown `inference.template`
+/// names are rejected when the config is normalised.
+pub fn register_prompt_template(name: impl Into<String>, template: impl PromptTemplate + 'static) {
+    PROMPT_TEMPLATES
+        .write()
+        .expect("prompt template registry poisoned")
+        .insert(name.into(), Arc::new(template));
+}
+
+/// Look up a prompt template by name. Conversation templates (`plain`, `deepseek`, ...) are
+/// prompt templates too and are consulted when no prompt template is registered under `name`.
+pub fn get_prompt_template(name: &str) -> Option<Arc<dyn PromptTemplate>> {
+    let registered = PROMPT_TEMPLATES
+        .read()
+        .expect("prompt template registry poisoned")
+        .get(name)
+        .cloned();
+    registered.or_else(|| {
+        get_conv_template(name).map(|template| Arc::new(template) as Arc<dyn PromptTemplate>)
+    })
+}
+
+/// Every name [`get_prompt_template`] resolves, sorted.
+pub fn prompt_template_names() -> Vec<String> {
+    let mut names: Vec<String> = PROMPT_TEMPLATES
+        .read()
+        .expect("prompt template registry poisoned")
+        .keys()
+        .cloned()
+        .collect();
+    names.extend(
+        CONVERSATION_TEMPLATES
+            .read()
+            .expect("conversation registry poisoned")
+            .keys()
+            .cloned(),
+    );
+    names.sort();
+    names.dedup();
+    names
+}
@@ -0,0 +1,401 @@
+//! Canonical JSON envelope for everything recognised on a page.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{inference::FinishReason, transformer::sampling::SamplingParams};
+
+/// Version of the [`DocumentResult`] layout. Bumped whenever a field is renamed, removed or
+/// changes meaning; adding a field keeps the version.
+pub const DOCUMENT_SCHEMA_VERSION: u32 = 1;
+
+/// Structured result of one OCR pass: the text plus everything that can be derived from it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocumentResult {
+    pub schema_version: u32,
+    /// Recognised text with grounding markup removed.
+    pub text: String,
+    /// Grounded regions, in output order. Empty unless the prompt asked for grounding.
+    pub regions: Vec<DocumentRegion>,
+    /// Tables found in the text, in output order.
+    pub tables: Vec<DocumentTable>,
+    pub usage: DocumentUsage,
+    pub finish_reason: FinishReason,
+    /// Decoded output with special tokens and grounding markup kept, before any post-processing.
+    /// Debugging aid for layout parsing; only set when raw output is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_output: Option<String>,
+}
+
+/// A labelled region from `<|ref|>label<|/ref|><|det|>[[x1, y1, x2, y2], ...]<|/det|>` markup.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocumentRegion {
+    pub label: String,
+    /// `[x1, y1, x2, y2]` boxes on the model's 0-999 grid, relative to the input image.
+    pub boxes: Vec<[u32; 4]>,
+}
+
+/// Largest coordinate of the grid grounding boxes are written on; `999` is the image's far edge.
+pub const GROUNDING_GRID_MAX: f32 = 999.0;
+
+/// One grounded box of an OCR pass, in pixels of the image it was recognised from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OcrRegion {
+    /// The `<|ref|>` label, e.g. `title` for layout prompts or the located phrase.
+    pub label: String,
+    /// Text the model wrote for the region, or the label when it wrote none.
+    pub text: String,
+    /// `[x1, y1, x2, y2]` in input image pixels.
+    pub bbox: [f32; 4],
+}
+
+/// Layout of an OCR pass: grounded regions when the output has boxes, the raw text otherwise.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "content", rename_all = "lowercase")]
+pub enum OcrLayout {
+    Regions(Vec<OcrRegion>),
+    Raw(String),
+}
+
+impl DocumentRegion {
+    /// This region's boxes scaled from the grounding grid to a `width` x `height` image.
+    ///
+    /// The grid spans the whole input image regardless of how preprocessing resized or
+    /// letterboxed it for the vision encoder, so pass the original image size.
+    pub fn pixel_boxes(&self, width: u32, height: u32) -> Vec<[f32; 4]> {
+        self.boxes
+            .iter()
+            .map(|&grid_box| grid_box_to_pixels(grid_box, width, height))
+            .collect()
+    }
+}
+
+/// Parse grounding markup in decoded `output` into one [`OcrRegion`] per box, scaled to a
+/// `width` x `height` image. A region's text is whatever the model wrote after its `det` block,
+/// up to the next `ref`, with markup removed. Output without any well-formed box comes back
+/// unchanged as [`OcrLayout::Raw`].
+pub fn parse_layout(output: &str, width: u32, height: u32) -> OcrLayout {
+    let mut regions = Vec::new();
+    let mut rest = output;
+    while let Some(start) = rest.find(REF_OPEN) {
+        let after_open = &rest[start + REF_OPEN.len()..];
+        let Some(label_end) = after_open.find(REF_CLOSE) else {
+            break;
+        };
+        let label = after_open[..label_end].trim();
+        rest = &after_open[label_end + REF_CLOSE.len()..];
+        let Some(det) = rest.strip_prefix(DET_OPEN) else {
+            continue;
+        };
+        let Some(end) = det.find(DET_CLOSE) else {
+            continue;
+        };
+        let Some(boxes) = parse_boxes(&det[..end]) else {
+            continue;
+        };
+        rest = &det[end + DET_CLOSE.len()..];
+        let body = &rest[..rest.find(REF_OPEN).unwrap_or(rest.len())];
+        let body = strip_stray_markup(body);
+        let text = match body.trim() {
+            "" => label,
+            body => body,
+        };
+        regions.extend(boxes.into_iter().map(|grid_box| OcrRegion {
+            label: label.to_string(),
+            text: text.to_string(),
+            bbox: grid_box_to_pixels(grid_box, width, height),
+        }));
+    }
+    if regions.is_empty() {
+        OcrLayout::Raw(output.to_string())
+    } else {
+        OcrLayout::Regions(regions)
+    }
+}
+
+fn grid_box_to_pixels(grid_box: [u32; 4], width: u32, height: u32) -> [f32; 4] {
+    let scale = |value: u32, extent: u32| {
+        (value as f32).min(GROUNDING_GRID_MAX) / GROUNDING_GRID_MAX * extent as f32
+    };
+    let [x1, y1, x2, y2] = grid_box;
+    [
+        scale(x1, width),
+        scale(y1, height),
+        scale(x2, width),
+        scale(y2, height),
+    ]
+}
+
+/// One table, kept in the markup the model wrote it in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocumentTable {
+    pub format: TableFormat,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableFormat {
+    Markdown,
+    Html,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DocumentUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    /// Skew corrected by the deskew preprocessor, per image it ran on, in degrees.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deskew_degrees: Vec<f32>,
+    /// Sampling settings the text was decoded with, when the producer reports them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<SamplingUsage>,
+    /// Exact prompt token ids fed to the model, image placeholders included, when the caller
+    /// asked for them (e.g. to seed an external prefix cache).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<i64>>,
+}
+
+/// Sampling settings a generation actually ran with. `top_p` and `seed` are left out when
+/// decoding greedily, since they have no effect then.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SamplingUsage {
+    pub temperature: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl From<SamplingParams> for SamplingUsage {
+    fn from(params: SamplingParams) -> Self {
+        let sampled = params.temperature > 0.0;
+        Self {
+            temperature: params.temperature,
+            top_p: params.top_p.filter(|_| sampled),
+            seed: params.seed.filter(|_| sampled),
+        }
+    }
+}
+
+impl DocumentResult {
+    /// Build the envelope from normalised output text, extracting regions and tables.
+    pub fn from_output(
+        output: &str,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        finish_reason: FinishReason,
+    ) -> Self {
+        let (text, regions) = extract_regions(output);
+        let tables = extract_tables(&text);
+        Self {
+            schema_version: DOCUMENT_SCHEMA_VERSION,
+            text,
+            regions,
+            tables,
+            usage: DocumentUsage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+                deskew_degrees: Vec::new(),
+                sampling: None,
+                prompt_token_ids: None,
+            },
+            finish_reason,
+            raw_output: None,
+        }
+    }
+}
+
+/// Writes one [`DocumentResult`] per line as soon as it is produced, so a batch never holds more
+/// than the current result in memory and a crash loses at most the result being written.
+pub struct JsonlSink<W: Write> {
+    writer: W,
+    written: usize,
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, written: 0 }
+    }
+
+    /// Serialize `document` as a single newline-terminated line and flush it.
+    pub fn write(&mut self, document: &DocumentResult) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, document)
+            .context("failed to serialize document result")?;
+        self.writer.write_all(b"\n")?;
+        self.writer
+            .flush()
+            .context("failed to flush document result")?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Number of results written through this sink (resumed lines are not counted).
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl JsonlSink<File> {
+    /// Open `path` for JSONL output, returning the sink and how many results it already holds.
+    ///
+    /// Without `resume` the file is truncated. With `resume` complete lines are kept and counted,
+    /// and a trailing line without its newline (an interrupted write) is dropped so the next
+    /// result starts on a fresh line.
+    pub fn open(path: &Path, resume: bool) -> Result<(Self, usize)> {
+        if !resume || !path.exists() {
+            let file = File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            return Ok((Self::new(file), 0));
+        }
+        let mut reader = BufReader::new(
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+        );
+        let mut complete = 0usize;
+        let mut complete_len = 0u64;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader
+                .read_until(b'\n', &mut line)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            complete_len += read as u64;
+            if line.iter().any(|byte| !byte.is_ascii_whitespace()) {
+                complete += 1;
+            }
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("failed to open {} for appending", path.display()))?;
+        file.set_len(complete_len)
+            .with_context(|| format!("failed to truncate {}", path.display()))?;
+        file.seek(SeekFrom::End(0))?;
+        Ok((Self::new(file), complete))
+    }
+}
+
+const REF_OPEN: &str = "<|ref|>";
+const REF_CLOSE: &str = "<|/ref|>";
+const DET_OPEN: &str = "<|det|>";
+const DET_CLOSE: &str = "<|/det|>";
+
+/// Split grounding markup out of `output`, returning the remaining text and the regions.
+///
+/// A `ref` without a well-formed `det` block keeps its label in the text and yields no region.
+fn extract_regions(output: &str) -> (String, Vec<DocumentRegion>) {
+    let mut text = String::with_capacity(output.len());
+    let mut regions = Vec::new();
+    let mut rest = output;
+    while let Some(start) = rest.find(REF_OPEN) {
+        text.push_str(&rest[..start]);
+        let after_open = &rest[start + REF_OPEN.len()..];
+        let Some(label_end) = after_open.find(REF_CLOSE) else {
+            rest = after_open;
+            break;
+        };
+        let label = after_open[..label_end].trim();
+        let after_label = &after_open[label_end + REF_CLOSE.len()..];
+        let det = after_label
+            .strip_prefix(DET_OPEN)
+            .and_then(|det| det.find(DET_CLOSE).map(|end| (&det[..end], &det[end..])));
+        match det.and_then(|(coords, tail)| parse_boxes(coords).map(|boxes| (boxes, tail))) {
+            Some((boxes, tail)) => {
+                regions.push(DocumentRegion {
+                    label: label.to_string(),
+                    boxes,
+                });
+                rest = tail[DET_CLOSE.len()..].trim_start_matches('\n');
+            }
+            None => {
+                text.push_str(label);
+                rest = after_label;
+            }
+        }
+    }
+    text.push_str(rest);
+    (strip_stray_markup(&text).trim().to_string(), regions)
+}
+
+/// Drop grounding tags left without a matching `ref`.
+fn strip_stray_markup(text: &str) -> String {
+    text.replace(REF_CLOSE, "")
+        .replace(DET_OPEN, "")
+        .replace(DET_CLOSE, "")
+}
+
+/// Parse `[[x1, y1, x2, y2], ...]`.
+fn parse_boxes(coords: &str) -> Option<Vec<[u32; 4]>> {
+    let inner = coords.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let mut boxes = Vec::new();
+    for group in inner.split(']') {
+        let group = group.trim_start_matches([',', ' ', '\n']);
+        if group.is_empty() {
+            continue;
+        }
+        let values = group
+            .strip_prefix('[')?
+            .split(',')
+            .map(|value| value.trim().parse().ok())
+            .collect::<Option<Vec<u32>>>()?;
+        boxes.push(<[u32; 4]>::try_from(values).ok()?);
+    }
+    (!boxes.is_empty()).then_some(boxes)
+}
+
+/// Collect HTML `<table>` blocks and runs of Markdown pipe rows.
+fn extract_tables(text: &str) -> Vec<DocumentTable> {
+    let mut tables = Vec::new();
+    let mut markdown: Vec<&str> = Vec::new();
+    let mut html: Option<String> = None;
+    let flush_markdown = |rows: &mut Vec<&str>, tables: &mut Vec<DocumentTable>| {
+        // A header and a delimiter row at minimum.
+        if rows.len() >= 2 {
+            tables.push(DocumentTable {
+                format: TableFormat::Markdown,
+                content: rows.join("\n"),
+            });
+        }
+        rows.clear();
+    };
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if let Some(buffer) = html.as_mut() {
+            buffer.push('\n');
+            buffer.push_str(line);
+        } else if let Some(start) = trimmed.find("<table") {
+            flush_markdown(&mut markdown, &mut tables);
+            html = Some(trimmed[start..].to_string());
+        } else if trimmed.starts_with('|') {
+            markdown.push(trimmed);
+            continue;
+        } else {
+            flush_markdown(&mut markdown, &mut tables);
+            continue;
+        }
+        if let Some(buffer) = html.take_if(|buffer| buffer.contains("</table>")) {
+            let end = buffer.find("</table>").map_or(buffer.len(), |end| end + 8);
+            tables.push(DocumentTable {
+                format: TableFormat::Html,
+                content: buffer[..end].to_string(),
+            });
+        }
+    }
+    flush_markdown(&mut markdown, &mut tables);
+    tables
+}
@@ -1,40 +1,83 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, mpsc},
+    time::{Duration, Instant},
+};
+
 use tracing::trace;
 
 use anyhow::{Context, Result, anyhow};
-use candle_core::Tensor;
+use candle_core::{Device, Tensor};
+use clap::ValueEnum;
 use image::DynamicImage;
+use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
 use crate::{
-    benchmark::Timer,
-    conversation::get_conv_template,
-    model::{DeepseekOcrModel, OwnedVisionInput, VisionInput},
+    benchmark::{Timer, sync},
+    conversation::{get_prompt_template, prompt_template_names},
+    model::{
+        BatchItem, DeepseekOcrModel, GenerateOptions, LoadOptions, OwnedVisionInput, VisionInput,
+    },
+    runtime::{Precision, dtype_from_precision},
+    vision::{PreprocessPipeline, PreprocessStats},
 };
 
-/// Render a prompt using the configured conversation template and system prompt.
+/// Render a prompt using the named [`PromptTemplate`](crate::conversation::PromptTemplate) and
+/// system prompt.
 pub fn render_prompt(template: &str, system_prompt: &str, raw_prompt: &str) -> Result<String> {
     let timer = Timer::new("prompt.render");
-    let mut template = get_conv_template(template)
-        .with_context(|| format!("unknown conversation template {template}"))?;
-    template.set_system_message(system_prompt.to_owned());
-    template.reset_messages();
-    template.append_message("User", Some(raw_prompt.to_owned()));
-    template.append_message("Assistant", None);
-    let prompt = template.get_prompt();
+    let template = get_prompt_template(template).with_context(|| {
+        format!(
+            "unknown prompt template `{template}` (known: {})",
+            prompt_template_names().join(", ")
+        )
+    })?;
+    let prompt = template.render(system_prompt, raw_prompt);
     timer.finish(|event| {
         event.add_field("chars", prompt.len() as u64);
     });
     Ok(prompt)
 }
 
-/// Prepare SAM/CLIP inputs for the provided images.
+/// Prepare SAM/CLIP inputs for the provided images. `strip_aspect_threshold` enables strip
+/// tiling for very elongated images (see [`crate::vision::select_tile_grid`]); `preprocess` runs
+/// on each image before the global view and crops are built.
 pub fn prepare_vision_inputs(
     model: &DeepseekOcrModel,
     images: &[DynamicImage],
     base_size: u32,
     image_size: u32,
     crop_mode: bool,
+    strip_aspect_threshold: Option<f32>,
+    preprocess: &PreprocessPipeline,
 ) -> Result<Vec<OwnedVisionInput>> {
+    prepare_vision_inputs_with_stats(
+        model,
+        images,
+        base_size,
+        image_size,
+        crop_mode,
+        strip_aspect_threshold,
+        preprocess,
+    )
+    .map(|(inputs, _)| inputs)
+}
+
+/// [`prepare_vision_inputs`], also returning the [`PreprocessStats`] gathered for each image.
+pub fn prepare_vision_inputs_with_stats(
+    model: &DeepseekOcrModel,
+    images: &[DynamicImage],
+    base_size: u32,
+    image_size: u32,
+    crop_mode: bool,
+    strip_aspect_threshold: Option<f32>,
+    preprocess: &PreprocessPipeline,
+) -> Result<(Vec<OwnedVisionInput>, Vec<PreprocessStats>)> {
     let timer = Timer::new("vision.prepare_inputs");
     if !images.is_empty() {
         trace!(
@@ -44,18 +87,35 @@ pub fn prepare_vision_inputs(
     let result = images
         .iter()
         .map(|image| {
-            model
-                .prepare_vision_input_from_image(image, base_size, image_size, crop_mode)
-                .with_context(|| "failed to build vision input")
+            let processed;
+            let (image, stats) = if preprocess.is_empty() {
+                (image, PreprocessStats::default())
+            } else {
+                let (image, stats) = preprocess.apply_with_stats(image.clone())?;
+                processed = image;
+                (&processed, stats)
+            };
+            let input = model
+                .prepare_vision_input_with_strips(
+                    image,
+                    base_size,
+                    image_size,
+                    crop_mode,
+                    strip_aspect_threshold,
+                )
+                .with_context(|| "failed to build vision input")?;
+            Ok((input, stats))
         })
-        .collect::<Result<Vec<_>>>();
+        .collect::<Result<(Vec<_>, Vec<_>)>>();
     match &result {
-        Ok(inputs) => {
+        Ok((inputs, _)) => {
             timer.finish(|event| {
                 event.add_field("images", inputs.len());
                 event.add_field("base_size", base_size as u64);
                 event.add_field("image_size", image_size as u64);
                 event.add_field("crop_mode", crop_mode);
+                event.add_field("strip_tiling", strip_aspect_threshold.is_some());
+                event.add_field("preprocess_steps", preprocess.len() as u64);
             });
         }
         Err(_) => {
@@ -82,7 +142,9 @@ pub fn compute_image_embeddings(
         .map(|owned| Some(owned.as_ref()))
         .collect();
     trace!("Computing image embeddings for {} image(s)...", refs.len());
+    sync(model.device());
     let outputs = model.compute_image_embeddings(&refs);
+    sync(model.device());
     match &outputs {
         Ok(values) => {
             let tokens_total: u64 = values
@@ -114,6 +176,94 @@ pub fn build_prompt_tokens(
     crop_mode: bool,
 ) -> Result<(Vec<i64>, Vec<u8>)> {
     let timer = Timer::new("prompt.build_tokens");
+    anyhow::ensure!(
+        embeddings.len() == vision_inputs.len(),
+        "vision input count {} does not match embeddings {}",
+        vision_inputs.len(),
+        embeddings.len()
+    );
+    let (tokens, mask, segments) =
+        assemble_prompt_tokens(tokenizer, prompt, embeddings, |idx, image_token_id| {
+            build_image_placeholders(
+                image_token_id,
+                &vision_inputs[idx],
+                embeddings[idx]
+                    .shape()
+                    .dims2()
+                    .context("vision embedding must be 2D")?
+                    .0,
+                base_size,
+                image_size,
+                crop_mode,
+            )
+            .with_context(|| format!("failed to expand placeholders for image {idx}"))
+        })?;
+
+    let total_tokens = tokens.len();
+    let image_tokens = mask.iter().filter(|&&flag| flag != 0).count();
+    timer.finish(|event| {
+        event.add_field("tokens", total_tokens);
+        event.add_field("image_tokens", image_tokens);
+        event.add_field("segments", segments);
+        event.add_field("crop_mode", crop_mode);
+    });
+
+    Ok((tokens, mask))
+}
+
+/// Tokenise a prompt for embeddings that were computed elsewhere (e.g. by a separate
+/// preprocessing service). Each `<image>` slot expands to one placeholder per embedding row.
+pub fn build_prompt_tokens_for_embeddings(
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    embeddings: &[Tensor],
+) -> Result<(Vec<i64>, Vec<u8>)> {
+    let timer = Timer::new("prompt.build_tokens");
+    let (tokens, mask, segments) =
+        assemble_prompt_tokens(tokenizer, prompt, embeddings, |idx, image_token_id| {
+            let rows = embeddings[idx]
+                .shape()
+                .dims2()
+                .context("vision embedding must be 2D")?
+                .0;
+            Ok(vec![image_token_id; rows])
+        })?;
+
+    let total_tokens = tokens.len();
+    let image_tokens = mask.iter().filter(|&&flag| flag != 0).count();
+    timer.finish(|event| {
+        event.add_field("tokens", total_tokens);
+        event.add_field("image_tokens", image_tokens);
+        event.add_field("segments", segments);
+        event.add_field("precomputed", true);
+    });
+
+    Ok((tokens, mask))
+}
+
+/// Ids of tokens that only stand in for image embeddings in the prompt, for
+/// [`GenerateOptions::banned_token_ids`]. Every image position, row breaks and view separator
+/// included, is spliced in under `<image>`, so decoding it only ever produces a stray placeholder.
+pub fn image_token_ids(tokenizer: &Tokenizer) -> Vec<i64> {
+    tokenizer
+        .token_to_id("<image>")
+        .map(i64::from)
+        .into_iter()
+        .collect()
+}
+
+/// Shared tokenisation loop: text segments are encoded and `expand_image(idx, image_token_id)`
+/// supplies the token run for the `idx`-th `<image>` slot. Returns tokens, mask and the number
+/// of text segments.
+fn assemble_prompt_tokens<F>(
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    embeddings: &[Tensor],
+    mut expand_image: F,
+) -> Result<(Vec<i64>, Vec<u8>, usize)>
+where
+    F: FnMut(usize, i64) -> Result<Vec<i64>>,
+{
     let image_token_id = tokenizer
         .token_to_id("<image>")
         .ok_or_else(|| anyhow!("tokenizer missing <image> token"))? as i64;
@@ -126,12 +276,6 @@ pub fn build_prompt_tokens(
         segments.len().saturating_sub(1),
         embeddings.len()
     );
-    anyhow::ensure!(
-        embeddings.len() == vision_inputs.len(),
-        "vision input count {} does not match embeddings {}",
-        vision_inputs.len(),
-        embeddings.len()
-    );
 
     let mut tokens = Vec::new();
     let mut mask = Vec::new();
@@ -145,33 +289,13 @@ pub fn build_prompt_tokens(
         tokens.extend(encoding.get_ids().iter().map(|&id| id as i64));
         mask.extend(std::iter::repeat(0u8).take(encoding.len()));
         if idx < embeddings.len() {
-            let placeholders = build_image_placeholders(
-                image_token_id,
-                &vision_inputs[idx],
-                embeddings[idx]
-                    .shape()
-                    .dims2()
-                    .context("vision embedding must be 2D")?
-                    .0,
-                base_size,
-                image_size,
-                crop_mode,
-            )?;
+            let placeholders = expand_image(idx, image_token_id)?;
             tokens.extend(&placeholders);
             mask.extend(std::iter::repeat(1u8).take(placeholders.len()));
         }
     }
 
-    let total_tokens = tokens.len();
-    let image_tokens = mask.iter().filter(|&&flag| flag != 0).count();
-    timer.finish(|event| {
-        event.add_field("tokens", total_tokens);
-        event.add_field("image_tokens", image_tokens);
-        event.add_field("segments", segments.len());
-        event.add_field("crop_mode", crop_mode);
-    });
-
-    Ok((tokens, mask))
+    Ok((tokens, mask, segments.len()))
 }
 
 /// Normalise decoder output by stripping sentinel tokens and Windows line-endings.
@@ -182,10 +306,501 @@ pub fn normalize_text(s: &str) -> String {
         .to_string()
 }
 
-fn build_image_placeholders(
+/// Markup the model may emit on a blank page in place of real content.
+const BLANK_PLACEHOLDERS: &[&str] = &[
+    "<image>",
+    "<｜▁pad▁｜>",
+    "<|grounding|>",
+    "<|ref|>",
+    "<|/ref|>",
+    "<|det|>",
+    "<|/det|>",
+];
+
+/// Why a recognition pass produced the text it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// Decoding ended on EOS, a stop sequence or the token budget.
+    Stop,
+    /// The output held nothing but whitespace or placeholder markup and was cleared, e.g. for a
+    /// blank scan.
+    Empty,
+    /// The token budget ran out mid-structure and the output was trimmed back to the last
+    /// complete block (see [`trim_to_structural_boundary`]).
+    Truncated,
+}
+
+impl FinishReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Empty => "empty",
+            FinishReason::Truncated => "truncated",
+        }
+    }
+}
+
+/// Whether decoded text carries no content: only whitespace and placeholder tokens.
+pub fn is_blank_output(text: &str) -> bool {
+    let mut rest = text.to_string();
+    for placeholder in BLANK_PLACEHOLDERS {
+        rest = rest.replace(placeholder, "");
+    }
+    rest.trim().is_empty()
+}
+
+/// Finish post-processing of normalised text. With `detect_empty`, blank output is replaced by
+/// an empty string and reported as [`FinishReason::Empty`] so callers can skip it.
+pub fn finish_output(text: String, detect_empty: bool) -> (String, FinishReason) {
+    if detect_empty && is_blank_output(&text) {
+        (String::new(), FinishReason::Empty)
+    } else {
+        (text, FinishReason::Stop)
+    }
+}
+
+/// Like [`finish_output`], reporting [`FinishReason::Truncated`] for non-blank output that
+/// [`trim_to_structural_boundary`] cut back.
+pub fn finish_trimmed_output(
+    text: String,
+    detect_empty: bool,
+    trimmed: bool,
+) -> (String, FinishReason) {
+    match finish_output(text, detect_empty) {
+        (text, FinishReason::Stop) if trimmed => (text, FinishReason::Truncated),
+        finished => finished,
+    }
+}
+
+/// Trim output that was cut off by the token budget back to the last complete Markdown
+/// structure, so a half-written table row, list item or HTML table does not leave the result
+/// unparseable. Returns the trimmed text and whether anything was removed.
+pub fn trim_to_structural_boundary(text: &str) -> (String, bool) {
+    // Markdown mode renders tables as HTML; close an unterminated one after its last full row.
+    if let Some(open) = text
+        .rfind("<table")
+        .filter(|&open| !text[open..].contains("</table>"))
+    {
+        let trimmed = match text[open..].rfind("</tr>") {
+            Some(row_end) => format!("{}</table>", &text[..open + row_end + "</tr>".len()]),
+            None => text[..open].trim_end().to_string(),
+        };
+        return (trimmed, true);
+    }
+    if text.ends_with('\n') {
+        return (text.to_string(), false);
+    }
+    let line_start = text.rfind('\n').map_or(0, |idx| idx + 1);
+    let last_line = text[line_start..].trim();
+    let partial = if last_line.starts_with('|') {
+        // A pipe-table row is only complete once it has emitted as many cells as the row above.
+        let previous_row = text[..line_start].lines().next_back().map(str::trim);
+        let expected_pipes = previous_row
+            .filter(|row| row.starts_with('|'))
+            .map(|row| row.matches('|').count());
+        last_line.len() < 2
+            || !last_line.ends_with('|')
+            || expected_pipes.is_some_and(|pipes| last_line.matches('|').count() < pipes)
+    } else {
+        is_list_item(last_line)
+    };
+    if partial {
+        (text[..line_start].trim_end().to_string(), true)
+    } else {
+        (text.to_string(), false)
+    }
+}
+
+/// How to treat a multibyte character that the token budget cut in half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartialUtf8 {
+    /// Drop the trailing tokens that only hold part of a character.
+    Drop,
+    /// Decode a few tokens past the budget to finish the character, dropping it if that fails.
+    Complete,
+}
+
+/// Text tokens budgeted per vision token by [`MaxNewTokens::Auto`]. The model reads back about
+/// ten text tokens per vision token on the densest pages it handles well, so this covers a full
+/// page; sparse pages reach EOS long before it.
+pub const AUTO_TEXT_TOKENS_PER_VISION_TOKEN: usize = 10;
+
+/// Smallest budget [`MaxNewTokens::Auto`] picks, e.g. for prompts without images.
+pub const AUTO_MIN_NEW_TOKENS: usize = 512;
+
+/// Generation budget: an explicit token count, or `auto` to size it from the prompt.
+///
+/// Written as a number or `"auto"` in config files and on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxNewTokens {
+    /// Scale with the vision token count, bounded by the remaining context and a ceiling.
+    Auto,
+    Fixed(usize),
+}
+
+impl MaxNewTokens {
+    /// Resolve to a token count for a prompt of `prompt_len` tokens, `vision_tokens` of which are
+    /// image placeholders, on a model with `context_len` positions. [`MaxNewTokens::Fixed`] is
+    /// returned unchanged; [`MaxNewTokens::Auto`] budgets
+    /// [`AUTO_TEXT_TOKENS_PER_VISION_TOKEN`] per vision token (at least [`AUTO_MIN_NEW_TOKENS`]),
+    /// clamped to the context left after the prompt and to `ceiling`.
+    pub fn resolve(
+        self,
+        prompt_len: usize,
+        vision_tokens: usize,
+        context_len: usize,
+        ceiling: usize,
+    ) -> usize {
+        match self {
+            Self::Fixed(tokens) => tokens,
+            Self::Auto => (vision_tokens * AUTO_TEXT_TOKENS_PER_VISION_TOKEN)
+                .max(AUTO_MIN_NEW_TOKENS)
+                .min(context_len.saturating_sub(prompt_len))
+                .min(ceiling),
+        }
+    }
+}
+
+impl Default for MaxNewTokens {
+    fn default() -> Self {
+        Self::Fixed(512)
+    }
+}
+
+impl From<usize> for MaxNewTokens {
+    fn from(tokens: usize) -> Self {
+        Self::Fixed(tokens)
+    }
+}
+
+impl FromStr for MaxNewTokens {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        s.parse()
+            .map(Self::Fixed)
+            .map_err(|_| anyhow!("expected a token count or `auto`, got `{s}`"))
+    }
+}
+
+impl fmt::Display for MaxNewTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Fixed(tokens) => write!(f, "{tokens}"),
+        }
+    }
+}
+
+impl Serialize for MaxNewTokens {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::Fixed(tokens) => serializer.serialize_u64(*tokens as u64),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MaxNewTokens {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Count(usize),
+            Name(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Count(tokens) => Ok(Self::Fixed(tokens)),
+            Repr::Name(name) => name.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Whether decoded text ends in the replacement character the tokenizer substitutes for an
+/// incomplete UTF-8 sequence.
+pub fn ends_with_partial_utf8(text: &str) -> bool {
+    text.ends_with(char::REPLACEMENT_CHARACTER)
+}
+
+/// Whether the generated `ids` end partway through a multibyte character, judged by decoding
+/// their last few tokens with `decode`. Used as [`GenerateOptions::extend_while`] under
+/// [`PartialUtf8::Complete`] to decode past the budget until the character is whole.
+///
+/// [`GenerateOptions::extend_while`]: crate::model::GenerateOptions::extend_while
+pub fn tail_is_partial_utf8<F>(ids: &[i64], decode: F) -> bool
+where
+    F: Fn(&[u32]) -> String,
+{
+    // A UTF-8 sequence is at most four bytes, so the last four tokens hold any partial one.
+    let tail: Vec<u32> = ids[ids.len().saturating_sub(4)..]
+        .iter()
+        .filter_map(|&id| u32::try_from(id).ok())
+        .collect();
+    ends_with_partial_utf8(&decode(&tail))
+}
+
+/// Decode `ids` with `decode`, backing off trailing tokens that only carry part of a multibyte
+/// character. A replacement character that survives the back-off is treated as genuine output.
+pub fn decode_without_partial_utf8<F>(ids: &[u32], decode: F) -> String
+where
+    F: Fn(&[u32]) -> String,
+{
+    // A UTF-8 sequence is at most four bytes, so at most three trailing byte tokens are partial.
+    const MAX_BACKOFF: usize = 3;
+
+    let full = decode(ids);
+    if !ends_with_partial_utf8(&full) {
+        return full;
+    }
+    for dropped in 1..=MAX_BACKOFF.min(ids.len()) {
+        let text = decode(&ids[..ids.len() - dropped]);
+        if !ends_with_partial_utf8(&text) {
+            return text;
+        }
+    }
+    full
+}
+
+/// Incremental detokenizer for streamed output. Each batch of new token ids yields only text whose
+/// UTF-8 is complete: a character split across tokens (common for CJK with byte-fallback tokens)
+/// is held back until its remaining bytes arrive, and [`Self::finish`] flushes what is left.
+#[derive(Debug, Clone, Default)]
+pub struct StreamingDetokenizer {
+    ids: Vec<u32>,
+    /// Start of the window re-decoded on every push, so decoding that depends on the previous
+    /// token (e.g. leading spaces) stays stable.
+    prefix_offset: usize,
+    /// End of the ids whose text has been emitted.
+    read_offset: usize,
+}
+
+impl StreamingDetokenizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `ids` and return the newly completed text, which may be empty.
+    pub fn push<F>(&mut self, ids: &[u32], decode: F) -> String
+    where
+        F: Fn(&[u32]) -> String,
+    {
+        self.ids.extend_from_slice(ids);
+        let prefix = decode(&self.ids[self.prefix_offset..self.read_offset]);
+        // Back off trailing ids that only carry part of a character; at most three are partial.
+        for end in (self.read_offset + 1..=self.ids.len()).rev().take(4) {
+            let text = decode(&self.ids[self.prefix_offset..end]);
+            if ends_with_partial_utf8(&text) {
+                continue;
+            }
+            let Some(delta) = text.get(prefix.len()..).filter(|delta| !delta.is_empty()) else {
+                break;
+            };
+            let delta = delta.to_string();
+            self.prefix_offset = self.read_offset;
+            self.read_offset = end;
+            return delta;
+        }
+        String::new()
+    }
+
+    /// Return the text still held back at the end of the stream. A trailing character that never
+    /// completed is dropped, as in [`decode_without_partial_utf8`].
+    pub fn finish<F>(&mut self, decode: F) -> String
+    where
+        F: Fn(&[u32]) -> String,
+    {
+        let prefix = decode(&self.ids[self.prefix_offset..self.read_offset]);
+        let text = decode_without_partial_utf8(&self.ids[self.prefix_offset..], &decode);
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.ids.len();
+        text.get(prefix.len()..).unwrap_or_default().to_string()
+    }
+}
+
+/// Sequences that end generation as soon as the output produces them, given as token ids or as
+/// text. Text is matched on the decoded output, so a stop string split across several tokens (or
+/// tokenized differently from how it would be encoded on its own) is still caught.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StopCriteria {
+    pub token_sequences: Vec<Vec<i64>>,
+    pub strings: Vec<String>,
+}
+
+impl StopCriteria {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_token_sequence(mut self, ids: Vec<i64>) -> Self {
+        if !ids.is_empty() {
+            self.token_sequences.push(ids);
+        }
+        self
+    }
+
+    pub fn with_strings<I, S>(mut self, strings: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.strings.extend(
+            strings
+                .into_iter()
+                .map(Into::into)
+                .filter(|string| !string.is_empty()),
+        );
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.token_sequences.is_empty() && self.strings.is_empty()
+    }
+
+    /// Whether generation should stop after the last token of `generated`: the ids end with a
+    /// stop sequence, or the text `decode` gives for the trailing tokens contains a stop string.
+    /// Call after every token so a match is seen on the step that completes it; only a window of
+    /// recent tokens long enough for the longest stop string is decoded.
+    pub fn matches<F>(&self, generated: &[i64], decode: F) -> bool
+    where
+        F: Fn(&[u32]) -> String,
+    {
+        if self
+            .token_sequences
+            .iter()
+            .any(|sequence| generated.ends_with(sequence))
+        {
+            return true;
+        }
+        let Some(longest) = self.strings.iter().map(String::len).max() else {
+            return false;
+        };
+        // Every token decodes to at least one byte of a stop string it contributes to, plus a few
+        // for a character split across byte-fallback tokens at the window's start.
+        let window = &generated[generated.len().saturating_sub(longest + 4)..];
+        let ids: Vec<u32> = window
+            .iter()
+            .filter_map(|&id| u32::try_from(id).ok())
+            .collect();
+        let text = decode(&ids);
+        self.strings
+            .iter()
+            .any(|string| text.contains(string.as_str()))
+    }
+
+    /// `text` up to the first stop string, so the marker that ended generation is not returned.
+    pub fn truncate<'t>(&self, text: &'t str) -> &'t str {
+        let end = self
+            .strings
+            .iter()
+            .filter_map(|string| text.find(string.as_str()))
+            .min()
+            .unwrap_or(text.len());
+        &text[..end]
+    }
+
+    /// Length of the longest suffix of `text` that could still grow into a stop string. A stream
+    /// holds that much back until the next tokens either complete the match or rule it out.
+    pub fn partial_match_len(&self, text: &str) -> usize {
+        let longest = self.strings.iter().map(String::len).max().unwrap_or(0);
+        (text.len().saturating_sub(longest)..text.len())
+            .filter(|&start| text.is_char_boundary(start))
+            .find(|&start| {
+                self.strings
+                    .iter()
+                    .any(|string| string.starts_with(&text[start..]))
+            })
+            .map_or(0, |start| text.len() - start)
+    }
+
+    /// `generated` without a stop token sequence it ends with.
+    pub fn strip_tokens<'g>(&self, generated: &'g [i64]) -> &'g [i64] {
+        self.token_sequences
+            .iter()
+            .filter(|sequence| generated.ends_with(sequence))
+            .map(|sequence| &generated[..generated.len() - sequence.len()])
+            .min_by_key(|ids| ids.len())
+            .unwrap_or(generated)
+    }
+}
+
+/// Create a channel that turns generation progress into an iterator of raw token ids.
+///
+/// Wire [`TokenIdSink::push`] into [`GenerateOptions::progress_callback`] and consume the
+/// [`TokenIdStream`] on another thread; the stream ends once the sink is dropped. To detokenize
+/// consistently with [`DeepseekOcrModel`], decode the accumulated ids with the model's tokenizer
+/// (`skip_special_tokens = true`) rather than token by token, since byte-fallback tokens only form
+/// valid UTF-8 together, then apply [`normalize_text`].
+///
+/// [`GenerateOptions::progress_callback`]: crate::model::GenerateOptions::progress_callback
+pub fn token_id_channel() -> (TokenIdSink, TokenIdStream) {
+    let (sender, receiver) = mpsc::channel();
+    (
+        TokenIdSink {
+            sender,
+            sent: Cell::new(0),
+        },
+        TokenIdStream { receiver },
+    )
+}
+
+/// Sending half of [`token_id_channel`].
+pub struct TokenIdSink {
+    sender: mpsc::Sender<i64>,
+    sent: Cell<usize>,
+}
+
+impl TokenIdSink {
+    /// Forward the ids among the first `count` generated tokens that have not been sent yet.
+    pub fn push(&self, count: usize, ids: &[i64]) {
+        let start = self.sent.get();
+        let end = count.min(ids.len());
+        if end <= start {
+            return;
+        }
+        for &id in &ids[start..end] {
+            // A dropped stream just means nobody is listening any more.
+            let _ = self.sender.send(id);
+        }
+        self.sent.set(end);
+    }
+}
+
+/// Receiving half of [`token_id_channel`]; yields each generated token id once, in order.
+pub struct TokenIdStream {
+    receiver: mpsc::Receiver<i64>,
+}
+
+impl Iterator for TokenIdStream {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        self.receiver.recv().ok()
+    }
+}
+
+fn is_list_item(line: &str) -> bool {
+    if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("+ ") {
+        return true;
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    digits > 0 && (line[digits..].starts_with(". ") || line[digits..].starts_with(") "))
+}
+
+/// Expand one `<image>` slot into the placeholder run the vision encoder fills in.
+///
+/// The run length follows from the view geometry (`base_size`, `image_size`, `crop_mode` and the
+/// crop grid), while `vision_tokens` is the row count the encoder actually produced. The two must
+/// agree, otherwise splicing would shift every image embedding against the prompt, so a mismatch
+/// is reported with both numbers instead of being silently misaligned.
+pub fn build_image_placeholders(
     image_token_id: i64,
     input: &OwnedVisionInput,
-    expected_tokens: usize,
+    vision_tokens: usize,
     base_size: u32,
     image_size: u32,
     crop_mode: bool,
@@ -230,11 +845,494 @@ fn build_image_placeholders(
         push_grid(&mut placeholders, num_queries, num_queries, true);
     }
 
+    let (width_crops, height_crops) = input.crop_shape.unwrap_or((1, 1));
     anyhow::ensure!(
-        placeholders.len() == expected_tokens,
-        "placeholder count {} does not match expected {}",
-        placeholders.len(),
-        expected_tokens
+        placeholders.len() == vision_tokens,
+        "vision encoder produced {vision_tokens} tokens but the prompt expects {} image \
+         placeholders (base_size={base_size}, image_size={image_size}, crop_mode={crop_mode}, \
+         crops={width_crops}x{height_crops}); preprocessing and model config disagree",
+        placeholders.len()
     );
     Ok(placeholders)
 }
+
+/// Output of one OCR pass in [`recognize_compare`].
+#[derive(Debug, Clone)]
+pub struct OcrResult {
+    /// Normalised decoded text.
+    pub text: String,
+    /// Raw generated token ids, for token-level diffs.
+    pub token_ids: Vec<i64>,
+    /// Exact prompt token ids fed to the model, image placeholders included. Only kept when
+    /// [`CompareSettings::return_prompt_token_ids`] is set, e.g. for external prefix caching or
+    /// inspecting what the model actually saw.
+    pub prompt_token_ids: Option<Vec<i64>>,
+    /// Wall-clock time of the pass, excluding the model load.
+    pub elapsed: Duration,
+}
+
+/// Model location and decoding settings shared by every pass of [`recognize_compare`] and every
+/// image of [`recognize_batch`].
+pub struct CompareSettings<'a> {
+    pub config_path: Option<&'a Path>,
+    pub weights_path: Option<&'a Path>,
+    pub device: Device,
+    pub load_options: LoadOptions,
+    pub tokenizer: &'a Tokenizer,
+    /// Rendered prompt holding exactly one `<image>` slot.
+    pub prompt: &'a str,
+    pub base_size: u32,
+    pub image_size: u32,
+    pub crop_mode: bool,
+    pub strip_aspect_threshold: Option<f32>,
+    pub preprocess: PreprocessPipeline,
+    pub max_new_tokens: MaxNewTokens,
+    /// Upper bound for [`MaxNewTokens::Auto`].
+    pub max_new_tokens_ceiling: usize,
+    /// Keep a copy of the prompt token ids in [`OcrResult::prompt_token_ids`].
+    pub return_prompt_token_ids: bool,
+    /// Never decode the image placeholder token (see [`image_token_ids`]).
+    pub ban_image_tokens: bool,
+    /// Receives a [`BatchProgress`] after each completed pass or item. Sends never block: when
+    /// the receiver falls behind, the oldest queued event makes way (see
+    /// [`batch_progress_channel`]).
+    pub progress: Option<BatchProgressSender>,
+}
+
+/// Progress of a multi-item run such as [`recognize_compare`], emitted after each completed item.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchProgress {
+    /// Items finished so far, including the one that triggered this event.
+    pub completed: usize,
+    /// Items in the whole run.
+    pub total: usize,
+    /// Wall-clock time since the run started.
+    pub elapsed: Duration,
+}
+
+impl BatchProgress {
+    /// Completed items per second over the run so far; zero before any time has elapsed.
+    pub fn items_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.completed as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether every item of the run has completed.
+    pub fn is_done(&self) -> bool {
+        self.completed >= self.total
+    }
+}
+
+/// Create a channel for [`BatchProgress`] events that holds at most `capacity` of them (at least
+/// one). Sending never blocks the run: when the receiver is that far behind, the oldest queued
+/// event is discarded for the new one. Events carry cumulative counts, so this only costs
+/// granularity, and the final event of a run always arrives.
+pub fn batch_progress_channel(capacity: usize) -> (BatchProgressSender, BatchProgressReceiver) {
+    let shared = Arc::new(ProgressChannel {
+        state: Mutex::new(ProgressChannelState {
+            events: VecDeque::new(),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        ready: Condvar::new(),
+        capacity: capacity.max(1),
+    });
+    (
+        BatchProgressSender {
+            shared: Arc::clone(&shared),
+        },
+        BatchProgressReceiver { shared },
+    )
+}
+
+struct ProgressChannel {
+    state: Mutex<ProgressChannelState>,
+    ready: Condvar,
+    capacity: usize,
+}
+
+struct ProgressChannelState {
+    events: VecDeque<BatchProgress>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+impl ProgressChannel {
+    fn lock(&self) -> std::sync::MutexGuard<'_, ProgressChannelState> {
+        self.state.lock().expect("batch progress lock poisoned")
+    }
+}
+
+/// Sending half of [`batch_progress_channel`].
+pub struct BatchProgressSender {
+    shared: Arc<ProgressChannel>,
+}
+
+impl BatchProgressSender {
+    /// Queue `progress`, discarding the oldest queued event when the channel is full. Does
+    /// nothing once the receiver is gone.
+    pub fn send(&self, progress: BatchProgress) {
+        let mut state = self.shared.lock();
+        if !state.receiver_alive {
+            return;
+        }
+        let full = state.events.len() >= self.shared.capacity;
+        if let Some(dropped) = full.then(|| state.events.pop_front()).flatten() {
+            trace!(
+                completed = dropped.completed,
+                total = dropped.total,
+                "batch progress receiver is behind; dropping oldest event"
+            );
+        }
+        state.events.push_back(progress);
+        drop(state);
+        self.shared.ready.notify_one();
+    }
+}
+
+impl Clone for BatchProgressSender {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for BatchProgressSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.ready.notify_all();
+        }
+    }
+}
+
+/// Receiving half of [`batch_progress_channel`]; iterating waits for each event and ends once
+/// every sender is dropped and the queued events are drained.
+pub struct BatchProgressReceiver {
+    shared: Arc<ProgressChannel>,
+}
+
+impl BatchProgressReceiver {
+    /// Wait for the next event; `None` once every sender is gone and nothing is queued.
+    pub fn recv(&self) -> Option<BatchProgress> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(progress) = state.events.pop_front() {
+                return Some(progress);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = self
+                .shared
+                .ready
+                .wait(state)
+                .expect("batch progress lock poisoned");
+        }
+    }
+
+    /// The oldest queued event, without waiting.
+    pub fn try_recv(&self) -> Option<BatchProgress> {
+        self.shared.lock().events.pop_front()
+    }
+}
+
+impl Iterator for BatchProgressReceiver {
+    type Item = BatchProgress;
+
+    fn next(&mut self) -> Option<BatchProgress> {
+        self.recv()
+    }
+}
+
+impl Drop for BatchProgressReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receiver_alive = false;
+        state.events.clear();
+    }
+}
+
+/// Offer `progress` to `sender` without blocking (see [`BatchProgressSender::send`]).
+pub fn send_batch_progress(sender: Option<&BatchProgressSender>, progress: BatchProgress) {
+    if let Some(sender) = sender {
+        sender.send(progress);
+    }
+}
+
+/// OCR `image` once per entry in `precisions` and return every result, in order, for A/B quality
+/// checks (e.g. whether F16 matches F32 closely enough).
+///
+/// This is a diagnostic, not a hot path: the model is reloaded from the weights file for each
+/// precision and greedy decoding runs without streaming. Models are loaded one at a time and
+/// dropped before the next load, so peak memory is that of a single model at the widest requested
+/// precision (roughly 2x an F16 load for F32), but total runtime is the sum of every load and pass.
+pub fn recognize_compare(
+    image: &DynamicImage,
+    precisions: &[Precision],
+    settings: &CompareSettings<'_>,
+) -> Result<Vec<(Precision, OcrResult)>> {
+    anyhow::ensure!(
+        settings.prompt.matches("<image>").count() == 1,
+        "recognize_compare expects a prompt with exactly one <image> slot"
+    );
+    let started = Instant::now();
+    let mut results = Vec::with_capacity(precisions.len());
+    for &precision in precisions {
+        let dtype = dtype_from_precision(precision);
+        let model = DeepseekOcrModel::load_with_options(
+            settings.config_path,
+            settings.weights_path,
+            settings.device.clone(),
+            dtype,
+            &settings.load_options,
+        )
+        .with_context(|| format!("failed to load model at {precision:?}"))?;
+        let result = recognize_once(&model, image, settings)
+            .with_context(|| format!("OCR pass at {precision:?} failed"))?;
+        results.push((precision, result));
+        send_batch_progress(
+            settings.progress.as_ref(),
+            BatchProgress {
+                completed: results.len(),
+                total: precisions.len(),
+                elapsed: started.elapsed(),
+            },
+        );
+    }
+    Ok(results)
+}
+
+/// OCR output for one page of a PDF, see [`recognize_pdf`].
+#[cfg(feature = "pdf")]
+#[derive(Debug, Clone)]
+pub struct PageResult {
+    /// One-based page number.
+    pub page: usize,
+    pub result: OcrResult,
+}
+
+/// Render each page of the PDF in `pdf` and OCR it with an already loaded `model`, exactly as a
+/// single image would be. Results are in page order.
+///
+/// Pages are rendered and recognised one at a time, so memory stays at one page however long the
+/// document is. Only the prompt, preprocessing and decoding fields of `settings` are used, and
+/// its progress channel receives an event per page.
+#[cfg(feature = "pdf")]
+pub fn recognize_pdf(
+    model: &DeepseekOcrModel,
+    pdf: &[u8],
+    render: &crate::vision::PdfRenderOptions,
+    settings: &CompareSettings<'_>,
+) -> Result<Vec<PageResult>> {
+    anyhow::ensure!(
+        settings.prompt.matches("<image>").count() == 1,
+        "recognize_pdf expects a prompt with exactly one <image> slot"
+    );
+    let started = Instant::now();
+    let mut pages = Vec::new();
+    crate::vision::for_each_pdf_page(pdf, render, |index, page_count, image| {
+        let page = index + 1;
+        let result = recognize_once(model, &image, settings)
+            .with_context(|| format!("OCR of PDF page {page} failed"))?;
+        pages.push(PageResult { page, result });
+        send_batch_progress(
+            settings.progress.as_ref(),
+            BatchProgress {
+                completed: page,
+                total: page_count,
+                elapsed: started.elapsed(),
+            },
+        );
+        Ok(())
+    })?;
+    Ok(pages)
+}
+
+fn recognize_once(
+    model: &DeepseekOcrModel,
+    image: &DynamicImage,
+    settings: &CompareSettings<'_>,
+) -> Result<OcrResult> {
+    let start = Instant::now();
+    let prepared = prepare_ocr(model, image, settings)?;
+    let banned_token_ids = ocr_banned_token_ids(settings);
+    let token_ids = model
+        .generate(
+            &prepared.input_ids,
+            prepared.options(model, &banned_token_ids),
+        )?
+        .to_vec2::<i64>()?
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    Ok(finish_ocr(
+        token_ids,
+        prepared.prompt_token_ids,
+        settings,
+        start.elapsed(),
+    ))
+}
+
+/// OCR every image in `images` with an already loaded `model`, decoding up to `max_num_seqs`
+/// of them together (see [`DeepseekOcrModel::generate_batch`]). Results are returned in input
+/// order and each [`OcrResult::elapsed`] is the wall-clock time of the whole batch. The progress
+/// channel of `settings` receives an event as each image finishes decoding.
+///
+/// Only the prompt, preprocessing and decoding fields of `settings` are used; the model
+/// location fields are ignored. Vision features for every image are computed up front and held
+/// until the batch ends, so memory grows with `images.len()` as well as `max_num_seqs`.
+pub fn recognize_batch(
+    model: &DeepseekOcrModel,
+    images: &[DynamicImage],
+    settings: &CompareSettings<'_>,
+    max_num_seqs: usize,
+) -> Result<Vec<OcrResult>> {
+    anyhow::ensure!(
+        settings.prompt.matches("<image>").count() == 1,
+        "recognize_batch expects a prompt with exactly one <image> slot"
+    );
+    let start = Instant::now();
+    let prepared = images
+        .iter()
+        .enumerate()
+        .map(|(idx, image)| {
+            prepare_ocr(model, image, settings)
+                .with_context(|| format!("failed to prepare image {idx}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let banned_token_ids = ocr_banned_token_ids(settings);
+    let items: Vec<BatchItem<'_>> = prepared
+        .iter()
+        .map(|item| BatchItem {
+            input_ids: &item.input_ids,
+            options: item.options(model, &banned_token_ids),
+        })
+        .collect();
+    let mut completed = 0;
+    let rows = model
+        .generate_batch_with_progress(&items, max_num_seqs, |_| {
+            completed += 1;
+            send_batch_progress(
+                settings.progress.as_ref(),
+                BatchProgress {
+                    completed,
+                    total: images.len(),
+                    elapsed: start.elapsed(),
+                },
+            );
+        })?
+        .rows()?;
+    drop(items);
+    let elapsed = start.elapsed();
+    Ok(prepared
+        .into_iter()
+        .zip(rows)
+        .map(|(item, token_ids)| finish_ocr(token_ids, item.prompt_token_ids, settings, elapsed))
+        .collect())
+}
+
+/// Prompt tensors and image features for one OCR pass.
+struct PreparedOcr {
+    input_ids: Tensor,
+    images_seq_mask: Tensor,
+    embeddings: Vec<Tensor>,
+    max_new_tokens: usize,
+    prompt_token_ids: Option<Vec<i64>>,
+}
+
+impl PreparedOcr {
+    fn options<'a>(
+        &'a self,
+        model: &DeepseekOcrModel,
+        banned_token_ids: &'a [i64],
+    ) -> GenerateOptions<'a> {
+        let mut options = GenerateOptions::new(self.max_new_tokens);
+        options.images_seq_mask = Some(&self.images_seq_mask);
+        if !self.embeddings.is_empty() {
+            options.image_embeddings = Some(self.embeddings.as_slice());
+        }
+        options.eos_token_id = model.language_model().config().eos_token_id;
+        options.banned_token_ids = banned_token_ids;
+        options
+    }
+}
+
+fn prepare_ocr(
+    model: &DeepseekOcrModel,
+    image: &DynamicImage,
+    settings: &CompareSettings<'_>,
+) -> Result<PreparedOcr> {
+    let images = std::slice::from_ref(image);
+    let owned_inputs = prepare_vision_inputs(
+        model,
+        images,
+        settings.base_size,
+        settings.image_size,
+        settings.crop_mode,
+        settings.strip_aspect_threshold,
+        &settings.preprocess,
+    )?;
+    let embeddings = compute_image_embeddings(model, &owned_inputs)?;
+    let (input_ids, mask) = build_prompt_tokens(
+        settings.tokenizer,
+        settings.prompt,
+        &embeddings,
+        &owned_inputs,
+        settings.base_size,
+        settings.image_size,
+        settings.crop_mode,
+    )?;
+    let input_len = input_ids.len();
+    let prompt_token_ids = settings.return_prompt_token_ids.then(|| input_ids.clone());
+    let max_new_tokens = settings.max_new_tokens.resolve(
+        input_len,
+        mask.iter().filter(|&&flag| flag != 0).count(),
+        model.language_model().config().max_position_embeddings,
+        settings.max_new_tokens_ceiling,
+    );
+    Ok(PreparedOcr {
+        input_ids: Tensor::from_vec(input_ids, (1, input_len), model.device())?,
+        images_seq_mask: Tensor::from_vec(mask, (1, input_len), model.device())?,
+        embeddings,
+        max_new_tokens,
+        prompt_token_ids,
+    })
+}
+
+fn ocr_banned_token_ids(settings: &CompareSettings<'_>) -> Vec<i64> {
+    if settings.ban_image_tokens {
+        image_token_ids(settings.tokenizer)
+    } else {
+        Vec::new()
+    }
+}
+
+fn finish_ocr(
+    token_ids: Vec<i64>,
+    prompt_token_ids: Option<Vec<i64>>,
+    settings: &CompareSettings<'_>,
+    elapsed: Duration,
+) -> OcrResult {
+    let decoded = decode_without_partial_utf8(
+        &token_ids
+            .iter()
+            .filter_map(|&id| u32::try_from(id).ok())
+            .collect::<Vec<_>>(),
+        |ids| settings.tokenizer.decode(ids, true).unwrap_or_default(),
+    );
+    OcrResult {
+        text: normalize_text(&decoded),
+        token_ids,
+        prompt_token_ids,
+        elapsed,
+    }
+}
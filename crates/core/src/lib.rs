@@ -1,6 +1,7 @@
 pub mod benchmark;
 pub mod config;
 pub mod conversation;
+pub mod document;
 pub mod inference;
 pub mod model;
 pub mod runtime;
@@ -0,0 +1,528 @@
+use anyhow::{Context, Result, ensure};
+use candle_core::{D, DType, Tensor};
+
+use crate::{
+    benchmark::{Timer, sync},
+    transformer::{
+        cache::{DynamicCache, KvCacheChunk},
+        sampling::LogitsSampler,
+    },
+};
+
+use super::{DeepseekOcrModel, GenerateOptions, MAX_BUDGET_EXTENSION, TokenSelection};
+
+/// A prompt that finished its prefill forward pass, ready to join a [`DecodeBatch`].
+pub struct PrefilledSequence {
+    cache: DynamicCache,
+    first_token: i64,
+    selection: TokenSelection,
+}
+
+impl PrefilledSequence {
+    /// Wrap a batch-1 KV cache and the token predicted from its last prompt position.
+    pub fn new(cache: DynamicCache, first_token: i64) -> Self {
+        Self {
+            cache,
+            first_token,
+            selection: TokenSelection::default(),
+        }
+    }
+
+    /// Token ids this sequence never selects during batched decode steps.
+    pub fn with_banned_token_ids(mut self, banned_token_ids: Vec<i64>) -> Self {
+        self.selection.banned_token_ids = banned_token_ids;
+        self
+    }
+
+    /// Sample this sequence's tokens with `sampler` instead of decoding greedily.
+    pub fn with_sampler(mut self, sampler: Option<LogitsSampler>) -> Self {
+        self.selection.sampler = sampler;
+        self
+    }
+
+    /// Token predicted from the final prompt position.
+    pub fn first_token(&self) -> i64 {
+        self.first_token
+    }
+
+    /// Number of cached prompt positions.
+    pub fn seq_len(&self) -> usize {
+        self.cache.seq_len().unwrap_or(0)
+    }
+}
+
+/// One prompt of a [`DeepseekOcrModel::generate_batch`] call.
+pub struct BatchItem<'a> {
+    /// Prompt ids with shape `[1, seq]`.
+    pub input_ids: &'a Tensor,
+    /// Per-item images, budget, stop and token selection settings.
+    pub options: GenerateOptions<'a>,
+}
+
+/// Tokens generated by [`DeepseekOcrModel::generate_batch`], one row per item in input order.
+#[derive(Debug, Clone)]
+pub struct BatchedGeneration {
+    /// Generated ids `[items, longest]`, right-padded with `0` past each row's length.
+    pub tokens: Tensor,
+    /// Number of generated ids in each row; the rest of the row is padding.
+    pub lengths: Vec<usize>,
+}
+
+impl BatchedGeneration {
+    /// Split the padded rows back into each item's generated ids.
+    pub fn rows(&self) -> Result<Vec<Vec<i64>>> {
+        let mut rows = self.tokens.to_vec2::<i64>()?;
+        for (row, &len) in rows.iter_mut().zip(&self.lengths) {
+            row.truncate(len);
+        }
+        Ok(rows)
+    }
+}
+
+struct BatchRow {
+    id: u64,
+    /// Left padding in front of this row's cached positions.
+    pad: usize,
+    /// Positions of this row a bounded cache evicted; its position ids keep counting them.
+    evicted: usize,
+    /// Token fed to the next decode step.
+    pending: i64,
+    selection: TokenSelection,
+    /// Tokens produced so far, starting with the prefill's, for repetition controls.
+    generated: Vec<i64>,
+}
+
+/// Decode steps for several in-flight sequences fused into one batched forward.
+///
+/// Rows share a left-padded KV cache: every row's newest position lines up in the last column,
+/// so a step appends one column for all rows at once. The cache is only repacked when a sequence
+/// joins or leaves. Padded positions are masked out of attention and each row keeps its own
+/// position ids, so outputs match decoding the sequences one at a time. Rows with a sampler or
+/// repetition controls pick their token from their own row of logits.
+#[derive(Default)]
+pub struct DecodeBatch {
+    cache: DynamicCache,
+    rows: Vec<BatchRow>,
+}
+
+impl DecodeBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of sequences currently decoding.
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Ids of the active sequences in row order.
+    pub fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.rows.iter().map(|row| row.id)
+    }
+
+    /// Shared (padded) cache backing the batch.
+    pub fn cache(&self) -> &DynamicCache {
+        &self.cache
+    }
+
+    /// Add a prefilled sequence. Its first token is fed on the next [`Self::step`].
+    pub fn join(&mut self, id: u64, sequence: PrefilledSequence) -> Result<()> {
+        ensure!(
+            self.rows.iter().all(|row| row.id != id),
+            "sequence {id} is already decoding"
+        );
+        let PrefilledSequence {
+            cache: mut incoming,
+            first_token,
+            selection,
+        } = sequence;
+        let incoming_len = incoming.seq_len().unwrap_or(0);
+        let evicted = incoming.next_position() - incoming_len;
+        if self.rows.is_empty() {
+            self.replace_cache(incoming);
+            self.rows.push(BatchRow {
+                id,
+                pad: 0,
+                evicted,
+                pending: first_token,
+                selection,
+                generated: vec![first_token],
+            });
+            return Ok(());
+        }
+
+        let current_len = self.cache.seq_len().unwrap_or(0);
+        let target_len = current_len.max(incoming_len);
+        let num_layers = self.cache.num_layers().max(incoming.num_layers());
+        let mut merged = self.empty_cache(num_layers);
+        for layer_idx in 0..num_layers {
+            let (existing, added) = match (self.cache.get(layer_idx), incoming.get(layer_idx)) {
+                (Some(existing), Some(added)) => (existing, added),
+                (None, None) => continue,
+                _ => anyhow::bail!("layer {layer_idx} is cached for only some batch rows"),
+            };
+            let key_t = Tensor::cat(
+                &[
+                    existing
+                        .key_view()?
+                        .pad_with_zeros(D::Minus1, target_len - current_len, 0)?,
+                    added
+                        .key_view()?
+                        .pad_with_zeros(D::Minus1, target_len - incoming_len, 0)?,
+                ],
+                0,
+            )?;
+            let value = Tensor::cat(
+                &[
+                    existing.value_view()?.pad_with_zeros(
+                        D::Minus2,
+                        target_len - current_len,
+                        0,
+                    )?,
+                    added
+                        .value_view()?
+                        .pad_with_zeros(D::Minus2, target_len - incoming_len, 0)?,
+                ],
+                0,
+            )?;
+            merged.append(layer_idx, KvCacheChunk::new(key_t, value)?)?;
+        }
+        incoming.clear();
+        self.replace_cache(merged);
+        for row in &mut self.rows {
+            row.pad += target_len - current_len;
+        }
+        self.rows.push(BatchRow {
+            id,
+            pad: target_len - incoming_len,
+            evicted,
+            pending: first_token,
+            selection,
+            generated: vec![first_token],
+        });
+        Ok(())
+    }
+
+    /// Drop a sequence from the batch, trimming padding no remaining row needs.
+    pub fn leave(&mut self, id: u64) -> Result<()> {
+        let position = self
+            .rows
+            .iter()
+            .position(|row| row.id == id)
+            .with_context(|| format!("sequence {id} is not decoding"))?;
+        self.rows.remove(position);
+        if self.rows.is_empty() {
+            self.replace_cache(DynamicCache::new());
+            return Ok(());
+        }
+
+        let keep: Vec<u32> = (0..=self.rows.len() as u32)
+            .filter(|&row| row as usize != position)
+            .collect();
+        let trim = self.rows.iter().map(|row| row.pad).min().unwrap_or(0);
+        let current_len = self.cache.seq_len().unwrap_or(0);
+        let num_layers = self.cache.num_layers();
+        let mut packed = self.empty_cache(num_layers);
+        for layer_idx in 0..num_layers {
+            let Some(entry) = self.cache.get(layer_idx) else {
+                continue;
+            };
+            let keep = Tensor::from_vec(keep.clone(), keep.len(), entry.key_view()?.device())?;
+            let key_t = entry.key_view()?.index_select(&keep, 0)?.narrow(
+                D::Minus1,
+                trim,
+                current_len - trim,
+            )?;
+            let value = entry.value_view()?.index_select(&keep, 0)?.narrow(
+                D::Minus2,
+                trim,
+                current_len - trim,
+            )?;
+            packed.append(layer_idx, KvCacheChunk::new(key_t, value)?)?;
+        }
+        self.replace_cache(packed);
+        for row in &mut self.rows {
+            row.pad -= trim;
+        }
+        Ok(())
+    }
+
+    /// Feed every row its pending token and return `(id, next_token)` in row order.
+    pub fn step(&mut self, model: &DeepseekOcrModel) -> Result<Vec<(u64, i64)>> {
+        if self.rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let timer = Timer::new("decode.batch_step");
+        let device = model.device();
+        let batch = self.rows.len();
+        let past_len = self.cache.seq_len().unwrap_or(0);
+        let evicted_before = self.cache.next_position() - past_len;
+        let pending: Vec<i64> = self.rows.iter().map(|row| row.pending).collect();
+        let input_ids = Tensor::from_vec(pending, (batch, 1), device)?;
+
+        // A lone unpadded row decodes exactly like `generate`, without mask or position tensors.
+        let padded = self.rows.iter().any(|row| row.pad > 0);
+        let attention_mask = if padded {
+            let mut mask = Vec::with_capacity(batch * (past_len + 1));
+            for row in &self.rows {
+                mask.extend(std::iter::repeat_n(0i64, row.pad));
+                mask.extend(std::iter::repeat_n(1i64, past_len + 1 - row.pad));
+            }
+            Some(Tensor::from_vec(mask, (batch, past_len + 1), device)?)
+        } else {
+            None
+        };
+        let position_ids = if padded || self.rows.iter().any(|row| row.evicted > 0) {
+            let positions: Vec<i64> = self
+                .rows
+                .iter()
+                .map(|row| (past_len - row.pad + row.evicted) as i64)
+                .collect();
+            Some(Tensor::from_vec(positions, (batch, 1), device)?)
+        } else {
+            None
+        };
+
+        let output = model.forward_language(
+            Some(&input_ids),
+            None,
+            attention_mask.as_ref(),
+            position_ids.as_ref(),
+            Some(&mut self.cache),
+            true,
+        )?;
+        self.account_evictions(evicted_before);
+        let last_logits = output
+            .logits
+            .narrow(1, output.logits.dim(1)? - 1, 1)?
+            .squeeze(1)?;
+        let last_logits = self.suppress_banned(&last_logits)?;
+        let mut next = last_logits
+            .argmax(D::Minus1)?
+            .to_dtype(DType::I64)?
+            .to_vec1::<i64>()
+            .context("failed to read batched decode tokens")?;
+        for (idx, row) in self.rows.iter().enumerate() {
+            if !row.selection.is_greedy() {
+                next[idx] = row
+                    .selection
+                    .select(&last_logits.get(idx)?, &row.generated)?;
+            }
+        }
+        let mut results = Vec::with_capacity(batch);
+        for (row, token) in self.rows.iter_mut().zip(next) {
+            row.pending = token;
+            row.generated.push(token);
+            results.push((row.id, token));
+        }
+        timer.finish(|event| {
+            event.add_field("batch", batch);
+            event.add_field("past_len", past_len);
+            event.add_field("padded", padded);
+        });
+        Ok(results)
+    }
+
+    /// Mask each row's banned ids out of `[batch, vocab]` logits.
+    fn suppress_banned(&self, logits: &Tensor) -> Result<Tensor> {
+        if self
+            .rows
+            .iter()
+            .all(|row| row.selection.banned_token_ids.is_empty())
+        {
+            return Ok(logits.clone());
+        }
+        let vocab = logits.dim(D::Minus1)?;
+        let mut mask = vec![0f32; self.rows.len() * vocab];
+        for (row_mask, row) in mask.chunks_exact_mut(vocab).zip(&self.rows) {
+            for &id in &row.selection.banned_token_ids {
+                if let Some(slot) = usize::try_from(id).ok().and_then(|id| row_mask.get_mut(id)) {
+                    *slot = f32::NEG_INFINITY;
+                }
+            }
+        }
+        let mask = Tensor::from_vec(mask, (self.rows.len(), vocab), logits.device())?;
+        Ok((logits.to_dtype(DType::F32)? + mask)?)
+    }
+
+    /// An empty cache with the same bound and compact stride as the current one, for repacking.
+    fn empty_cache(&self, num_layers: usize) -> DynamicCache {
+        let mut cache = DynamicCache::with_num_layers(num_layers);
+        if let Some((max_seq_len, eviction)) = self.cache.max_seq_len() {
+            cache = cache.with_max_seq_len(max_seq_len, eviction);
+        }
+        if let Some(stride) = self.cache.compact_stride() {
+            cache = cache.with_compact_stride(stride);
+        }
+        cache
+    }
+
+    /// Charge the columns a bounded cache evicted during the last step to each row: padding
+    /// columns shrink the row's padding, the rest count as its evicted positions.
+    fn account_evictions(&mut self, evicted_before: usize) {
+        let Some((_, eviction)) = self.cache.max_seq_len() else {
+            return;
+        };
+        let evicted =
+            self.cache.next_position() - self.cache.seq_len().unwrap_or(0) - evicted_before;
+        if evicted == 0 {
+            return;
+        }
+        let keep_head = eviction.kept_head();
+        for row in &mut self.rows {
+            let padding = evicted.min(row.pad.saturating_sub(keep_head));
+            row.pad -= padding;
+            row.evicted += evicted - padding;
+        }
+    }
+
+    fn replace_cache(&mut self, cache: DynamicCache) {
+        // Clearing keeps memlog's KV accounting in step with the tensors being dropped.
+        self.cache.clear();
+        self.cache = cache;
+    }
+}
+
+impl DeepseekOcrModel {
+    /// Run the prompt forward pass for one sequence and keep its KV cache for batched decoding
+    /// with [`DecodeBatch`]. Only the prompt-related and token selection fields of `options` are
+    /// used.
+    pub fn prefill(
+        &self,
+        input_ids: &Tensor,
+        options: &GenerateOptions<'_>,
+    ) -> Result<PrefilledSequence> {
+        let (batch, seq_len) = input_ids.shape().dims2()?;
+        ensure!(
+            batch == 1,
+            "prefill expects a single sequence (got batch {batch})"
+        );
+        ensure!(seq_len > 0, "prefill requires at least one prompt token");
+        sync(self.device());
+        let timer = Timer::new("decode.prefill");
+        let mut cache = self.new_cache();
+        let last_logits = self.prefill_cache(input_ids, options, &mut cache)?;
+        sync(self.device());
+        timer.finish(|event| {
+            event.add_field("prompt_tokens", seq_len as u64);
+            event.add_field("has_image_mask", options.images_seq_mask.is_some());
+            event.add_field("batched", true);
+        });
+        let selection = TokenSelection::from_options(options);
+        let first_token = selection.select(&last_logits, &[])?;
+        Ok(PrefilledSequence {
+            cache,
+            first_token,
+            selection,
+        })
+    }
+    /// Generate for several prompts, e.g. one per image, decoding up to `max_num_seqs` of them
+    /// together in a [`DecodeBatch`].
+    ///
+    /// Each prompt is prefilled on its own, so prompts of different lengths (and image token
+    /// counts) need no padding there; decode rows are left-padded and masked by the batch. Items
+    /// past `max_num_seqs` wait and join as earlier rows finish, so pass the server's
+    /// `max_num_seqs` to keep the shared KV cache within the budget sized for it. Every item
+    /// stops exactly as [`Self::generate`] would on its own (EOS, `max_new_tokens`,
+    /// `extend_while`, `stop_when`); `use_cache` is ignored since batching needs the cache.
+    pub fn generate_batch(
+        &self,
+        items: &[BatchItem<'_>],
+        max_num_seqs: usize,
+    ) -> Result<BatchedGeneration> {
+        self.generate_batch_with_progress(items, max_num_seqs, |_| {})
+    }
+
+    /// Like [`Self::generate_batch`], calling `on_finished` with an item's index as soon as that
+    /// item stops generating, in completion order.
+    pub fn generate_batch_with_progress(
+        &self,
+        items: &[BatchItem<'_>],
+        max_num_seqs: usize,
+        mut on_finished: impl FnMut(usize),
+    ) -> Result<BatchedGeneration> {
+        ensure!(max_num_seqs > 0, "max_num_seqs must be at least 1");
+        let timer = Timer::new("decode.generate_batch");
+        let mut outputs = vec![Vec::new(); items.len()];
+        let mut queued = 0..items.len();
+        let mut batch = DecodeBatch::new();
+        let mut steps = 0usize;
+        loop {
+            while batch.len() < max_num_seqs {
+                let Some(idx) = queued.next() else {
+                    break;
+                };
+                let item = &items[idx];
+                if item.options.max_new_tokens == 0 {
+                    on_finished(idx);
+                    continue;
+                }
+                let prefilled = self
+                    .prefill(item.input_ids, &item.options)
+                    .with_context(|| format!("prefill failed for batch item {idx}"))?;
+                let first = prefilled.first_token();
+                if item.options.eos_token_id == Some(first) {
+                    on_finished(idx);
+                    continue;
+                }
+                outputs[idx].push(first);
+                if batch_item_finished(&item.options, &outputs[idx]) {
+                    on_finished(idx);
+                } else {
+                    batch.join(idx as u64, prefilled)?;
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+            steps += 1;
+            for (id, token) in batch.step(self)? {
+                let idx = id as usize;
+                let options = &items[idx].options;
+                if options.eos_token_id == Some(token) {
+                    batch.leave(id)?;
+                    on_finished(idx);
+                    continue;
+                }
+                outputs[idx].push(token);
+                if batch_item_finished(options, &outputs[idx]) {
+                    batch.leave(id)?;
+                    on_finished(idx);
+                }
+            }
+        }
+
+        let lengths: Vec<usize> = outputs.iter().map(Vec::len).collect();
+        let longest = lengths.iter().copied().max().unwrap_or(0);
+        let mut padded = Vec::with_capacity(items.len() * longest);
+        for row in &outputs {
+            padded.extend_from_slice(row);
+            padded.extend(std::iter::repeat_n(0i64, longest - row.len()));
+        }
+        let tokens = Tensor::from_vec(padded, (items.len(), longest), self.device())?;
+        timer.finish(|event| {
+            event.add_field("items", items.len());
+            event.add_field("max_num_seqs", max_num_seqs);
+            event.add_field("steps", steps);
+        });
+        Ok(BatchedGeneration { tokens, lengths })
+    }
+}
+
+/// Whether a batch item that just produced the last of `generated` is done, reporting progress
+/// first like [`DeepseekOcrModel::generate`] does.
+fn batch_item_finished(options: &GenerateOptions<'_>, generated: &[i64]) -> bool {
+    if let Some(cb) = options.progress_callback {
+        cb(generated.len(), generated);
+    }
+    if options.stop_when.is_some_and(|stop| stop(generated)) {
+        return true;
+    }
+    generated.len() >= options.max_new_tokens
+        && !(generated.len() < options.max_new_tokens + MAX_BUDGET_EXTENSION
+            && options.extend_while.is_some_and(|extend| extend(generated)))
+}
@@ -0,0 +1,274 @@
+use anyhow::{Context, Result, ensure};
+use candle_core::{DType, Tensor};
+
+use crate::benchmark::{Timer, sync};
+
+use super::{DeepseekOcrModel, GenerateOptions, TokenSelection};
+
+/// Beam search settings for [`DeepseekOcrModel::generate_beams`], or for
+/// [`DeepseekOcrModel::generate`] through [`GenerateOptions::beam_search`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeamSearch {
+    /// Hypotheses kept after every step; `1` is greedy decoding.
+    pub num_beams: usize,
+    /// Finished hypotheses are ranked by their summed log-probability divided by
+    /// `length^length_penalty`. Above `1.0` favours longer outputs, below favours shorter ones.
+    pub length_penalty: f32,
+    /// How many finished hypotheses to return, best first; at most `num_beams`.
+    pub num_return_sequences: usize,
+}
+
+impl BeamSearch {
+    pub fn new(num_beams: usize) -> Self {
+        Self {
+            num_beams,
+            length_penalty: 1.0,
+            num_return_sequences: 1,
+        }
+    }
+
+    pub fn with_length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    pub fn with_num_return_sequences(mut self, num_return_sequences: usize) -> Self {
+        self.num_return_sequences = num_return_sequences;
+        self
+    }
+
+    fn score(&self, log_prob: f32, len: usize) -> f32 {
+        log_prob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+
+    /// Run beam search over any autoregressive model.
+    ///
+    /// `first_logits` are the `[vocab]` logits for the first generated position. `step` feeds the
+    /// last of the given tokens to a state and returns the logits for the position after it;
+    /// `fork` branches a state when several hypotheses continue from it. A hypothesis ends at
+    /// `eos_token_id` (not included in its tokens, but counted in its length) or after
+    /// `max_new_tokens` tokens.
+    pub fn search<S>(
+        &self,
+        root: S,
+        first_logits: Vec<f32>,
+        max_new_tokens: usize,
+        eos_token_id: Option<i64>,
+        mut fork: impl FnMut(&S) -> Result<S>,
+        mut step: impl FnMut(&mut S, &[i64]) -> Result<Vec<f32>>,
+    ) -> Result<Vec<BeamHypothesis>> {
+        ensure!(self.num_beams > 0, "beam search needs at least one beam");
+        ensure!(
+            (1..=self.num_beams).contains(&self.num_return_sequences),
+            "num_return_sequences must be between 1 and num_beams ({}), got {}",
+            self.num_beams,
+            self.num_return_sequences
+        );
+        let mut live = vec![Beam {
+            state: Some(root),
+            tokens: Vec::new(),
+            log_prob: 0.0,
+            logits: first_logits,
+        }];
+        let mut finished: Vec<BeamHypothesis> = Vec::new();
+        for step_idx in 0..max_new_tokens {
+            // Every beam offers twice as many candidates as there are beams, so enough
+            // non-EOS continuations survive even when several candidates end.
+            let mut candidates = Vec::new();
+            for (parent, beam) in live.iter().enumerate() {
+                let log_probs = log_softmax(&beam.logits);
+                let mut order: Vec<usize> = (0..log_probs.len())
+                    .filter(|&token| log_probs[token].is_finite())
+                    .collect();
+                order.sort_by(|&a, &b| log_probs[b].total_cmp(&log_probs[a]));
+                order.truncate(2 * self.num_beams);
+                candidates.extend(
+                    order
+                        .into_iter()
+                        .map(|token| (parent, token as i64, beam.log_prob + log_probs[token])),
+                );
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut next = Vec::with_capacity(self.num_beams);
+            for (parent, token, log_prob) in candidates {
+                if next.len() == self.num_beams {
+                    break;
+                }
+                let tokens = &live[parent].tokens;
+                if eos_token_id == Some(token) {
+                    finished.push(BeamHypothesis {
+                        tokens: tokens.clone(),
+                        log_prob,
+                        score: self.score(log_prob, tokens.len() + 1),
+                    });
+                } else {
+                    next.push((parent, token, log_prob));
+                }
+            }
+            if next.is_empty() || self.is_done(&mut finished, &next, step_idx + 1) {
+                live.clear();
+                break;
+            }
+
+            let mut children = vec![0usize; live.len()];
+            for &(parent, _, _) in &next {
+                children[parent] += 1;
+            }
+            let mut branched = Vec::with_capacity(next.len());
+            for (parent, token, log_prob) in next {
+                children[parent] -= 1;
+                let source = &mut live[parent];
+                let mut state = if children[parent] == 0 {
+                    source.state.take().context("beam state already taken")?
+                } else {
+                    fork(source.state.as_ref().context("beam state already taken")?)?
+                };
+                let mut tokens = source.tokens.clone();
+                tokens.push(token);
+                // The last step's logits would never be read.
+                let logits = if step_idx + 1 < max_new_tokens {
+                    step(&mut state, &tokens)?
+                } else {
+                    Vec::new()
+                };
+                branched.push(Beam {
+                    state: Some(state),
+                    tokens,
+                    log_prob,
+                    logits,
+                });
+            }
+            live = branched;
+        }
+
+        finished.extend(live.into_iter().map(|beam| BeamHypothesis {
+            score: self.score(beam.log_prob, beam.tokens.len()),
+            tokens: beam.tokens,
+            log_prob: beam.log_prob,
+        }));
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(self.num_return_sequences);
+        Ok(finished)
+    }
+
+    /// Whether `num_beams` hypotheses have finished and none of the `live` candidates (of
+    /// length `len`) currently scores above the worst of them.
+    fn is_done(
+        &self,
+        finished: &mut [BeamHypothesis],
+        live: &[(usize, i64, f32)],
+        len: usize,
+    ) -> bool {
+        if finished.len() < self.num_beams {
+            return false;
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        let worst_kept = finished[self.num_beams - 1].score;
+        live.iter()
+            .all(|&(_, _, log_prob)| self.score(log_prob, len) <= worst_kept)
+    }
+}
+
+/// One finished (or budget-truncated) beam search hypothesis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamHypothesis {
+    /// Generated tokens, excluding EOS.
+    pub tokens: Vec<i64>,
+    /// Summed natural-log probability of the tokens, EOS included when the hypothesis ended
+    /// on it.
+    pub log_prob: f32,
+    /// `log_prob` normalised by length, see [`BeamSearch::length_penalty`].
+    pub score: f32,
+}
+
+struct Beam<S> {
+    state: Option<S>,
+    tokens: Vec<i64>,
+    log_prob: f32,
+    logits: Vec<f32>,
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&value| (value - max).exp()).sum();
+    let log_sum = max + sum.ln();
+    logits.iter().map(|&value| value - log_sum).collect()
+}
+
+impl DeepseekOcrModel {
+    /// Decode with beam search and return the best `beam.num_return_sequences` hypotheses, best
+    /// first.
+    ///
+    /// The prompt is prefilled once and every beam forks its KV cache from its parent with
+    /// [`DynamicCache::fork`](crate::transformer::cache::DynamicCache::fork), so branches share
+    /// the prompt until they append. Banned ids and repetition controls from `options` shape
+    /// each beam's distribution; the sampler, callbacks and `extend_while` are not used.
+    pub fn generate_beams(
+        &self,
+        input_ids: &Tensor,
+        options: &GenerateOptions<'_>,
+        beam: &BeamSearch,
+    ) -> Result<Vec<BeamHypothesis>> {
+        let (batch, seq_len) = input_ids.shape().dims2()?;
+        ensure!(
+            batch == 1,
+            "beam search expects a single sequence (got batch {batch})"
+        );
+        ensure!(
+            seq_len > 0,
+            "beam search requires at least one prompt token"
+        );
+        let timer = Timer::new("decode.beam_search");
+        let selection = TokenSelection::from_options(options);
+        let mut cache = self.new_cache();
+        let last_logits = self.prefill_cache(input_ids, options, &mut cache)?;
+        let first_logits = selection
+            .process(&last_logits, &[])?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        let hypotheses = beam.search(
+            cache,
+            first_logits,
+            options.max_new_tokens,
+            options.eos_token_id,
+            |cache| cache.fork(),
+            |cache, tokens| {
+                let token = *tokens.last().context("beam step without a token")?;
+                let token_index = usize::try_from(token)
+                    .context("token id out of range while preparing decode embedding")?;
+                let decode_inputs = self
+                    .language
+                    .token_embedding_for_id(token_index)?
+                    .unsqueeze(0)?
+                    .unsqueeze(0)?;
+                let output = self.forward(
+                    None,
+                    Some(&decode_inputs),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    Some(cache),
+                    true,
+                )?;
+                let logits = output.logits.get(0)?.get(0)?;
+                Ok(selection
+                    .process(&logits, tokens)?
+                    .to_dtype(DType::F32)?
+                    .to_vec1::<f32>()?)
+            },
+        )?;
+        sync(self.device());
+        timer.finish(|event| {
+            event.add_field("prompt_tokens", seq_len as u64);
+            event.add_field("num_beams", beam.num_beams);
+            event.add_field(
+                "generated_tokens",
+                hypotheses.first().map_or(0, |best| best.tokens.len()),
+            );
+        });
+        Ok(hypotheses)
+    }
+}
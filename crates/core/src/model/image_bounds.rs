@@ -0,0 +1,193 @@
+use crate::vision::preprocess::{MAX_STRIP_TILES, select_tile_grid};
+
+use super::{DeepseekOcrModel, MAX_CROP_TILES, MIN_CROP_TILES, global_view_rect};
+
+/// Image sizes the vision pipeline makes use of for a given preprocessing setup, for clients that
+/// resize before uploading.
+///
+/// Every image is accepted: the global view is letterboxed to `base_size` and, in crop mode, the
+/// image is resized to a grid of `tile_size` crops picked by aspect ratio alone. The number of
+/// vision tokens therefore depends on the image's shape, not its resolution, and pixels beyond
+/// [`Self::recommended_size`] are resized away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageSizeBounds {
+    /// Edge of a vision patch; `base_size` and `tile_size` are multiples of it.
+    pub patch_size: u32,
+    /// Side of the square global view.
+    pub base_size: u32,
+    /// Side of each local crop.
+    pub tile_size: u32,
+    /// Fewest and most crops per image; both 0 when crop mode is off.
+    pub min_tiles: u32,
+    pub max_tiles: u32,
+    /// Aspect ratio from which images are cut into a strip of up to [`MAX_STRIP_TILES`] crops
+    /// instead of a grid; see [`select_tile_grid`]. `None` when strip tiling is off.
+    pub strip_aspect_threshold: Option<f32>,
+    /// Below this long side the global view upscales the image; such images work, but small text
+    /// in them gains no detail.
+    pub min_long_side: u32,
+    /// Past this long side extra pixels are discarded whatever the aspect ratio.
+    pub max_long_side: u32,
+}
+
+impl ImageSizeBounds {
+    pub fn new(
+        patch_size: u32,
+        base_size: u32,
+        image_size: u32,
+        crop_mode: bool,
+        strip_aspect_threshold: Option<f32>,
+    ) -> Self {
+        let bounds = Self {
+            patch_size,
+            base_size,
+            tile_size: image_size,
+            min_tiles: 0,
+            max_tiles: 0,
+            strip_aspect_threshold,
+            min_long_side: base_size,
+            max_long_side: base_size,
+        };
+        if crop_mode {
+            Self {
+                min_tiles: MIN_CROP_TILES,
+                ..bounds
+            }
+            .with_max_tiles(MAX_CROP_TILES)
+        } else {
+            bounds
+        }
+    }
+
+    /// Allow up to `max_tiles` crops per image instead of [`MAX_CROP_TILES`], as
+    /// [`LoadOptions::max_tiles`](super::LoadOptions::max_tiles) does. No effect with crop mode
+    /// off.
+    pub fn with_max_tiles(mut self, max_tiles: u32) -> Self {
+        if self.min_tiles == 0 {
+            return self;
+        }
+        self.max_tiles = max_tiles;
+        let longest_grid = match self.strip_aspect_threshold {
+            Some(_) => MAX_STRIP_TILES.max(max_tiles),
+            None => max_tiles,
+        };
+        self.max_long_side = self.base_size.max(self.tile_size * longest_grid);
+        self
+    }
+
+    /// Crop grid `(columns, rows)` used for a `width` x `height` image, if crop mode is on.
+    pub fn tile_grid(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        (self.max_tiles > 0).then(|| {
+            select_tile_grid(
+                width,
+                height,
+                self.min_tiles,
+                self.max_tiles,
+                self.tile_size,
+                self.strip_aspect_threshold,
+            )
+        })
+    }
+
+    /// How a `width` x `height` image is split for the vision encoder: the global view and, in
+    /// crop mode, the crop grid with each crop's source rectangle. Computed without touching
+    /// pixels or a model, e.g. to check why a wide page gets the crops it does.
+    pub fn tile_layout(&self, width: u32, height: u32) -> TileLayout {
+        let (columns, rows) = self.tile_grid(width, height).unwrap_or((0, 0));
+        let tiles = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| {
+                // The image is resized to the whole grid and cut evenly, so crop `i` covers the
+                // `i`-th fraction of each axis.
+                let x0 = (u64::from(column) * u64::from(width) / u64::from(columns)) as u32;
+                let x1 = (u64::from(column + 1) * u64::from(width) / u64::from(columns)) as u32;
+                let y0 = (u64::from(row) * u64::from(height) / u64::from(rows)) as u32;
+                let y1 = (u64::from(row + 1) * u64::from(height) / u64::from(rows)) as u32;
+                TileRect {
+                    x: x0,
+                    y: y0,
+                    width: x1 - x0,
+                    height: y1 - y0,
+                }
+            })
+            .collect();
+        TileLayout {
+            base_size: self.base_size,
+            global_content: global_view_rect(width, height, self.base_size),
+            columns,
+            rows,
+            tile_size: self.tile_size,
+            tiles,
+        }
+    }
+
+    /// Smallest size, keeping the aspect ratio, that still fills the global view and every crop
+    /// of a `width` x `height` image without upscaling. Larger images only cost upload and resize
+    /// time; smaller ones are returned unchanged.
+    pub fn recommended_size(&self, width: u32, height: u32) -> (u32, u32) {
+        if width == 0 || height == 0 {
+            return (width, height);
+        }
+        let mut scale = self.base_size as f64 / width.max(height) as f64;
+        if let Some((columns, rows)) = self.tile_grid(width, height) {
+            scale = scale
+                .max((columns * self.tile_size) as f64 / width as f64)
+                .max((rows * self.tile_size) as f64 / height as f64);
+        }
+        if scale >= 1.0 {
+            return (width, height);
+        }
+        (
+            (width as f64 * scale).ceil() as u32,
+            (height as f64 * scale).ceil() as u32,
+        )
+    }
+}
+
+/// Axis-aligned rectangle in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How one image is split into the global view and local crops, see
+/// [`ImageSizeBounds::tile_layout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileLayout {
+    /// Side of the square global view.
+    pub base_size: u32,
+    /// Where the resized image sits on the global view; the rest is grey padding. `None` for an
+    /// empty image.
+    pub global_content: Option<TileRect>,
+    /// Crop grid; both 0 when crop mode is off.
+    pub columns: u32,
+    pub rows: u32,
+    /// Side each crop is resized to.
+    pub tile_size: u32,
+    /// Source rectangle of every crop in the original image, row by row.
+    pub tiles: Vec<TileRect>,
+}
+
+impl DeepseekOcrModel {
+    /// Size bounds for images prepared with these preprocessing settings, the ones passed to
+    /// [`Self::prepare_vision_input_with_strips`].
+    pub fn image_size_bounds(
+        &self,
+        base_size: u32,
+        image_size: u32,
+        crop_mode: bool,
+        strip_aspect_threshold: Option<f32>,
+    ) -> ImageSizeBounds {
+        ImageSizeBounds::new(
+            self.vision.sam.params.patch_size as u32,
+            base_size,
+            image_size,
+            crop_mode,
+            strip_aspect_threshold,
+        )
+        .with_max_tiles(self.max_tiles)
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, Weak},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use candle_core::{DType, Device, Shape, Tensor, safetensors::MmapedSafetensors};
+use candle_nn::{Init, VarBuilder, var_builder::SimpleBackend};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// How many similar checkpoint keys a missing-tensor error lists.
+const SUGGESTED_KEYS: usize = 8;
+
+/// File-name suffix of a sharded checkpoint's index, e.g. `model.safetensors.index.json`.
+const SHARD_INDEX_SUFFIX: &str = ".index.json";
+
+/// Rewrites a tensor-name prefix the loader expects into the prefix a checkpoint actually uses,
+/// e.g. `model.layers.` to `model.language_model.layers.`, so oddly named third-party
+/// checkpoints load without renaming their tensors.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeightKeyRemap {
+    pub from: String,
+    pub to: String,
+}
+
+impl WeightKeyRemap {
+    fn apply<'a>(&self, name: &'a str) -> Option<Cow<'a, str>> {
+        name.strip_prefix(self.from.as_str())
+            .map(|rest| Cow::Owned(format!("{}{rest}", self.to)))
+    }
+}
+
+impl FromStr for WeightKeyRemap {
+    type Err = anyhow::Error;
+
+    /// Parse `FROM=TO`; either side may be empty to add or strip a prefix.
+    fn from_str(s: &str) -> Result<Self> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected FROM=TO, got `{s}`"))?;
+        Ok(Self {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for WeightKeyRemap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.from, self.to)
+    }
+}
+
+struct CachedMapping {
+    modified: Option<SystemTime>,
+    tensors: Weak<MmapedSafetensors>,
+}
+
+/// Live mappings keyed by canonical path. Entries hold weak references, so a mapping is released
+/// as soon as the last loader using it finishes.
+static MAPPINGS: Lazy<Mutex<HashMap<PathBuf, CachedMapping>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Memory-map a safetensors file, reusing an existing mapping of the same resolved path while it
+/// is still alive and the file's modification time is unchanged.
+///
+/// `path` may also be the `*.safetensors.index.json` of a sharded checkpoint, in which case every
+/// shard it references (relative to the index) is mapped together; changing any shard counts as
+/// a modification.
+pub fn shared_mmaped_safetensors(path: &Path) -> Result<Arc<MmapedSafetensors>> {
+    let resolved = fs::canonicalize(path)
+        .with_context(|| format!("failed to resolve weights path {}", path.display()))?;
+    let shards = if is_shard_index(&resolved) {
+        Some(ShardIndex::read(&resolved)?)
+    } else {
+        None
+    };
+    let mut modified = modified_time(&resolved)?;
+    if let Some(index) = &shards {
+        for shard in &index.shard_paths {
+            modified = modified.max(modified_time(shard)?);
+        }
+    }
+    let mut mappings = MAPPINGS.lock().expect("mmap cache lock poisoned");
+    mappings.retain(|_, entry| entry.tensors.strong_count() > 0);
+    if let Some(tensors) = mappings
+        .get(&resolved)
+        .filter(|entry| entry.modified == modified)
+        .and_then(|entry| entry.tensors.upgrade())
+    {
+        tracing::debug!("Reusing weights mapping for {}", resolved.display());
+        return Ok(tensors);
+    }
+    let tensors = Arc::new(match &shards {
+        Some(index) => index.mmap()?,
+        None => unsafe { MmapedSafetensors::new(&resolved) }
+            .with_context(|| format!("failed to mmap weights at {}", resolved.display()))?,
+    });
+    mappings.insert(
+        resolved,
+        CachedMapping {
+            modified,
+            tensors: Arc::downgrade(&tensors),
+        },
+    );
+    Ok(tensors)
+}
+
+fn modified_time(path: &Path) -> Result<Option<SystemTime>> {
+    Ok(fs::metadata(path)
+        .with_context(|| format!("failed to stat weights at {}", path.display()))?
+        .modified()
+        .ok())
+}
+
+/// Every file a weights path consists of: the file itself, followed by the shards it references
+/// when it is a `*.safetensors.index.json`.
+pub fn weight_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![path.to_path_buf()];
+    if is_shard_index(path) {
+        files.extend(ShardIndex::read(path)?.shard_paths);
+    }
+    Ok(files)
+}
+
+fn is_shard_index(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(SHARD_INDEX_SUFFIX))
+}
+
+/// A parsed `*.safetensors.index.json`: which shard holds each tensor.
+struct ShardIndex {
+    path: PathBuf,
+    weight_map: BTreeMap<String, String>,
+    /// Distinct shard files, resolved next to the index.
+    shard_paths: Vec<PathBuf>,
+}
+
+impl ShardIndex {
+    fn read(path: &Path) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct IndexFile {
+            weight_map: BTreeMap<String, String>,
+        }
+        let contents = fs::read(path)
+            .with_context(|| format!("failed to read weights index {}", path.display()))?;
+        let IndexFile { weight_map } = serde_json::from_slice(&contents)
+            .with_context(|| format!("failed to parse weights index {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let shards: BTreeSet<&str> = weight_map.values().map(String::as_str).collect();
+        ensure!(
+            !shards.is_empty(),
+            "weights index {} lists no shards",
+            path.display()
+        );
+        let missing: Vec<&str> = shards
+            .iter()
+            .copied()
+            .filter(|shard| !dir.join(shard).is_file())
+            .collect();
+        ensure!(
+            missing.is_empty(),
+            "weights index {} references missing shard(s) in {}: {}",
+            path.display(),
+            dir.display(),
+            missing.join(", ")
+        );
+        let shard_paths = shards.iter().map(|shard| dir.join(shard)).collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            weight_map,
+            shard_paths,
+        })
+    }
+
+    /// Map every shard and check that each tensor of the index is found in its shard.
+    fn mmap(&self) -> Result<MmapedSafetensors> {
+        let tensors = unsafe { MmapedSafetensors::multi(&self.shard_paths) }
+            .with_context(|| format!("failed to mmap the shards of {}", self.path.display()))?;
+        let absent: Vec<(&String, &String)> = self
+            .weight_map
+            .iter()
+            .filter(|(name, _)| tensors.get(name).is_err())
+            .collect();
+        if let Some((name, shard)) = absent.first() {
+            bail!(
+                "{} tensor(s) listed in {} are missing from their shards, e.g. `{name}` in {shard}",
+                absent.len(),
+                self.path.display()
+            );
+        }
+        tracing::debug!(
+            "Mapped {} shards for {}",
+            self.shard_paths.len(),
+            self.path.display()
+        );
+        Ok(tensors)
+    }
+}
+
+/// Build a `VarBuilder` over a (possibly shared) mapping of a weights file or shard index, e.g.
+/// for [`crate::transformer::model::DeepseekLanguageModel::load`].
+pub fn weights_var_builder(
+    path: &Path,
+    dtype: DType,
+    device: &Device,
+) -> Result<VarBuilder<'static>> {
+    shared_var_builder(path, dtype, device, &[])
+}
+
+/// Build a `VarBuilder` over a (possibly shared) mapping of `path`. Tensor names are rewritten
+/// by the first matching entry of `remap` before lookup.
+pub(crate) fn shared_var_builder(
+    path: &Path,
+    dtype: DType,
+    device: &Device,
+    remap: &[WeightKeyRemap],
+) -> Result<VarBuilder<'static>> {
+    let tensors = shared_mmaped_safetensors(path)?;
+    Ok(VarBuilder::from_backend(
+        Box::new(SharedSafetensors {
+            tensors,
+            remap: remap.to_vec(),
+        }),
+        dtype,
+        device.clone(),
+    ))
+}
+
+struct SharedSafetensors {
+    tensors: Arc<MmapedSafetensors>,
+    remap: Vec<WeightKeyRemap>,
+}
+
+impl SharedSafetensors {
+    fn resolve<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        self.remap
+            .iter()
+            .find_map(|remap| remap.apply(name))
+            .unwrap_or(Cow::Borrowed(name))
+    }
+
+    /// Explain a failed lookup, listing checkpoint keys that end like `name` (or failing that,
+    /// the first few keys) so a prefix mismatch is easy to spot.
+    fn missing_tensor(&self, requested: &str, resolved: &str) -> candle_core::Error {
+        let mut keys: Vec<String> = self
+            .tensors
+            .tensors()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        keys.sort();
+        let total = keys.len();
+        // Last three segments, e.g. `self_attn.q_proj.weight`.
+        let suffix = match resolved.rsplitn(4, '.').nth(3) {
+            Some(head) => &resolved[head.len() + 1..],
+            None => resolved,
+        };
+        let similar: Vec<&String> = keys
+            .iter()
+            .filter(|key| key.ends_with(suffix))
+            .take(SUGGESTED_KEYS)
+            .collect();
+        let (label, shown) = if similar.is_empty() {
+            ("first keys", keys.iter().take(SUGGESTED_KEYS).collect())
+        } else {
+            ("similar keys", similar)
+        };
+        let renamed = if requested == resolved {
+            String::new()
+        } else {
+            format!(" (remapped from `{requested}`)")
+        };
+        candle_core::Error::Msg(format!(
+            "tensor `{resolved}`{renamed} not found in checkpoint ({total} tensors); {label}: {}. \
+             Use a weight key remap if the checkpoint prefixes its tensor names differently",
+            shown
+                .iter()
+                .map(|key| format!("`{key}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+}
+
+impl SimpleBackend for SharedSafetensors {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let resolved = self.resolve(name);
+        if !SimpleBackend::contains_tensor(self.tensors.as_ref(), &resolved) {
+            return Err(self.missing_tensor(name, &resolved));
+        }
+        SimpleBackend::get(self.tensors.as_ref(), s, &resolved, h, dtype, dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        SimpleBackend::contains_tensor(self.tensors.as_ref(), &self.resolve(name))
+    }
+}
@@ -1,29 +1,56 @@
 use std::{
-    convert::TryFrom,
+    cell::RefCell,
+    fs,
+    ops::ControlFlow,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, anyhow, bail, ensure};
 use candle_core::{DType, Device, Tensor, shape::D};
 use candle_nn::VarBuilder;
 use image::GenericImageView;
 use image::{DynamicImage, Rgb, RgbImage, imageops};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    benchmark::Timer,
-    config::{DeepseekOcrConfig, ProjectorConfig, load_ocr_config},
+    benchmark::{Timer, sync},
+    config::{DeepseekOcrConfig, LanguageConfigOverrides, ProjectorConfig, load_ocr_config},
+    runtime::PreprocessDevice,
     transformer::{
-        cache::{DynamicCache, PromptCacheGuard},
+        cache::{CacheEviction, DynamicCache, PromptCacheGuard},
+        decoder::EarlyExit,
+        lora::LoraAdapter,
         model::{DeepseekLanguageModel, LanguageModelOutput},
+        sampling::{LogitsSampler, RepetitionPenalty, repeated_ngram_tokens},
+        weights::WeightQuant,
     },
     vision::{
-        ClipDebugTrace, ClipVisionModel, SamBackbone, SamDebugTrace, dynamic_preprocess,
-        resample::resize_bicubic,
+        ClipDebugTrace, ClipVisionModel, SamBackbone, SamDebugTrace,
+        dynamic_preprocess_with_strips, resample::resize_bicubic,
     },
 };
 
+mod batch;
+mod beam;
+mod image_bounds;
+mod mmap;
+mod prefix_cache;
+mod step;
+
+pub use batch::{BatchItem, BatchedGeneration, DecodeBatch, PrefilledSequence};
+pub use beam::{BeamHypothesis, BeamSearch};
+pub use image_bounds::{ImageSizeBounds, TileLayout, TileRect};
+pub use mmap::{WeightKeyRemap, shared_mmaped_safetensors, weight_files, weights_var_builder};
+pub use prefix_cache::{
+    LruPrefixCache, MIN_PREFIX_TOKENS, PrefixCache, PrefixCacheStore, PrefixKey,
+};
+pub use step::{GeneratedTokens, GenerationState, StepOutput};
+
 pub const DEFAULT_WEIGHTS_PATH: &str = "DeepSeek-OCR/model-00001-of-000001.safetensors";
 
 /// Vision inputs associated with a single batch element.
@@ -84,6 +111,17 @@ pub struct VisionDebugFeatures {
     pub local_sam_trace: Option<SamDebugTrace>,
 }
 
+/// Upper bound on tokens decoded past `max_new_tokens` when `extend_while` asks for more.
+pub const MAX_BUDGET_EXTENSION: usize = 4;
+
+/// Range of local crops per image in crop mode.
+pub const MIN_CROP_TILES: u32 = 2;
+pub const MAX_CROP_TILES: u32 = 9;
+
+/// Predicate over the tokens generated so far, used by [`GenerateOptions::stop_when`] and
+/// [`GenerateOptions::extend_while`].
+pub type TokensPredicate<'a> = &'a dyn Fn(&[i64]) -> bool;
+
 /// Options controlling autoregressive generation.
 pub struct GenerateOptions<'a> {
     pub attention_mask: Option<&'a Tensor>,
@@ -93,8 +131,34 @@ pub struct GenerateOptions<'a> {
     pub image_embeddings: Option<&'a [Tensor]>,
     pub max_new_tokens: usize,
     pub eos_token_id: Option<i64>,
+    /// Token ids never selected while decoding, e.g. the image placeholder (see
+    /// [`crate::inference::image_token_ids`]). Empty by default.
+    pub banned_token_ids: &'a [i64],
+    /// Token selection; `None` decodes greedily. Each generation samples from its own copy, so a
+    /// seeded sampler reproduces the same output for the same prompt.
+    pub sampler: Option<&'a LogitsSampler>,
+    /// Down-weight tokens that were already generated. `None` by default.
+    pub repetition_penalty: Option<RepetitionPenalty>,
+    /// Never generate a token that would repeat an n-gram of this size. `None` by default.
+    pub no_repeat_ngram_size: Option<usize>,
     pub progress_callback: Option<&'a dyn Fn(usize, &[i64])>,
+    /// Checked after every generated token; returning `true` ends generation with that token as
+    /// the last one (e.g. [`crate::inference::StopCriteria::matches`]).
+    pub stop_when: Option<TokensPredicate<'a>>,
+    /// Consulted once `max_new_tokens` is reached; returning `true` decodes one more token, up to
+    /// [`MAX_BUDGET_EXTENSION`] extra tokens (e.g. to finish a multibyte character).
+    pub extend_while: Option<TokensPredicate<'a>>,
     pub use_cache: bool,
+    /// Reuse the KV cache of the prompt text before the first image across prefills.
+    pub prefix_cache: Option<PrefixCache<'a>>,
+    /// Decode with beam search instead of one token at a time; [`DeepseekOcrModel::generate`]
+    /// then returns the best hypothesis. `None` by default.
+    pub beam_search: Option<BeamSearch>,
+    /// Record the log-probability of every generated token plus this many most likely
+    /// alternatives per position (`Some(0)` for the chosen token only), read back through
+    /// [`GenerationState::logprobs`] or [`DeepseekOcrModel::generate_with_logprobs`]. `None` by
+    /// default, which skips the log-softmax and every host read of the distribution.
+    pub logprobs: Option<usize>,
 }
 
 impl<'a> GenerateOptions<'a> {
@@ -107,8 +171,17 @@ impl<'a> GenerateOptions<'a> {
             image_embeddings: None,
             max_new_tokens,
             eos_token_id: None,
+            banned_token_ids: &[],
+            sampler: None,
+            repetition_penalty: None,
+            no_repeat_ngram_size: None,
             progress_callback: None,
+            stop_when: None,
+            extend_while: None,
             use_cache: true,
+            prefix_cache: None,
+            beam_search: None,
+            logprobs: None,
         }
     }
 }
@@ -254,8 +327,21 @@ pub struct DeepseekOcrModel {
     projector: ImageProjector,
     vision: VisionModules,
     device: Device,
+    preprocess_device: Device,
     dtype: DType,
     weights_path: PathBuf,
+    debug_crops: Option<CropDump>,
+    max_tiles: u32,
+    normalization: Normalization,
+    kv_max_seq_len: Option<(usize, CacheEviction)>,
+    kv_compact_stride: Option<usize>,
+}
+
+/// Destination for [`LoadOptions::debug_crops_dir`]: one numbered subdirectory per prepared
+/// image.
+struct CropDump {
+    dir: PathBuf,
+    next: AtomicUsize,
 }
 
 struct VisionModules {
@@ -640,28 +726,133 @@ impl<'a> VisionContext<'a> {
     }
 }
 
+/// Optional adjustments applied while loading a [`DeepseekOcrModel`].
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Quantize the language model's linear layers as they load.
+    pub quantize: Option<WeightQuant>,
+    /// Fields merged over the checkpoint's language config before the decoder is built.
+    pub language_overrides: LanguageConfigOverrides,
+    /// Where image tensors are assembled before the vision forward.
+    pub preprocess_device: PreprocessDevice,
+    /// Tensor-name prefix rewrites for checkpoints that name their weights differently; the
+    /// first matching entry wins.
+    pub weight_key_remap: Vec<WeightKeyRemap>,
+    /// Write the global view and every crop, as the vision encoders receive them, to numbered
+    /// subdirectories of this directory. Debugging aid; `None` skips the work entirely.
+    pub debug_crops_dir: Option<PathBuf>,
+    /// Compute the MoE load-balancing loss on every decoder forward (see
+    /// [`DeepseekLanguageModel::with_aux_loss`]).
+    pub aux_loss: bool,
+    /// Re-run a layer with eager attention when flash attention emits NaNs (see
+    /// [`DeepseekLanguageModel::with_flash_nan_check`]).
+    pub flash_nan_check: bool,
+    /// Experimental early exit for decode steps (see [`DeepseekLanguageModel::with_early_exit`]).
+    pub early_exit: Option<EarlyExit>,
+    /// Prefill prompts in blocks of this many tokens (see
+    /// [`DeepseekLanguageModel::with_prefill_chunk_size`]). `None` prefills in one pass.
+    pub prefill_chunk_size: Option<usize>,
+    /// Most crops the aspect-matched grid may use per image in crop mode (see
+    /// [`select_tile_grid`](crate::vision::select_tile_grid)). `None` keeps [`MAX_CROP_TILES`].
+    pub max_tiles: Option<u32>,
+    /// Pixel normalisation applied to the global view and every crop. Defaults to
+    /// [`Normalization::DEEPSEEK_OCR`]; only change it for fine-tunes trained with other values.
+    pub normalization: Normalization,
+    /// LoRA adapters applied over the decoder's projections, in order. Reweight or disable them
+    /// later with [`DeepseekOcrModel::set_lora_scale`] without reloading the base weights.
+    pub lora_adapters: Vec<LoraAdapter>,
+    /// Bound every KV cache from [`DeepseekOcrModel::new_cache`] to this many positions (see
+    /// [`DynamicCache::with_max_seq_len`]). `None` lets caches grow with the sequence.
+    pub kv_max_seq_len: Option<usize>,
+    /// Which positions a bounded cache keeps; ignored without `kv_max_seq_len`.
+    pub kv_eviction: CacheEviction,
+    /// Positions a bounded cache frees per eviction (see [`DynamicCache::with_compact_stride`]).
+    /// `None` frees half the window; ignored without `kv_max_seq_len`.
+    pub kv_compact_stride: Option<usize>,
+}
+
 impl DeepseekOcrModel {
     /// Load the OCR model from disk, pulling configuration and language-model weights.
     ///
     /// The vision/projector paths are stubbed for now; they will be filled in once the Candle
-    /// kernels land. `device` controls where tensors are allocated (CPU/GPU).
+    /// kernels land. `device` controls where tensors are allocated (CPU/GPU). `weights_path` is
+    /// either a single safetensors file or the `model.safetensors.index.json` of a sharded
+    /// checkpoint, whose shards are read from the same directory.
     pub fn load(
         config_path: Option<&Path>,
         weights_path: Option<&Path>,
         device: Device,
         dtype: DType,
     ) -> Result<Self> {
+        Self::load_with_quantization(config_path, weights_path, device, dtype, None)
+    }
+
+    /// Like [`Self::load`], quantizing the language model's linear layers when `quantize` is set.
+    /// Vision towers, the projector, embeddings and norms keep `dtype`.
+    pub fn load_with_quantization(
+        config_path: Option<&Path>,
+        weights_path: Option<&Path>,
+        device: Device,
+        dtype: DType,
+        quantize: Option<WeightQuant>,
+    ) -> Result<Self> {
+        let options = LoadOptions {
+            quantize,
+            ..LoadOptions::default()
+        };
+        Self::load_with_options(config_path, weights_path, device, dtype, &options)
+    }
+
+    /// Like [`Self::load`], applying every knob in [`LoadOptions`].
+    pub fn load_with_options(
+        config_path: Option<&Path>,
+        weights_path: Option<&Path>,
+        device: Device,
+        dtype: DType,
+        options: &LoadOptions,
+    ) -> Result<Self> {
+        let quantize = options.quantize;
         let cfg = Arc::new(load_ocr_config(config_path)?);
-        let language_cfg = Arc::new(cfg.resolved_language_config()?);
+        let mut language_cfg = cfg.resolved_language_config()?;
+        if !options.language_overrides.is_empty() {
+            language_cfg.apply_overrides(&options.language_overrides)?;
+            tracing::info!(
+                "Applied model config overrides: {:?}",
+                options.language_overrides
+            );
+        }
+        let language_cfg = Arc::new(language_cfg);
         let resolved_weights = weights_path
             .map(PathBuf::from)
             .unwrap_or_else(|| PathBuf::from(DEFAULT_WEIGHTS_PATH));
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(&[resolved_weights.as_path()], dtype, &device)
+        if !options.weight_key_remap.is_empty() {
+            tracing::info!(
+                "Remapping weight keys: {}",
+                options
+                    .weight_key_remap
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
         }
-        .with_context(|| format!("failed to mmap weights at {}", resolved_weights.display()))?;
-        let language = DeepseekLanguageModel::load(language_cfg, &vb)
-            .context("failed to load language model")?;
+        let vb = mmap::shared_var_builder(
+            &resolved_weights,
+            dtype,
+            &device,
+            &options.weight_key_remap,
+        )?;
+        let language = DeepseekLanguageModel::load_with_adapters(
+            language_cfg,
+            &vb,
+            quantize,
+            &options.lora_adapters,
+        )
+        .context("failed to load language model")?
+            .with_aux_loss(options.aux_loss)
+            .with_flash_nan_check(options.flash_nan_check)
+            .with_early_exit(options.early_exit)?
+            .with_prefill_chunk_size(options.prefill_chunk_size);
         let projector_cfg = Arc::new(
             cfg.resolved_projector_config()
                 .context("projector configuration missing")?,
@@ -679,6 +870,31 @@ impl DeepseekOcrModel {
         let clip = ClipVisionModel::load(cfg.as_ref(), &vb.pp("model").pp("vision_model"))
             .context("failed to load CLIP vision model")?;
         let vision = VisionModules { sam, clip };
+        let preprocess_device = match options.preprocess_device {
+            PreprocessDevice::Compute => device.clone(),
+            PreprocessDevice::Cpu => Device::Cpu,
+        };
+        let max_tiles = options.max_tiles.unwrap_or(MAX_CROP_TILES);
+        ensure!(
+            max_tiles >= MIN_CROP_TILES,
+            "max_tiles must be at least {MIN_CROP_TILES}, got {max_tiles}"
+        );
+        options.normalization.validate()?;
+        if let Some(max_seq_len) = options.kv_max_seq_len {
+            ensure!(
+                options.kv_eviction.kept_head() < max_seq_len,
+                "kv eviction `{}` keeps no recent positions within kv_max_seq_len {max_seq_len}",
+                options.kv_eviction
+            );
+            if let Some(stride) = options.kv_compact_stride {
+                let window = max_seq_len - options.kv_eviction.kept_head();
+                ensure!(
+                    stride > 0 && stride < window,
+                    "kv_compact_stride must be at least 1 and below the {window} positions the \
+                     cache keeps outside the attention sink, got {stride}"
+                );
+            }
+        }
 
         Ok(Self {
             cfg,
@@ -687,11 +903,35 @@ impl DeepseekOcrModel {
             projector,
             vision,
             device,
+            preprocess_device,
             dtype,
             weights_path: resolved_weights,
+            debug_crops: options.debug_crops_dir.clone().map(|dir| CropDump {
+                dir,
+                next: AtomicUsize::new(0),
+            }),
+            max_tiles,
+            normalization: options.normalization,
+            kv_max_seq_len: options
+                .kv_max_seq_len
+                .map(|max_seq_len| (max_seq_len, options.kv_eviction)),
+            kv_compact_stride: options.kv_compact_stride,
         })
     }
 
+    /// Drop the model and wait for the device to finish outstanding work, so the memory held by
+    /// its weights is back with the driver (and shows up in [`device_memory`]) on return. Plain
+    /// `drop` frees the same tensors, but CUDA releases them asynchronously on the stream.
+    ///
+    /// [`device_memory`]: crate::runtime::device_memory
+    pub fn unload(self) -> Result<()> {
+        let device = self.device.clone();
+        drop(self);
+        device
+            .synchronize()
+            .context("failed to synchronize device after unloading the model")
+    }
+
     /// Access the currently loaded configuration.
     pub fn config(&self) -> &DeepseekOcrConfig {
         self.cfg.as_ref()
@@ -702,6 +942,11 @@ impl DeepseekOcrModel {
         &self.device
     }
 
+    /// Device image tensors are built on before moving to [`Self::device`].
+    pub fn preprocess_device(&self) -> &Device {
+        &self.preprocess_device
+    }
+
     /// DType the model was loaded with.
     pub fn dtype(&self) -> DType {
         self.dtype
@@ -717,6 +962,13 @@ impl DeepseekOcrModel {
         &self.language
     }
 
+    /// Reweight a LoRA adapter from [`LoadOptions::lora_adapters`] by its index; `0.0` disables
+    /// it. See [`DeepseekLanguageModel::set_lora_scale`]. Prefix cache keys include the scales,
+    /// so prefixes computed under the old scale are not restored afterwards.
+    pub fn set_lora_scale(&self, adapter: usize, scale: f32) -> Result<()> {
+        self.language.set_lora_scale(adapter, scale)
+    }
+
     /// Whether flash attention is enabled for the underlying decoder.
     pub fn flash_attention_enabled(&self) -> bool {
         self.language.flash_attention_enabled()
@@ -727,10 +979,33 @@ impl DeepseekOcrModel {
         self.projector_cfg.as_ref()
     }
 
-    /// Construct a fresh dynamic cache sized for this model.
+    /// Write the effective language configuration (after defaults and overrides are resolved)
+    /// to `path` as pretty-printed JSON.
+    pub fn dump_config(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self.language.config())
+            .context("failed to serialize language config")?;
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        fs::write(path, json)
+            .with_context(|| format!("failed to write config to {}", path.display()))
+    }
+
+    /// Construct a fresh dynamic cache sized for this model, bounded by
+    /// [`LoadOptions::kv_max_seq_len`] and compacted by [`LoadOptions::kv_compact_stride`] when
+    /// set.
     pub fn new_cache(&self) -> DynamicCache {
         let layers = self.language.transformer_weights().layers.len();
-        DynamicCache::with_num_layers(layers)
+        let cache = DynamicCache::with_num_layers(layers);
+        let Some((max_seq_len, eviction)) = self.kv_max_seq_len else {
+            return cache;
+        };
+        let cache = cache.with_max_seq_len(max_seq_len, eviction);
+        match self.kv_compact_stride {
+            Some(stride) => cache.with_compact_stride(stride),
+            None => cache,
+        }
     }
 
     /// Helper to guard prompt-scoped cache state.
@@ -1179,30 +1454,62 @@ impl DeepseekOcrModel {
         image_size: u32,
         crop_mode: bool,
     ) -> Result<OwnedVisionInput> {
+        self.prepare_vision_input_with_strips(image, base_size, image_size, crop_mode, None)
+    }
+
+    /// Like [`Self::prepare_vision_input_from_image`], cutting images whose long side is at least
+    /// `strip_aspect_threshold` times the short side into a strip of square crops instead of the
+    /// closest grid (see [`crate::vision::preprocess::select_tile_grid`]).
+    pub fn prepare_vision_input_with_strips(
+        &self,
+        image: &DynamicImage,
+        base_size: u32,
+        image_size: u32,
+        crop_mode: bool,
+        strip_aspect_threshold: Option<f32>,
+    ) -> Result<OwnedVisionInput> {
+        let staging = self.preprocess_device();
         let global_view = build_global_view(image, base_size);
-        let global = image_to_tensor(&global_view, self.device(), self.dtype)?
-            .unsqueeze(0)?
+        let normalization = &self.normalization;
+        let global = image_to_tensor(
+            &global_view,
+            staging,
+            self.dtype,
+            TensorLayout::Nchw,
+            normalization,
+        )?
+        .unsqueeze(0)?
             .contiguous()?;
 
         let (patches, crop_shape) = if crop_mode {
-            let preprocess = dynamic_preprocess(image, 2, 9, image_size, false);
+            let preprocess = dynamic_preprocess_with_strips(
+                image,
+                MIN_CROP_TILES,
+                self.max_tiles,
+                image_size,
+                false,
+                strip_aspect_threshold,
+            );
             let crop = (preprocess.ratio.0 as usize, preprocess.ratio.1 as usize);
             let tiles = preprocess.tiles;
             if tiles.is_empty() {
                 (None, Some(crop))
             } else {
                 tracing::info!("Preparing {} image crops for vision input", tiles.len());
-                let device = self.device().clone();
                 let dtype = self.dtype();
-                let tensors: Vec<Tensor> = if matches!(self.device(), Device::Cpu) {
+                let tensors: Vec<Tensor> = if matches!(staging, Device::Cpu) {
                     tiles
                         .into_par_iter()
-                        .map(|tile| image_to_tensor(&tile, &device, dtype))
+                        .map(|tile| {
+                            image_to_tensor(&tile, staging, dtype, TensorLayout::Nchw, normalization)
+                        })
                         .collect::<Result<Vec<_>>>()?
                 } else {
                     tiles
                         .into_iter()
-                        .map(|tile| image_to_tensor(&tile, &device, dtype))
+                        .map(|tile| {
+                            image_to_tensor(&tile, staging, dtype, TensorLayout::Nchw, normalization)
+                        })
                         .collect::<Result<Vec<_>>>()?
                 };
                 let stacked = Tensor::stack(&tensors, 0)?.contiguous()?;
@@ -1211,6 +1518,25 @@ impl DeepseekOcrModel {
         } else {
             (None, None)
         };
+        if let Some(dump) = &self.debug_crops {
+            dump.write(&global, patches.as_ref(), normalization)?;
+        }
+
+        let (global, patches) = if staging.same_device(self.device()) {
+            (global, patches)
+        } else {
+            sync(staging);
+            let timer = Timer::new("vision.upload");
+            let global = global.to_device(self.device())?;
+            let patches = patches
+                .map(|patches| patches.to_device(self.device()))
+                .transpose()?;
+            sync(self.device());
+            timer.finish(|event| {
+                event.add_field("crops", patches.is_some());
+            });
+            (global, patches)
+        };
 
         Ok(OwnedVisionInput {
             global,
@@ -1329,7 +1655,8 @@ impl DeepseekOcrModel {
         self.inject_image_tokens(embeddings, mask, image_embeddings)
     }
 
-    /// Greedy autoregressive generation for the multimodal model.
+    /// Autoregressive generation for the multimodal model; greedy unless `options.sampler` or
+    /// `options.beam_search` is set.
     pub fn generate(&self, input_ids: &Tensor, options: GenerateOptions<'_>) -> Result<Tensor> {
         let total_timer = Timer::new("decode.generate");
         ensure!(
@@ -1341,6 +1668,15 @@ impl DeepseekOcrModel {
             batch == 1,
             "generate currently supports batch size 1 (got {batch})"
         );
+        if let Some(beam) = options.beam_search.filter(|beam| beam.num_beams > 1) {
+            let best = self
+                .generate_beams(input_ids, &options, &beam)?
+                .into_iter()
+                .next()
+                .map_or_else(Vec::new, |best| best.tokens);
+            let len = best.len();
+            return Ok(Tensor::from_vec(best, (1, len), self.device())?);
+        }
         if !options.use_cache {
             total_timer.finish(|event| {
                 event.add_field("mode", "no_cache");
@@ -1349,7 +1685,6 @@ impl DeepseekOcrModel {
             });
             return self.generate_without_cache(input_ids, options);
         }
-        let progress_callback = options.progress_callback;
         if options.max_new_tokens == 0 {
             total_timer.finish(|event| {
                 event.add_field("prompt_tokens", seq_len as u64);
@@ -1359,88 +1694,79 @@ impl DeepseekOcrModel {
             return self.empty_generation();
         }
 
-        let mut cache = self.new_cache();
-        let mut guard = self.prompt_guard(&mut cache);
-        let prefill_timer = Timer::new("decode.prefill");
-        let prefill = self.forward(
-            Some(input_ids),
-            None,
-            options.attention_mask,
-            options.position_ids,
-            options.images_seq_mask,
-            options.image_inputs,
-            options.image_embeddings,
-            Some(guard.cache()),
-            true,
+        let generated = self.decode_cached(input_ids, options, total_timer)?.tokens;
+        let len = generated.len();
+        Ok(Tensor::from_vec(generated, (1, len), self.device())?.to_dtype(DType::I64)?)
+    }
+
+    /// Generate like [`Self::generate`], also returning the log-probability of each generated
+    /// token and, when `options.logprobs` is `Some(k)`, the `k` most likely alternatives at every
+    /// position. Log-probabilities come from the distribution the token was chosen from: the raw
+    /// logits with banned ids and repetition controls applied, before any sampler temperature or
+    /// nucleus filtering. Requires `use_cache`; beam search is not supported.
+    pub fn generate_with_logprobs(
+        &self,
+        input_ids: &Tensor,
+        options: GenerateOptions<'_>,
+    ) -> Result<GeneratedTokens> {
+        ensure!(
+            options.use_cache,
+            "generate_with_logprobs requires use_cache"
+        );
+        ensure!(
+            options.beam_search.is_none_or(|beam| beam.num_beams <= 1),
+            "generate_with_logprobs does not support beam search"
+        );
+        if options.max_new_tokens == 0 {
+            return Ok(GeneratedTokens::default());
+        }
+        let total_timer = Timer::new("decode.generate");
+        let logprobs = Some(options.logprobs.unwrap_or(0));
+        self.decode_cached(
+            input_ids,
+            GenerateOptions { logprobs, ..options },
+            total_timer,
+        )
+    }
+
+    /// The KV-cached decode loop behind [`Self::generate`]; `options.max_new_tokens` must be
+    /// positive.
+    fn decode_cached(
+        &self,
+        input_ids: &Tensor,
+        options: GenerateOptions<'_>,
+        total_timer: Timer,
+    ) -> Result<GeneratedTokens> {
+        let (_batch, seq_len) = input_ids.shape().dims2()?;
+        let max_steps =
+            options.max_new_tokens + options.extend_while.map_or(0, |_| MAX_BUDGET_EXTENSION);
+        let mut state = self.prepare_generation(
+            input_ids,
+            &GenerateOptions {
+                max_new_tokens: max_steps,
+                ..options
+            },
         )?;
-        prefill_timer.finish(|event| {
-            event.add_field("prompt_tokens", seq_len as u64);
-            event.add_field("has_image_mask", options.images_seq_mask.is_some());
-            event.add_field("use_cache", true);
-        });
-        let logits = prefill
-            .logits
-            .get(0)
-            .context("prefill logits missing batch dimension")?;
-        let last_logits = logits
-            .get(seq_len - 1)
-            .context("prefill logits missing final timestep")?;
-        let mut current = self.select_token_id(&last_logits)?;
-        if let Some(eos) = options.eos_token_id {
-            if current == eos {
-                total_timer.finish(|event| {
-                    event.add_field("prompt_tokens", seq_len as u64);
-                    event.add_field("generated_tokens", 0u64);
-                    event.add_field("max_new_tokens", options.max_new_tokens as u64);
-                    event.add_field("terminated_on_prefill", true);
-                });
-                return self.empty_generation();
-            }
+        // Returns the prefill token without another forward pass, so it cannot leave the cache
+        // half-filled.
+        let first = self.step(&mut state)?;
+        if options.eos_token_id == Some(first.token) {
+            self.end_generation(&mut state);
+            total_timer.finish(|event| {
+                event.add_field("prompt_tokens", seq_len as u64);
+                event.add_field("generated_tokens", 0u64);
+                event.add_field("max_new_tokens", options.max_new_tokens as u64);
+                event.add_field("terminated_on_prefill", true);
+            });
+            return Ok(GeneratedTokens::default());
         }
 
-        let mut generated = Vec::with_capacity(options.max_new_tokens);
         let decode_timer = Timer::new("decode.iterative");
-        for step in 0..options.max_new_tokens {
-            generated.push(current);
-            if let Some(cb) = progress_callback {
-                cb(generated.len(), &generated);
-            }
-            if step + 1 == options.max_new_tokens {
-                break;
-            }
-            let token_index = usize::try_from(current)
-                .context("token id out of range while preparing decode embedding")?;
-            let decode_inputs = self
-                .language
-                .token_embedding_for_id(token_index)
-                .context("failed to gather embedding for decode token")?
-                .unsqueeze(0)?
-                .unsqueeze(0)?;
-            let decode = self.forward(
-                None,
-                Some(&decode_inputs),
-                None,
-                None,
-                None,
-                None,
-                None,
-                Some(guard.cache()),
-                true,
-            )?;
-            let next_logits = decode
-                .logits
-                .get(0)
-                .context("decode logits missing batch dimension")?
-                .get(0)
-                .context("decode logits missing timestep")?;
-            current = self.select_token_id(&next_logits)?;
-            if let Some(eos) = options.eos_token_id {
-                if current == eos {
-                    break;
-                }
-            }
-        }
-        let len = generated.len();
+        let decoded = self.decode_steps(&mut state, first, &options);
+        self.end_generation(&mut state);
+        decoded?;
+        let generated = state.take_tokens();
+        let len = generated.tokens.len();
         decode_timer.finish(|event| {
             event.add_field("steps", len as u64);
             event.add_field("max_new_tokens", options.max_new_tokens as u64);
@@ -1452,7 +1778,43 @@ impl DeepseekOcrModel {
             event.add_field("terminated_on_prefill", false);
             event.add_field("use_cache", true);
         });
-        Ok(Tensor::from_vec(generated, (1, len), self.device())?.to_dtype(DType::I64)?)
+        Ok(generated)
+    }
+
+    /// Generate like [`Self::generate`], handing each token to `on_token` as soon as it is
+    /// produced, e.g. to stream text to a UI. Returning [`ControlFlow::Break`] ends generation
+    /// after that token; the KV cache is released either way. Returns every generated token, the
+    /// one that broke off included. EOS is neither passed to `on_token` nor returned.
+    pub fn generate_streaming<F>(
+        &self,
+        input_ids: &Tensor,
+        options: GenerateOptions<'_>,
+        on_token: F,
+    ) -> Result<Vec<u32>>
+    where
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let on_token = RefCell::new(on_token);
+        let stop_when = options.stop_when;
+        let stream = |generated: &[i64]| {
+            let Some(token) = generated.last().and_then(|&id| u32::try_from(id).ok()) else {
+                return false;
+            };
+            (on_token.borrow_mut())(token).is_break()
+                || stop_when.is_some_and(|stop| stop(generated))
+        };
+        let generated = self.generate(
+            input_ids,
+            GenerateOptions {
+                stop_when: Some(&stream),
+                ..options
+            },
+        )?;
+        generated
+            .to_dtype(DType::U32)?
+            .squeeze(0)?
+            .to_vec1::<u32>()
+            .context("failed to read generated tokens")
     }
 
     fn generate_without_cache(
@@ -1576,6 +1938,7 @@ impl DeepseekOcrModel {
             to_tensor_i64(&tokens, self.device()).context("failed to build prefill tokens")?;
         let mut forward_calls = 0u64;
         let mut max_seq_len_seen = tokens.len() as u64;
+        sync(self.device());
         let prefill_timer = Timer::new("decode.prefill_no_cache");
         let prefill = self.forward(
             Some(&input_tensor),
@@ -1588,6 +1951,7 @@ impl DeepseekOcrModel {
             None,
             false,
         )?;
+        sync(self.device());
         prefill_timer.finish(|event| {
             event.add_field("prompt_tokens", seq_len as u64);
             event.add_field("final_seq", tokens.len() as u64);
@@ -1600,7 +1964,8 @@ impl DeepseekOcrModel {
             .context("prefill logits missing batch dimension")?
             .get(tokens.len() - 1)
             .context("prefill logits missing final timestep")?;
-        let mut current = self.select_token_id(&logits)?;
+        let selection = TokenSelection::from_options(&options);
+        let mut current = selection.select(&logits, &[])?;
         if let Some(eos) = options.eos_token_id {
             if current == eos {
                 total_timer.finish(|event| {
@@ -1618,12 +1983,22 @@ impl DeepseekOcrModel {
 
         let progress_callback = options.progress_callback;
         let mut generated = Vec::with_capacity(options.max_new_tokens);
-        for step in 0..options.max_new_tokens {
+        let max_steps =
+            options.max_new_tokens + options.extend_while.map_or(0, |_| MAX_BUDGET_EXTENSION);
+        for step in 0..max_steps {
             generated.push(current);
             if let Some(cb) = progress_callback {
                 cb(generated.len(), &generated);
             }
-            if step + 1 == options.max_new_tokens {
+            if options.stop_when.is_some_and(|stop| stop(&generated)) {
+                break;
+            }
+            if step + 1 >= options.max_new_tokens
+                && !(step + 1 < max_steps
+                    && options
+                        .extend_while
+                        .is_some_and(|extend| extend(&generated)))
+            {
                 break;
             }
 
@@ -1665,7 +2040,7 @@ impl DeepseekOcrModel {
                 .context("decode logits missing batch dimension")?
                 .get(seq_pos)
                 .context("decode logits missing timestep")?;
-            current = self.select_token_id(&next_logits)?;
+            current = selection.select(&next_logits, &generated)?;
             if let Some(eos) = options.eos_token_id {
                 if current == eos {
                     break;
@@ -1689,8 +2064,53 @@ impl DeepseekOcrModel {
     fn empty_generation(&self) -> Result<Tensor> {
         Ok(Tensor::from_vec(Vec::<i64>::new(), (1, 0), self.device())?.to_dtype(DType::I64)?)
     }
+}
+
+/// How the next token is picked from one position's `[vocab]` logits: banned ids, repetition
+/// controls and the sampler from [`GenerateOptions`], owned so decode state can outlive them.
+#[derive(Debug, Clone, Default)]
+struct TokenSelection {
+    banned_token_ids: Vec<i64>,
+    repetition_penalty: Option<RepetitionPenalty>,
+    no_repeat_ngram_size: Option<usize>,
+    sampler: Option<LogitsSampler>,
+}
 
-    fn select_token_id(&self, logits: &Tensor) -> Result<i64> {
+impl TokenSelection {
+    fn from_options(options: &GenerateOptions<'_>) -> Self {
+        Self {
+            banned_token_ids: options.banned_token_ids.to_vec(),
+            repetition_penalty: options.repetition_penalty,
+            no_repeat_ngram_size: options.no_repeat_ngram_size,
+            sampler: options.sampler.cloned(),
+        }
+    }
+
+    /// Whether selection is a plain argmax over the logits with banned ids removed, independent of
+    /// the tokens generated so far.
+    fn is_greedy(&self) -> bool {
+        self.repetition_penalty.is_none()
+            && self.no_repeat_ngram_size.is_none()
+            && self.sampler.as_ref().is_none_or(LogitsSampler::is_greedy)
+    }
+
+    /// `logits` as F32 with banned ids and repeats of `generated` removed or penalised.
+    fn process(&self, logits: &Tensor, generated: &[i64]) -> Result<Tensor> {
+        let mut logits = match self.repetition_penalty {
+            Some(penalty) => penalty.apply(logits, generated)?,
+            None => logits.clone(),
+        };
+        if let Some(ngram_size) = self.no_repeat_ngram_size {
+            logits = suppress_tokens(&logits, &repeated_ngram_tokens(generated, ngram_size))?;
+        }
+        suppress_tokens(&logits, &self.banned_token_ids)
+    }
+
+    /// Pick a token from logits that already went through [`Self::process`].
+    fn pick(&self, logits: &Tensor) -> Result<i64> {
+        if let Some(sampler) = &self.sampler {
+            return Ok(i64::from(sampler.sample(logits)?));
+        }
         let idx = logits.argmax(D::Minus1)?;
         let idx = if idx.dtype() == DType::I64 {
             idx
@@ -1700,6 +2120,27 @@ impl DeepseekOcrModel {
         idx.to_scalar::<i64>()
             .context("failed to convert argmax index to scalar")
     }
+
+    fn select(&self, logits: &Tensor, generated: &[i64]) -> Result<i64> {
+        self.pick(&self.process(logits, generated)?)
+    }
+}
+
+/// `logits` (`[vocab]` or `[batch, vocab]`) as F32 with the `banned` ids set to `-inf`, so greedy
+/// selection and log-probabilities skip them. Ids outside the vocabulary are ignored.
+fn suppress_tokens(logits: &Tensor, banned: &[i64]) -> Result<Tensor> {
+    if banned.is_empty() {
+        return Ok(logits.clone());
+    }
+    let vocab = logits.dim(D::Minus1)?;
+    let mut mask = vec![0f32; vocab];
+    for &id in banned {
+        if let Some(slot) = usize::try_from(id).ok().and_then(|id| mask.get_mut(id)) {
+            *slot = f32::NEG_INFINITY;
+        }
+    }
+    let mask = Tensor::from_vec(mask, vocab, logits.device())?;
+    Ok(logits.to_dtype(DType::F32)?.broadcast_add(&mask)?)
 }
 
 fn round_ties_to_even(value: f64) -> f64 {
@@ -1719,40 +2160,200 @@ pub fn build_global_view(image: &DynamicImage, base_size: u32) -> DynamicImage {
     let mean = (0.5 * 255.0) as u8;
     let mut canvas = RgbImage::from_pixel(base_size, base_size, Rgb([mean, mean, mean]));
     let (orig_w, orig_h) = image.dimensions();
-    if orig_w == 0 || orig_h == 0 {
+    let Some(placed) = global_view_rect(orig_w, orig_h, base_size) else {
         return DynamicImage::ImageRgb8(canvas);
+    };
+
+    let rgb_image = image.to_rgb8();
+    let resized = resize_bicubic(&rgb_image, placed.width, placed.height);
+    imageops::replace(&mut canvas, &resized, placed.x as i64, placed.y as i64);
+    DynamicImage::ImageRgb8(canvas)
+}
+
+/// Where [`build_global_view`] letterboxes a `width` x `height` image on its `base_size` canvas;
+/// `None` for an empty image, which leaves the canvas blank.
+fn global_view_rect(width: u32, height: u32, base_size: u32) -> Option<TileRect> {
+    if width == 0 || height == 0 {
+        return None;
     }
-    let scale = (base_size as f64 / orig_w as f64).min(base_size as f64 / orig_h as f64);
-    let new_w = round_ties_to_even(orig_w as f64 * scale)
+    let scale = (base_size as f64 / width as f64).min(base_size as f64 / height as f64);
+    let new_w = round_ties_to_even(width as f64 * scale)
         .max(1.0)
         .min(base_size as f64) as u32;
-    let new_h = round_ties_to_even(orig_h as f64 * scale)
+    let new_h = round_ties_to_even(height as f64 * scale)
         .max(1.0)
         .min(base_size as f64) as u32;
+    Some(TileRect {
+        x: round_ties_to_even((base_size as f64 - new_w as f64) * 0.5) as u32,
+        y: round_ties_to_even((base_size as f64 - new_h as f64) * 0.5) as u32,
+        width: new_w,
+        height: new_h,
+    })
+}
 
-    let rgb_image = image.to_rgb8();
-    let resized = resize_bicubic(&rgb_image, new_w, new_h);
+impl CropDump {
+    /// Save `global` (`[1, 3, H, W]`) and each row of `patches` (`[n, 3, H, W]`), built with
+    /// `normalization`, as PNGs.
+    fn write(
+        &self,
+        global: &Tensor,
+        patches: Option<&Tensor>,
+        normalization: &Normalization,
+    ) -> Result<()> {
+        let dir = self
+            .dir
+            .join(format!("{:04}", self.next.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create crop directory {}", dir.display()))?;
+        let save = |tensor: &Tensor, name: String| -> Result<()> {
+            let path = dir.join(name);
+            tensor_to_image(tensor, normalization)?
+                .save(&path)
+                .with_context(|| format!("failed to write crop {}", path.display()))
+        };
+        save(&global.get(0)?, "global.png".into())?;
+        if let Some(patches) = patches {
+            for idx in 0..patches.dim(0)? {
+                save(&patches.get(idx)?, format!("tile_{idx:02}.png"))?;
+            }
+        }
+        tracing::info!("Wrote vision crops to {}", dir.display());
+        Ok(())
+    }
+}
 
-    let x_off = round_ties_to_even((base_size as f64 - new_w as f64) * 0.5) as i64;
-    let y_off = round_ties_to_even((base_size as f64 - new_h as f64) * 0.5) as i64;
-    imageops::replace(&mut canvas, &resized, x_off, y_off);
-    DynamicImage::ImageRgb8(canvas)
+/// Per-channel normalisation of pixel values, `(value / 255 - mean) / std`, in RGB order.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Normalization {
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+impl Normalization {
+    /// What DeepSeek-OCR was trained with: `mean = std = [0.5, 0.5, 0.5]`, mapping every
+    /// channel from `[0, 255]` to `[-1, 1]`.
+    pub const DEEPSEEK_OCR: Self = Self {
+        mean: [0.5; 3],
+        std: [0.5; 3],
+    };
+
+    /// Parse one per-channel triple written as `r,g,b`, e.g. `0.485,0.456,0.406`.
+    pub fn parse_channels(value: &str) -> Result<[f32; 3]> {
+        let channels = value
+            .split(',')
+            .map(|part| {
+                part.trim()
+                    .parse::<f32>()
+                    .with_context(|| format!("invalid channel value `{}`", part.trim()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        channels
+            .try_into()
+            .map_err(|channels: Vec<f32>| {
+                anyhow!("expected 3 comma-separated values, got {}", channels.len())
+            })
+    }
+
+    /// Reject non-finite values and zero or negative standard deviations.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.mean.iter().all(|value| value.is_finite()),
+            "normalization mean must be finite, got {:?}",
+            self.mean
+        );
+        ensure!(
+            self.std.iter().all(|value| value.is_finite() && *value > 0.0),
+            "normalization std must be positive, got {:?}",
+            self.std
+        );
+        Ok(())
+    }
 }
 
-pub fn image_to_tensor(image: &DynamicImage, device: &Device, dtype: DType) -> Result<Tensor> {
+impl Default for Normalization {
+    fn default() -> Self {
+        Self::DEEPSEEK_OCR
+    }
+}
+
+/// Undo `normalization` for an image tensor, e.g. to look at what a vision encoder was fed.
+///
+/// Accepts `[3, H, W]` or `[H, W, 3]` (channels first wins when both sides are 3), optionally
+/// with a leading batch dimension of 1. Values are rounded and clamped to `[0, 255]`.
+pub fn tensor_to_image(tensor: &Tensor, normalization: &Normalization) -> Result<DynamicImage> {
+    let tensor = match tensor.dims() {
+        [1, _, _, _] => tensor.squeeze(0)?,
+        _ => tensor.clone(),
+    };
+    let (height, width, hwc) = match *tensor.dims() {
+        [3, height, width] => (height, width, tensor.permute((1, 2, 0))?),
+        [height, width, 3] => (height, width, tensor),
+        _ => bail!(
+            "expected a [3, height, width] or [height, width, 3] image tensor, got {:?}",
+            tensor.dims()
+        ),
+    };
+    let values = hwc.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+    let pixels = values
+        .into_iter()
+        .enumerate()
+        .map(|(idx, value)| {
+            let channel = idx % 3;
+            let value = value * normalization.std[channel] + normalization.mean[channel];
+            (value * 255.0).round().clamp(0.0, 255.0) as u8
+        })
+        .collect();
+    let image = RgbImage::from_raw(width as u32, height as u32, pixels)
+        .context("tensor size does not match its dimensions")?;
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+/// Axis order of the tensor built by [`image_to_tensor`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TensorLayout {
+    /// Channels first, `[3, height, width]`. The SAM and CLIP encoders only accept this layout
+    /// (batched as `[batch, 3, height, width]`); it is what the model's own preprocessing uses.
+    #[default]
+    Nchw,
+    /// Channels last, `[height, width, 3]`, for handing pixels to frameworks that expect it.
+    Nhwc,
+}
+
+/// Convert an image to RGB values normalised with `normalization`, in the requested axis order.
+/// [`Normalization::DEEPSEEK_OCR`] maps every channel to `[-1, 1]`, as the model expects.
+///
+/// No batch dimension is added; callers stack or `unsqueeze(0)` as needed.
+pub fn image_to_tensor(
+    image: &DynamicImage,
+    device: &Device,
+    dtype: DType,
+    layout: TensorLayout,
+    normalization: &Normalization,
+) -> Result<Tensor> {
     let rgb = image.to_rgb8();
     let (width, height) = rgb.dimensions();
-    let mut data = Vec::with_capacity((width * height * 3) as usize);
-    for c in 0..3 {
-        for y in 0..height {
-            for x in 0..width {
-                let value = rgb.get_pixel(x, y)[c as usize] as f32 / 255.0;
-                let normalized = (value - 0.5) / 0.5;
-                data.push(normalized);
+    let (width, height) = (width as usize, height as usize);
+    let Normalization { mean, std } = *normalization;
+    let normalize = |value: u8, c: usize| (value as f32 / 255.0 - mean[c]) / std[c];
+    let tensor = match layout {
+        TensorLayout::Nchw => {
+            let mut data = Vec::with_capacity(width * height * 3);
+            for c in 0..3 {
+                data.extend(rgb.pixels().map(|pixel| normalize(pixel[c], c)));
             }
+            Tensor::from_vec(data, (3, height, width), device)?
         }
-    }
-    let tensor = Tensor::from_vec(data, (3, height as usize, width as usize), device)?;
+        TensorLayout::Nhwc => {
+            let data = rgb
+                .as_raw()
+                .iter()
+                .enumerate()
+                .map(|(idx, &value)| normalize(value, idx % 3))
+                .collect();
+            Tensor::from_vec(data, (height, width, 3), device)?
+        }
+    };
     if tensor.dtype() == dtype {
         Ok(tensor)
     } else {
@@ -0,0 +1,309 @@
+use std::{collections::VecDeque, fmt, sync::Mutex};
+
+use anyhow::{Result, ensure};
+use candle_core::{DType, Device, Tensor};
+use tracing::{debug, warn};
+
+use crate::transformer::{cache::DynamicCache, lora::LoraAdapter};
+
+use super::{DeepseekOcrModel, GenerateOptions};
+
+/// Prompt prefixes shorter than this are prefilled directly; restoring them would cost about as
+/// much as recomputing them.
+pub const MIN_PREFIX_TOKENS: usize = 16;
+
+/// Identifies a prompt prefix: model id, cache dtype, prompt template, the prefix token ids and
+/// any LoRA adapters in effect, hashed with FNV-1a so keys are stable across processes and builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PrefixKey(u64);
+
+impl PrefixKey {
+    pub fn new(model_id: &str, dtype: DType, template: &str, tokens: &[i64]) -> Self {
+        let mut hash = Fnv1a::new();
+        hash.write_str(model_id);
+        hash.write_str(dtype.as_str());
+        hash.write_str(template);
+        for token in tokens {
+            hash.write(&token.to_le_bytes());
+        }
+        Self(hash.0)
+    }
+
+    /// Scope the key to the LoRA adapters and their current scales, so reweighting an adapter
+    /// never restores a prefix computed under the old weights. No adapters leaves the key as is.
+    pub fn with_lora_adapters(self, adapters: &[LoraAdapter]) -> Self {
+        let mut hash = Fnv1a(self.0);
+        for adapter in adapters {
+            hash.write_str(&adapter.path.to_string_lossy());
+            hash.write(&adapter.scale.to_le_bytes());
+        }
+        Self(hash.0)
+    }
+}
+
+impl fmt::Display for PrefixKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Storage for serialized prefix KV caches. Implement it to share warmed prefixes between
+/// replicas (shared memory, Redis, ...); [`LruPrefixCache`] keeps them in process.
+///
+/// Errors are logged and treated as a miss, so a flaky remote store only costs a full prefill.
+pub trait PrefixCacheStore: Send + Sync {
+    fn get(&self, key: PrefixKey) -> Result<Option<Vec<u8>>>;
+    fn put(&self, key: PrefixKey, snapshot: Vec<u8>) -> Result<()>;
+}
+
+/// In-process store keeping the `capacity` most recently used snapshots.
+pub struct LruPrefixCache {
+    capacity: usize,
+    /// Most recently used last.
+    entries: Mutex<VecDeque<(PrefixKey, Vec<u8>)>>,
+}
+
+impl LruPrefixCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().map_or(0, |entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl PrefixCacheStore for LruPrefixCache {
+    fn get(&self, key: PrefixKey) -> Result<Option<Vec<u8>>> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow::anyhow!("prefix cache lock poisoned"))?;
+        let Some(idx) = entries.iter().position(|(entry, _)| *entry == key) else {
+            return Ok(None);
+        };
+        let entry = entries.remove(idx).expect("index found above");
+        let snapshot = entry.1.clone();
+        entries.push_back(entry);
+        Ok(Some(snapshot))
+    }
+
+    fn put(&self, key: PrefixKey, snapshot: Vec<u8>) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow::anyhow!("prefix cache lock poisoned"))?;
+        entries.retain(|(entry, _)| *entry != key);
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((key, snapshot));
+        Ok(())
+    }
+}
+
+/// Where [`DeepseekOcrModel::prepare_generation`] and [`DeepseekOcrModel::prefill`] look up and
+/// store prompt prefixes, and what the keys are scoped to.
+#[derive(Clone, Copy)]
+pub struct PrefixCache<'a> {
+    pub store: &'a dyn PrefixCacheStore,
+    pub model_id: &'a str,
+    pub template: &'a str,
+}
+
+impl DeepseekOcrModel {
+    /// Prefill `input_ids` into the empty `cache` and return the logits of the last position.
+    ///
+    /// With [`GenerateOptions::prefix_cache`] set, the tokens before the first image token are
+    /// restored from the store when present and stored after being computed otherwise; the rest
+    /// of the prompt is then appended on top. Prompts with an explicit attention mask or
+    /// position ids always run in one pass.
+    pub(super) fn prefill_cache(
+        &self,
+        input_ids: &Tensor,
+        options: &GenerateOptions<'_>,
+        cache: &mut DynamicCache,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let cached = match options.prefix_cache {
+            Some(prefix_cache)
+                if options.attention_mask.is_none() && options.position_ids.is_none() =>
+            {
+                self.load_prefix(prefix_cache, input_ids, options.images_seq_mask, cache)?
+            }
+            _ => 0,
+        };
+        let rest = seq_len - cached;
+        let images_seq_mask = options
+            .images_seq_mask
+            .map(|mask| mask.narrow(1, cached, rest))
+            .transpose()?;
+        let output = self.forward(
+            Some(&input_ids.narrow(1, cached, rest)?),
+            None,
+            options.attention_mask,
+            options.position_ids,
+            images_seq_mask.as_ref(),
+            options.image_inputs,
+            options.image_embeddings,
+            Some(cache),
+            true,
+        )?;
+        // A chunked prefill returns only the last position, a single pass every one.
+        let last = output.logits.dim(1)? - 1;
+        Ok(output.logits.get(0)?.get(last)?)
+    }
+
+    /// Fill `cache` with the shareable prefix of `input_ids`, from the store or by running it.
+    /// Returns how many prompt positions are now cached.
+    fn load_prefix(
+        &self,
+        prefix_cache: PrefixCache<'_>,
+        input_ids: &Tensor,
+        images_seq_mask: Option<&Tensor>,
+        cache: &mut DynamicCache,
+    ) -> Result<usize> {
+        let seq_len = input_ids.dim(1)?;
+        let first_image = match images_seq_mask {
+            Some(mask) => mask
+                .to_dtype(DType::U8)?
+                .flatten_all()?
+                .to_vec1::<u8>()?
+                .iter()
+                .position(|&flag| flag != 0),
+            None => None,
+        };
+        // Keep at least one position for the forward that produces the next-token logits.
+        let prefix_len = first_image.unwrap_or(seq_len).min(seq_len - 1);
+        if prefix_len < MIN_PREFIX_TOKENS {
+            return Ok(0);
+        }
+        let prefix_ids = input_ids.narrow(1, 0, prefix_len)?;
+        let tokens = prefix_ids
+            .to_dtype(DType::I64)?
+            .flatten_all()?
+            .to_vec1::<i64>()?;
+        let key = PrefixKey::new(
+            prefix_cache.model_id,
+            self.dtype(),
+            prefix_cache.template,
+            &tokens,
+        )
+        .with_lora_adapters(&self.language.lora_adapters());
+
+        match prefix_cache.store.get(key) {
+            Ok(Some(snapshot)) => {
+                let restored = restore_snapshot(
+                    &snapshot,
+                    &tokens,
+                    cache.num_layers(),
+                    self.dtype(),
+                    self.device(),
+                );
+                match restored {
+                    Ok(restored) => {
+                        debug!("Prefix cache hit for {key} ({prefix_len} tokens)");
+                        // Snapshots carry no bound; keep the one the empty cache was created with.
+                        *cache = match cache.max_seq_len() {
+                            Some((max_seq_len, eviction)) => {
+                                restored.with_max_seq_len(max_seq_len, eviction)
+                            }
+                            None => restored,
+                        };
+                        return Ok(prefix_len);
+                    }
+                    Err(err) => warn!("Ignoring unusable prefix cache entry {key}: {err:#}"),
+                }
+            }
+            Ok(None) => debug!("Prefix cache miss for {key} ({prefix_len} tokens)"),
+            Err(err) => warn!("Prefix cache lookup for {key} failed: {err:#}"),
+        }
+
+        self.forward(
+            Some(&prefix_ids),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(cache),
+            true,
+        )?;
+        let stored = encode_snapshot(&tokens, cache)
+            .and_then(|snapshot| prefix_cache.store.put(key, snapshot));
+        if let Err(err) = stored {
+            warn!("Failed to store prefix cache entry {key}: {err:#}");
+        }
+        Ok(prefix_len)
+    }
+}
+
+/// Snapshot layout: token count, the prefix token ids (checked on restore, so a hash collision
+/// is a miss rather than a wrong cache), then the [`DynamicCache::to_snapshot`] bytes.
+fn encode_snapshot(tokens: &[i64], cache: &DynamicCache) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(4 + tokens.len() * 8);
+    out.extend_from_slice(&(tokens.len() as u32).to_le_bytes());
+    for token in tokens {
+        out.extend_from_slice(&token.to_le_bytes());
+    }
+    out.extend_from_slice(&cache.to_snapshot()?);
+    Ok(out)
+}
+
+fn restore_snapshot(
+    snapshot: &[u8],
+    tokens: &[i64],
+    num_layers: usize,
+    dtype: DType,
+    device: &Device,
+) -> Result<DynamicCache> {
+    let header = 4 + tokens.len() * 8;
+    ensure!(snapshot.len() >= header, "snapshot is truncated");
+    let stored_len = u32::from_le_bytes([snapshot[0], snapshot[1], snapshot[2], snapshot[3]]);
+    let matches = stored_len as usize == tokens.len()
+        && snapshot[4..header]
+            .chunks_exact(8)
+            .zip(tokens)
+            .all(|(bytes, token)| bytes == token.to_le_bytes());
+    ensure!(matches, "snapshot was stored for different prefix tokens");
+    let cache = DynamicCache::from_snapshot(&snapshot[header..], dtype, device)?;
+    ensure!(
+        cache.num_layers() == num_layers && cache.seq_len() == Some(tokens.len()),
+        "snapshot holds {} layers and {:?} positions, expected {num_layers} and {}",
+        cache.num_layers(),
+        cache.seq_len(),
+        tokens.len()
+    );
+    Ok(cache)
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, its output is fixed, so keys written by one build can
+/// be looked up by another.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Length-prefixed, so `("ab", "c")` and `("a", "bc")` hash differently.
+    fn write_str(&mut self, text: &str) {
+        self.write(&(text.len() as u64).to_le_bytes());
+        self.write(text.as_bytes());
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::{Context, Result, ensure};
+use candle_core::{D, DType, Tensor};
+use candle_nn::ops::log_softmax;
+
+use crate::{
+    benchmark::{Timer, sync},
+    transformer::cache::DynamicCache,
+};
+
+use super::{DeepseekOcrModel, GenerateOptions, TokenSelection};
+
+/// Decode state of one sequence, advanced one token at a time with
+/// [`DeepseekOcrModel::step`].
+///
+/// Holds the minimal state machine behind [`DeepseekOcrModel::generate`]: the KV cache, the token
+/// to feed next and the tokens produced so far. States are independent, so a custom scheduler can
+/// interleave steps across requests in any order.
+pub struct GenerationState {
+    cache: DynamicCache,
+    prompt_len: usize,
+    /// Token predicted by the last forward pass, not yet returned by a step.
+    next: SelectedToken,
+    /// Whether `next` is still waiting to be returned; otherwise the next step runs a forward.
+    ready: bool,
+    generated: Vec<i64>,
+    max_new_tokens: usize,
+    eos_token_id: Option<i64>,
+    selection: TokenSelection,
+    finished: bool,
+    /// Alternatives recorded per position, see [`GenerateOptions::logprobs`].
+    top_logprobs: Option<usize>,
+    logprobs: Vec<f32>,
+    top: Vec<Vec<(i64, f32)>>,
+}
+
+/// Tokens generated by [`DeepseekOcrModel::generate_with_logprobs`] with their
+/// log-probabilities.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeneratedTokens {
+    /// Generated tokens, excluding EOS.
+    pub tokens: Vec<i64>,
+    /// Natural-log probability of each of `tokens`, see [`StepOutput::logprob`].
+    pub logprobs: Vec<f32>,
+    /// For each of `tokens`, the [`GenerateOptions::logprobs`] most likely tokens at that position
+    /// with their log-probabilities, most likely first. Empty per position when no alternatives
+    /// were requested.
+    pub top_logprobs: Vec<Vec<(i64, f32)>>,
+}
+
+/// One token produced by [`DeepseekOcrModel::step`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepOutput {
+    pub token: i64,
+    /// Natural-log probability of `token` under the model's distribution for this position, with
+    /// banned ids and repetition controls applied. Only computed when
+    /// [`GenerateOptions::logprobs`] is set; `None` otherwise.
+    pub logprob: Option<f32>,
+    /// No further steps are possible: `token` is the EOS token (which is not appended to
+    /// [`GenerationState::generated`]) or the token budget is used up.
+    pub finished: bool,
+}
+
+impl GenerationState {
+    /// Number of prompt positions in the cache.
+    pub fn prompt_len(&self) -> usize {
+        self.prompt_len
+    }
+
+    /// Tokens produced so far, excluding EOS.
+    pub fn generated(&self) -> &[i64] {
+        &self.generated
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// KV cache holding the prompt and every generated token fed back so far.
+    pub fn cache(&self) -> &DynamicCache {
+        &self.cache
+    }
+
+    /// Log-probability of each of [`Self::generated`], recorded when
+    /// [`GenerateOptions::logprobs`] is set; empty otherwise.
+    pub fn logprobs(&self) -> &[f32] {
+        &self.logprobs
+    }
+
+    /// Most likely alternatives at each position of [`Self::generated`], most likely first,
+    /// recorded when [`GenerateOptions::logprobs`] is set; empty otherwise.
+    pub fn top_logprobs(&self) -> &[Vec<(i64, f32)>] {
+        &self.top
+    }
+
+    /// Move the generated tokens and recorded log-probabilities out of the state.
+    pub(super) fn take_tokens(&mut self) -> GeneratedTokens {
+        GeneratedTokens {
+            tokens: std::mem::take(&mut self.generated),
+            logprobs: std::mem::take(&mut self.logprobs),
+            top_logprobs: std::mem::take(&mut self.top),
+        }
+    }
+}
+
+impl Drop for GenerationState {
+    fn drop(&mut self) {
+        // Clearing keeps memlog's KV accounting in step with the tensors being dropped.
+        self.cache.clear();
+    }
+}
+
+impl DeepseekOcrModel {
+    /// Run the prompt forward pass for one sequence and return a state ready for
+    /// [`Self::step`]. Uses the prompt-related fields of `options` plus `max_new_tokens`,
+    /// `eos_token_id`, `logprobs` and the token selection fields (`banned_token_ids`, `sampler`,
+    /// `repetition_penalty`, `no_repeat_ngram_size`); callbacks are left to the caller.
+    pub fn prepare_generation(
+        &self,
+        input_ids: &Tensor,
+        options: &GenerateOptions<'_>,
+    ) -> Result<GenerationState> {
+        ensure!(
+            input_ids.rank() == 2,
+            "prepare_generation expects input_ids with shape [batch, seq]"
+        );
+        let (batch, seq_len) = input_ids.shape().dims2()?;
+        ensure!(
+            batch == 1,
+            "prepare_generation expects a single sequence (got batch {batch})"
+        );
+        ensure!(seq_len > 0, "prepare_generation requires a prompt token");
+        let mut cache = self.new_cache();
+        sync(self.device());
+        let prefill_timer = Timer::new("decode.prefill");
+        let last_logits = match self.prefill_cache(input_ids, options, &mut cache) {
+            Ok(last_logits) => last_logits,
+            Err(err) => {
+                drop(self.prompt_guard(&mut cache));
+                return Err(err);
+            }
+        };
+        sync(self.device());
+        prefill_timer.finish(|event| {
+            event.add_field("prompt_tokens", seq_len as u64);
+            event.add_field("has_image_mask", options.images_seq_mask.is_some());
+            event.add_field("use_cache", true);
+        });
+        let selection = TokenSelection::from_options(options);
+        let record = options.logprobs.is_some();
+        Ok(GenerationState {
+            cache,
+            prompt_len: seq_len,
+            next: SelectedToken::select(&selection, &last_logits, &[], options.logprobs)?,
+            ready: true,
+            generated: Vec::with_capacity(options.max_new_tokens),
+            max_new_tokens: options.max_new_tokens,
+            eos_token_id: options.eos_token_id,
+            selection,
+            finished: options.max_new_tokens == 0,
+            top_logprobs: options.logprobs,
+            logprobs: Vec::with_capacity(if record { options.max_new_tokens } else { 0 }),
+            top: Vec::new(),
+        })
+    }
+
+    /// Produce the next token of `state`. The first call returns the token predicted by the
+    /// prefill; every later call runs one decode forward pass. Fails once the state is finished.
+    pub fn step(&self, state: &mut GenerationState) -> Result<StepOutput> {
+        ensure!(!state.finished, "generation already finished");
+        if !state.ready {
+            let token_index = usize::try_from(state.next.token)
+                .context("token id out of range while preparing decode embedding")?;
+            let decode_inputs = self
+                .language
+                .token_embedding_for_id(token_index)
+                .context("failed to gather embedding for decode token")?
+                .unsqueeze(0)?
+                .unsqueeze(0)?;
+            let decode = self.forward(
+                None,
+                Some(&decode_inputs),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(&mut state.cache),
+                true,
+            )?;
+            let next_logits = decode
+                .logits
+                .get(0)
+                .context("decode logits missing batch dimension")?
+                .get(0)
+                .context("decode logits missing timestep")?;
+            state.next = SelectedToken::select(
+                &state.selection,
+                &next_logits,
+                &state.generated,
+                state.top_logprobs,
+            )?;
+        }
+        let SelectedToken { token, logprob, .. } = state.next;
+        state.ready = false;
+        if state.eos_token_id == Some(token) {
+            state.finished = true;
+        } else {
+            state.generated.push(token);
+            if let Some(logprob) = logprob {
+                state.logprobs.push(logprob);
+                state.top.push(std::mem::take(&mut state.next.top));
+            }
+            state.finished = state.generated.len() >= state.max_new_tokens;
+        }
+        Ok(StepOutput {
+            token,
+            logprob,
+            finished: state.finished,
+        })
+    }
+
+    /// Step `state` until EOS, the budget in `options` runs out or `extend_while` declines to
+    /// extend it, reporting progress after each token. `first` is the already-taken first step.
+    pub(super) fn decode_steps(
+        &self,
+        state: &mut GenerationState,
+        first: StepOutput,
+        options: &GenerateOptions<'_>,
+    ) -> Result<()> {
+        let mut output = first;
+        loop {
+            let generated = state.generated();
+            if let Some(cb) = options.progress_callback {
+                cb(generated.len(), generated);
+            }
+            if options.stop_when.is_some_and(|stop| stop(generated)) {
+                return Ok(());
+            }
+            if output.finished
+                || (generated.len() >= options.max_new_tokens
+                    && !options.extend_while.is_some_and(|extend| extend(generated)))
+            {
+                return Ok(());
+            }
+            output = self.step(state)?;
+            if state.eos_token_id == Some(output.token) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Clear `state`'s cache and drop prompt-scoped decoder state such as RoPE tables.
+    pub(super) fn end_generation(&self, state: &mut GenerationState) {
+        drop(self.prompt_guard(&mut state.cache));
+    }
+}
+
+/// A token chosen from one position's logits, with its log-probability and the most likely
+/// alternatives when requested.
+struct SelectedToken {
+    token: i64,
+    logprob: Option<f32>,
+    top: Vec<(i64, f32)>,
+}
+
+impl SelectedToken {
+    fn select(
+        selection: &TokenSelection,
+        logits: &Tensor,
+        generated: &[i64],
+        top_logprobs: Option<usize>,
+    ) -> Result<Self> {
+        let logits = selection.process(logits, generated)?;
+        let token = selection.pick(&logits)?;
+        let Some(k) = top_logprobs else {
+            // Nobody asked for logprobs: skip the softmax and the host read.
+            return Ok(Self {
+                token,
+                logprob: None,
+                top: Vec::new(),
+            });
+        };
+        let token_index = usize::try_from(token).context("token index out of range")?;
+        let log_probs = log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+        if k == 0 {
+            let logprob = log_probs
+                .get(token_index)?
+                .to_scalar::<f32>()
+                .context("failed to read token logprob")?;
+            return Ok(Self {
+                token,
+                logprob: Some(logprob),
+                top: Vec::new(),
+            });
+        }
+        let log_probs = log_probs
+            .to_vec1::<f32>()
+            .context("failed to read token logprobs")?;
+        let logprob = *log_probs
+            .get(token_index)
+            .context("token index out of range")?;
+        Ok(Self {
+            token,
+            logprob: Some(logprob),
+            top: top_k_logprobs(&log_probs, k),
+        })
+    }
+}
+
+/// The `k` most likely tokens in `log_probs`, most likely first. Banned (`-inf`) tokens are
+/// never reported.
+fn top_k_logprobs(log_probs: &[f32], k: usize) -> Vec<(i64, f32)> {
+    let mut top: Vec<(i64, f32)> = log_probs
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| value.is_finite())
+        .map(|(token, &value)| (token as i64, value))
+        .collect();
+    if top.len() > k {
+        top.select_nth_unstable_by(k, |a, b| b.1.total_cmp(&a.1));
+        top.truncate(k);
+    }
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    top
+}
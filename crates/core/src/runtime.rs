@@ -1,4 +1,4 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, ensure};
 use candle_core::{DType, Device};
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
@@ -9,6 +9,9 @@ pub enum DeviceKind {
     Cpu,
     Metal,
     Cuda,
+    /// The GPU backend this build supports (CUDA, else Metal), falling back to the CPU with a
+    /// warning when it fails to initialise. Use `metal`/`cuda` to make GPU failures fatal.
+    Auto,
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize)]
@@ -19,59 +22,229 @@ pub enum Precision {
     Bf16,
 }
 
+/// Where image tensors are built before being handed to the vision towers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreprocessDevice {
+    /// Build each tensor directly on the compute device.
+    #[default]
+    Compute,
+    /// Build on the CPU (tiles in parallel), then move each finished tensor to the compute device
+    /// in a single transfer.
+    Cpu,
+}
+
+/// Which memory figure `gpu_memory_utilization` is a fraction of.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UtilizationOf {
+    /// The device's whole memory, regardless of what other processes hold.
+    Total,
+    /// Memory still free when the model loads, so a shared GPU is not oversubscribed.
+    #[default]
+    Free,
+}
+
+/// Memory of a compute device as reported by its driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceMemory {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+}
+
+impl DeviceMemory {
+    /// Bytes a load may use: `utilization` of total or free memory.
+    pub fn budget(&self, utilization: f32, of: UtilizationOf) -> u64 {
+        let base = match of {
+            UtilizationOf::Total => self.total_bytes,
+            UtilizationOf::Free => self.free_bytes,
+        };
+        (base as f64 * f64::from(utilization.clamp(0.0, 1.0))) as u64
+    }
+}
+
+/// Query total and free memory of a device. The CPU reports system RAM, with available memory as
+/// free. Returns `None` where the figures cannot be read, including backends compiled out of this
+/// build.
+pub fn device_memory(device: &Device) -> Option<DeviceMemory> {
+    #[cfg(feature = "cuda")]
+    if let Device::Cuda(cuda) = device {
+        use candle_core::cuda_backend::cudarc::driver::result;
+        cuda.cuda_stream().context().bind_to_thread().ok()?;
+        let (free, total) = result::mem_get_info().ok()?;
+        return Some(DeviceMemory {
+            total_bytes: total as u64,
+            free_bytes: free as u64,
+        });
+    }
+    #[cfg(feature = "metal")]
+    if let Device::Metal(metal) = device {
+        // Metal exposes a recommended working-set size rather than physical VRAM.
+        let total = metal.device().recommended_max_working_set_size();
+        let used = metal.device().current_allocated_size();
+        return Some(DeviceMemory {
+            total_bytes: total,
+            free_bytes: total.saturating_sub(used),
+        });
+    }
+    if device.is_cpu() {
+        return system_memory();
+    }
+    None
+}
+
+/// Total and available system RAM from `/proc/meminfo`. `None` on platforms without it.
+pub fn system_memory() -> Option<DeviceMemory> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|rest| {
+                rest.trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .map(|kib| kib * 1024)
+    };
+    Some(DeviceMemory {
+        total_bytes: field("MemTotal")?,
+        free_bytes: field("MemAvailable")?,
+    })
+}
+
+/// A compute device ready for loading a model, with the memory budget granted by
+/// `gpu_memory_utilization`.
+#[derive(Debug, Clone)]
+pub struct DevicePlan {
+    pub device: Device,
+    /// Requested precision, or the device's preferred one; `None` leaves the choice to the caller.
+    pub dtype: Option<DType>,
+    /// Device memory (system RAM for the CPU), when it can be queried.
+    pub memory: Option<DeviceMemory>,
+    /// Bytes the model may use in total: weights, activations and KV cache. `None` when no
+    /// utilization was requested or the memory is not queryable.
+    pub memory_budget: Option<u64>,
+}
+
+impl DevicePlan {
+    /// Bytes of the budget left for the KV cache once `resident_bytes` (weights and activation
+    /// headroom) are set aside.
+    pub fn kv_cache_bytes(&self, resident_bytes: u64) -> Option<u64> {
+        self.memory_budget
+            .map(|budget| budget.saturating_sub(resident_bytes))
+    }
+}
+
 pub fn prepare_device_and_dtype(
     device: DeviceKind,
+    device_index: usize,
     precision: Option<Precision>,
 ) -> Result<(Device, Option<DType>)> {
-    prepare_device_and_dtype_with_options(device, precision, None, None)
+    let plan = prepare_device_and_dtype_with_options(
+        device,
+        device_index,
+        precision,
+        None,
+        UtilizationOf::default(),
+        None,
+    )?;
+    Ok((plan.device, plan.dtype))
 }
 
+/// Initialise `device` (GPU number `device_index` for CUDA and Metal; ignored on the CPU) and
+/// work out the memory budget for `gpu_memory_utilization` of its total or free memory.
 pub fn prepare_device_and_dtype_with_options(
     device: DeviceKind,
+    device_index: usize,
     precision: Option<Precision>,
     gpu_memory_utilization: Option<f32>,
+    utilization_of: UtilizationOf,
     max_num_seqs: Option<usize>,
-) -> Result<(Device, Option<DType>)> {
-    // Validate GPU memory utilization if provided
+) -> Result<DevicePlan> {
     if let Some(utilization) = gpu_memory_utilization {
-        if !(0.0..=1.0).contains(&utilization) {
-            bail!("GPU memory utilization must be between 0.0 and 1.0, got {}", utilization);
-        }
+        ensure!(
+            (0.0..=1.0).contains(&utilization),
+            "GPU memory utilization must be between 0.0 and 1.0, got {utilization}"
+        );
     }
-    
-    // Validate max_num_seqs if provided
+    ensure!(
+        max_num_seqs != Some(0),
+        "Maximum number of sequences must be greater than 0"
+    );
+
+    let (device, default_precision) = init_device(device, device_index)?;
+
+    let memory = device_memory(&device);
+    let memory_budget = gpu_memory_utilization
+        .zip(memory)
+        .map(|(utilization, memory)| memory.budget(utilization, utilization_of));
     if let Some(max_seqs) = max_num_seqs {
-        if max_seqs == 0 {
-            bail!("Maximum number of sequences must be greater than 0");
-        }
+        tracing::info!("Maximum concurrent sequences set to: {}", max_seqs);
     }
-    
-    let (device, default_precision) = match device {
+
+    let dtype = precision.or(default_precision).map(dtype_from_precision);
+    Ok(DevicePlan {
+        device,
+        dtype,
+        memory,
+        memory_budget,
+    })
+}
+
+/// Seed the RNG behind candle's random tensor ops (`rand`, `randn`) on `device`. The CPU backend
+/// draws from a thread-local RNG that cannot be seeded, and inference never uses it, so the CPU is
+/// left as is.
+pub fn seed_device(device: &Device, seed: u64) -> Result<()> {
+    if device.is_cpu() {
+        return Ok(());
+    }
+    device
+        .set_seed(seed)
+        .with_context(|| format!("failed to seed {device:?}"))
+}
+
+/// Backend [`DeviceKind::Auto`] tries first: CUDA or Metal when this build supports one, else
+/// the CPU.
+pub fn auto_device_kind() -> DeviceKind {
+    if candle_core::utils::cuda_is_available() {
+        DeviceKind::Cuda
+    } else if candle_core::utils::metal_is_available() {
+        DeviceKind::Metal
+    } else {
+        DeviceKind::Cpu
+    }
+}
+
+/// Create the device for `kind` (GPU number `index`) along with its preferred precision.
+fn init_device(kind: DeviceKind, index: usize) -> Result<(Device, Option<Precision>)> {
+    Ok(match kind {
         DeviceKind::Cpu => (Device::Cpu, None),
         DeviceKind::Metal => (
-            Device::new_metal(0).context("failed to initialise Metal device")?,
+            Device::new_metal(index)
+                .with_context(|| format!("failed to initialise Metal device {index}"))?,
             Some(Precision::F16),
         ),
         DeviceKind::Cuda => (
-            Device::new_cuda(0).context("failed to initialise CUDA device")?,
+            Device::new_cuda(index)
+                .with_context(|| format!("failed to initialise CUDA device {index}"))?,
             Some(Precision::F16),
         ),
-    };
-    
-    // Log the GPU configuration options if provided
-    if let Some(utilization) = gpu_memory_utilization {
-        tracing::info!("GPU memory utilization set to: {:.2}%", utilization * 100.0);
-    }
-    if let Some(max_seqs) = max_num_seqs {
-        tracing::info!("Maximum concurrent sequences set to: {}", max_seqs);
-    }
-    
-    // TODO: Implement actual GPU memory utilization and sequence limiting
-    // This would require integration with candle_core's memory management
-    // and the inference pipeline's concurrency control
-    
-    let dtype = precision.or(default_precision).map(dtype_from_precision);
-    Ok((device, dtype))
+        DeviceKind::Auto => {
+            let preferred = auto_device_kind();
+            match init_device(preferred, index) {
+                Ok(initialised) => initialised,
+                Err(err) => {
+                    tracing::warn!(
+                        "{preferred:?} device unavailable, falling back to CPU: {err:#}"
+                    );
+                    (Device::Cpu, None)
+                }
+            }
+        }
+    })
 }
 
 pub fn default_dtype_for_device(device: &Device) -> DType {
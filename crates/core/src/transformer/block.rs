@@ -1,4 +1,7 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use crate::{
+    benchmark::{BenchField, record_instant},
     config::DeepseekV2Config,
     transformer::{
         cache::{KvCacheChunk, KvCacheEntry},
@@ -9,7 +12,7 @@ use crate::{
     },
 };
 use anyhow::{Context, Result, bail, ensure};
-use candle_core::{DType, Device, Tensor, shape::D};
+use candle_core::{DType, Device, Module, Tensor, shape::D};
 #[cfg(feature = "flash-attn")]
 use candle_flash_attn::flash_attn;
 use candle_nn::ops::{rms_norm, sigmoid, softmax};
@@ -21,6 +24,15 @@ pub struct TransformerBlock<'a> {
     pub cfg: &'a DeepseekV2Config,
     pub weights: &'a TransformerBlockWeights,
     use_flash_attention: bool,
+    flash_nan_check: bool,
+    aux_loss: bool,
+}
+
+static FLASH_NAN_FALLBACKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of attention forwards re-run eagerly because flash attention produced NaNs.
+pub fn flash_nan_fallback_count() -> u64 {
+    FLASH_NAN_FALLBACKS.load(Ordering::Relaxed)
 }
 
 pub struct BlockOutput {
@@ -44,9 +56,25 @@ impl<'a> TransformerBlock<'a> {
             cfg,
             weights,
             use_flash_attention,
+            flash_nan_check: false,
+            aux_loss: false,
         }
     }
 
+    /// Scan flash-attention outputs for NaNs and recompute them with eager attention when found.
+    /// Costs a device sync per layer, so it is off by default.
+    pub fn with_flash_nan_check(mut self, enabled: bool) -> Self {
+        self.flash_nan_check = enabled;
+        self
+    }
+
+    /// Compute the MoE load-balancing loss in [`BlockOutput::aux_loss`]. Copies the router scores
+    /// to the host once per MoE layer, so it is off by default; dense layers never report one.
+    pub fn with_aux_loss(mut self, enabled: bool) -> Self {
+        self.aux_loss = enabled;
+        self
+    }
+
     /// Forward pass for a single transformer block.
     ///
     /// * `hidden_states` – shape `[batch, seq, hidden]`
@@ -67,17 +95,24 @@ impl<'a> TransformerBlock<'a> {
         )
         .context("input rms norm failed")?;
 
-        let (attn_out, present_cache) = attention_forward(
-            &normed,
-            &self.weights.attention,
-            self.cfg,
-            additive_attn_bias,
-            rope,
-            past_key_value,
-            use_cache,
-            self.use_flash_attention,
-        )
-        .context("attention forward failed")?;
+        let attention = |use_flash_attention| {
+            attention_forward(
+                &normed,
+                &self.weights.attention,
+                self.cfg,
+                additive_attn_bias,
+                rope,
+                past_key_value,
+                use_cache,
+                use_flash_attention,
+            )
+            .context("attention forward failed")
+        };
+        let mut output = attention(self.use_flash_attention)?;
+        if self.use_flash_attention && self.flash_nan_check {
+            output = rerun_on_nan(output, || attention(false))?;
+        }
+        let (attn_out, present_cache) = output;
         let hidden_states = residual
             .add(&attn_out)
             .context("residual add (attention)")?;
@@ -92,7 +127,8 @@ impl<'a> TransformerBlock<'a> {
         let MlpForwardOutput {
             hidden_states: mlp_hidden,
             aux_loss,
-        } = mlp_forward(&normed, &self.weights.mlp, self.cfg).context("mlp forward failed")?;
+        } = mlp_forward(&normed, &self.weights.mlp, self.cfg, self.aux_loss)
+            .context("mlp forward failed")?;
 
         let output = residual.add(&mlp_hidden).context("residual add (mlp)")?;
         let present = if use_cache { present_cache } else { None };
@@ -102,6 +138,65 @@ impl<'a> TransformerBlock<'a> {
             aux_loss,
         })
     }
+
+    /// Run only the attention half to produce this layer's key/value entry for `hidden_states`,
+    /// skipping the MLP. Used by early exit to keep the cache of skipped layers in step.
+    pub fn present_key_value(
+        &self,
+        hidden_states: &Tensor,
+        additive_attn_bias: Option<&Tensor>,
+        rope: Option<(&Tensor, &Tensor)>,
+        past_key_value: Option<&KvCacheEntry>,
+    ) -> Result<Option<KvCacheChunk>> {
+        let normed = rms_norm(
+            hidden_states,
+            &self.weights.input_layernorm.weight,
+            self.cfg.rms_norm_eps,
+        )
+        .context("input rms norm failed")?;
+        let (_, present) = attention_forward(
+            &normed,
+            &self.weights.attention,
+            self.cfg,
+            additive_attn_bias,
+            rope,
+            past_key_value,
+            true,
+            self.use_flash_attention,
+        )
+        .context("attention forward failed")?;
+        Ok(present)
+    }
+}
+
+/// Whether any element of `tensor` is NaN.
+pub fn contains_nan(tensor: &Tensor) -> Result<bool> {
+    // NaN is the only value that compares unequal to itself.
+    Ok(tensor.ne(tensor)?.max_all()?.to_scalar::<u8>()? != 0)
+}
+
+/// Keep a flash-attention `output` (`[batch, seq, hidden]` plus its cache entry) unless it holds
+/// NaNs; then count the fallback in [`flash_nan_fallback_count`] and return `eager()` instead.
+pub fn rerun_on_nan<T>(
+    output: (Tensor, T),
+    eager: impl FnOnce() -> Result<(Tensor, T)>,
+) -> Result<(Tensor, T)> {
+    if !contains_nan(&output.0)? {
+        return Ok(output);
+    }
+    let fallbacks = FLASH_NAN_FALLBACKS.fetch_add(1, Ordering::Relaxed) + 1;
+    tracing::warn!(
+        "flash attention produced NaNs (seq_len={}); re-running with eager attention",
+        output.0.dim(1)?
+    );
+    record_instant(
+        "attention.flash_nan_fallback",
+        [BenchField {
+            key: "fallbacks_total",
+            value: fallbacks.into(),
+        }],
+    );
+    eager()
 }
 
 fn attention_forward(
@@ -512,10 +607,11 @@ fn mlp_forward(
     hidden_states: &Tensor,
     weights: &MlpWeights,
     cfg: &DeepseekV2Config,
+    aux_loss: bool,
 ) -> Result<MlpForwardOutput> {
     match weights {
         MlpWeights::Dense(dense) => run_dense_mlp(hidden_states, dense, cfg),
-        MlpWeights::Moe(moe) => run_moe(hidden_states, moe, cfg),
+        MlpWeights::Moe(moe) => run_moe(hidden_states, moe, cfg, aux_loss),
     }
 }
 
@@ -540,12 +636,23 @@ fn apply_linear(input: &Tensor, weights: &LinearWeights) -> Result<Tensor> {
 
     let leading = dims[..dims.len() - 1].iter().product::<usize>();
     let input2d = input.reshape((leading, in_dim))?;
-    let proj = input2d.matmul(&transpose(&weights.weight, 0, 1)?)?;
-    let proj = if let Some(bias) = &weights.bias {
+    let proj = match &weights.qmatmul {
+        // Quantized kernels only accept contiguous f32 activations.
+        Some(qmatmul) => qmatmul
+            .forward(&input2d.to_dtype(DType::F32)?.contiguous()?)?
+            .to_dtype(input.dtype())?,
+        None => input2d.matmul(&transpose(&weights.weight, 0, 1)?)?,
+    };
+    let mut proj = if let Some(bias) = &weights.bias {
         proj.broadcast_add(&bias.reshape((1, out_dim))?)?
     } else {
         proj
     };
+    for delta in &weights.lora {
+        if let Some(update) = delta.forward(&input2d)? {
+            proj = (proj + update)?;
+        }
+    }
     proj.reshape(
         dims[..dims.len() - 1]
             .iter()
@@ -606,6 +713,7 @@ fn run_moe(
     hidden_states: &Tensor,
     weights: &MoeWeights,
     cfg: &DeepseekV2Config,
+    compute_aux_loss: bool,
 ) -> Result<MlpForwardOutput> {
     let n_routed = cfg
         .n_routed_experts
@@ -678,6 +786,14 @@ fn run_moe(
     let weights_tensor = topk_weights.contiguous()?;
     let assignments = assignments_tensor.to_vec2::<i64>()?;
     let weight_vectors = weights_tensor.to_vec2::<f32>()?;
+    let aux_loss = if compute_aux_loss {
+        let group = if cfg.seq_aux { seq_len } else { token_count };
+        let loss = load_balancing_loss(&scores.to_vec2::<f32>()?, &assignments, n_routed, group)
+            * cfg.aux_loss_alpha;
+        Some(Tensor::new(loss, hidden_states.device())?)
+    } else {
+        None
+    };
     let mut expert_routes: Vec<Vec<(usize, f32)>> = vec![Vec::new(); n_routed];
     for (token_idx, (experts_row, weights_row)) in
         assignments.iter().zip(weight_vectors.iter()).enumerate()
@@ -730,10 +846,50 @@ fn run_moe(
 
     Ok(MlpForwardOutput {
         hidden_states: combined,
-        aux_loss: None,
+        aux_loss,
     })
 }
 
+/// DeepSeek-V2's expert load-balancing loss before scaling by `aux_loss_alpha`:
+/// `Σ_i f_i · P_i`, where `f_i` is expert `i`'s share of the top-k picks times the expert count
+/// and `P_i` its mean routing score. Tokens are scored in consecutive groups of `group` (one
+/// sequence when `seq_aux` is set) and the group losses averaged; 1.0 means perfectly balanced.
+pub fn load_balancing_loss(
+    scores: &[Vec<f32>],
+    assignments: &[Vec<i64>],
+    n_routed: usize,
+    group: usize,
+) -> f32 {
+    let group = group.max(1);
+    let mut total = 0.0f32;
+    let mut groups = 0usize;
+    for (scores, assignments) in scores.chunks(group).zip(assignments.chunks(group)) {
+        let mut picks = vec![0usize; n_routed];
+        let mut mean_scores = vec![0.0f32; n_routed];
+        for (row_scores, row_experts) in scores.iter().zip(assignments) {
+            for &expert in row_experts {
+                picks[expert as usize] += 1;
+            }
+            for (mean, &score) in mean_scores.iter_mut().zip(row_scores) {
+                *mean += score;
+            }
+        }
+        let total_picks = picks.iter().sum::<usize>().max(1) as f32;
+        let rows = scores.len() as f32;
+        total += picks
+            .iter()
+            .zip(&mean_scores)
+            .map(|(&count, &score)| count as f32 * n_routed as f32 / total_picks * score / rows)
+            .sum::<f32>();
+        groups += 1;
+    }
+    if groups == 0 {
+        0.0
+    } else {
+        total / groups as f32
+    }
+}
+
 fn transpose(t: &Tensor, dim0: usize, dim1: usize) -> Result<Tensor> {
     let mut dims: Vec<usize> = (0..t.rank()).collect();
     dims.swap(dim0, dim1);
@@ -783,6 +939,61 @@ pub fn lengths_to_padding_mask(
     Ok(Tensor::from_vec(data, (batch, seq_len), device)?)
 }
 
+/// Additive attention mask `[batch, 1, seq_len, past_len + seq_len]` for `seq_len` new tokens
+/// after `past_len` cached ones, in the form [`TransformerBlock::forward`] takes: `0` where a
+/// query may attend and a large negative value elsewhere.
+///
+/// Every query sees the keys up to its own position. `padding` gives each row's number of
+/// left-padding positions (counted from the start of the cache), which no query attends to;
+/// batched prompts padded on the left keep their last token aligned. The decoder's own
+/// `attention_mask` takes the `[batch, past_len + seq_len]` keep-mask instead and builds this
+/// internally.
+pub fn build_causal_mask(
+    batch: usize,
+    seq_len: usize,
+    past_len: usize,
+    padding: Option<&[usize]>,
+    device: &Device,
+    dtype: DType,
+) -> Result<Tensor> {
+    ensure!(seq_len > 0, "causal mask needs at least one query");
+    let k_len = past_len + seq_len;
+    let pad_mask = match padding {
+        Some(padding) => {
+            ensure!(
+                padding.len() == batch,
+                "padding has {} rows but the batch has {batch}",
+                padding.len()
+            );
+            let mut keep = Vec::with_capacity(batch * k_len);
+            for &pad in padding {
+                ensure!(
+                    pad < k_len,
+                    "left padding {pad} leaves no key out of {k_len} to attend to"
+                );
+                keep.extend((0..k_len).map(|col| if col < pad { 0f32 } else { 1f32 }));
+            }
+            Some(Tensor::from_vec(keep, (batch, k_len), device)?)
+        }
+        None => None,
+    };
+    let bias = build_attention_bias(
+        pad_mask.as_ref(),
+        batch,
+        seq_len,
+        k_len,
+        past_len,
+        dtype,
+        device,
+    )?;
+    match bias {
+        Some(bias) => Ok(bias
+            .broadcast_as((batch, 1, seq_len, k_len))?
+            .contiguous()?),
+        None => Ok(Tensor::zeros((batch, 1, seq_len, k_len), dtype, device)?),
+    }
+}
+
 fn mask_fill_value(dtype: DType) -> f32 {
     match dtype {
         DType::F16 | DType::BF16 => -1e4f32,
@@ -790,6 +1001,9 @@ fn mask_fill_value(dtype: DType) -> f32 {
     }
 }
 
+/// Additive attention bias for `q_len` queries at positions `past_len..past_len + q_len` over
+/// `k_len` keys: causal whenever more than one query is fed (including a prompt chunk appended to
+/// a cache), plus the padding mask when given.
 pub fn build_attention_bias(
     pad_mask: Option<&Tensor>,
     batch: usize,
@@ -801,8 +1015,12 @@ pub fn build_attention_bias(
 ) -> Result<Option<Tensor>> {
     let mut bias: Option<Tensor> = None;
 
-    if past_len == 0 && q_len == k_len && q_len > 1 {
-        let rows = Tensor::arange(0i64, q_len as i64, device)?.reshape((q_len, 1))?;
+    if q_len > 1 {
+        ensure!(
+            past_len + q_len == k_len,
+            "{q_len} queries after {past_len} cached positions do not match key length {k_len}"
+        );
+        let rows = Tensor::arange(past_len as i64, k_len as i64, device)?.reshape((q_len, 1))?;
         let cols = Tensor::arange(0i64, k_len as i64, device)?.reshape((1, k_len))?;
         let mask = cols.broadcast_gt(&rows)?;
         let mask = mask.to_dtype(dtype)?;
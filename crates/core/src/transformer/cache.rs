@@ -1,6 +1,7 @@
-use anyhow::{Context, Result, ensure};
-use candle_core::{DType, Tensor, shape::D};
-use std::boxed::Box;
+use anyhow::{Context, Result, anyhow, ensure};
+use candle_core::{DType, Device, Tensor, shape::D};
+use serde::{Deserialize, Serialize};
+use std::{boxed::Box, fmt, str::FromStr};
 
 #[cfg(feature = "memlog")]
 use crate::memlog;
@@ -233,6 +234,27 @@ impl KvCacheEntry {
         Ok(())
     }
 
+    /// Remove `count` cached positions starting at `start`, moving later positions down. The
+    /// allocated capacity is kept.
+    ///
+    /// The buffers are rebuilt rather than shifted in place, since clones of the cache share
+    /// them.
+    pub fn remove_positions(&mut self, start: usize, count: usize) -> Result<()> {
+        ensure!(
+            start + count <= self.len,
+            "cannot remove positions {start}..{} from a cache holding {}",
+            start + count,
+            self.len
+        );
+        if count == 0 {
+            return Ok(());
+        }
+        self.key_t = remove_range(&self.key_t, 3, start, count)?;
+        self.value = remove_range(&self.value, 2, start, count)?;
+        self.len -= count;
+        Ok(())
+    }
+
     pub fn key_view(&self) -> Result<Tensor> {
         Ok(self
             .key_t
@@ -249,9 +271,9 @@ impl KvCacheEntry {
         self.len
     }
 
-    #[cfg(feature = "memlog")]
+    /// Bytes allocated for this layer's keys and values, growth headroom included.
     pub fn storage_bytes(&self) -> usize {
-        memlog::tensor_bytes(&self.key_t) + memlog::tensor_bytes(&self.value)
+        (self.key_t.elem_count() + self.value.elem_count()) * self.key_t.dtype().size_in_bytes()
     }
 }
 
@@ -354,11 +376,104 @@ impl LayerKvCache {
     }
 }
 
+/// Which positions a [`DynamicCache`] bounded by [`DynamicCache::with_max_seq_len`] keeps once
+/// it grows past the limit.
+///
+/// Written as `drop-oldest` or `sink:N` in config files and on the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheEviction {
+    /// Keep the most recent positions.
+    #[default]
+    DropOldest,
+    /// Keep the first `sink` positions, which soak up a large share of attention in long
+    /// sequences, plus the most recent ones.
+    AttentionSink { sink: usize },
+}
+
+impl CacheEviction {
+    /// Positions at the start of the cache that are never evicted.
+    pub fn kept_head(self) -> usize {
+        match self {
+            Self::DropOldest => 0,
+            Self::AttentionSink { sink } => sink,
+        }
+    }
+}
+
+impl FromStr for CacheEviction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("drop-oldest") {
+            return Ok(Self::DropOldest);
+        }
+        s.strip_prefix("sink:")
+            .and_then(|sink| sink.trim().parse().ok())
+            .map(|sink| Self::AttentionSink { sink })
+            .ok_or_else(|| anyhow!("expected `drop-oldest` or `sink:N`, got `{s}`"))
+    }
+}
+
+impl fmt::Display for CacheEviction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DropOldest => f.write_str("drop-oldest"),
+            Self::AttentionSink { sink } => write!(f, "sink:{sink}"),
+        }
+    }
+}
+
+impl Serialize for CacheEviction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CacheEviction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 /// Dynamic cache that can grow across decoding steps.
+///
+/// By default positions are never evicted. With [`Self::with_max_seq_len`] the decoder trims the
+/// cache below the limit, a block at a time (see [`Self::with_compact_stride`]), after a forward
+/// pass that goes over it. Keys are cached after RoPE has been applied,
+/// so the kept positions stay valid, and new tokens continue from [`Self::next_position`] rather
+/// than from the cached length.
 #[derive(Debug, Clone, Default)]
 pub struct DynamicCache {
     layers: LayerKvCache,
     seq_len: Option<usize>,
+    max_seq_len: Option<(usize, CacheEviction)>,
+    compact_stride: Option<usize>,
+    /// Positions evicted since the last clear.
+    evicted: usize,
+    appended_positions: u64,
+    evicted_positions: u64,
+    clears: u64,
+}
+
+/// Snapshot of a [`DynamicCache`], returned by [`DynamicCache::stats`].
+///
+/// The counters are plain integers updated on append/clear, so collecting them is always on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Positions currently cached.
+    pub seq_len: usize,
+    /// Layers holding cached positions.
+    pub populated_layers: usize,
+    /// Bytes allocated for keys and values across layers, growth headroom included.
+    pub bytes: usize,
+    /// Positions appended over the cache's lifetime, across clears.
+    pub appended_positions: u64,
+    /// Positions evicted by [`DynamicCache::with_max_seq_len`] over the cache's lifetime.
+    pub evicted_positions: u64,
+    /// Times the cache was cleared while holding positions.
+    pub clears: u64,
 }
 
 /// Clears a [`DynamicCache`] when dropped, ensuring prompt-scoped state cannot leak. Optionally
@@ -408,8 +523,50 @@ impl DynamicCache {
     pub fn with_num_layers(num_layers: usize) -> Self {
         Self {
             layers: LayerKvCache::with_num_layers(num_layers),
-            seq_len: None,
+            ..Self::default()
+        }
+    }
+
+    /// A copy that shares the cached keys and values instead of duplicating them, e.g. one per
+    /// beam branching off a common prefix.
+    ///
+    /// The fork's buffers hold no growth headroom, so its first append copies them before
+    /// writing; the original keeps appending into its own headroom, which the fork never reads.
+    pub fn fork(&self) -> Result<Self> {
+        let mut fork = self.clone();
+        for entry in fork.layers.entries_mut().iter_mut().flatten() {
+            entry.key_t = entry.key_view()?;
+            entry.value = entry.value_view()?;
         }
+        Ok(fork)
+    }
+
+    /// Bound the cache to `max_seq_len` positions, evicting by `eviction` once a forward pass
+    /// leaves more than that cached. Evictions happen in blocks (half the window outside the
+    /// attention sink unless [`Self::with_compact_stride`] says otherwise), so the buffers are
+    /// rebuilt once per block rather than on every token.
+    pub fn with_max_seq_len(mut self, max_seq_len: usize, eviction: CacheEviction) -> Self {
+        self.max_seq_len = Some((max_seq_len, eviction));
+        self
+    }
+
+    /// The bound and eviction policy set with [`Self::with_max_seq_len`], if any.
+    pub fn max_seq_len(&self) -> Option<(usize, CacheEviction)> {
+        self.max_seq_len
+    }
+
+    /// Leave `stride` positions free below the bound after each eviction, so the cache is
+    /// rebuilt once every `stride + 1` tokens. Larger strides rebuild less often but keep fewer
+    /// recent positions right after an eviction. Must stay below the window outside the
+    /// attention sink; checked when the bound is enforced.
+    pub fn with_compact_stride(mut self, stride: usize) -> Self {
+        self.compact_stride = Some(stride);
+        self
+    }
+
+    /// The stride set with [`Self::with_compact_stride`], if any.
+    pub fn compact_stride(&self) -> Option<usize> {
+        self.compact_stride
     }
 
     /// Returns the cached entry for `layer_idx`, if present.
@@ -429,6 +586,7 @@ impl DynamicCache {
         match self.seq_len {
             None => {
                 self.seq_len = Some(new_len);
+                self.appended_positions += new_len as u64;
             }
             Some(prev) => {
                 ensure!(
@@ -437,6 +595,7 @@ impl DynamicCache {
                 );
                 if new_len > prev {
                     self.seq_len = Some(new_len);
+                    self.appended_positions += (new_len - prev) as u64;
                 }
             }
         }
@@ -454,10 +613,91 @@ impl DynamicCache {
         self.seq_len
     }
 
-    /// Clears all cached state.
+    /// Position id of the next token: the cached length plus every position evicted since the
+    /// last clear.
+    pub fn next_position(&self) -> usize {
+        self.seq_len.unwrap_or(0) + self.evicted
+    }
+
+    /// Evict positions once more than `max_seq_len` are cached, when a bound is set, leaving
+    /// the kept head plus the newest positions of the window after it, `compact_stride` (half
+    /// the window by default) short of the bound. Called by the decoder after each forward
+    /// pass; returns the number of positions evicted.
+    pub fn enforce_max_seq_len(&mut self) -> Result<usize> {
+        let (Some((max_seq_len, eviction)), Some(seq_len)) = (self.max_seq_len, self.seq_len)
+        else {
+            return Ok(0);
+        };
+        if seq_len <= max_seq_len {
+            return Ok(0);
+        }
+        let keep_head = eviction.kept_head();
+        ensure!(
+            keep_head < max_seq_len,
+            "attention sink of {keep_head} positions leaves no room for recent ones within \
+             max_seq_len {max_seq_len}"
+        );
+        let window = max_seq_len - keep_head;
+        let stride = match self.compact_stride {
+            Some(stride) => {
+                ensure!(
+                    stride > 0 && stride < window,
+                    "compact stride {stride} must be at least 1 and below the {window} positions \
+                     outside the attention sink"
+                );
+                stride
+            }
+            None => window / 2,
+        };
+        let target = max_seq_len - stride;
+        let evicted = seq_len - target;
+        for entry in self.layers.entries_mut().iter_mut().flatten() {
+            let evict = entry.seq_len().saturating_sub(target);
+            entry.remove_positions(keep_head, evict)?;
+        }
+        self.seq_len = Some(target);
+        self.evicted += evicted;
+        self.evicted_positions += evicted as u64;
+        Ok(evicted)
+    }
+
+    /// Drops every layer's keys and values so the cache can be reused for an unrelated sequence.
+    /// Lifetime counters in [`Self::stats`], the `max_seq_len` bound and the compact stride are kept.
     pub fn clear(&mut self) {
+        if self.seq_len.is_some() {
+            self.clears += 1;
+        }
         self.layers.clear();
         self.seq_len = None;
+        self.evicted = 0;
+    }
+
+    /// Current length, footprint and lifetime counters of this cache.
+    pub fn stats(&self) -> CacheStats {
+        let (populated_layers, bytes) = self
+            .layers
+            .iter()
+            .flatten()
+            .fold((0, 0), |(layers, bytes), entry| {
+                (layers + 1, bytes + entry.storage_bytes())
+            });
+        CacheStats {
+            seq_len: self.seq_len.unwrap_or(0),
+            populated_layers,
+            bytes,
+            appended_positions: self.appended_positions,
+            evicted_positions: self.evicted_positions,
+            clears: self.clears,
+        }
+    }
+
+    /// Bytes currently allocated for keys and values across layers, growth headroom included.
+    pub fn memory_bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .flatten()
+            .map(KvCacheEntry::storage_bytes)
+            .sum()
     }
 
     /// Ensure the underlying cache tracks at least `total_layers` entries.
@@ -475,6 +715,58 @@ impl DynamicCache {
         &mut self.layers
     }
 
+    /// Serialize the cached keys and values (without growth headroom) so the cache can be
+    /// rebuilt with [`Self::from_snapshot`], possibly in another process. Values are stored as
+    /// little-endian `f32`, which round-trips every supported cache dtype exactly.
+    ///
+    /// A cache that has evicted positions cannot be snapshotted: the rebuilt cache would restart
+    /// position ids at its cached length.
+    pub fn to_snapshot(&self) -> Result<Vec<u8>> {
+        ensure!(
+            self.evicted == 0,
+            "cannot snapshot a KV cache that has evicted {} positions",
+            self.evicted
+        );
+        let mut out = Vec::new();
+        out.extend_from_slice(SNAPSHOT_MAGIC);
+        out.extend_from_slice(&(self.layers.len() as u32).to_le_bytes());
+        for entry in self.layers.iter() {
+            let Some(entry) = entry else {
+                out.push(0);
+                continue;
+            };
+            out.push(1);
+            write_snapshot_tensor(&mut out, &entry.key_view()?)?;
+            write_snapshot_tensor(&mut out, &entry.value_view()?)?;
+        }
+        Ok(out)
+    }
+
+    /// Rebuild a cache written by [`Self::to_snapshot`], casting tensors to `dtype` on `device`.
+    pub fn from_snapshot(bytes: &[u8], dtype: DType, device: &Device) -> Result<Self> {
+        let mut reader = SnapshotReader { bytes };
+        ensure!(
+            reader.take(SNAPSHOT_MAGIC.len())? == SNAPSHOT_MAGIC,
+            "not a KV cache snapshot"
+        );
+        let num_layers = reader.u32()? as usize;
+        let mut cache = Self::with_num_layers(num_layers);
+        for layer_idx in 0..num_layers {
+            if reader.take(1)?[0] == 0 {
+                continue;
+            }
+            let key_t = reader.tensor(dtype, device)?;
+            let value = reader.tensor(dtype, device)?;
+            cache.append(layer_idx, KvCacheChunk::new(key_t, value)?)?;
+        }
+        ensure!(
+            reader.bytes.is_empty(),
+            "KV cache snapshot has {} trailing bytes",
+            reader.bytes.len()
+        );
+        Ok(cache)
+    }
+
     /// Returns a guard that automatically clears the cache when it falls out of scope.
     pub fn prompt_guard(&mut self) -> PromptCacheGuard<'_> {
         PromptCacheGuard::new(self)
@@ -488,3 +780,71 @@ impl DynamicCache {
         PromptCacheGuard::with_rope_reset(self, reset)
     }
 }
+
+/// Drop `count` entries at `start` along `dim`, appending as many zeros so the length along `dim`
+/// is unchanged.
+fn remove_range(tensor: &Tensor, dim: usize, start: usize, count: usize) -> Result<Tensor> {
+    let total = tensor.dim(dim)?;
+    let mut padding_shape = tensor.dims().to_vec();
+    padding_shape[dim] = count;
+    let mut parts = Vec::with_capacity(3);
+    if start > 0 {
+        parts.push(tensor.narrow(dim, 0, start)?);
+    }
+    if start + count < total {
+        parts.push(tensor.narrow(dim, start + count, total - start - count)?);
+    }
+    parts.push(Tensor::zeros(
+        padding_shape,
+        tensor.dtype(),
+        tensor.device(),
+    )?);
+    Ok(Tensor::cat(&parts, dim)?.contiguous()?)
+}
+
+const SNAPSHOT_MAGIC: &[u8] = b"DSKV\x01";
+
+fn write_snapshot_tensor(out: &mut Vec<u8>, tensor: &Tensor) -> Result<()> {
+    let dims = tensor.dims4()?;
+    for dim in [dims.0, dims.1, dims.2, dims.3] {
+        out.extend_from_slice(&(dim as u32).to_le_bytes());
+    }
+    let values = tensor
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    out.reserve(values.len() * 4);
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(())
+}
+
+struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(self.bytes.len() >= len, "KV cache snapshot is truncated");
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn tensor(&mut self, dtype: DType, device: &Device) -> Result<Tensor> {
+        let dims = [self.u32()?, self.u32()?, self.u32()?, self.u32()?].map(|dim| dim as usize);
+        let count: usize = dims.iter().product();
+        let values = self
+            .take(count * 4)?
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect::<Vec<_>>();
+        Ok(Tensor::from_vec(values, dims.as_slice(), device)?.to_dtype(dtype)?)
+    }
+}
@@ -3,12 +3,14 @@ use crate::{
     transformer::{
         block::{TransformerBlock, build_attention_bias},
         cache::{DynamicCache, PromptCacheGuard},
+        model::OutputHead,
         rope::RopeCache,
         weights::TransformerWeights,
     },
 };
 use anyhow::{Result, ensure};
-use candle_core::{DType, Tensor};
+use candle_core::{D, DType, Tensor};
+use candle_nn::ops::log_softmax;
 use std::{cell::RefCell, sync::Arc};
 
 /// Runs the stacked transformer decoder layers, handling optional KV cache reuse.
@@ -17,6 +19,19 @@ pub struct TransformerDecoder {
     weights: Arc<TransformerWeights>,
     rope_cache: RefCell<Option<RopeCache>>,
     use_flash_attention: bool,
+    flash_nan_check: bool,
+    aux_loss: bool,
+    early_exit: Option<(EarlyExit, OutputHead)>,
+}
+
+/// Experimental early exit for single-token decode steps (see
+/// [`TransformerDecoder::with_early_exit`]).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EarlyExit {
+    /// Layers run in full before the exit check, between 1 and the layer count minus one.
+    pub layer: usize,
+    /// Exit when every row's next-token entropy at that layer, in nats, is below this.
+    pub max_entropy: f32,
 }
 
 fn parse_layer_slice(spec: &str) -> Option<(usize, Option<usize>)> {
@@ -44,6 +59,10 @@ fn parse_layer_slice(spec: &str) -> Option<(usize, Option<usize>)> {
 pub struct DecoderOutput {
     pub hidden_states: Tensor,
     pub aux_loss: Option<Tensor>,
+    /// Layers whose MLP ran, i.e. fewer than the full depth when the step exited early.
+    pub layers_run: usize,
+    /// Logits read at the exit layer when the step exited early, `[batch, seq, vocab]`.
+    pub exit_logits: Option<Tensor>,
 }
 
 impl TransformerDecoder {
@@ -57,9 +76,34 @@ impl TransformerDecoder {
             weights,
             rope_cache: RefCell::new(None),
             use_flash_attention,
+            flash_nan_check: false,
+            aux_loss: false,
+            early_exit: None,
         }
     }
 
+    /// See [`TransformerBlock::with_flash_nan_check`].
+    pub fn with_flash_nan_check(mut self, enabled: bool) -> Self {
+        self.flash_nan_check = enabled;
+        self
+    }
+
+    /// See [`TransformerBlock::with_aux_loss`]. [`DecoderOutput::aux_loss`] sums the MoE layers'
+    /// losses and stays `None` for dense-only configs.
+    pub fn with_aux_loss(mut self, enabled: bool) -> Self {
+        self.aux_loss = enabled;
+        self
+    }
+
+    /// On single-token decode steps, read the next-token distribution off layer
+    /// [`EarlyExit::layer`] through `head` and, when it is confident enough, skip the MLP of the
+    /// remaining layers. Those layers still run attention on the exited hidden state so their
+    /// KV cache stays in step. Off by default: output can differ from a full-depth decode.
+    pub fn with_early_exit(mut self, early_exit: Option<EarlyExit>, head: OutputHead) -> Self {
+        self.early_exit = early_exit.map(|settings| (settings, head));
+        self
+    }
+
     pub fn flash_attention_enabled(&self) -> bool {
         self.use_flash_attention
     }
@@ -93,6 +137,7 @@ impl TransformerDecoder {
             "use_cache=true requires a mutable DynamicCache"
         );
         let past_len = cache.as_ref().and_then(|c| c.seq_len()).unwrap_or(0);
+        let next_position = cache.as_ref().map_or(0, |c| c.next_position());
         let (batch, q_len, _) = hidden_states.shape().dims3()?;
         let dtype = hidden_states.dtype();
         let device = hidden_states.device();
@@ -131,7 +176,7 @@ impl TransformerDecoder {
                 ids.to_dtype(DType::I64)?
             })
         } else {
-            let start = past_len as i64;
+            let start = next_position as i64;
             let end = start + q_len as i64;
             Some(
                 Tensor::arange(start, end, device)?
@@ -156,10 +201,10 @@ impl TransformerDecoder {
                 if let Some(cache) = rope_entry.as_mut() {
                     if let Some(ids) = ids_for_rope {
                         let want = if q_len == 0 {
-                            past_len
+                            next_position
                         } else {
                             let max_pos = ids.max_all()?.to_scalar::<i64>()? as usize;
-                            (past_len + q_len).max(max_pos + 1)
+                            (next_position + q_len).max(max_pos + 1)
                         };
                         cache.ensure_len(&self.cfg, want)?;
                         rope_tensors = Some(cache.select(batch, q_len, Some(ids))?);
@@ -172,6 +217,12 @@ impl TransformerDecoder {
 
         let mut hidden = hidden_states.clone();
         let mut aux_loss: Option<Tensor> = None;
+        let mut layers_run = layer_end - layer_start;
+        let mut exit_logits = None;
+        let early_exit = self
+            .early_exit
+            .as_ref()
+            .filter(|_| q_len == 1 && use_cache && layer_start == 0);
         if let Some(existing) = cache.as_ref() {
             ensure!(
                 existing.num_layers() == 0 || existing.num_layers() >= total_layers,
@@ -189,7 +240,9 @@ impl TransformerDecoder {
             .enumerate()
             .map(|(i, w)| (layer_start + i, w))
         {
-            let block = TransformerBlock::new(&self.cfg, layer_weights, self.use_flash_attention);
+            let block = TransformerBlock::new(&self.cfg, layer_weights, self.use_flash_attention)
+                .with_flash_nan_check(self.flash_nan_check)
+                .with_aux_loss(self.aux_loss);
             let output = {
                 let past = cache.as_deref().and_then(|cache| cache.get(idx));
                 let rope_refs = rope_tensors.as_ref().map(|(cos, sin)| (cos, sin));
@@ -207,11 +260,63 @@ impl TransformerDecoder {
                     None => loss,
                 });
             }
+            let Some((settings, head)) =
+                early_exit.filter(|(settings, _)| settings.layer == idx + 1)
+            else {
+                continue;
+            };
+            if idx + 1 >= layer_end {
+                break;
+            }
+            let (_, logits) = head.forward(&hidden)?;
+            let entropy = next_token_entropy(&logits)?;
+            if entropy.iter().any(|&value| value >= settings.max_entropy) {
+                continue;
+            }
+            for (skipped, layer_weights) in self.weights.layers[idx + 1..layer_end]
+                .iter()
+                .enumerate()
+                .map(|(i, w)| (idx + 1 + i, w))
+            {
+                let block =
+                    TransformerBlock::new(&self.cfg, layer_weights, self.use_flash_attention);
+                let present = {
+                    let past = cache.as_deref().and_then(|cache| cache.get(skipped));
+                    let rope_refs = rope_tensors.as_ref().map(|(cos, sin)| (cos, sin));
+                    block.present_key_value(&hidden, attn_bias.as_ref(), rope_refs, past)?
+                };
+                if let (Some(present), Some(cache)) = (present, cache.as_mut()) {
+                    cache.append(skipped, present)?;
+                }
+            }
+            layers_run = idx + 1;
+            exit_logits = Some(logits);
+            break;
+        }
+        if let Some(cache) = cache.as_mut().filter(|_| use_cache) {
+            cache.enforce_max_seq_len()?;
         }
 
         Ok(DecoderOutput {
             hidden_states: hidden,
             aux_loss,
+            layers_run,
+            exit_logits,
         })
     }
 }
+
+/// Entropy in nats of the next-token distribution at the last position of each row of
+/// `[batch, seq, vocab]` logits.
+pub fn next_token_entropy(logits: &Tensor) -> Result<Vec<f32>> {
+    let (_, seq_len, _) = logits.dims3()?;
+    let last = logits.narrow(1, seq_len - 1, 1)?.squeeze(1)?;
+    let log_probs = log_softmax(&last.to_dtype(DType::F32)?, D::Minus1)?;
+    let entropy = log_probs
+        .exp()?
+        .mul(&log_probs)?
+        .sum(D::Minus1)?
+        .neg()?
+        .to_vec1::<f32>()?;
+    Ok(entropy)
+}
@@ -0,0 +1,236 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use candle_core::{DType, Device, Tensor};
+use serde::{Deserialize, Serialize};
+
+use super::weights::{LinearWeights, MlpWeights, TransformerWeights};
+
+/// File PEFT writes next to `adapter_model.safetensors`, holding `r` and `lora_alpha`.
+const ADAPTER_CONFIG_FILE: &str = "adapter_config.json";
+
+/// A LoRA adapter applied over the decoder's linear layers at load time.
+///
+/// The safetensors file uses the PEFT layout: a `<module>.lora_A.weight` (`[rank, in]`) and
+/// `<module>.lora_B.weight` (`[out, rank]`) pair for every adapted projection, where `<module>`
+/// ends in a decoder path such as `layers.3.self_attn.q_proj` or `layers.5.mlp.experts.2.up_proj`.
+/// When an `adapter_config.json` sits next to the file, its `lora_alpha / r` is folded into the
+/// update as PEFT does.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraAdapter {
+    pub path: PathBuf,
+    /// Multiplier on the adapter's update; `0.0` loads it disabled.
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+impl LoraAdapter {
+    pub fn new(path: impl Into<PathBuf>, scale: f32) -> Self {
+        Self {
+            path: path.into(),
+            scale,
+        }
+    }
+}
+
+impl FromStr for LoraAdapter {
+    type Err = anyhow::Error;
+
+    /// Parse `PATH` or `PATH=SCALE`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, scale) = match s.rsplit_once('=') {
+            Some((path, scale)) => {
+                let scale = scale
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("expected PATH=SCALE with a numeric scale, got `{s}`"))?;
+                (path, scale)
+            }
+            None => (s, default_scale()),
+        };
+        ensure!(!path.is_empty(), "LoRA adapter path is empty");
+        Ok(Self::new(path, scale))
+    }
+}
+
+impl fmt::Display for LoraAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.path.display(), self.scale)
+    }
+}
+
+/// Scale shared by every update of one adapter, so the adapter can be reweighted or switched off
+/// without touching the weights.
+#[derive(Debug, Clone)]
+pub struct LoraScale(Arc<AtomicU32>);
+
+impl LoraScale {
+    pub fn new(scale: f32) -> Self {
+        Self(Arc::new(AtomicU32::new(scale.to_bits())))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, scale: f32) {
+        self.0.store(scale.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Low-rank update `scale * x Aᵀ Bᵀ` added to a linear layer's output.
+#[derive(Debug, Clone)]
+pub struct LoraDelta {
+    /// `[rank, in]` down projection.
+    pub a: Tensor,
+    /// `[out, rank]` up projection, with the adapter's `lora_alpha / r` already applied.
+    pub b: Tensor,
+    pub scale: LoraScale,
+}
+
+impl LoraDelta {
+    /// The update for a `[rows, in]` input, or `None` while the adapter is scaled to zero.
+    pub fn forward(&self, input2d: &Tensor) -> Result<Option<Tensor>> {
+        let scale = self.scale.get();
+        if scale == 0.0 {
+            return Ok(None);
+        }
+        let input = input2d.to_dtype(self.a.dtype())?;
+        let update = input
+            .matmul(&self.a.t()?)?
+            .matmul(&self.b.t()?)?
+            .affine(f64::from(scale), 0.0)?;
+        Ok(Some(update.to_dtype(input2d.dtype())?))
+    }
+}
+
+impl TransformerWeights {
+    /// Attach the adapter at `adapter.path` to the projections it targets and return the scale
+    /// handle shared by all of them.
+    ///
+    /// Base weights are left as they are (quantized ones included); the updates run beside them
+    /// in every forward. Fails when the file targets a module that is not a decoder projection
+    /// or whose shape does not match.
+    pub fn attach_lora(
+        &mut self,
+        adapter: &LoraAdapter,
+        device: &Device,
+        dtype: DType,
+    ) -> Result<LoraScale> {
+        let path = adapter.path.as_path();
+        let mut tensors = candle_core::safetensors::load(path, device)
+            .with_context(|| format!("failed to read LoRA adapter {}", path.display()))?;
+        let alpha_scale = adapter_alpha_scale(path)?;
+        let scale = LoraScale::new(adapter.scale);
+
+        let mut modules: Vec<String> = tensors
+            .keys()
+            .filter_map(|name| name.strip_suffix(".lora_A.weight"))
+            .map(str::to_owned)
+            .collect();
+        modules.sort();
+        ensure!(
+            !modules.is_empty(),
+            "LoRA adapter {} has no `*.lora_A.weight` tensors",
+            path.display()
+        );
+        for module in &modules {
+            let a = tensors
+                .remove(&format!("{module}.lora_A.weight"))
+                .expect("listed above");
+            let b = tensors
+                .remove(&format!("{module}.lora_B.weight"))
+                .with_context(|| format!("LoRA adapter is missing `{module}.lora_B.weight`"))?;
+            let linear = self
+                .lora_target(module)
+                .with_context(|| format!("LoRA adapter targets unknown module `{module}`"))?;
+            let (out_dim, in_dim) = linear.weight.shape().dims2()?;
+            let (rank, a_in) = a.shape().dims2()?;
+            let (b_out, b_rank) = b.shape().dims2()?;
+            ensure!(
+                a_in == in_dim && b_out == out_dim && b_rank == rank,
+                "LoRA update for `{module}` is [{b_out}, {b_rank}] x [{rank}, {a_in}], but the \
+                 layer is [{out_dim}, {in_dim}]"
+            );
+            let b = match alpha_scale {
+                Some(alpha_scale) => b.affine(alpha_scale / rank as f64, 0.0)?,
+                None => b,
+            };
+            linear.lora.push(LoraDelta {
+                a: a.to_dtype(dtype)?,
+                b: b.to_dtype(dtype)?,
+                scale: scale.clone(),
+            });
+        }
+        tracing::info!(
+            "Attached LoRA adapter {} to {} projections (scale {})",
+            path.display(),
+            modules.len(),
+            adapter.scale
+        );
+        Ok(scale)
+    }
+
+    /// Resolve a PEFT module name to the projection it adapts, from its `layers.N.` suffix on.
+    fn lora_target(&mut self, module: &str) -> Option<&mut LinearWeights> {
+        let (_, rest) = module.rsplit_once("layers.")?;
+        let (layer_idx, rest) = rest.split_once('.')?;
+        let layer = self.layers.get_mut(layer_idx.parse::<usize>().ok()?)?;
+        let parts: Vec<&str> = rest.split('.').collect();
+        match (parts.as_slice(), &mut layer.mlp) {
+            (["self_attn", proj], _) => match *proj {
+                "q_proj" => Some(&mut layer.attention.q_proj),
+                "k_proj" => Some(&mut layer.attention.k_proj),
+                "v_proj" => Some(&mut layer.attention.v_proj),
+                "o_proj" => Some(&mut layer.attention.o_proj),
+                _ => None,
+            },
+            (["mlp", proj], MlpWeights::Dense(mlp)) => mlp.projection_mut(proj),
+            (["mlp", "shared_experts", proj], MlpWeights::Moe(moe)) => {
+                moe.shared_experts.as_mut()?.projection_mut(proj)
+            }
+            (["mlp", "experts", expert, proj], MlpWeights::Moe(moe)) => moe
+                .experts
+                .get_mut(expert.parse::<usize>().ok()?)?
+                .projection_mut(proj),
+            _ => None,
+        }
+    }
+}
+
+/// PEFT's `lora_alpha` from the adapter's `adapter_config.json`, or `None` without one. It is
+/// divided by each module's own rank when attached, since PEFT allows ranks to differ per module.
+fn adapter_alpha_scale(path: &Path) -> Result<Option<f64>> {
+    let Some(config_path) = path.parent().map(|dir| dir.join(ADAPTER_CONFIG_FILE)) else {
+        return Ok(None);
+    };
+    if !config_path.is_file() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&config_path)
+        .with_context(|| format!("failed to read {}", config_path.display()))?;
+    let config: serde_json::Value = serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", config_path.display()))?;
+    match config.get("lora_alpha") {
+        None => Ok(None),
+        Some(alpha) => match alpha.as_f64() {
+            Some(alpha) => Ok(Some(alpha)),
+            None => bail!(
+                "`lora_alpha` in {} must be a number, got {alpha}",
+                config_path.display()
+            ),
+        },
+    }
+}
@@ -1,6 +1,8 @@
 pub mod block;
 pub mod cache;
 pub mod decoder;
+pub mod lora;
 pub mod model;
 pub mod rope;
+pub mod sampling;
 pub mod weights;
@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::{cell::Cell, path::PathBuf, sync::Arc};
 
-use anyhow::{Result, ensure};
+use anyhow::{Context, Result, ensure};
 use candle_core::{DType, IndexOp, Tensor};
 use candle_nn::ops::rms_norm;
 
@@ -8,8 +8,9 @@ use crate::{
     config::DeepseekV2Config,
     transformer::{
         cache::{DynamicCache, PromptCacheGuard},
-        decoder::TransformerDecoder,
-        weights::{DeepseekLanguageModelWeights, TransformerWeights},
+        decoder::{EarlyExit, TransformerDecoder},
+        lora::{LoraAdapter, LoraScale},
+        weights::{DeepseekLanguageModelWeights, TransformerWeights, WeightQuant},
     },
 };
 
@@ -18,9 +19,53 @@ use crate::{
 pub struct LanguageModelOutput {
     pub hidden_states: Tensor,
     pub logits: Tensor,
+    /// Summed MoE load-balancing loss (scalar). `None` unless aux-loss tracking is on (see
+    /// [`DeepseekLanguageModel::with_aux_loss`]), and always `None` for configs without MoE
+    /// layers.
     pub aux_loss: Option<Tensor>,
 }
 
+/// Running MoE load-balancing loss over the forwards that reported one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AuxLossStats {
+    pub forwards: u64,
+    /// Loss of the most recent forward, summed over MoE layers.
+    pub last: f32,
+    pub mean: f32,
+}
+
+/// Decode steps seen with early exit on, and how deep they went.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EarlyExitStats {
+    /// Single-token decode steps.
+    pub steps: u64,
+    /// Steps that exited before the last layer.
+    pub exits: u64,
+    /// Average number of layers whose MLP ran per step.
+    pub mean_layers: f32,
+}
+
+/// Final RMSNorm plus vocab projection, shared by the full-depth output and early exit.
+#[derive(Debug, Clone)]
+pub struct OutputHead {
+    norm: Tensor,
+    lm_head: Tensor,
+    eps: f32,
+}
+
+impl OutputHead {
+    /// Returns the normed hidden states and the logits, `[batch, seq, vocab]`.
+    pub fn forward(&self, hidden_states: &Tensor) -> Result<(Tensor, Tensor)> {
+        let normed = rms_norm(hidden_states, &self.norm, self.eps)?;
+        let (b, s, h) = normed.shape().dims3()?;
+        let flat = normed.reshape((b * s, h))?;
+        let logits = flat.matmul(&self.lm_head.transpose(0, 1)?)?;
+        let vocab = logits.dim(1)?;
+        let logits = logits.reshape((b, s, vocab))?;
+        Ok((normed, logits))
+    }
+}
+
 /// Candle-backed implementation of the DeepSeek text decoder stack.
 ///
 /// Responsibilities covered here:
@@ -32,15 +77,57 @@ pub struct DeepseekLanguageModel {
     decoder: TransformerDecoder,
     transformer_weights: Arc<TransformerWeights>,
     token_embedding: Tensor,
-    final_layernorm: Tensor,
-    lm_head: Tensor,
+    head: OutputHead,
+    aux_loss_stats: Cell<AuxLossStats>,
+    early_exit_stats: Option<Cell<EarlyExitStats>>,
+    prefill_chunk_size: Option<usize>,
+    lora_scales: Vec<LoraScale>,
+    lora_paths: Vec<PathBuf>,
 }
 
 impl DeepseekLanguageModel {
     /// Load language-model weights from a [`VarBuilder`]-compatible source.
     pub fn load(cfg: Arc<DeepseekV2Config>, vb: &candle_nn::VarBuilder) -> Result<Self> {
-        let weights = DeepseekLanguageModelWeights::load(&cfg, vb)?;
-        Ok(Self::from_weights(cfg, weights))
+        Self::load_with_quantization(cfg, vb, None)
+    }
+
+    /// Like [`Self::load`], optionally quantizing the decoder's linear layers as they load.
+    pub fn load_with_quantization(
+        cfg: Arc<DeepseekV2Config>,
+        vb: &candle_nn::VarBuilder,
+        quantize: Option<WeightQuant>,
+    ) -> Result<Self> {
+        Self::load_with_adapters(cfg, vb, quantize, &[])
+    }
+
+    /// Like [`Self::load_with_quantization`], then attaching each LoRA adapter in `adapters`
+    /// over the decoder's projections (see [`TransformerWeights::attach_lora`]). Adapters are
+    /// addressed by their position in `adapters` in [`Self::set_lora_scale`].
+    pub fn load_with_adapters(
+        cfg: Arc<DeepseekV2Config>,
+        vb: &candle_nn::VarBuilder,
+        quantize: Option<WeightQuant>,
+        adapters: &[LoraAdapter],
+    ) -> Result<Self> {
+        let mut weights = DeepseekLanguageModelWeights::load_quantized(&cfg, vb, quantize)?;
+        if let Some(quant) = quantize {
+            tracing::info!("Quantized decoder linear layers to {quant:?} while loading");
+        }
+        let lora_scales = adapters
+            .iter()
+            .map(|adapter| {
+                weights
+                    .transformer
+                    .attach_lora(adapter, vb.device(), vb.dtype())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut model = Self::from_weights(cfg, weights);
+        model.lora_scales = lora_scales;
+        model.lora_paths = adapters
+            .iter()
+            .map(|adapter| adapter.path.clone())
+            .collect();
+        Ok(model)
     }
 
     /// Construct the language model from pre-loaded weight tensors.
@@ -51,13 +138,7 @@ impl DeepseekLanguageModel {
             .as_deref()
             .map(|s| s.eq_ignore_ascii_case("flash_attention_2"))
             .unwrap_or(false);
-        let flash_override = std::env::var("DEEPSEEK_OCR_FLASH_ATTENTION")
-            .ok()
-            .and_then(|value| match value.to_ascii_lowercase().as_str() {
-                "1" | "true" | "yes" => Some(true),
-                "0" | "false" | "no" => Some(false),
-                _ => None,
-            });
+        let flash_override = env_flag("DEEPSEEK_OCR_FLASH_ATTENTION");
         let use_flash_attention = flash_override.unwrap_or(flash_from_config);
         let decoder = TransformerDecoder::new(
             Arc::clone(&cfg),
@@ -65,15 +146,111 @@ impl DeepseekLanguageModel {
             use_flash_attention,
         );
         Self {
-            cfg,
             decoder,
             transformer_weights: transformer,
             token_embedding: weights.token_embedding,
-            final_layernorm: weights.final_layernorm.weight,
-            lm_head: weights.lm_head,
+            head: OutputHead {
+                norm: weights.final_layernorm.weight,
+                lm_head: weights.lm_head,
+                eps: cfg.rms_norm_eps,
+            },
+            cfg,
+            aux_loss_stats: Cell::new(AuxLossStats::default()),
+            early_exit_stats: None,
+            prefill_chunk_size: None,
+            lora_scales: Vec::new(),
+            lora_paths: Vec::new(),
         }
     }
 
+    /// Scan every layer's flash-attention output for NaNs and recompute that layer with eager
+    /// attention when found (see [`TransformerDecoder::with_flash_nan_check`]). Off by default:
+    /// it costs a device sync per layer. Has no effect without flash attention.
+    pub fn with_flash_nan_check(mut self, enabled: bool) -> Self {
+        self.decoder = self.decoder.with_flash_nan_check(enabled);
+        self
+    }
+
+    /// Compute the MoE load-balancing loss on every forward, report it in
+    /// [`LanguageModelOutput::aux_loss`], log it at debug level and accumulate
+    /// [`Self::aux_loss_stats`]. Off by default: it costs a host copy of the router scores per MoE
+    /// layer.
+    pub fn with_aux_loss(mut self, enabled: bool) -> Self {
+        self.decoder = self.decoder.with_aux_loss(enabled);
+        self
+    }
+
+    /// Aux-loss figures so far; `None` until a forward reported one, so always `None` when
+    /// tracking is off or the config has no MoE layers.
+    pub fn aux_loss_stats(&self) -> Option<AuxLossStats> {
+        let stats = self.aux_loss_stats.get();
+        (stats.forwards > 0).then_some(stats)
+    }
+
+    /// Experimental: let decode steps stop after [`EarlyExit::layer`] when the next token is
+    /// already certain (see [`TransformerDecoder::with_early_exit`]), and track the depth used in
+    /// [`Self::early_exit_stats`]. `None` keeps every step at full depth.
+    pub fn with_early_exit(mut self, early_exit: Option<EarlyExit>) -> Result<Self> {
+        if let Some(settings) = early_exit {
+            let layers = self.transformer_weights.layers.len();
+            ensure!(
+                settings.layer >= 1 && settings.layer < layers,
+                "early-exit layer must be between 1 and {}, got {}",
+                layers.saturating_sub(1),
+                settings.layer
+            );
+        }
+        self.decoder = self.decoder.with_early_exit(early_exit, self.head.clone());
+        self.early_exit_stats = early_exit.map(|_| Cell::new(EarlyExitStats::default()));
+        Ok(self)
+    }
+
+    /// Early-exit figures so far; `None` when early exit is off.
+    pub fn early_exit_stats(&self) -> Option<EarlyExitStats> {
+        self.early_exit_stats.as_ref().map(Cell::get)
+    }
+
+    /// Run cached prompts longer than `chunk_size` tokens as consecutive chunks that each append
+    /// to the KV cache, bounding the attention scratch of a long prefill at the cost of a few
+    /// more kernel launches. `None` (or 0) prefills in one pass.
+    pub fn with_prefill_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.prefill_chunk_size = chunk_size.filter(|&size| size > 0);
+        self
+    }
+
+    pub fn prefill_chunk_size(&self) -> Option<usize> {
+        self.prefill_chunk_size
+    }
+
+    /// Current scale of each LoRA adapter, in load order.
+    pub fn lora_scales(&self) -> Vec<f32> {
+        self.lora_scales.iter().map(LoraScale::get).collect()
+    }
+
+    /// The attached LoRA adapters with their current scales, in load order.
+    pub fn lora_adapters(&self) -> Vec<LoraAdapter> {
+        self.lora_paths
+            .iter()
+            .zip(&self.lora_scales)
+            .map(|(path, scale)| LoraAdapter::new(path.clone(), scale.get()))
+            .collect()
+    }
+
+    /// Reweight the LoRA adapter at `adapter` (its position in the load list) for every later
+    /// forward; `0.0` switches it off and `1.0` restores the loaded behaviour. The base weights
+    /// are not touched.
+    pub fn set_lora_scale(&self, adapter: usize, scale: f32) -> Result<()> {
+        ensure!(scale.is_finite(), "LoRA scale must be finite, got {scale}");
+        let handle = self.lora_scales.get(adapter).with_context(|| {
+            format!(
+                "no LoRA adapter {adapter}; {} loaded",
+                self.lora_scales.len()
+            )
+        })?;
+        handle.set(scale);
+        Ok(())
+    }
+
     pub fn config(&self) -> &DeepseekV2Config {
         self.cfg.as_ref()
     }
@@ -118,7 +295,11 @@ impl DeepseekLanguageModel {
     ///
     /// Provide either `input_ids` **or** `inputs_embeds`. When `input_ids` are supplied, token
     /// embeddings are gathered using the stored embedding matrix. If `position_ids` are omitted,
-    /// monotonically increasing positions are synthesized based on the current cache length.
+    /// monotonically increasing positions are synthesized from
+    /// [`DynamicCache::next_position`], which keeps counting past positions the cache evicted.
+    ///
+    /// Cached inputs longer than [`Self::with_prefill_chunk_size`] are run through
+    /// [`Self::forward_chunked`], whose hidden states and logits cover only the last position.
     pub fn forward(
         &self,
         input_ids: Option<&Tensor>,
@@ -136,10 +317,89 @@ impl DeepseekLanguageModel {
             !use_cache || cache.is_some(),
             "use_cache=true requires a mutable DynamicCache"
         );
+        let embeds = self.input_embeddings(input_ids, inputs_embeds)?;
+        match (self.prefill_chunk_size, cache) {
+            (Some(chunk_size), Some(cache)) if use_cache && embeds.dim(1)? > chunk_size => {
+                self.forward_chunked(&embeds, attention_mask, position_ids, cache, chunk_size)
+            }
+            (_, cache) => self.forward_embeds(
+                &embeds,
+                attention_mask,
+                position_ids,
+                cache,
+                use_cache,
+                false,
+            ),
+        }
+    }
 
-        let past_len = cache.as_ref().and_then(|c| c.seq_len()).unwrap_or(0);
-        let embeds = match inputs_embeds {
-            Some(t) => t.clone(),
+    /// Prefill `inputs_embeds` in consecutive blocks of at most `chunk_size` tokens, appending
+    /// each block to `cache` before the next attends to it. Only the final position reaches the LM
+    /// head, so the hidden states and logits have a sequence length of one; they match the last
+    /// position of a single pass up to floating-point summation order. Earlier chunks are kept
+    /// only in `cache`. `attention_mask` spans the cached and new positions, `position_ids` the
+    /// new ones.
+    pub fn forward_chunked(
+        &self,
+        inputs_embeds: &Tensor,
+        attention_mask: Option<&Tensor>,
+        position_ids: Option<&Tensor>,
+        cache: &mut DynamicCache,
+        chunk_size: usize,
+    ) -> Result<LanguageModelOutput> {
+        ensure!(chunk_size > 0, "prefill chunk size must be positive");
+        ensure!(
+            attention_mask.is_none() || cache.max_seq_len().is_none(),
+            "chunked prefill with an attention mask needs an unbounded KV cache"
+        );
+        let past_len = cache.seq_len().unwrap_or(0);
+        let seq_len = inputs_embeds.dim(1)?;
+        let mut last = None;
+        let mut aux_loss: Option<Tensor> = None;
+        for start in (0..seq_len).step_by(chunk_size) {
+            let len = chunk_size.min(seq_len - start);
+            let embeds = inputs_embeds.narrow(1, start, len)?;
+            let mask = attention_mask
+                .map(|mask| mask.narrow(1, 0, past_len + start + len))
+                .transpose()?;
+            let positions = position_ids
+                .map(|ids| ids.narrow(1, start, len))
+                .transpose()?;
+            let out = self.forward_embeds(
+                &embeds,
+                mask.as_ref(),
+                positions.as_ref(),
+                Some(cache),
+                true,
+                start + len == seq_len,
+            )?;
+            // The balancing loss is computed per chunk; weight each by its share of the prompt.
+            if let Some(loss) = &out.aux_loss {
+                let weighted = loss
+                    .to_dtype(DType::F32)?
+                    .affine(len as f64 / seq_len as f64, 0.0)?;
+                aux_loss = Some(match aux_loss {
+                    Some(total) => total.add(&weighted)?,
+                    None => weighted,
+                });
+            }
+            last = Some(out);
+        }
+        let last = last.context("chunked prefill needs at least one position")?;
+        Ok(LanguageModelOutput {
+            hidden_states: last.hidden_states,
+            logits: last.logits,
+            aux_loss,
+        })
+    }
+
+    fn input_embeddings(
+        &self,
+        input_ids: Option<&Tensor>,
+        inputs_embeds: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        match inputs_embeds {
+            Some(t) => Ok(t.clone()),
             None => {
                 let ids = input_ids.expect("input_ids validity checked above");
                 let ids = if ids.dtype() == DType::I64 {
@@ -147,17 +407,28 @@ impl DeepseekLanguageModel {
                 } else {
                     ids.to_dtype(DType::I64)?
                 };
-                gather_embeddings(&self.token_embedding, &ids)?
+                gather_embeddings(&self.token_embedding, &ids)
             }
-        };
+        }
+    }
 
+    fn forward_embeds(
+        &self,
+        embeds: &Tensor,
+        attention_mask: Option<&Tensor>,
+        position_ids: Option<&Tensor>,
+        cache: Option<&mut DynamicCache>,
+        use_cache: bool,
+        last_only: bool,
+    ) -> Result<LanguageModelOutput> {
+        let next_position = cache.as_ref().map_or(0, |c| c.next_position());
         let (batch, seq_len, _) = embeds.shape().dims3()?;
 
         let position_buf: Option<Tensor> = if position_ids.is_some() {
             None
         } else {
             let device = embeds.device();
-            let start = past_len as i64;
+            let start = next_position as i64;
             let end = start + seq_len as i64;
             Some(
                 Tensor::arange(start, end, device)?
@@ -173,17 +444,48 @@ impl DeepseekLanguageModel {
 
         let decoder_out =
             self.decoder
-                .forward(&embeds, attention_mask, position_ids_ref, cache, use_cache)?;
+                .forward(embeds, attention_mask, position_ids_ref, cache, use_cache)?;
 
-        let normed = rms_norm(
-            &decoder_out.hidden_states,
-            &self.final_layernorm,
-            self.cfg.rms_norm_eps as f32,
-        )?;
-        let (b, s, h) = normed.shape().dims3()?;
-        let flat = normed.reshape((b * s, h))?;
-        let logits = flat.matmul(&self.lm_head.transpose(0, 1)?)?;
-        let logits = logits.reshape((b, s, self.cfg.vocab_size))?;
+        // Earlier positions are dropped before the head so their logits are never computed.
+        let last_position = |t: &Tensor| -> Result<Tensor> {
+            if last_only {
+                Ok(t.narrow(1, t.dim(1)? - 1, 1)?)
+            } else {
+                Ok(t.clone())
+            }
+        };
+        let hidden_states = last_position(&decoder_out.hidden_states)?;
+        let (normed, logits) = match &decoder_out.exit_logits {
+            Some(logits) => (
+                rms_norm(&hidden_states, &self.head.norm, self.head.eps)?,
+                last_position(logits)?,
+            ),
+            None => self.head.forward(&hidden_states)?,
+        };
+        if let Some(cell) = self
+            .early_exit_stats
+            .as_ref()
+            .filter(|_| seq_len == 1 && use_cache)
+        {
+            let mut stats = cell.get();
+            stats.steps += 1;
+            if decoder_out.layers_run < self.transformer_weights.layers.len() {
+                stats.exits += 1;
+            }
+            stats.mean_layers +=
+                (decoder_out.layers_run as f32 - stats.mean_layers) / stats.steps as f32;
+            cell.set(stats);
+        }
+
+        if let Some(loss) = &decoder_out.aux_loss {
+            let value = loss.to_dtype(DType::F32)?.to_scalar::<f32>()?;
+            let mut stats = self.aux_loss_stats.get();
+            stats.forwards += 1;
+            stats.last = value;
+            stats.mean += (value - stats.mean) / stats.forwards as f32;
+            self.aux_loss_stats.set(stats);
+            tracing::debug!(aux_loss = value, seq_len, "MoE load-balancing loss");
+        }
 
         Ok(LanguageModelOutput {
             hidden_states: normed,
@@ -193,6 +495,16 @@ impl DeepseekLanguageModel {
     }
 }
 
+fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => Some(true),
+            "0" | "false" | "no" => Some(false),
+            _ => None,
+        })
+}
+
 fn gather_embeddings(weight: &Tensor, ids: &Tensor) -> Result<Tensor> {
     ensure!(
         ids.rank() == 2,
@@ -1,4 +1,4 @@
-use crate::config::DeepseekV2Config;
+use crate::config::{DeepseekV2Config, RopeScalingPreset};
 use anyhow::{Result, ensure};
 use candle_core::{DType, Device, Tensor};
 
@@ -167,21 +167,56 @@ fn build_rope_tables(
         rope_dim % 2 == 0,
         "rope dimension must be even, got {rope_dim}"
     );
-    let base = cfg.rope_theta as f32;
+    let scaling = cfg.rope_scaling_preset()?;
+    let mut base = cfg.rope_theta as f32;
+    if let Some(RopeScalingPreset::Ntk { factor }) = scaling {
+        base *= factor.powf(rope_dim as f32 / (rope_dim as f32 - 2.0).max(1.0));
+    }
     let half = rope_dim / 2;
     let mut inv_freq = Vec::with_capacity(half);
     for i in 0..half {
         let exponent = i as f32 / half as f32;
         inv_freq.push(1.0f32 / base.powf(exponent));
     }
+    let mut magnitude = 1.0f32;
+    match scaling {
+        Some(RopeScalingPreset::Linear { factor }) => {
+            inv_freq.iter_mut().for_each(|freq| *freq /= factor);
+        }
+        Some(RopeScalingPreset::Yarn {
+            factor,
+            original_max_position_embeddings,
+            beta_fast,
+            beta_slow,
+        }) => {
+            let original = original_max_position_embeddings.unwrap_or(cfg.max_position_embeddings);
+            yarn_blend(
+                &mut inv_freq,
+                base,
+                rope_dim,
+                original,
+                factor,
+                beta_fast,
+                beta_slow,
+            );
+            if factor > 1.0 {
+                magnitude = 0.1 * factor.ln() + 1.0;
+            }
+        }
+        Some(RopeScalingPreset::Ntk { .. }) | None => {}
+    }
 
     let pos = Tensor::arange(0i64, cache_len as i64, device)?
         .to_dtype(DType::F32)?
         .reshape((cache_len, 1))?;
     let inv_freq = Tensor::from_vec(inv_freq, (1, half), device)?;
     let angles = pos.matmul(&inv_freq)?;
-    let cos_half = angles.cos()?;
-    let sin_half = angles.sin()?;
+    let mut cos_half = angles.cos()?;
+    let mut sin_half = angles.sin()?;
+    if magnitude != 1.0 {
+        cos_half = cos_half.affine(magnitude as f64, 0.0)?;
+        sin_half = sin_half.affine(magnitude as f64, 0.0)?;
+    }
     let cos_full = Tensor::cat(&[cos_half.clone(), cos_half], 1)?;
     let sin_full = Tensor::cat(&[sin_half.clone(), sin_half], 1)?;
     let cos = cos_full
@@ -192,3 +227,33 @@ fn build_rope_tables(
         .reshape((1, 1, cache_len, rope_dim))?;
     Ok((cos, sin))
 }
+
+/// Mix interpolated (`/ factor`) and original frequencies per YaRN: dimensions completing fewer
+/// than `beta_slow` rotations over the original context are fully interpolated, those completing
+/// more than `beta_fast` are kept, and a linear ramp covers the band in between.
+fn yarn_blend(
+    inv_freq: &mut [f32],
+    base: f32,
+    rope_dim: usize,
+    original_max_positions: usize,
+    factor: f32,
+    beta_fast: f32,
+    beta_slow: f32,
+) {
+    let correction_dim = |rotations: f32| {
+        rope_dim as f32
+            * (original_max_positions as f32 / (rotations * 2.0 * std::f32::consts::PI)).ln()
+            / (2.0 * base.ln())
+    };
+    let max_index = (rope_dim - 1) as f32;
+    let low = correction_dim(beta_fast).floor().clamp(0.0, max_index);
+    let mut high = correction_dim(beta_slow).ceil().clamp(0.0, max_index);
+    if high == low {
+        high += 0.001;
+    }
+    for (i, freq) in inv_freq.iter_mut().enumerate() {
+        let ramp = ((i as f32 - low) / (high - low)).clamp(0.0, 1.0);
+        let interpolated = *freq / factor;
+        *freq = interpolated * ramp + *freq * (1.0 - ramp);
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::Mutex;
+
+use anyhow::{Context, Result, ensure};
+use candle_core::{D, DType, Tensor};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+/// Picks the next token from language-model logits: greedy argmax, or sampling at `temperature`
+/// from the candidates left by `top_k` and `top_p` (nucleus) filtering.
+///
+/// Cloning copies the RNG state, so every clone of a seeded sampler draws the same sequence.
+#[derive(Debug)]
+pub struct LogitsSampler {
+    /// Logits are divided by this before the softmax; `0` (or less) selects greedy argmax.
+    pub temperature: f64,
+    /// Keep only the `k` most likely tokens. `None` keeps the whole vocabulary.
+    pub top_k: Option<usize>,
+    /// Keep the smallest set of most likely tokens whose probabilities sum to at least `p`.
+    /// `None` disables nucleus filtering.
+    pub top_p: Option<f64>,
+    rng: Mutex<StdRng>,
+}
+
+impl LogitsSampler {
+    /// Sample at `temperature` from the full distribution, seeded from the OS.
+    pub fn new(temperature: f64) -> Self {
+        Self {
+            temperature,
+            top_k: None,
+            top_p: None,
+            rng: Mutex::new(StdRng::from_os_rng()),
+        }
+    }
+
+    /// Always pick the most likely token.
+    pub fn greedy() -> Self {
+        Self::new(0.0)
+    }
+
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f64) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    /// Reseed the RNG so samples are reproducible.
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            ..self
+        }
+    }
+
+    pub fn is_greedy(&self) -> bool {
+        self.temperature <= 0.0
+    }
+
+    /// Pick a token id from the last position of `logits` (`[vocab]`, `[seq, vocab]` or
+    /// `[batch, seq, vocab]`; leading dimensions are flattened and the final row is used).
+    /// Greedy samplers return the argmax without touching the RNG.
+    pub fn sample(&self, logits: &Tensor) -> Result<u32> {
+        let vocab = logits.dim(D::Minus1)?;
+        ensure!(vocab > 0, "cannot sample from empty logits");
+        let rows = logits.elem_count() / vocab;
+        ensure!(rows > 0, "cannot sample from empty logits");
+        let last = logits.reshape((rows, vocab))?.get(rows - 1)?;
+        if self.is_greedy() {
+            return last
+                .argmax(D::Minus1)?
+                .to_dtype(DType::U32)?
+                .to_scalar::<u32>()
+                .context("failed to convert argmax index to scalar");
+        }
+
+        let logits = last
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()
+            .context("failed to read logits for sampling")?;
+        let mut candidates: Vec<(u32, f64)> = logits
+            .iter()
+            .enumerate()
+            .filter(|(_, logit)| logit.is_finite())
+            .map(|(idx, &logit)| (idx as u32, f64::from(logit) / self.temperature))
+            .collect();
+        ensure!(
+            !candidates.is_empty(),
+            "every token has a non-finite logit; nothing to sample"
+        );
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        if let Some(top_k) = self.top_k {
+            candidates.truncate(top_k.max(1));
+        }
+
+        let max = candidates[0].1;
+        for (_, weight) in candidates.iter_mut() {
+            *weight = (*weight - max).exp();
+        }
+        if let Some(top_p) = self.top_p {
+            let total: f64 = candidates.iter().map(|(_, weight)| weight).sum();
+            let mut cumulative = 0.0;
+            let keep = candidates
+                .iter()
+                .position(|(_, weight)| {
+                    cumulative += weight / total;
+                    cumulative >= top_p
+                })
+                .map_or(candidates.len(), |idx| idx + 1);
+            candidates.truncate(keep);
+        }
+
+        let total: f64 = candidates.iter().map(|(_, weight)| weight).sum();
+        let mut target = self
+            .rng
+            .lock()
+            .expect("sampler RNG lock poisoned")
+            .random::<f64>()
+            * total;
+        for &(token, weight) in &candidates {
+            if target < weight {
+                return Ok(token);
+            }
+            target -= weight;
+        }
+        Ok(candidates[candidates.len() - 1].0)
+    }
+}
+
+impl Clone for LogitsSampler {
+    fn clone(&self) -> Self {
+        let rng = self.rng.lock().expect("sampler RNG lock poisoned").clone();
+        Self {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            rng: Mutex::new(rng),
+        }
+    }
+}
+
+/// Temperature, nucleus cutoff and seed as exposed in settings and request APIs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SamplingParams {
+    /// `0` decodes greedily.
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    /// Seeds the sampler RNG; `None` seeds it from the OS.
+    pub seed: Option<u64>,
+}
+
+impl SamplingParams {
+    /// Sampler for these parameters, `None` when decoding greedily. With a seed, every sampler
+    /// built here starts from the same RNG state, so repeating a generation with the same prompt
+    /// and settings reproduces its tokens.
+    pub fn sampler(&self) -> Option<LogitsSampler> {
+        if self.temperature <= 0.0 {
+            return None;
+        }
+        let mut sampler = LogitsSampler::new(self.temperature);
+        if let Some(top_p) = self.top_p {
+            sampler = sampler.with_top_p(top_p);
+        }
+        if let Some(seed) = self.seed {
+            sampler = sampler.with_seed(seed);
+        }
+        Some(sampler)
+    }
+}
+
+/// Discourages the decoder from looping by rescaling the logits of tokens it already generated:
+/// positive logits are divided by `penalty` and negative ones multiplied by it, so `penalty > 1`
+/// always makes a repeat less likely. `1.0` leaves logits unchanged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepetitionPenalty {
+    penalty: f32,
+}
+
+impl RepetitionPenalty {
+    /// Fails unless `penalty` is a positive finite number; `0` or a negative value would zero or
+    /// flip the sign of every repeated logit.
+    pub fn new(penalty: f32) -> Result<Self> {
+        ensure!(
+            penalty.is_finite() && penalty > 0.0,
+            "repetition penalty must be a positive finite number, got {penalty}"
+        );
+        Ok(Self { penalty })
+    }
+
+    pub fn penalty(&self) -> f32 {
+        self.penalty
+    }
+
+    /// Penalise every id in `generated` (once, however often it occurs) in `[vocab]` logits.
+    /// Returns F32 logits; ids outside the vocabulary are ignored.
+    pub fn apply(&self, logits: &Tensor, generated: &[i64]) -> Result<Tensor> {
+        ensure!(
+            logits.rank() == 1,
+            "repetition penalty expects [vocab] logits (got rank {})",
+            logits.rank()
+        );
+        if generated.is_empty() || self.penalty == 1.0 {
+            return Ok(logits.clone());
+        }
+        let mut values = logits
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()
+            .context("failed to read logits for repetition penalty")?;
+        let mut seen = vec![false; values.len()];
+        for &id in generated {
+            let Some(idx) = usize::try_from(id).ok().filter(|&idx| idx < values.len()) else {
+                continue;
+            };
+            if std::mem::replace(&mut seen[idx], true) {
+                continue;
+            }
+            let value = &mut values[idx];
+            *value = if *value > 0.0 {
+                *value / self.penalty
+            } else {
+                *value * self.penalty
+            };
+        }
+        let vocab = values.len();
+        Ok(Tensor::from_vec(values, vocab, logits.device())?)
+    }
+}
+
+/// Tokens that would complete an `ngram_size`-gram already present in `generated`: every token
+/// that followed an earlier occurrence of the last `ngram_size - 1` tokens. Empty when
+/// `ngram_size` is 0 or longer than the history allows.
+pub fn repeated_ngram_tokens(generated: &[i64], ngram_size: usize) -> Vec<i64> {
+    if ngram_size == 0 || generated.len() < ngram_size {
+        return Vec::new();
+    }
+    let prefix = &generated[generated.len() + 1 - ngram_size..];
+    let mut banned: Vec<i64> = generated
+        .windows(ngram_size)
+        .filter(|window| &window[..ngram_size - 1] == prefix)
+        .map(|window| window[ngram_size - 1])
+        .collect();
+    banned.sort_unstable();
+    banned.dedup();
+    banned
+}
@@ -1,19 +1,69 @@
-use std::fmt::Write as _;
+use std::{fmt::Write as _, sync::Arc};
 
-use crate::config::DeepseekV2Config;
+use crate::{config::DeepseekV2Config, transformer::lora::LoraDelta};
 use anyhow::{Context, Result, ensure};
-use candle_core::Tensor;
+use candle_core::{
+    Tensor,
+    quantized::{GgmlDType, QMatMul, QTensor},
+};
 use candle_nn::VarBuilder;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// In-memory weight format the decoder's linear layers are converted to as they load. Only
+/// safetensors checkpoints are read; pre-quantized GGUF files are not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WeightQuant {
+    /// Symmetric 8-bit blocks of 32 weights with one scale each (GGML `Q8_0`).
+    Int8,
+    /// Symmetric 4-bit blocks of 32 weights with one scale each (GGML `Q4_0`); roughly half the
+    /// footprint of `int8` with a larger accuracy cost.
+    Int4,
+}
+
+impl WeightQuant {
+    fn ggml_dtype(self) -> GgmlDType {
+        match self {
+            WeightQuant::Int8 => GgmlDType::Q8_0,
+            WeightQuant::Int4 => GgmlDType::Q4_0,
+        }
+    }
+
+    /// Bytes an `[out_dim, in_dim]` linear weight occupies once [`LinearWeights::quantize`] has
+    /// run, or `None` when its input dimension does not split into blocks and it stays dense.
+    pub fn linear_bytes(self, out_dim: usize, in_dim: usize) -> Option<u64> {
+        let ggml_dtype = self.ggml_dtype();
+        let block = ggml_dtype.block_size();
+        in_dim
+            .is_multiple_of(block)
+            .then(|| (out_dim * in_dim / block * ggml_dtype.type_size()) as u64)
+    }
+}
 
 /// Fully connected layer weights captured directly from safetensors via [`VarBuilder`].
 #[derive(Debug, Clone)]
 pub struct LinearWeights {
+    /// Dense `[out, in]` weight. Once [`LinearWeights::quantize`] has run this is a zero-strided
+    /// placeholder that only carries the shape and dtype; the values live in `qmatmul`.
     pub weight: Tensor,
     pub bias: Option<Tensor>,
+    /// Quantized kernel used in place of `weight` when set.
+    pub qmatmul: Option<QMatMul>,
+    /// LoRA updates added to the output, see [`TransformerWeights::attach_lora`].
+    pub lora: Vec<LoraDelta>,
 }
 
 impl LinearWeights {
-    fn load(vb: &VarBuilder, out_dim: usize, in_dim: usize, bias: bool) -> Result<Self> {
+    /// Load the layer, quantizing it right away when `quant` is set so only one dense weight is
+    /// resident at a time.
+    fn load(
+        vb: &VarBuilder,
+        out_dim: usize,
+        in_dim: usize,
+        bias: bool,
+        quant: Option<WeightQuant>,
+    ) -> Result<Self> {
         let weight = vb
             .get((out_dim, in_dim), "weight")
             .with_context(|| format!("missing linear weight `{}`", qualified_name(vb, "weight")))?;
@@ -25,7 +75,43 @@ impl LinearWeights {
             } else {
                 None
             };
-        Ok(Self { weight, bias })
+        let mut linear = Self {
+            weight,
+            bias,
+            qmatmul: None,
+            lora: Vec::new(),
+        };
+        if let Some(quant) = quant {
+            linear.quantize(quant)?;
+        }
+        Ok(linear)
+    }
+
+    /// Replace the dense weight with a quantized copy and return the number of bytes freed.
+    ///
+    /// Layers whose input dimension is not a multiple of the quantization block size are left
+    /// untouched and report zero.
+    pub fn quantize(&mut self, quant: WeightQuant) -> Result<usize> {
+        if self.qmatmul.is_some() {
+            return Ok(0);
+        }
+        let ggml_dtype = quant.ggml_dtype();
+        let (out_dim, in_dim) = self
+            .weight
+            .shape()
+            .dims2()
+            .context("linear weights must be 2D")?;
+        if in_dim % ggml_dtype.block_size() != 0 {
+            return Ok(0);
+        }
+        let dense_bytes = self.weight.elem_count() * self.weight.dtype().size_in_bytes();
+        let qtensor = QTensor::quantize(&self.weight, ggml_dtype)
+            .with_context(|| format!("failed to quantize linear weight {out_dim}x{in_dim}"))?;
+        let quantized_bytes = qtensor.storage_size_in_bytes();
+        self.qmatmul = Some(QMatMul::from_arc(Arc::new(qtensor))?);
+        self.weight = Tensor::zeros((), self.weight.dtype(), self.weight.device())?
+            .broadcast_as((out_dim, in_dim))?;
+        Ok(dense_bytes.saturating_sub(quantized_bytes))
     }
 }
 
@@ -52,7 +138,7 @@ pub struct AttentionWeights {
 }
 
 impl AttentionWeights {
-    fn load(cfg: &DeepseekV2Config, vb: &VarBuilder) -> Result<Self> {
+    fn load(cfg: &DeepseekV2Config, vb: &VarBuilder, quant: Option<WeightQuant>) -> Result<Self> {
         let hidden_size = cfg.hidden_size;
         let num_heads = cfg.num_attention_heads;
         ensure!(
@@ -70,24 +156,28 @@ impl AttentionWeights {
             num_heads * head_dim,
             hidden_size,
             true,
+            quant,
         )?;
         let k_proj = LinearWeights::load(
             &attn_vb.pp("k_proj"),
             num_kv_heads * kv_head_dim,
             hidden_size,
             true,
+            quant,
         )?;
         let v_proj = LinearWeights::load(
             &attn_vb.pp("v_proj"),
             num_kv_heads * v_head_dim,
             hidden_size,
             true,
+            quant,
         )?;
         let o_proj = LinearWeights::load(
             &attn_vb.pp("o_proj"),
             hidden_size,
             num_heads * v_head_dim,
             true,
+            quant,
         )?;
         Ok(Self {
             q_proj,
@@ -96,6 +186,19 @@ impl AttentionWeights {
             o_proj,
         })
     }
+
+    fn quantize(&mut self, quant: WeightQuant) -> Result<usize> {
+        let mut saved = 0;
+        for proj in [
+            &mut self.q_proj,
+            &mut self.k_proj,
+            &mut self.v_proj,
+            &mut self.o_proj,
+        ] {
+            saved += proj.quantize(quant)?;
+        }
+        Ok(saved)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -106,18 +209,56 @@ pub struct DenseMlpWeights {
 }
 
 impl DenseMlpWeights {
-    fn load(vb: &VarBuilder, hidden_size: usize, intermediate_size: usize) -> Result<Self> {
-        let gate_proj =
-            LinearWeights::load(&vb.pp("gate_proj"), intermediate_size, hidden_size, true)?;
-        let up_proj = LinearWeights::load(&vb.pp("up_proj"), intermediate_size, hidden_size, true)?;
-        let down_proj =
-            LinearWeights::load(&vb.pp("down_proj"), hidden_size, intermediate_size, true)?;
+    fn load(
+        vb: &VarBuilder,
+        hidden_size: usize,
+        intermediate_size: usize,
+        quant: Option<WeightQuant>,
+    ) -> Result<Self> {
+        let gate_proj = LinearWeights::load(
+            &vb.pp("gate_proj"),
+            intermediate_size,
+            hidden_size,
+            true,
+            quant,
+        )?;
+        let up_proj = LinearWeights::load(
+            &vb.pp("up_proj"),
+            intermediate_size,
+            hidden_size,
+            true,
+            quant,
+        )?;
+        let down_proj = LinearWeights::load(
+            &vb.pp("down_proj"),
+            hidden_size,
+            intermediate_size,
+            true,
+            quant,
+        )?;
         Ok(Self {
             gate_proj,
             up_proj,
             down_proj,
         })
     }
+
+    pub(crate) fn projection_mut(&mut self, name: &str) -> Option<&mut LinearWeights> {
+        match name {
+            "gate_proj" => Some(&mut self.gate_proj),
+            "up_proj" => Some(&mut self.up_proj),
+            "down_proj" => Some(&mut self.down_proj),
+            _ => None,
+        }
+    }
+
+    fn quantize(&mut self, quant: WeightQuant) -> Result<usize> {
+        let mut saved = 0;
+        for proj in [&mut self.gate_proj, &mut self.up_proj, &mut self.down_proj] {
+            saved += proj.quantize(quant)?;
+        }
+        Ok(saved)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -129,7 +270,12 @@ pub struct MoeWeights {
 }
 
 impl MoeWeights {
-    fn load(cfg: &DeepseekV2Config, layer_idx: usize, vb: &VarBuilder) -> Result<Self> {
+    fn load(
+        cfg: &DeepseekV2Config,
+        layer_idx: usize,
+        vb: &VarBuilder,
+        quant: Option<WeightQuant>,
+    ) -> Result<Self> {
         let hidden_size = cfg.hidden_size;
         let moe_intermediate_size = cfg
             .moe_intermediate_size
@@ -158,10 +304,11 @@ impl MoeWeights {
         let mut experts = Vec::with_capacity(num_routed);
         for expert_idx in 0..num_routed {
             let expert_vb = vb.pp(format!("experts.{expert_idx}"));
-            let expert = DenseMlpWeights::load(&expert_vb, hidden_size, moe_intermediate_size)
-                .with_context(|| {
-                    format!("failed to load MoE expert {expert_idx} (layer {layer_idx})")
-                })?;
+            let expert =
+                DenseMlpWeights::load(&expert_vb, hidden_size, moe_intermediate_size, quant)
+                    .with_context(|| {
+                        format!("failed to load MoE expert {expert_idx} (layer {layer_idx})")
+                    })?;
             experts.push(expert);
         }
 
@@ -169,9 +316,9 @@ impl MoeWeights {
             let vb = vb.pp("shared_experts");
             let intermediate = moe_intermediate_size * count;
             Some(
-                DenseMlpWeights::load(&vb, hidden_size, intermediate).with_context(|| {
-                    format!("failed to load shared_experts for layer {layer_idx}")
-                })?,
+                DenseMlpWeights::load(&vb, hidden_size, intermediate, quant).with_context(
+                    || format!("failed to load shared_experts for layer {layer_idx}"),
+                )?,
             )
         } else {
             None
@@ -184,6 +331,15 @@ impl MoeWeights {
             aux_bias,
         })
     }
+
+    /// Quantize expert projections. The router gate stays dense since its scores pick experts.
+    fn quantize(&mut self, quant: WeightQuant) -> Result<usize> {
+        let mut saved = 0;
+        for expert in self.experts.iter_mut().chain(self.shared_experts.as_mut()) {
+            saved += expert.quantize(quant)?;
+        }
+        Ok(saved)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -193,13 +349,25 @@ pub enum MlpWeights {
 }
 
 impl MlpWeights {
-    fn load(cfg: &DeepseekV2Config, layer_idx: usize, vb: &VarBuilder) -> Result<Self> {
+    fn load(
+        cfg: &DeepseekV2Config,
+        layer_idx: usize,
+        vb: &VarBuilder,
+        quant: Option<WeightQuant>,
+    ) -> Result<Self> {
         let hidden_size = cfg.hidden_size;
         let intermediate_size = cfg.intermediate_size;
         if should_use_moe(cfg, layer_idx) {
-            MoeWeights::load(cfg, layer_idx, vb).map(MlpWeights::Moe)
+            MoeWeights::load(cfg, layer_idx, vb, quant).map(MlpWeights::Moe)
         } else {
-            DenseMlpWeights::load(vb, hidden_size, intermediate_size).map(MlpWeights::Dense)
+            DenseMlpWeights::load(vb, hidden_size, intermediate_size, quant).map(MlpWeights::Dense)
+        }
+    }
+
+    fn quantize(&mut self, quant: WeightQuant) -> Result<usize> {
+        match self {
+            MlpWeights::Dense(dense) => dense.quantize(quant),
+            MlpWeights::Moe(moe) => moe.quantize(quant),
         }
     }
 }
@@ -214,8 +382,18 @@ pub struct TransformerBlockWeights {
 
 impl TransformerBlockWeights {
     pub fn load(cfg: &DeepseekV2Config, layer_idx: usize, vb: &VarBuilder) -> Result<Self> {
-        let attention = AttentionWeights::load(cfg, vb)?;
-        let mlp = MlpWeights::load(cfg, layer_idx, &vb.pp("mlp"))?;
+        Self::load_quantized(cfg, layer_idx, vb, None)
+    }
+
+    /// Like [`Self::load`], quantizing each attention and MLP projection as it is read.
+    pub fn load_quantized(
+        cfg: &DeepseekV2Config,
+        layer_idx: usize,
+        vb: &VarBuilder,
+        quant: Option<WeightQuant>,
+    ) -> Result<Self> {
+        let attention = AttentionWeights::load(cfg, vb, quant)?;
+        let mlp = MlpWeights::load(cfg, layer_idx, &vb.pp("mlp"), quant)?;
         let input_layernorm = RmsNormWeights::load(&vb.pp("input_layernorm"), cfg.hidden_size)?;
         let post_attention_layernorm =
             RmsNormWeights::load(&vb.pp("post_attention_layernorm"), cfg.hidden_size)?;
@@ -235,15 +413,40 @@ pub struct TransformerWeights {
 
 impl TransformerWeights {
     pub fn load(cfg: &DeepseekV2Config, vb: &VarBuilder) -> Result<Self> {
+        Self::load_quantized(cfg, vb, None)
+    }
+
+    /// Like [`Self::load`], quantizing each projection as soon as it is read, so peak memory
+    /// holds the quantized decoder plus a single dense weight rather than the whole dense
+    /// decoder.
+    pub fn load_quantized(
+        cfg: &DeepseekV2Config,
+        vb: &VarBuilder,
+        quant: Option<WeightQuant>,
+    ) -> Result<Self> {
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
         for layer_idx in 0..cfg.num_hidden_layers {
             let layer_vb = vb.pp(format!("layers.{layer_idx}"));
-            let layer = TransformerBlockWeights::load(cfg, layer_idx, &layer_vb)
-                .with_context(|| format!("failed to load transformer layer `{layer_idx}`"))?;
+            let layer =
+                TransformerBlockWeights::load_quantized(cfg, layer_idx, &layer_vb, quant)
+                    .with_context(|| format!("failed to load transformer layer `{layer_idx}`"))?;
             layers.push(layer);
         }
         Ok(Self { layers })
     }
+
+    /// Quantize every attention and MLP projection in place, returning the bytes freed.
+    ///
+    /// RMSNorm weights, embeddings and the LM head are owned elsewhere and keep the compute dtype.
+    pub fn quantize(&mut self, quant: WeightQuant) -> Result<usize> {
+        let mut saved = 0;
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
+            let context = || format!("failed to quantize transformer layer `{layer_idx}`");
+            saved += layer.attention.quantize(quant).with_context(context)?;
+            saved += layer.mlp.quantize(quant).with_context(context)?;
+        }
+        Ok(saved)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -256,6 +459,16 @@ pub struct DeepseekLanguageModelWeights {
 
 impl DeepseekLanguageModelWeights {
     pub fn load(cfg: &DeepseekV2Config, vb: &VarBuilder) -> Result<Self> {
+        Self::load_quantized(cfg, vb, None)
+    }
+
+    /// Like [`Self::load`], quantizing the decoder projections while they load (see
+    /// [`TransformerWeights::load_quantized`]).
+    pub fn load_quantized(
+        cfg: &DeepseekV2Config,
+        vb: &VarBuilder,
+        quant: Option<WeightQuant>,
+    ) -> Result<Self> {
         let model_vb = vb.pp("model");
         let token_embedding = model_vb
             .pp("embed_tokens")
@@ -267,7 +480,7 @@ impl DeepseekLanguageModelWeights {
                 )
             })?;
         let token_embedding = token_embedding.contiguous()?;
-        let transformer = TransformerWeights::load(cfg, &model_vb)?;
+        let transformer = TransformerWeights::load_quantized(cfg, &model_vb, quant)?;
         let final_layernorm = RmsNormWeights::load(&model_vb.pp("norm"), cfg.hidden_size)
             .with_context(|| {
                 format!(
@@ -304,7 +517,7 @@ impl DeepseekLanguageModelWeights {
     }
 }
 
-fn should_use_moe(cfg: &DeepseekV2Config, layer_idx: usize) -> bool {
+pub(crate) fn should_use_moe(cfg: &DeepseekV2Config, layer_idx: usize) -> bool {
     let num_routed = cfg.n_routed_experts.unwrap_or(0);
     if num_routed == 0 {
         return false;
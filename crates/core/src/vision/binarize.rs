@@ -0,0 +1,98 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use image::{DynamicImage, GrayImage, Luma, imageops};
+use serde::{Deserialize, Serialize};
+
+use super::pipeline::Preprocessor;
+
+/// Gaussian sigma, in pixels, of the neighbourhood adaptive thresholding compares against.
+const ADAPTIVE_SIGMA: f32 = 8.0;
+/// A pixel must be this much darker than its neighbourhood mean to count as ink.
+const ADAPTIVE_OFFSET: i16 = 8;
+
+/// How [`Binarize`] picks the black/white threshold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BinarizeMethod {
+    /// One global threshold chosen by Otsu's method. Fast; suits evenly lit scans.
+    #[default]
+    Otsu,
+    /// Per-pixel threshold against a Gaussian-weighted local mean. Handles shadows and uneven
+    /// lighting at the cost of a blur pass.
+    Adaptive,
+}
+
+/// Convert to a clean black-on-white image. Opt-in: it discards colour and greyscale detail,
+/// which can hurt photos and colour documents.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Binarize {
+    pub method: BinarizeMethod,
+}
+
+impl Preprocessor for Binarize {
+    fn name(&self) -> &str {
+        "binarize"
+    }
+
+    fn process(&self, image: DynamicImage) -> Result<DynamicImage> {
+        let luma = image.to_luma8();
+        let binary = match self.method {
+            BinarizeMethod::Otsu => {
+                let threshold = otsu_threshold(&luma);
+                threshold_with(&luma, |_, _, value| value > threshold)
+            }
+            BinarizeMethod::Adaptive => {
+                let mean = imageops::blur(&luma, ADAPTIVE_SIGMA);
+                threshold_with(&luma, |x, y, value| {
+                    value as i16 > mean.get_pixel(x, y).0[0] as i16 - ADAPTIVE_OFFSET
+                })
+            }
+        };
+        Ok(DynamicImage::ImageRgb8(
+            DynamicImage::ImageLuma8(binary).to_rgb8(),
+        ))
+    }
+}
+
+/// Luma level maximising the between-class variance of the histogram; pixels above it are
+/// background.
+pub fn otsu_threshold(image: &GrayImage) -> u8 {
+    let mut histogram = [0u64; 256];
+    for pixel in image.pixels() {
+        histogram[pixel.0[0] as usize] += 1;
+    }
+    let total: u64 = histogram.iter().sum();
+    let weighted_total: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(level, &count)| level as f64 * count as f64)
+        .sum();
+
+    let (mut dark, mut weighted_dark) = (0u64, 0f64);
+    let mut best = (0u8, f64::MIN);
+    for (level, &count) in histogram.iter().enumerate() {
+        dark += count;
+        weighted_dark += level as f64 * count as f64;
+        let light = total - dark;
+        if dark == 0 || light == 0 {
+            continue;
+        }
+        let mean_dark = weighted_dark / dark as f64;
+        let mean_light = (weighted_total - weighted_dark) / light as f64;
+        let variance = dark as f64 * light as f64 * (mean_dark - mean_light).powi(2);
+        if variance > best.1 {
+            best = (level as u8, variance);
+        }
+    }
+    best.0
+}
+
+fn threshold_with(image: &GrayImage, is_background: impl Fn(u32, u32, u8) -> bool) -> GrayImage {
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        if is_background(x, y, image.get_pixel(x, y).0[0]) {
+            Luma([255])
+        } else {
+            Luma([0])
+        }
+    })
+}
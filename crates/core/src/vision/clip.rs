@@ -454,7 +454,12 @@ fn load_linear(
     } else {
         None
     };
-    Ok(LinearWeights { weight, bias })
+    Ok(LinearWeights {
+        weight,
+        bias,
+        qmatmul: None,
+        lora: Vec::new(),
+    })
 }
 
 fn adapt_position_embedding(table: &Tensor, target_tokens: usize) -> Result<Tensor> {
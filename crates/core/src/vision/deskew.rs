@@ -0,0 +1,159 @@
+use anyhow::Result;
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage, imageops::FilterType};
+
+use super::pipeline::{PreprocessStats, Preprocessor};
+
+/// Luma value below which a pixel counts as ink when scoring candidate angles.
+const INK_LUMA: u8 = 160;
+/// Long side of the thumbnail the skew is estimated on.
+const ESTIMATE_SIZE: u32 = 1024;
+const COARSE_STEP: f32 = 0.25;
+const FINE_STEP: f32 = 0.025;
+/// Corrections smaller than this are not worth resampling the image for.
+const MIN_CORRECTION: f32 = 0.05;
+
+/// Straighten slightly rotated scans; see [`estimate_skew`].
+#[derive(Debug, Clone, Copy)]
+pub struct Deskew {
+    /// Largest skew in degrees, either direction, that is searched for and corrected.
+    pub max_angle: f32,
+}
+
+impl Default for Deskew {
+    fn default() -> Self {
+        Self { max_angle: 5.0 }
+    }
+}
+
+impl Preprocessor for Deskew {
+    fn name(&self) -> &str {
+        "deskew"
+    }
+
+    fn process(&self, image: DynamicImage) -> Result<DynamicImage> {
+        Ok(deskew(image, self.max_angle).0)
+    }
+
+    fn process_with_stats(
+        &self,
+        image: DynamicImage,
+        stats: &mut PreprocessStats,
+    ) -> Result<DynamicImage> {
+        let (image, angle) = deskew(image, self.max_angle);
+        stats.deskew_degrees = Some(angle);
+        Ok(image)
+    }
+}
+
+/// Estimate the skew of `image` and rotate it back to level, returning the straightened image and
+/// the detected skew in degrees (see [`estimate_skew`] for the sign convention).
+pub fn deskew(image: DynamicImage, max_angle: f32) -> (DynamicImage, f32) {
+    let angle = estimate_skew(&image, max_angle);
+    if angle.abs() < MIN_CORRECTION {
+        return (image, angle);
+    }
+    let rotated = rotate_about_center(&image.to_rgb8(), angle.to_radians());
+    (DynamicImage::ImageRgb8(rotated), angle)
+}
+
+/// Estimate the dominant text-line angle in degrees within `[-max_angle, max_angle]`.
+///
+/// Uses a projection profile: dark pixels are projected onto the vertical axis at each candidate
+/// angle and the angle with the sharpest row histogram (highest sum of squared bin counts) wins,
+/// first on a coarse grid and then refined around the best candidate. Positive angles mean text
+/// lines slope downward to the right. Returns `0.0` for blank images or when `max_angle` is not
+/// positive.
+pub fn estimate_skew(image: &DynamicImage, max_angle: f32) -> f32 {
+    let max_angle = if max_angle.is_finite() {
+        max_angle.min(45.0)
+    } else {
+        0.0
+    };
+    if max_angle <= 0.0 {
+        return 0.0;
+    }
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return 0.0;
+    }
+    let luma = if width.max(height) > ESTIMATE_SIZE {
+        image
+            .resize(ESTIMATE_SIZE, ESTIMATE_SIZE, FilterType::Triangle)
+            .to_luma8()
+    } else {
+        image.to_luma8()
+    };
+    let ink: Vec<(f32, f32)> = luma
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.0[0] < INK_LUMA)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect();
+    if ink.is_empty() {
+        return 0.0;
+    }
+
+    let span = luma.width() + luma.height();
+    let coarse = best_angle(&ink, span, -max_angle, max_angle, COARSE_STEP);
+    best_angle(
+        &ink,
+        span,
+        (coarse - COARSE_STEP).max(-max_angle),
+        (coarse + COARSE_STEP).min(max_angle),
+        FINE_STEP,
+    )
+}
+
+fn best_angle(ink: &[(f32, f32)], span: u32, from: f32, to: f32, step: f32) -> f32 {
+    let steps = ((to - from) / step).round() as i32;
+    let mut best = (0.0, f64::MIN);
+    let mut bins = vec![0u32; 2 * span as usize + 1];
+    for index in 0..=steps {
+        let angle = from + index as f32 * step;
+        let (sin, cos) = angle.to_radians().sin_cos();
+        bins.iter_mut().for_each(|bin| *bin = 0);
+        for &(x, y) in ink {
+            let row = (y * cos - x * sin).round() as i64 + span as i64;
+            bins[row as usize] += 1;
+        }
+        let score: f64 = bins.iter().map(|&count| (count as f64).powi(2)).sum();
+        // Prefer the smallest correction on ties so level images stay untouched.
+        if score > best.1 || (score == best.1 && angle.abs() < f32::abs(best.0)) {
+            best = (angle, score);
+        }
+    }
+    best.0
+}
+
+/// Rotate by `-radians` around the image centre on a same-sized white canvas (bilinear sampling).
+fn rotate_about_center(image: &RgbImage, radians: f32) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let (sin, cos) = radians.sin_cos();
+    let (cx, cy) = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+    RgbImage::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let sx = dx * cos - dy * sin + cx;
+        let sy = dx * sin + dy * cos + cy;
+        sample_bilinear(image, sx, sy)
+    })
+}
+
+fn sample_bilinear(image: &RgbImage, x: f32, y: f32) -> Rgb<u8> {
+    let (width, height) = image.dimensions();
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let mut out = [0f32; 3];
+    for (oy, wy) in [(0, 1.0 - fy), (1, fy)] {
+        for (ox, wx) in [(0, 1.0 - fx), (1, fx)] {
+            let (px, py) = (x0 as i64 + ox, y0 as i64 + oy);
+            let pixel = if px >= 0 && py >= 0 && px < width as i64 && py < height as i64 {
+                image.get_pixel(px as u32, py as u32).0
+            } else {
+                [255; 3]
+            };
+            for (channel, value) in out.iter_mut().zip(pixel) {
+                *channel += wx * wy * value as f32;
+            }
+        }
+    }
+    Rgb(out.map(|value| value.round().clamp(0.0, 255.0) as u8))
+}
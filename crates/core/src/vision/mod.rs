@@ -1,8 +1,27 @@
+pub mod binarize;
 pub mod clip;
+pub mod deskew;
+pub mod orientation;
+pub mod pagebreak;
+#[cfg(feature = "pdf")]
+pub mod pdf;
+pub mod pipeline;
 pub mod preprocess;
 pub mod resample;
 pub mod sam;
 
+pub use binarize::{Binarize, BinarizeMethod, otsu_threshold};
 pub use clip::{ClipDebugTrace, ClipVisionModel, ClipVisionParams};
-pub use preprocess::{DynamicPreprocessResult, dynamic_preprocess};
+pub use deskew::{Deskew, deskew, estimate_skew};
+pub use orientation::{apply_exif_orientation, load_image, load_image_from_memory};
+pub use pagebreak::{ImageSlice, PageBreakOptions, detect_page_breaks, detect_page_breaks_with};
+#[cfg(feature = "pdf")]
+pub use pdf::{DEFAULT_PDF_DPI, PdfRenderOptions, for_each_pdf_page, render_pdf, render_pdf_file};
+pub use pipeline::{
+    BuiltinPreprocessor, ContrastNormalize, Grayscale, PreprocessOptions, PreprocessPipeline,
+    PreprocessStats, Preprocessor,
+};
+pub use preprocess::{
+    DynamicPreprocessResult, dynamic_preprocess, dynamic_preprocess_with_strips, select_tile_grid,
+};
 pub use sam::{SamBackbone, SamBackboneParams, SamDebugTrace};
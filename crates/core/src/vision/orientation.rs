@@ -0,0 +1,59 @@
+use std::{
+    io::{BufRead, Cursor, Seek},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageDecoder, ImageReader, metadata::Orientation};
+
+/// Decode an image from disk, optionally applying the EXIF orientation stored in its metadata.
+///
+/// The `image` crate never rotates decoded pixels on its own, so phone photos of documents would
+/// otherwise reach the vision stack sideways or mirrored.
+pub fn load_image(path: &Path, apply_exif_orientation: bool) -> Result<DynamicImage> {
+    let reader = ImageReader::open(path)
+        .with_context(|| format!("failed to open image at {}", path.display()))?
+        .with_guessed_format()
+        .with_context(|| format!("failed to detect image format for {}", path.display()))?;
+    decode_with_orientation(reader, apply_exif_orientation)
+        .with_context(|| format!("failed to decode image at {}", path.display()))
+}
+
+/// In-memory counterpart of [`load_image`].
+pub fn load_image_from_memory(bytes: &[u8], apply_exif_orientation: bool) -> Result<DynamicImage> {
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .context("failed to detect image format")?;
+    decode_with_orientation(reader, apply_exif_orientation)
+}
+
+/// Rotate/flip `image` according to a raw EXIF orientation value (1-8). Unknown values are
+/// treated as "no transform".
+pub fn apply_exif_orientation(mut image: DynamicImage, exif_orientation: u8) -> DynamicImage {
+    if let Some(orientation) = Orientation::from_exif(exif_orientation) {
+        image.apply_orientation(orientation);
+    }
+    image
+}
+
+fn decode_with_orientation<R>(
+    reader: ImageReader<R>,
+    apply_exif_orientation: bool,
+) -> Result<DynamicImage>
+where
+    R: BufRead + Seek,
+{
+    let mut decoder = reader.into_decoder()?;
+    let orientation = if apply_exif_orientation {
+        // Malformed EXIF blocks should not make an otherwise valid image unreadable.
+        decoder.orientation().unwrap_or(Orientation::NoTransforms)
+    } else {
+        Orientation::NoTransforms
+    };
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    if !matches!(orientation, Orientation::NoTransforms) {
+        tracing::debug!("Applying EXIF orientation {orientation:?}");
+        image.apply_orientation(orientation);
+    }
+    Ok(image)
+}
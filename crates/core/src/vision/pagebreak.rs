@@ -0,0 +1,110 @@
+use image::{DynamicImage, GenericImageView};
+
+/// Luma value below which a pixel counts as ink when profiling rows.
+const INK_LUMA: u8 = 160;
+
+/// Heuristic knobs for [`detect_page_breaks_with`].
+#[derive(Debug, Clone, Copy)]
+pub struct PageBreakOptions {
+    /// Minimum run of blank rows (in pixels) treated as a gap between pages.
+    pub min_gap_height: u32,
+    /// A row is blank when at most this fraction of its pixels is ink (0.0 - 1.0).
+    pub threshold: f32,
+    /// Sections shorter than this are merged into their neighbour instead of OCR'd alone.
+    pub min_section_height: u32,
+}
+
+impl Default for PageBreakOptions {
+    fn default() -> Self {
+        Self {
+            min_gap_height: 48,
+            threshold: 0.01,
+            min_section_height: 256,
+        }
+    }
+}
+
+/// A horizontal band cut out of a taller image.
+#[derive(Debug, Clone)]
+pub struct ImageSlice {
+    /// First row of the band in the source image.
+    pub top: u32,
+    pub height: u32,
+    pub image: DynamicImage,
+}
+
+/// Split a tall scan of stacked pages into page-sized sections using [`PageBreakOptions::default`].
+pub fn detect_page_breaks(image: &DynamicImage) -> Vec<ImageSlice> {
+    detect_page_breaks_with(image, &PageBreakOptions::default())
+}
+
+/// Split `image` at wide horizontal whitespace bands.
+///
+/// Each row is scored by the fraction of dark pixels it contains; runs of at least
+/// `min_gap_height` blank rows between content become cut points, cut through the middle of the
+/// gap so every section keeps some margin. This is a row-projection heuristic for simple stacked
+/// scans, not layout analysis: multi-column pages and dark backgrounds are not handled. An image
+/// without qualifying gaps comes back as a single slice.
+pub fn detect_page_breaks_with(
+    image: &DynamicImage,
+    options: &PageBreakOptions,
+) -> Vec<ImageSlice> {
+    let (width, height) = image.dimensions();
+    let cuts = find_cut_rows(image, options);
+    let mut bounds = Vec::with_capacity(cuts.len() + 2);
+    bounds.push(0);
+    bounds.extend(cuts);
+    bounds.push(height);
+
+    let min_section = options.min_section_height.max(1);
+    let mut sections: Vec<(u32, u32)> = Vec::new();
+    for window in bounds.windows(2) {
+        let (top, bottom) = (window[0], window[1]);
+        match sections.last_mut() {
+            Some(last) if last.1 - last.0 < min_section || bottom - top < min_section => {
+                last.1 = bottom;
+            }
+            _ => sections.push((top, bottom)),
+        }
+    }
+
+    sections
+        .into_iter()
+        .map(|(top, bottom)| ImageSlice {
+            top,
+            height: bottom - top,
+            image: image.crop_imm(0, top, width, bottom - top),
+        })
+        .collect()
+}
+
+fn find_cut_rows(image: &DynamicImage, options: &PageBreakOptions) -> Vec<u32> {
+    let luma = image.to_luma8();
+    let (width, height) = luma.dimensions();
+    if width == 0 || height == 0 {
+        return Vec::new();
+    }
+    let max_ink = (options.threshold.clamp(0.0, 1.0) * width as f32).floor() as usize;
+    let blank: Vec<bool> = luma
+        .rows()
+        .map(|row| row.filter(|pixel| pixel.0[0] < INK_LUMA).count() <= max_ink)
+        .collect();
+
+    let mut cuts = Vec::new();
+    let mut seen_content = false;
+    let mut gap_start = None;
+    for (row, &is_blank) in blank.iter().enumerate() {
+        if is_blank {
+            gap_start.get_or_insert(row);
+            continue;
+        }
+        if let Some(start) = gap_start.take() {
+            // Margins above the first content row are not page breaks.
+            if seen_content && (row - start) as u32 >= options.min_gap_height.max(1) {
+                cuts.push(((start + row) / 2) as u32);
+            }
+        }
+        seen_content = true;
+    }
+    cuts
+}
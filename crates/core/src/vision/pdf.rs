@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use image::DynamicImage;
+use pdfium_render::prelude::{
+    PdfRenderConfig, Pdfium, PdfiumError, PdfiumInternalError, PdfiumLibraryBindings,
+};
+
+/// Resolution pages are rendered at by default; high enough for body text in scans.
+pub const DEFAULT_PDF_DPI: f32 = 144.0;
+
+/// How PDF pages are rasterised before OCR.
+///
+/// Rendering uses the pdfium shared library, loaded at runtime: from `library_path` when set
+/// (a directory holding the platform's `libpdfium` or the library file itself), otherwise from
+/// the working directory and then the system library path.
+#[derive(Debug, Clone)]
+pub struct PdfRenderOptions {
+    /// Pixels per inch. PDF pages are measured in points (1/72 inch), so an A4 page at 144 DPI
+    /// renders to 1190x1684.
+    pub dpi: f32,
+    pub library_path: Option<PathBuf>,
+}
+
+impl Default for PdfRenderOptions {
+    fn default() -> Self {
+        Self::new(DEFAULT_PDF_DPI)
+    }
+}
+
+impl PdfRenderOptions {
+    pub fn new(dpi: f32) -> Self {
+        Self {
+            dpi,
+            library_path: None,
+        }
+    }
+
+    pub fn with_library_path(mut self, library_path: impl Into<PathBuf>) -> Self {
+        self.library_path = Some(library_path.into());
+        self
+    }
+}
+
+/// Render every page of the PDF in `bytes`, in page order.
+///
+/// Every page is held in memory at once; use [`for_each_pdf_page`] to process long documents
+/// page by page. Encrypted PDFs that need a password to open are rejected.
+pub fn render_pdf(bytes: &[u8], options: &PdfRenderOptions) -> Result<Vec<DynamicImage>> {
+    let mut pages = Vec::new();
+    for_each_pdf_page(bytes, options, |_, _, image| {
+        pages.push(image);
+        Ok(())
+    })?;
+    Ok(pages)
+}
+
+/// File counterpart of [`render_pdf`].
+pub fn render_pdf_file(path: &Path, options: &PdfRenderOptions) -> Result<Vec<DynamicImage>> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read PDF at {}", path.display()))?;
+    render_pdf(&bytes, options).with_context(|| format!("failed to render {}", path.display()))
+}
+
+/// Render the pages of the PDF in `bytes` one at a time and hand each to `visit` with its
+/// zero-based index and the document's page count, in page order. Only the page being visited is
+/// kept in memory.
+pub fn for_each_pdf_page(
+    bytes: &[u8],
+    options: &PdfRenderOptions,
+    mut visit: impl FnMut(usize, usize, DynamicImage) -> Result<()>,
+) -> Result<()> {
+    ensure!(
+        options.dpi.is_finite() && options.dpi > 0.0,
+        "PDF render DPI must be positive, got {}",
+        options.dpi
+    );
+    let pdfium = Pdfium::new(bind_pdfium(options.library_path.as_deref())?);
+    let document = pdfium
+        .load_pdf_from_byte_slice(bytes, None)
+        .map_err(|err| match err {
+            PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError) => {
+                anyhow!("PDF is encrypted; remove its password before running OCR")
+            }
+            err => anyhow!("failed to open PDF: {err}"),
+        })?;
+    let config = PdfRenderConfig::new().scale_page_by_factor(options.dpi / 72.0);
+    let pages = document.pages();
+    let page_count = usize::from(pages.len());
+    for (index, page) in pages.iter().enumerate() {
+        let bitmap = page
+            .render_with_config(&config)
+            .map_err(|err| anyhow!("failed to render PDF page {}: {err}", index + 1))?;
+        visit(index, page_count, bitmap.as_image())?;
+    }
+    Ok(())
+}
+
+fn bind_pdfium(library_path: Option<&Path>) -> Result<Box<dyn PdfiumLibraryBindings>> {
+    match library_path {
+        Some(path) => {
+            let path = if path.is_dir() {
+                Pdfium::pdfium_platform_library_name_at_path(path)
+            } else {
+                path.to_path_buf()
+            };
+            Pdfium::bind_to_library(&path)
+                .map_err(|err| anyhow!("failed to load pdfium from {}: {err}", path.display()))
+        }
+        None => Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./"))
+            .or_else(|_| Pdfium::bind_to_system_library())
+            .map_err(|err| {
+                anyhow!(
+                    "pdfium library not found in the working directory or on the system library \
+                     path ({err}); set PdfRenderOptions::library_path"
+                )
+            }),
+    }
+}
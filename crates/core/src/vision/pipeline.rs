@@ -0,0 +1,228 @@
+use std::{fmt, sync::Arc};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    binarize::{Binarize, BinarizeMethod},
+    deskew::Deskew,
+};
+
+/// One image-to-image step run before the global view and crops are built.
+///
+/// Implement this to plug document-specific cleanup (deskew, denoise, binarize, ...) into
+/// [`PreprocessPipeline`] without touching the vision stack.
+pub trait Preprocessor: Send + Sync {
+    /// Short label used in logs and error messages.
+    fn name(&self) -> &str;
+
+    fn process(&self, image: DynamicImage) -> Result<DynamicImage>;
+
+    /// Like [`process`](Self::process), additionally recording measurements in `stats`. Steps
+    /// with nothing to report can rely on the default.
+    fn process_with_stats(
+        &self,
+        image: DynamicImage,
+        _stats: &mut PreprocessStats,
+    ) -> Result<DynamicImage> {
+        self.process(image)
+    }
+}
+
+/// Measurements collected while running a [`PreprocessPipeline`] over one image.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PreprocessStats {
+    /// Skew detected and corrected by the deskew step, in degrees.
+    pub deskew_degrees: Option<f32>,
+}
+
+/// Settings for the configurable built-in preprocessors.
+#[derive(Debug, Clone, Copy)]
+pub struct PreprocessOptions {
+    /// Largest skew in degrees the deskew step corrects.
+    pub deskew_max_angle: f32,
+    /// Threshold selection used by the binarize step.
+    pub binarize_method: BinarizeMethod,
+}
+
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        Self {
+            deskew_max_angle: Deskew::default().max_angle,
+            binarize_method: BinarizeMethod::default(),
+        }
+    }
+}
+
+/// Ordered chain of [`Preprocessor`]s. The default pipeline is empty and leaves images untouched.
+#[derive(Clone, Default)]
+pub struct PreprocessPipeline {
+    steps: Vec<Arc<dyn Preprocessor>>,
+}
+
+impl PreprocessPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build a pipeline from the built-in steps, in order, using [`PreprocessOptions::default`].
+    pub fn from_builtins(steps: &[BuiltinPreprocessor]) -> Self {
+        Self::from_builtins_with(steps, &PreprocessOptions::default())
+    }
+
+    pub fn from_builtins_with(steps: &[BuiltinPreprocessor], options: &PreprocessOptions) -> Self {
+        Self {
+            steps: steps.iter().map(|step| step.build(options)).collect(),
+        }
+    }
+
+    /// Append a step to the end of the chain.
+    pub fn with<P>(mut self, step: P) -> Self
+    where
+        P: Preprocessor + 'static,
+    {
+        self.steps.push(Arc::new(step));
+        self
+    }
+
+    pub fn push(&mut self, step: Arc<dyn Preprocessor>) {
+        self.steps.push(step);
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Run every step in order.
+    pub fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        self.apply_with_stats(image).map(|(image, _)| image)
+    }
+
+    /// Run every step in order, returning what the steps measured along the way.
+    pub fn apply_with_stats(&self, image: DynamicImage) -> Result<(DynamicImage, PreprocessStats)> {
+        let mut stats = PreprocessStats::default();
+        let image = self.steps.iter().try_fold(image, |image, step| {
+            step.process_with_stats(image, &mut stats)
+                .with_context(|| format!("preprocessor `{}` failed", step.name()))
+        })?;
+        Ok((image, stats))
+    }
+}
+
+impl fmt::Debug for PreprocessPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.steps.iter().map(|step| step.name()))
+            .finish()
+    }
+}
+
+/// Preprocessors shipped with the crate, selectable from config and command-line flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuiltinPreprocessor {
+    /// Convert to grayscale; see [`Grayscale`].
+    Grayscale,
+    /// Stretch contrast to the full range; see [`ContrastNormalize`].
+    Contrast,
+    /// Straighten slightly rotated scans; see [`Deskew`].
+    Deskew,
+    /// Threshold to black-on-white; see [`Binarize`].
+    Binarize,
+}
+
+impl BuiltinPreprocessor {
+    fn build(self, options: &PreprocessOptions) -> Arc<dyn Preprocessor> {
+        match self {
+            BuiltinPreprocessor::Grayscale => Arc::new(Grayscale),
+            BuiltinPreprocessor::Contrast => Arc::new(ContrastNormalize::default()),
+            BuiltinPreprocessor::Deskew => Arc::new(Deskew {
+                max_angle: options.deskew_max_angle,
+            }),
+            BuiltinPreprocessor::Binarize => Arc::new(Binarize {
+                method: options.binarize_method,
+            }),
+        }
+    }
+}
+
+/// Drop colour information. Useful for scans with coloured paper or stamps; the result stays
+/// three-channel so downstream normalisation is unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Grayscale;
+
+impl Preprocessor for Grayscale {
+    fn name(&self) -> &str {
+        "grayscale"
+    }
+
+    fn process(&self, image: DynamicImage) -> Result<DynamicImage> {
+        Ok(DynamicImage::ImageRgb8(
+            DynamicImage::ImageLuma8(image.to_luma8()).to_rgb8(),
+        ))
+    }
+}
+
+/// Linearly stretch luminance so the darkest and brightest `clip_fraction` of pixels map to black
+/// and white, lifting faded or low-contrast scans. The same mapping is applied to every channel.
+#[derive(Debug, Clone, Copy)]
+pub struct ContrastNormalize {
+    /// Fraction of pixels clipped at each end of the histogram (0.0 - 0.5).
+    pub clip_fraction: f32,
+}
+
+impl Default for ContrastNormalize {
+    fn default() -> Self {
+        Self {
+            clip_fraction: 0.01,
+        }
+    }
+}
+
+impl Preprocessor for ContrastNormalize {
+    fn name(&self) -> &str {
+        "contrast"
+    }
+
+    fn process(&self, image: DynamicImage) -> Result<DynamicImage> {
+        let (width, height) = image.dimensions();
+        let total = width as u64 * height as u64;
+        if total == 0 {
+            return Ok(image);
+        }
+        let mut histogram = [0u64; 256];
+        for pixel in image.to_luma8().pixels() {
+            histogram[pixel.0[0] as usize] += 1;
+        }
+        let clip = (self.clip_fraction.clamp(0.0, 0.5) as f64 * total as f64) as u64;
+        let low = clipped_level(&histogram, clip, 0..256);
+        let high = clipped_level(&histogram, clip, (0..256).rev());
+        if high <= low {
+            return Ok(image);
+        }
+        let scale = 255.0 / (high - low);
+        let mut rgb = image.to_rgb8();
+        for channel in rgb.iter_mut() {
+            *channel = ((*channel as f32 - low) * scale).round().clamp(0.0, 255.0) as u8;
+        }
+        Ok(DynamicImage::ImageRgb8(rgb))
+    }
+}
+
+/// First luma level, walking `levels` in order, past which more than `clip` pixels have been seen.
+fn clipped_level(histogram: &[u64; 256], clip: u64, levels: impl Iterator<Item = usize>) -> f32 {
+    let mut seen = 0u64;
+    for level in levels {
+        seen += histogram[level];
+        if seen > clip {
+            return level as f32;
+        }
+    }
+    0.0
+}
@@ -10,15 +10,95 @@ pub struct DynamicPreprocessResult {
     pub ratio: (u32, u32),
 }
 
+/// Longest strip [`select_tile_grid`] produces for elongated images.
+pub const MAX_STRIP_TILES: u32 = 20;
+
 pub fn dynamic_preprocess(
     image: &DynamicImage,
     min_num: u32,
     max_num: u32,
     image_size: u32,
     use_thumbnail: bool,
+) -> DynamicPreprocessResult {
+    dynamic_preprocess_with_strips(image, min_num, max_num, image_size, use_thumbnail, None)
+}
+
+/// [`dynamic_preprocess`] with optional strip tiling for extreme aspect ratios; see
+/// [`select_tile_grid`].
+pub fn dynamic_preprocess_with_strips(
+    image: &DynamicImage,
+    min_num: u32,
+    max_num: u32,
+    image_size: u32,
+    use_thumbnail: bool,
+    strip_aspect_threshold: Option<f32>,
 ) -> DynamicPreprocessResult {
     let (orig_width, orig_height) = image.dimensions();
-    let aspect_ratio = orig_width as f64 / orig_height as f64;
+    let target_aspect_ratio = select_tile_grid(
+        orig_width,
+        orig_height,
+        min_num,
+        max_num,
+        image_size,
+        strip_aspect_threshold,
+    );
+
+    let target_width = image_size * target_aspect_ratio.0;
+    let target_height = image_size * target_aspect_ratio.1;
+    let base_rgb: RgbImage = image.to_rgb8();
+    let resized_rgb = resize_bicubic(&base_rgb, target_width, target_height);
+    let resized = DynamicImage::ImageRgb8(resized_rgb);
+
+    let mut tiles = Vec::new();
+    let tiles_w = target_width / image_size;
+    let tiles_h = target_height / image_size;
+    for i in 0..tiles_w * tiles_h {
+        let x = (i % tiles_w) * image_size;
+        let y = (i / tiles_w) * image_size;
+        let tile = resized.crop_imm(x, y, image_size, image_size);
+        tiles.push(tile);
+    }
+
+    if use_thumbnail && tiles.len() > 1 {
+        let thumb_rgb = resize_bicubic(&base_rgb, image_size, image_size);
+        tiles.push(DynamicImage::ImageRgb8(thumb_rgb));
+    }
+
+    DynamicPreprocessResult {
+        tiles,
+        ratio: target_aspect_ratio,
+    }
+}
+
+/// Choose the `(columns, rows)` crop grid for an image of `width` x `height`.
+///
+/// By default this is the `min_num..=max_num` tile layout whose aspect ratio is closest to the
+/// image's. That search tops out at a 1x9 grid, so a 1:20 receipt gets stretched to twice its
+/// width. When `strip_aspect_threshold` is set and the long side is at least that many times the
+/// short side, the image is instead cut into a single column (or row) of tiles, one per short
+/// side's length of the long side (the aspect ratio, rounded), so each tile covers a roughly
+/// square part of the image whatever its resolution. The count is at least `min_num` and 2, and
+/// at most [`MAX_STRIP_TILES`].
+pub fn select_tile_grid(
+    width: u32,
+    height: u32,
+    min_num: u32,
+    max_num: u32,
+    image_size: u32,
+    strip_aspect_threshold: Option<f32>,
+) -> (u32, u32) {
+    let aspect_ratio = width as f64 / height as f64;
+    if let Some(threshold) = strip_aspect_threshold {
+        let elongation = aspect_ratio.max(1.0 / aspect_ratio);
+        if width > 0 && height > 0 && elongation >= threshold as f64 {
+            let strips = (elongation.round() as u32).clamp(min_num.max(2), MAX_STRIP_TILES);
+            return if width >= height {
+                (strips, 1)
+            } else {
+                (1, strips)
+            };
+        }
+    }
 
     let mut target_ratios: BTreeSet<(u32, u32)> = BTreeSet::new();
     for n in min_num..=max_num {
@@ -33,7 +113,7 @@ pub fn dynamic_preprocess(
 
     let mut target_aspect_ratio = (1, 1);
     let mut best_ratio_diff = f64::MAX;
-    let area = (orig_width * orig_height) as f64;
+    let area = (width * height) as f64;
 
     for (w_ratio, h_ratio) in &target_ratios {
         let target_ratio = *w_ratio as f64 / *h_ratio as f64;
@@ -47,30 +127,5 @@ pub fn dynamic_preprocess(
             }
         }
     }
-
-    let target_width = image_size * target_aspect_ratio.0;
-    let target_height = image_size * target_aspect_ratio.1;
-    let base_rgb: RgbImage = image.to_rgb8();
-    let resized_rgb = resize_bicubic(&base_rgb, target_width, target_height);
-    let resized = DynamicImage::ImageRgb8(resized_rgb);
-
-    let mut tiles = Vec::new();
-    let tiles_w = target_width / image_size;
-    let tiles_h = target_height / image_size;
-    for i in 0..tiles_w * tiles_h {
-        let x = (i % tiles_w) * image_size;
-        let y = (i / tiles_w) * image_size;
-        let tile = resized.crop_imm(x, y, image_size, image_size);
-        tiles.push(tile);
-    }
-
-    if use_thumbnail && tiles.len() > 1 {
-        let thumb_rgb = resize_bicubic(&base_rgb, image_size, image_size);
-        tiles.push(DynamicImage::ImageRgb8(thumb_rgb));
-    }
-
-    DynamicPreprocessResult {
-        tiles,
-        ratio: target_aspect_ratio,
-    }
+    target_aspect_ratio
 }
@@ -180,12 +180,7 @@ impl SamBackbone {
             .dims4()
             .map_err(|_| anyhow!("expected input shape [batch, channels, height, width]"))?;
 
-        if channels != 3 {
-            bail!(
-                "sam backbone expects 3-channel input, received {} channels",
-                channels
-            );
-        }
+        check_channels_first(channels, width)?;
 
         let _patch_shape = patch_embed_shape(
             batch,
@@ -255,12 +250,7 @@ impl SamBackbone {
             .shape()
             .dims4()
             .map_err(|_| anyhow!("expected input shape [batch, channels, height, width]"))?;
-        if channels != 3 {
-            bail!(
-                "sam backbone expects 3-channel input, received {} channels",
-                channels
-            );
-        }
+        check_channels_first(channels, width)?;
 
         let _patch_shape = patch_embed_shape(
             batch,
@@ -498,6 +488,24 @@ impl SamDownsample {
     }
 }
 
+/// Reject anything but `[batch, 3, height, width]`, naming the likely cause when the input
+/// is channels-last.
+fn check_channels_first(channels: usize, width: usize) -> Result<()> {
+    if channels == 3 {
+        return Ok(());
+    }
+    if width == 3 {
+        bail!(
+            "sam backbone expects NCHW input but the last axis has 3 entries; build image tensors \
+             with TensorLayout::Nchw"
+        );
+    }
+    bail!(
+        "sam backbone expects 3-channel input, received {} channels",
+        channels
+    );
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 struct PatchShape {
@@ -10,9 +10,12 @@ use std::{
 };
 
 use anyhow::{Context, Result, anyhow};
-use candle_core::{DType, Tensor};
+use candle_core::{D, DType, Device, Tensor};
 use common::test_utils::{with_shared_ocr_model, workspace_path};
-use deepseek_ocr_core::model::{DEFAULT_WEIGHTS_PATH, GenerateOptions, VisionProjectionOutputs};
+use deepseek_ocr_core::model::{
+    DEFAULT_WEIGHTS_PATH, DeepseekOcrModel, GenerateOptions, VisionProjectionOutputs,
+};
+use deepseek_ocr_core::transformer::weights::WeightQuant;
 use deepseek_ocr_core::vision::dynamic_preprocess;
 use image::{GenericImageView, open};
 use ndarray::Array2;
@@ -1119,6 +1122,97 @@ fn baseline_teacher_forcing_matches_reference() -> Result<()> {
     }
 }
 
+#[test]
+fn baseline_int8_quantization_tracks_float_logits() -> Result<()> {
+    let weights_path = workspace_path(DEFAULT_WEIGHTS_PATH);
+    let config_path = workspace_path("DeepSeek-OCR/config.json");
+    let prompt_path = workspace_path("baselines/sample/prompt.json");
+    let output_tokens_path = workspace_path("baselines/sample/output_tokens.json");
+    let projector_path = workspace_path("baselines/sample/projector_outputs.npz");
+    for path in [
+        &weights_path,
+        &config_path,
+        &prompt_path,
+        &output_tokens_path,
+        &projector_path,
+    ] {
+        if !path.exists() {
+            eprintln!("skipping int8 quantization test: missing {path:?}");
+            return Ok(());
+        }
+    }
+    let prompt: PromptAssets = serde_json::from_str(&fs::read_to_string(prompt_path)?)?;
+    let outputs: OutputTokens = serde_json::from_str(&fs::read_to_string(output_tokens_path)?)?;
+    let mut projector_npz = ndarray_npy::NpzReader::new(fs::File::open(projector_path)?)?;
+    let fused_tokens_py: Array2<f32> = projector_npz
+        .by_name("fused_concat.npy")
+        .context("missing fused_concat.npy for quantization test")?;
+    drop(projector_npz);
+
+    let teacher_forced_logits = |model: &DeepseekOcrModel| -> Result<Tensor> {
+        let device = model.device();
+        let seq_len = outputs.tokens.len();
+        let mut mask_vec = prompt.images_seq_mask.clone();
+        mask_vec.extend(std::iter::repeat_n(0u8, outputs.generated_len));
+        let attention_mask = Tensor::ones((1, seq_len), DType::I64, device)?;
+        let mask_tensor = Tensor::from_vec(mask_vec, (1, seq_len), device)?;
+        let input_ids = Tensor::from_vec(outputs.tokens.clone(), (1, seq_len), device)?;
+        let (rows, cols) = (fused_tokens_py.nrows(), fused_tokens_py.ncols());
+        let fused = Tensor::from_vec(
+            fused_tokens_py.iter().copied().collect::<Vec<f32>>(),
+            (rows, cols),
+            device,
+        )?;
+        let forward = model.forward(
+            Some(&input_ids),
+            None,
+            Some(&attention_mask),
+            None,
+            Some(&mask_tensor),
+            None,
+            Some(&[fused]),
+            None,
+            false,
+        )?;
+        // Only positions predicting generated tokens matter for decoding quality.
+        Ok(forward
+            .logits
+            .get(0)?
+            .narrow(0, outputs.prefill_len - 1, outputs.generated_len)?)
+    };
+
+    let reference = match with_shared_ocr_model(&teacher_forced_logits) {
+        Ok(logits) => logits,
+        Err(err) => {
+            eprintln!("skipping int8 quantization test: failed to use shared model:\n{err:#}");
+            return Ok(());
+        }
+    };
+    let quantized = DeepseekOcrModel::load_with_quantization(
+        Some(&config_path),
+        Some(&weights_path),
+        Device::Cpu,
+        DType::F32,
+        Some(WeightQuant::Int8),
+    )?;
+    let logits = teacher_forced_logits(&quantized)?;
+
+    let reference_argmax = reference.argmax(D::Minus1)?.to_vec1::<u32>()?;
+    let quantized_argmax = logits.argmax(D::Minus1)?.to_vec1::<u32>()?;
+    let agree = reference_argmax
+        .iter()
+        .zip(&quantized_argmax)
+        .filter(|(lhs, rhs)| lhs == rhs)
+        .count();
+    let agreement = agree as f32 / reference_argmax.len().max(1) as f32;
+    println!("int8 greedy agreement with float baseline: {agreement:.3}");
+    assert!(
+        agreement >= 0.95,
+        "int8 decoder diverges from float baseline (greedy agreement {agreement:.3})"
+    );
+    Ok(())
+}
+
 fn normalize_text(s: &str) -> String {
     s.replace("\r\n", "\n")
         .replace("<｜end▁of▁sentence｜>", "")
@@ -9,13 +9,19 @@ use anyhow::{Context, Result, anyhow};
 use once_cell::sync::OnceCell;
 
 use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
+use candle_nn::{VarBuilder, VarMap};
 use image::DynamicImage;
 
 use deepseek_ocr_core::{
     config::{DeepseekV2Config, load_ocr_config},
-    model::{DEFAULT_WEIGHTS_PATH, DeepseekOcrModel, build_global_view, image_to_tensor},
-    transformer::{model::DeepseekLanguageModel, weights::TransformerWeights},
+    model::{
+        DEFAULT_WEIGHTS_PATH, DeepseekOcrModel, Normalization, TensorLayout, build_global_view,
+        image_to_tensor, weights_var_builder,
+    },
+    transformer::{
+        model::DeepseekLanguageModel,
+        weights::{TransformerWeights, WeightQuant},
+    },
 };
 
 static OCR_MODEL: OnceCell<Arc<Mutex<DeepseekOcrModel>>> = OnceCell::new();
@@ -43,10 +49,8 @@ fn load_language_assets() -> Result<SharedLanguageAssets> {
         .context("missing language config")?;
     let cfg = Arc::new(cfg);
     let device = Device::Cpu;
-    let vb = unsafe {
-        VarBuilder::from_mmaped_safetensors(&[weights.as_path()], DType::F32, &device)
-            .context("failed to mmap language model weights")?
-    };
+    let vb = weights_var_builder(&weights, DType::F32, &device)
+        .context("failed to mmap language model weights")?;
     let model = DeepseekLanguageModel::load(Arc::clone(&cfg), &vb)
         .context("failed to construct language model")?;
     let transformer = model.transformer_weights_arc();
@@ -126,6 +130,37 @@ pub fn shared_transformer_weights() -> Result<Arc<TransformerWeights>> {
     ))
 }
 
+/// A two-layer dense decoder with random weights (vocab 96, hidden 64), small enough to build in
+/// every test that needs real forward passes without the checkpoint.
+pub fn tiny_language_model(quantize: Option<WeightQuant>) -> Result<DeepseekLanguageModel> {
+    let (cfg, varmap) = tiny_language_weights()?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    DeepseekLanguageModel::load_with_quantization(cfg, &vb, quantize)
+}
+
+/// Config and random weights of [`tiny_language_model`], for tests that load several models over
+/// the same weights. Models loaded from the map share its storage, so `Var::set` reaches them.
+pub fn tiny_language_weights() -> Result<(Arc<DeepseekV2Config>, VarMap)> {
+    let cfg: DeepseekV2Config = serde_json::from_value(serde_json::json!({
+        "vocab_size": 96,
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 2,
+        "max_position_embeddings": 64,
+    }))?;
+    let cfg = Arc::new(cfg);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    // The first load only creates the (zero-initialised) variables; randomise them before the
+    // real load so quantization sees the final values.
+    DeepseekLanguageModel::load(Arc::clone(&cfg), &vb)?;
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 0.2, var.shape(), &Device::Cpu)?)?;
+    }
+    Ok((cfg, varmap))
+}
+
 fn load_image(path: &Path) -> Result<DynamicImage> {
     image::ImageReader::open(path)
         .with_context(|| format!("failed to open image at {}", path.display()))?
@@ -141,7 +176,13 @@ pub fn global_view_tensor_from_path(
 ) -> Result<Tensor> {
     let image = load_image(image_path)?;
     let global = build_global_view(&image, base_size);
-    image_to_tensor(&global, device, dtype)
+    image_to_tensor(
+        &global,
+        device,
+        dtype,
+        TensorLayout::Nchw,
+        &Normalization::DEEPSEEK_OCR,
+    )
 }
 
 pub fn build_global_view_from_path(image_path: &Path, base_size: u32) -> Result<DynamicImage> {
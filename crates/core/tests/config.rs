@@ -1,8 +1,16 @@
 mod common;
 
 use anyhow::{Context, Result};
+use candle_core::{DType, Device};
 use common::test_utils::workspace_path;
-use deepseek_ocr_core::config::{DeepseekOcrConfig, load_ocr_config};
+use deepseek_ocr_core::{
+    config::{
+        DeepseekOcrConfig, DeepseekV2Config, LanguageConfigOverrides, RopeScalingPreset,
+        load_ocr_config,
+    },
+    runtime::Precision,
+    transformer::{rope::RopeCache, weights::WeightQuant},
+};
 
 fn load_test_config() -> Result<DeepseekOcrConfig> {
     let path = workspace_path("DeepSeek-OCR/config.json");
@@ -56,3 +64,174 @@ fn vision_backbone_lookup() -> Result<()> {
     assert_eq!(sam.heads, Some(12));
     Ok(())
 }
+
+#[test]
+fn memory_estimate_counts_dense_decoder() -> Result<()> {
+    let language: DeepseekV2Config = serde_json::from_value(serde_json::json!({
+        "vocab_size": 10,
+        "hidden_size": 4,
+        "intermediate_size": 8,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 2,
+        "max_position_embeddings": 16,
+    }))?;
+    let f32_estimate = language.estimated_memory(Precision::F32, 16, 1);
+    // embed (40) + 2 * (attention 64 + mlp 96 + norms 8) + final norm (4) + lm_head (40)
+    assert_eq!(f32_estimate.weights_bytes, 420 * 4);
+    // layers * tokens * kv_heads * (k + v head dims) * bytes
+    assert_eq!(f32_estimate.kv_cache_bytes, 2 * 16 * 2 * 4 * 4);
+
+    let f16_estimate = language.estimated_memory(Precision::F16, 16, 2);
+    assert_eq!(f16_estimate.weights_bytes, 420 * 2);
+    assert_eq!(f16_estimate.kv_cache_bytes, f32_estimate.kv_cache_bytes);
+    assert_eq!(
+        f16_estimate.total_bytes(),
+        f16_estimate.weights_bytes + f16_estimate.activation_bytes + f16_estimate.kv_cache_bytes
+    );
+    Ok(())
+}
+
+#[test]
+fn memory_estimate_counts_quantized_projections() -> Result<()> {
+    let language: DeepseekV2Config = serde_json::from_value(serde_json::json!({
+        "vocab_size": 10,
+        "hidden_size": 32,
+        "intermediate_size": 64,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 2,
+        "max_position_embeddings": 16,
+    }))?;
+    let dense = language.estimated_memory(Precision::F16, 16, 1);
+    let int8 = language.estimated_memory_quantized(Precision::F16, 16, 1, Some(WeightQuant::Int8));
+    // Attention (4 * 32 * 32) and MLP (3 * 32 * 64) projections shrink from 2 bytes per weight
+    // to 34 bytes per block of 32; embeddings, norms and the head stay at f16.
+    let projections = 2 * (4 * 32 * 32 + 3 * 32 * 64);
+    assert_eq!(
+        dense.weights_bytes - int8.weights_bytes,
+        projections * 2 - projections / 32 * 34
+    );
+    assert_eq!(int8.kv_cache_bytes, dense.kv_cache_bytes);
+
+    // Projections whose input dimension does not split into blocks stay dense, as when loading.
+    let mut narrow = language.clone();
+    narrow.hidden_size = 4;
+    narrow.intermediate_size = 8;
+    assert_eq!(
+        narrow.estimated_memory_quantized(Precision::F16, 16, 1, Some(WeightQuant::Int4)),
+        narrow.estimated_memory(Precision::F16, 16, 1)
+    );
+    Ok(())
+}
+
+#[test]
+fn language_overrides_merge_and_validate() -> Result<()> {
+    let mut language: DeepseekV2Config = serde_json::from_value(serde_json::json!({
+        "vocab_size": 10,
+        "hidden_size": 4,
+        "intermediate_size": 8,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 2,
+        "max_position_embeddings": 16,
+    }))?;
+    let overrides: LanguageConfigOverrides = serde_json::from_value(serde_json::json!({
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 50000.0,
+        "attn_implementation": "eager",
+    }))?;
+    language.apply_overrides(&overrides)?;
+    assert_eq!(language.rms_norm_eps, 1e-5);
+    assert_eq!(language.rope_theta, 50000.0);
+    assert_eq!(language.attn_implementation.as_deref(), Some("eager"));
+    assert_eq!(language.max_position_embeddings, 16);
+
+    let bad_eps = LanguageConfigOverrides {
+        rms_norm_eps: Some(0.0),
+        ..LanguageConfigOverrides::default()
+    };
+    assert!(language.clone().apply_overrides(&bad_eps).is_err());
+    let bad_attn = LanguageConfigOverrides {
+        attn_implementation: Some("paged".into()),
+        ..LanguageConfigOverrides::default()
+    };
+    assert!(language.clone().apply_overrides(&bad_attn).is_err());
+    // Shape-bearing fields are not overridable.
+    assert!(
+        serde_json::from_value::<LanguageConfigOverrides>(serde_json::json!({"hidden_size": 8}))
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn rope_scaling_presets_expand_and_validate() -> Result<()> {
+    let mut language: DeepseekV2Config = serde_json::from_value(serde_json::json!({
+        "vocab_size": 10,
+        "hidden_size": 4,
+        "intermediate_size": 8,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 2,
+        "max_position_embeddings": 16,
+    }))?;
+    let overrides: LanguageConfigOverrides = serde_json::from_value(serde_json::json!({
+        "rope_scaling": { "type": "yarn", "factor": 4.0 },
+        "max_position_embeddings": 64,
+    }))?;
+    language.apply_overrides(&overrides)?;
+    assert_eq!(language.max_position_embeddings, 64);
+    assert_eq!(
+        language.rope_scaling_preset()?,
+        Some(RopeScalingPreset::Yarn {
+            factor: 4.0,
+            original_max_position_embeddings: Some(16),
+            beta_fast: 32.0,
+            beta_slow: 1.0,
+        })
+    );
+
+    let shrink = LanguageConfigOverrides {
+        rope_scaling: Some(RopeScalingPreset::Ntk { factor: 0.5 }),
+        ..LanguageConfigOverrides::default()
+    };
+    assert!(language.clone().apply_overrides(&shrink).is_err());
+    assert!(
+        serde_json::from_value::<LanguageConfigOverrides>(serde_json::json!({
+            "rope_scaling": { "type": "dynamic", "factor": 2.0 }
+        }))
+        .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn linear_rope_scaling_interpolates_positions() -> Result<()> {
+    let mut language: DeepseekV2Config = serde_json::from_value(serde_json::json!({
+        "vocab_size": 10,
+        "hidden_size": 8,
+        "intermediate_size": 8,
+        "num_hidden_layers": 1,
+        "num_attention_heads": 1,
+        "max_position_embeddings": 16,
+    }))?;
+    let device = Device::Cpu;
+    let mut plain = RopeCache::new(&device, DType::F32, 8)?;
+    plain.ensure_len(&language, 4)?;
+    let (plain_cos, _) = plain.select(1, 4, None)?;
+
+    language.apply_overrides(&LanguageConfigOverrides {
+        rope_scaling: Some(RopeScalingPreset::Linear { factor: 2.0 }),
+        ..LanguageConfigOverrides::default()
+    })?;
+    let mut scaled = RopeCache::new(&device, DType::F32, 8)?;
+    scaled.ensure_len(&language, 4)?;
+    let (scaled_cos, _) = scaled.select(1, 4, None)?;
+
+    let plain_row = plain_cos.narrow(2, 1, 1)?.flatten_all()?.to_vec1::<f32>()?;
+    let scaled_row = scaled_cos
+        .narrow(2, 2, 1)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    for (a, b) in plain_row.iter().zip(&scaled_row) {
+        assert!((a - b).abs() < 1e-5, "{plain_row:?} vs {scaled_row:?}");
+    }
+    Ok(())
+}
@@ -1,4 +1,11 @@
-use deepseek_ocr_core::conversation::get_conv_template;
+use anyhow::Result;
+use deepseek_ocr_core::{
+    conversation::{
+        InstructionTemplate, PromptTemplate, get_conv_template, get_prompt_template,
+        prompt_template_names, register_prompt_template,
+    },
+    inference::render_prompt,
+};
 
 #[test]
 fn conversation_deepseek_prompt_contains_expected_markers() {
@@ -16,3 +23,69 @@ fn conversation_deepseek_prompt_contains_expected_markers() {
     assert!(prompt.contains("Hello!"));
     assert!(prompt.contains("<｜end▁of▁sentence｜>"));
 }
+
+#[test]
+fn instruction_templates_add_image_and_grounding_markers() -> Result<()> {
+    let markdown = get_prompt_template("markdown").expect("markdown template registered");
+    assert_eq!(
+        markdown.render("", ""),
+        "<image>\n<|grounding|>Convert the document to markdown."
+    );
+    assert_eq!(
+        markdown.render("", "<image><image>\nCompare these pages."),
+        "<image><image>\n<|grounding|>Compare these pages."
+    );
+    assert_eq!(
+        render_prompt("grounding", "", "<image>\n<|grounding|>Locate the title.")?,
+        "<image>\n<|grounding|>Locate the title."
+    );
+    assert_eq!(render_prompt("free", "", "")?, "<image>\nFree OCR.");
+    assert_eq!(render_prompt("plain", "", "<image>\nhi")?, "<image>\nhi");
+    assert!(render_prompt("no-such-template", "", "hi").is_err());
+    Ok(())
+}
+
+struct Shouting;
+
+impl PromptTemplate for Shouting {
+    fn render(&self, _system_prompt: &str, user_prompt: &str) -> String {
+        format!("<image>\n{}", user_prompt.to_uppercase())
+    }
+}
+
+#[test]
+fn custom_prompt_templates_can_be_registered() -> Result<()> {
+    register_prompt_template("shouting", Shouting);
+    register_prompt_template(
+        "table",
+        InstructionTemplate::new("Extract the table.", false),
+    );
+    assert!(
+        prompt_template_names()
+            .iter()
+            .any(|name| name == "shouting")
+    );
+    assert_eq!(render_prompt("shouting", "", "read")?, "<image>\nREAD");
+    assert_eq!(
+        render_prompt("table", "", "")?,
+        "<image>\nExtract the table."
+    );
+    Ok(())
+}
+
+#[test]
+fn only_markdown_templates_report_structured_output() {
+    let structured = |name: &str| {
+        get_prompt_template(name)
+            .expect("template registered")
+            .structured_output()
+    };
+    assert!(structured("markdown"));
+    assert!(!structured("free"));
+    assert!(!structured("plain"));
+    assert!(
+        InstructionTemplate::new("Extract the table.", false)
+            .with_structured_output()
+            .structured_output()
+    );
+}
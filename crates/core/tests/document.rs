@@ -0,0 +1,101 @@
+use deepseek_ocr_core::{
+    document::{
+        DOCUMENT_SCHEMA_VERSION, DocumentResult, JsonlSink, OcrLayout, TableFormat, parse_layout,
+    },
+    inference::FinishReason,
+};
+
+const GROUNDED_PAGE: &str = "<|ref|>title<|/ref|><|det|>[[12, 30, 980, 88]]<|/det|>\n# Report\n\n<|ref|>table<|/ref|><|det|>[[40, 100, 960, 400], [40, 420, 960, 600]]<|/det|>\n| a | b |\n|---|---|\n| 1 | 2 |\n\n<table><tr><td>x</td></tr>\n</table>\nEnd";
+
+#[test]
+fn document_result_extracts_regions_and_tables() {
+    let document = DocumentResult::from_output(GROUNDED_PAGE, 100, 40, FinishReason::Stop);
+    assert_eq!(document.schema_version, DOCUMENT_SCHEMA_VERSION);
+    assert!(!document.text.contains("<|"));
+    assert!(document.text.starts_with("# Report"));
+
+    assert_eq!(document.regions.len(), 2);
+    assert_eq!(document.regions[0].label, "title");
+    assert_eq!(document.regions[0].boxes, vec![[12, 30, 980, 88]]);
+    assert_eq!(document.regions[1].boxes.len(), 2);
+
+    assert_eq!(document.tables.len(), 2);
+    assert_eq!(document.tables[0].format, TableFormat::Markdown);
+    assert_eq!(
+        document.tables[0].content,
+        "| a | b |\n|---|---|\n| 1 | 2 |"
+    );
+    assert_eq!(document.tables[1].format, TableFormat::Html);
+    assert!(document.tables[1].content.ends_with("</table>"));
+
+    let json = serde_json::to_value(&document).unwrap();
+    assert_eq!(json["finish_reason"], "stop");
+    assert_eq!(json["usage"]["total_tokens"], 140);
+    assert!(json["usage"].get("deskew_degrees").is_none());
+}
+
+#[test]
+fn malformed_grounding_keeps_the_label_as_text() {
+    let document =
+        DocumentResult::from_output("<|ref|>caption<|/ref|> text", 1, 1, FinishReason::Stop);
+    assert!(document.regions.is_empty());
+    assert_eq!(document.text, "caption text");
+}
+
+#[test]
+fn layout_scales_grounding_boxes_to_image_pixels() {
+    let OcrLayout::Regions(regions) = parse_layout(GROUNDED_PAGE, 1998, 999) else {
+        panic!("grounded output should parse into regions");
+    };
+    assert_eq!(regions.len(), 3);
+    assert_eq!(regions[0].label, "title");
+    assert_eq!(regions[0].text, "# Report");
+    assert_eq!(regions[0].bbox, [24.0, 30.0, 1960.0, 88.0]);
+    assert_eq!(regions[1].text, regions[2].text);
+    assert!(regions[1].text.starts_with("| a | b |"));
+    assert_eq!(regions[2].bbox, [80.0, 420.0, 1920.0, 600.0]);
+
+    let document = DocumentResult::from_output(GROUNDED_PAGE, 1, 1, FinishReason::Stop);
+    assert_eq!(
+        document.regions[1].pixel_boxes(1998, 999)[1],
+        regions[2].bbox
+    );
+
+    let plain = "<|ref|>caption<|/ref|> no boxes here";
+    assert_eq!(
+        parse_layout(plain, 100, 100),
+        OcrLayout::Raw(plain.to_string())
+    );
+}
+
+#[test]
+fn jsonl_sink_resume_drops_a_partial_trailing_line() {
+    let path =
+        std::env::temp_dir().join(format!("deepseek-ocr-jsonl-{}.jsonl", std::process::id()));
+    let document = DocumentResult::from_output("page", 10, 2, FinishReason::Stop);
+
+    let (mut sink, done) = JsonlSink::open(&path, false).unwrap();
+    assert_eq!(done, 0);
+    sink.write(&document).unwrap();
+    sink.write(&document).unwrap();
+    assert_eq!(sink.written(), 2);
+    drop(sink);
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(contents.ends_with('\n'));
+    assert_eq!(contents.lines().count(), 2);
+
+    // Simulate a crash in the middle of the third write.
+    std::fs::write(&path, format!("{contents}{{\"schema_version\":1,\"te")).unwrap();
+    let (mut sink, done) = JsonlSink::open(&path, true).unwrap();
+    assert_eq!(done, 2);
+    sink.write(&document).unwrap();
+    drop(sink);
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(lines.len(), 3);
+    for line in lines {
+        let value: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(value["text"], "page");
+    }
+    std::fs::remove_file(&path).unwrap();
+}
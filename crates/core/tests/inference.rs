@@ -0,0 +1,306 @@
+use std::{thread, time::Duration};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use deepseek_ocr_core::{
+    inference::{
+        BatchProgress, FinishReason, MaxNewTokens, StopCriteria, StreamingDetokenizer,
+        batch_progress_channel, build_image_placeholders, decode_without_partial_utf8,
+        ends_with_partial_utf8, finish_output, finish_trimmed_output, is_blank_output,
+        normalize_text, send_batch_progress, tail_is_partial_utf8, token_id_channel,
+        trim_to_structural_boundary,
+    },
+    model::OwnedVisionInput,
+};
+
+#[test]
+fn partial_pipe_table_row_is_dropped() {
+    let text = "| a | b |\n|---|---|\n| 1 | 2 |\n| 3 |";
+    let (trimmed, truncated) = trim_to_structural_boundary(text);
+    assert!(truncated);
+    assert_eq!(trimmed, "| a | b |\n|---|---|\n| 1 | 2 |");
+}
+
+#[test]
+fn complete_pipe_table_row_is_kept() {
+    let text = "| a | b |\n| 1 | 2 |";
+    let (trimmed, truncated) = trim_to_structural_boundary(text);
+    assert!(!truncated);
+    assert_eq!(trimmed, text);
+}
+
+#[test]
+fn partial_list_item_is_dropped() {
+    let text = "Steps:\n1. first\n2. sec";
+    let (trimmed, truncated) = trim_to_structural_boundary(text);
+    assert!(truncated);
+    assert_eq!(trimmed, "Steps:\n1. first");
+}
+
+#[test]
+fn unterminated_html_table_is_closed_after_last_row() {
+    let text = "Intro\n<table><tr><td>1</td></tr><tr><td>2";
+    let (trimmed, truncated) = trim_to_structural_boundary(text);
+    assert!(truncated);
+    assert_eq!(trimmed, "Intro\n<table><tr><td>1</td></tr></table>");
+
+    let (trimmed, truncated) = trim_to_structural_boundary("Intro\n<table><tr><td>1");
+    assert!(truncated);
+    assert_eq!(trimmed, "Intro");
+}
+
+#[test]
+fn plain_prose_is_left_untouched() {
+    let text = "A paragraph that ran out of tok";
+    let (trimmed, truncated) = trim_to_structural_boundary(text);
+    assert!(!truncated);
+    assert_eq!(trimmed, text);
+}
+
+/// Decoder output observed on blank scans: stray whitespace, an echoed image token, an empty
+/// grounding box.
+const BLANK_PAGE_OUTPUTS: &[&str] = &[
+    "   \n\t ",
+    "<image>\n",
+    "<|ref|><|/ref|><|det|><|/det|>",
+    " <｜end▁of▁sentence｜>",
+];
+
+#[test]
+fn blank_page_output_is_cleared_when_enabled() {
+    for raw in BLANK_PAGE_OUTPUTS {
+        let (text, reason) = finish_output(normalize_text(raw), true);
+        assert_eq!(text, "", "{raw:?}");
+        assert_eq!(reason, FinishReason::Empty, "{raw:?}");
+    }
+    let (text, reason) = finish_output(normalize_text("<image>\n"), false);
+    assert_eq!(text, "<image>");
+    assert_eq!(reason, FinishReason::Stop);
+}
+
+#[test]
+fn output_with_content_is_not_blank() {
+    assert!(!is_blank_output("<|ref|>Title<|/ref|>"));
+    let (text, reason) = finish_output("Page 1".to_string(), true);
+    assert_eq!(text, "Page 1");
+    assert_eq!(reason, FinishReason::Stop);
+}
+
+#[test]
+fn trimmed_output_reports_truncation() {
+    let (trimmed, truncated) = trim_to_structural_boundary("| a | b |\n| 1 | 2 |\n| 3 |");
+    assert!(truncated);
+    let (_, reason) = finish_trimmed_output(trimmed, true, truncated);
+    assert_eq!(reason, FinishReason::Truncated);
+    assert_eq!(reason.as_str(), "truncated");
+    // Blank output is still reported as empty.
+    let (_, reason) = finish_trimmed_output(" ".to_string(), true, true);
+    assert_eq!(reason, FinishReason::Empty);
+}
+
+/// Byte-level stand-in for the tokenizer: every id is one byte, decoded lossily like BPE.
+fn decode_bytes(ids: &[u32]) -> String {
+    let bytes: Vec<u8> = ids.iter().map(|&id| id as u8).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[test]
+fn truncation_mid_multibyte_is_dropped() {
+    let ids: Vec<u32> = "héllo 世界".bytes().map(u32::from).collect();
+    // Cut inside the three-byte encoding of the final character.
+    let truncated = &ids[..ids.len() - 1];
+    assert!(ends_with_partial_utf8(&decode_bytes(truncated)));
+
+    let decoded = decode_without_partial_utf8(truncated, decode_bytes);
+    assert_eq!(decoded, "héllo 世");
+    assert!(!ends_with_partial_utf8(&decoded));
+}
+
+#[test]
+fn generation_extends_only_through_a_cut_character() {
+    let ids: Vec<i64> = "héllo 世界".bytes().map(i64::from).collect();
+    assert!(tail_is_partial_utf8(&ids[..ids.len() - 2], decode_bytes));
+    assert!(!tail_is_partial_utf8(&ids, decode_bytes));
+    assert!(!tail_is_partial_utf8(&[], decode_bytes));
+}
+
+#[test]
+fn complete_output_is_decoded_unchanged() {
+    let ids: Vec<u32> = "héllo 世界".bytes().map(u32::from).collect();
+    assert_eq!(
+        decode_without_partial_utf8(&ids, decode_bytes),
+        "héllo 世界"
+    );
+}
+
+#[test]
+fn streaming_chinese_byte_tokens_emits_only_complete_characters() {
+    let text = "深度求索：光学字符识别，测试流式输出。";
+    let ids: Vec<u32> = text.bytes().map(u32::from).collect();
+    let mut detokenizer = StreamingDetokenizer::new();
+    let mut streamed = String::new();
+    for &id in &ids {
+        let chunk = detokenizer.push(&[id], decode_bytes);
+        assert!(
+            !chunk.contains(char::REPLACEMENT_CHARACTER),
+            "chunk {chunk:?} holds a partial character"
+        );
+        streamed.push_str(&chunk);
+    }
+    streamed.push_str(&detokenizer.finish(decode_bytes));
+    assert_eq!(streamed, text);
+}
+
+#[test]
+fn streaming_holds_back_and_finally_drops_an_incomplete_character() {
+    let mut ids: Vec<u32> = "完成".bytes().map(u32::from).collect();
+    ids.extend("字".bytes().take(2).map(u32::from));
+    let mut detokenizer = StreamingDetokenizer::new();
+    assert_eq!(detokenizer.push(&ids, decode_bytes), "完成");
+    assert_eq!(detokenizer.finish(decode_bytes), "");
+}
+
+#[test]
+fn token_id_stream_yields_each_generated_id_once() {
+    let (sink, stream) = token_id_channel();
+    let producer = thread::spawn(move || {
+        let generated = [11i64, 12, 13, 14];
+        // Progress callbacks may repeat a count or skip ahead several tokens at once.
+        for count in [1, 1, 3, 4, 4] {
+            sink.push(count, &generated[..count]);
+        }
+    });
+    let ids: Vec<i64> = stream.collect();
+    producer.join().expect("producer thread panicked");
+    assert_eq!(ids, vec![11, 12, 13, 14]);
+}
+
+fn two_crop_input() -> Result<OwnedVisionInput> {
+    Ok(OwnedVisionInput {
+        global: Tensor::zeros((3, 1024, 1024), DType::F32, &Device::Cpu)?,
+        patches: None,
+        crop_shape: Some((2, 1)),
+    })
+}
+
+#[test]
+fn placeholders_match_vision_token_count() -> Result<()> {
+    // 16x16 global grid with row/terminal separators plus a 10x20 local grid with row separators.
+    let expected = 16 * 17 + 1 + 10 * 21;
+    let placeholders = build_image_placeholders(7, &two_crop_input()?, expected, 1024, 640, true)?;
+    assert_eq!(placeholders.len(), expected);
+    assert!(placeholders.iter().all(|&id| id == 7));
+    Ok(())
+}
+
+#[test]
+fn placeholder_count_mismatch_reports_both_counts() -> Result<()> {
+    // Vision tokens for the global view only, as if the crops had been dropped upstream.
+    let err = build_image_placeholders(7, &two_crop_input()?, 273, 1024, 640, true)
+        .expect_err("mismatched vision token count must be rejected");
+    let message = err.to_string();
+    assert!(message.contains("produced 273 tokens"), "{message}");
+    assert!(
+        message.contains("expects 483 image placeholders"),
+        "{message}"
+    );
+    assert!(message.contains("crops=2x1"), "{message}");
+    Ok(())
+}
+
+#[test]
+fn batch_progress_send_never_blocks_on_a_full_channel() {
+    let (sender, receiver) = batch_progress_channel(1);
+    for completed in 1..=3 {
+        send_batch_progress(
+            Some(&sender),
+            BatchProgress {
+                completed,
+                total: 3,
+                elapsed: Duration::from_secs(completed as u64),
+            },
+        );
+    }
+    // The oldest events made way, so the newest counts are what the receiver sees.
+    let newest = receiver.try_recv().expect("newest event is buffered");
+    assert_eq!(newest.completed, 3);
+    assert!((newest.items_per_sec() - 1.0).abs() < 1e-9);
+    assert!(receiver.try_recv().is_none());
+
+    drop(receiver);
+    send_batch_progress(
+        Some(&sender),
+        BatchProgress {
+            completed: 3,
+            total: 3,
+            elapsed: Duration::ZERO,
+        },
+    );
+}
+
+#[test]
+fn slow_batch_progress_receiver_still_gets_the_final_event() {
+    let (sender, receiver) = batch_progress_channel(2);
+    let total = 50;
+    let producer = thread::spawn(move || {
+        for completed in 1..=total {
+            send_batch_progress(
+                Some(&sender),
+                BatchProgress {
+                    completed,
+                    total,
+                    elapsed: Duration::from_millis(completed as u64),
+                },
+            );
+        }
+    });
+
+    let mut seen = Vec::new();
+    for progress in receiver {
+        seen.push(progress.completed);
+        thread::sleep(Duration::from_millis(2));
+    }
+    producer.join().expect("producer thread panicked");
+
+    assert_eq!(seen.last(), Some(&total), "final event delivered: {seen:?}");
+    assert!(seen.windows(2).all(|pair| pair[0] < pair[1]), "{seen:?}");
+}
+
+#[test]
+fn auto_max_new_tokens_scales_with_vision_tokens() {
+    assert_eq!("auto".parse::<MaxNewTokens>().unwrap(), MaxNewTokens::Auto);
+    assert_eq!(
+        "768".parse::<MaxNewTokens>().unwrap(),
+        MaxNewTokens::Fixed(768)
+    );
+    // Explicit budgets are used as-is.
+    assert_eq!(MaxNewTokens::Fixed(64).resolve(100, 400, 8192, 1024), 64);
+    // 400 vision tokens -> 4000, capped by the ceiling.
+    assert_eq!(MaxNewTokens::Auto.resolve(500, 400, 8192, 3000), 3000);
+    // ... and by the context left after the prompt.
+    assert_eq!(MaxNewTokens::Auto.resolve(6000, 400, 8192, 8192), 2192);
+    // Text-only prompts still get the minimum budget.
+    assert_eq!(MaxNewTokens::Auto.resolve(10, 0, 8192, 8192), 512);
+}
+
+#[test]
+fn stop_string_spanning_tokens_matches_on_completing_step() {
+    // Each id decodes to one letter, so "END" needs three tokens.
+    let decode =
+        |ids: &[u32]| -> String { ids.iter().map(|&id| char::from(b'A' + id as u8)).collect() };
+    let stop = StopCriteria::new()
+        .with_strings(["END"])
+        .with_token_sequence(vec![25, 25]);
+    let generated = [7, 4, 13, 3, 1];
+    let stops: Vec<bool> = (1..=generated.len())
+        .map(|len| stop.matches(&generated[..len], decode))
+        .collect();
+    assert_eq!(stops, vec![false, false, false, true, true]);
+    assert_eq!(stop.truncate("HEND"), "H");
+    assert_eq!(stop.partial_match_len("HEN"), 2);
+    assert_eq!(stop.partial_match_len("HE"), 1);
+    assert_eq!(stop.partial_match_len("HEX"), 0);
+    assert!(stop.matches(&[1, 25, 25], decode));
+    assert_eq!(stop.strip_tokens(&[1, 25, 25]), &[1]);
+    assert!(!StopCriteria::new().matches(&generated, decode));
+}
@@ -1,9 +1,23 @@
 mod common;
 
+use std::ops::ControlFlow;
+
 use anyhow::Result;
-use candle_core::{DType, Tensor};
+use candle_core::{DType, Device, Tensor};
 use common::test_utils::with_shared_ocr_model;
-use deepseek_ocr_core::model::{DeepseekOcrModel, GenerateOptions, VisionInput};
+use deepseek_ocr_core::{
+    config::DeepseekV2Config,
+    model::{
+        BatchItem, BeamSearch, DecodeBatch, DeepseekOcrModel, GenerateOptions, LruPrefixCache,
+        MIN_PREFIX_TOKENS, Normalization, PrefilledSequence, PrefixCache, PrefixCacheStore,
+        PrefixKey, TensorLayout, VisionInput, image_to_tensor, tensor_to_image,
+    },
+    transformer::{
+        cache::{CacheEviction, DynamicCache, KvCacheChunk},
+        lora::LoraAdapter,
+        sampling::SamplingParams,
+    },
+};
 
 fn with_model<F>(label: &str, f: F) -> Result<()>
 where
@@ -98,6 +112,441 @@ fn generate_respects_max_tokens() -> Result<()> {
     })
 }
 
+#[test]
+fn image_to_tensor_honours_layout() -> Result<()> {
+    let mut rgb = image::RgbImage::new(5, 2);
+    rgb.put_pixel(4, 1, image::Rgb([255, 0, 128]));
+    let image = image::DynamicImage::ImageRgb8(rgb);
+
+    let nchw = image_to_tensor(
+        &image,
+        &Device::Cpu,
+        DType::F32,
+        TensorLayout::Nchw,
+        &Normalization::DEEPSEEK_OCR,
+    )?;
+    assert_eq!(nchw.dims(), &[3, 2, 5]);
+    let nhwc = image_to_tensor(
+        &image,
+        &Device::Cpu,
+        DType::F32,
+        TensorLayout::Nhwc,
+        &Normalization::DEEPSEEK_OCR,
+    )?;
+    assert_eq!(nhwc.dims(), &[2, 5, 3]);
+
+    assert_eq!(
+        nhwc.permute((2, 0, 1))?.to_vec3::<f32>()?,
+        nchw.to_vec3::<f32>()?
+    );
+    assert_eq!(nhwc.get(1)?.get(4)?.to_vec1::<f32>()?[..2], [1.0, -1.0]);
+    Ok(())
+}
+
+#[test]
+fn tensor_to_image_inverts_image_to_tensor() -> Result<()> {
+    let rgb = image::RgbImage::from_fn(5, 4, |x, y| image::Rgb([x as u8 * 60, y as u8 * 80, 17]));
+    let image = image::DynamicImage::ImageRgb8(rgb.clone());
+    let normalization = Normalization::DEEPSEEK_OCR;
+    for layout in [TensorLayout::Nchw, TensorLayout::Nhwc] {
+        let tensor = image_to_tensor(&image, &Device::Cpu, DType::F32, layout, &normalization)?;
+        assert_eq!(tensor_to_image(&tensor, &normalization)?.to_rgb8(), rgb);
+        let batched = tensor.unsqueeze(0)?;
+        assert_eq!(tensor_to_image(&batched, &normalization)?.to_rgb8(), rgb);
+    }
+    Ok(())
+}
+
+#[test]
+fn image_to_tensor_applies_custom_normalization() -> Result<()> {
+    let rgb = image::RgbImage::from_pixel(1, 1, image::Rgb([255, 0, 51]));
+    let image = image::DynamicImage::ImageRgb8(rgb.clone());
+    let normalization = Normalization {
+        mean: [0.5, 0.0, 0.1],
+        std: [0.25, 1.0, 0.5],
+    };
+    let tensor = image_to_tensor(
+        &image,
+        &Device::Cpu,
+        DType::F32,
+        TensorLayout::Nhwc,
+        &normalization,
+    )?;
+    let values = tensor.flatten_all()?.to_vec1::<f32>()?;
+    for (value, expected) in values.iter().zip([2.0, 0.0, 0.2]) {
+        assert!((value - expected).abs() < 1e-6, "{values:?}");
+    }
+    assert_eq!(tensor_to_image(&tensor, &normalization)?.to_rgb8(), rgb);
+
+    assert!(Normalization::default().validate().is_ok());
+    let zero_std = Normalization {
+        std: [0.5, 0.0, 0.5],
+        ..Normalization::DEEPSEEK_OCR
+    };
+    assert!(zero_std.validate().is_err());
+    Ok(())
+}
+
+#[test]
+fn tensor_to_image_clamps_out_of_range_values() -> Result<()> {
+    let tensor = Tensor::new(&[[[-3.0f32]], [[0.0]], [[3.0]]], &Device::Cpu)?;
+    let image = tensor_to_image(&tensor, &Normalization::DEEPSEEK_OCR)?.to_rgb8();
+    assert_eq!(image.get_pixel(0, 0).0, [0, 128, 255]);
+    Ok(())
+}
+
+fn filled_sequence(seq: usize, fill: f32, first_token: i64) -> Result<PrefilledSequence> {
+    let device = candle_core::Device::Cpu;
+    let key_t = Tensor::full(fill, (1, 2, 4, seq), &device)?;
+    let value = Tensor::full(fill, (1, 2, seq, 4), &device)?;
+    let mut cache = DynamicCache::with_num_layers(1);
+    cache.append(0, KvCacheChunk::new(key_t, value)?)?;
+    Ok(PrefilledSequence::new(cache, first_token))
+}
+
+#[test]
+fn decode_batch_left_pads_on_join_and_trims_on_leave() -> Result<()> {
+    let mut batch = DecodeBatch::new();
+    batch.join(7, filled_sequence(3, 1.0, 11)?)?;
+    batch.join(9, filled_sequence(5, 2.0, 12)?)?;
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch.ids().collect::<Vec<_>>(), vec![7, 9]);
+    assert_eq!(batch.cache().seq_len(), Some(5));
+    let entry = batch.cache().get(0).expect("layer 0 cached");
+    assert_eq!(entry.key_view()?.dims(), &[2, 2, 4, 5]);
+    let first_row = entry.value_view()?.get(0)?.get(0)?.to_vec2::<f32>()?;
+    assert_eq!(first_row[0], vec![0.0; 4], "shorter row is left padded");
+    assert_eq!(first_row[2], vec![1.0; 4]);
+
+    batch.leave(9)?;
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch.cache().seq_len(), Some(3), "padding trimmed");
+    let entry = batch.cache().get(0).expect("layer 0 cached");
+    let remaining = entry.value_view()?.get(0)?.get(0)?.to_vec2::<f32>()?;
+    assert_eq!(remaining, vec![vec![1.0; 4]; 3]);
+
+    batch.leave(7)?;
+    assert!(batch.is_empty());
+    assert!(batch.leave(7).is_err());
+    Ok(())
+}
+
+#[test]
+fn decode_batch_keeps_the_cache_bound_when_repacking() -> Result<()> {
+    let bounded = |seq: usize, fill: f32, first_token: i64| -> Result<PrefilledSequence> {
+        let device = candle_core::Device::Cpu;
+        let mut cache =
+            DynamicCache::with_num_layers(1).with_max_seq_len(8, CacheEviction::DropOldest);
+        cache.append(
+            0,
+            KvCacheChunk::new(
+                Tensor::full(fill, (1, 2, 4, seq), &device)?,
+                Tensor::full(fill, (1, 2, seq, 4), &device)?,
+            )?,
+        )?;
+        Ok(PrefilledSequence::new(cache, first_token))
+    };
+    let mut batch = DecodeBatch::new();
+    batch.join(1, bounded(3, 1.0, 11)?)?;
+    batch.join(2, bounded(5, 2.0, 12)?)?;
+    let bound = Some((8, CacheEviction::DropOldest));
+    assert_eq!(batch.cache().max_seq_len(), bound, "kept when rows merge");
+    batch.leave(2)?;
+    assert_eq!(batch.cache().max_seq_len(), bound, "kept when rows leave");
+    Ok(())
+}
+
+#[test]
+fn batched_decode_matches_sequential_generate() -> Result<()> {
+    with_model("DeepseekOcrModel batched decode test", |model| {
+        let device = model.device().clone();
+        let prompts = [vec![0i64, 1, 2, 3], vec![0i64, 5, 6, 7, 8, 9]];
+        let steps = 4;
+        let mut expected = Vec::new();
+        for prompt in &prompts {
+            let input_ids = Tensor::from_vec(prompt.clone(), (1, prompt.len()), &device)?;
+            let generated = model.generate(&input_ids, GenerateOptions::new(steps))?;
+            expected.push(generated.to_vec2::<i64>()?.remove(0));
+        }
+
+        let mut batch = DecodeBatch::new();
+        let mut actual = vec![Vec::new(); prompts.len()];
+        for (idx, prompt) in prompts.iter().enumerate() {
+            let input_ids = Tensor::from_vec(prompt.clone(), (1, prompt.len()), &device)?;
+            let prefilled = model.prefill(&input_ids, &GenerateOptions::new(steps))?;
+            actual[idx].push(prefilled.first_token());
+            batch.join(idx as u64, prefilled)?;
+        }
+        for _ in 1..steps {
+            for (id, token) in batch.step(model)? {
+                actual[id as usize].push(token);
+            }
+        }
+        assert_eq!(actual, expected);
+        Ok(())
+    })
+}
+
+#[test]
+fn lru_prefix_cache_evicts_least_recently_used() -> Result<()> {
+    let key = |token| PrefixKey::new("model", DType::F16, "plain", &[token]);
+    let cache = LruPrefixCache::new(2);
+    cache.put(key(1), vec![1])?;
+    cache.put(key(2), vec![2])?;
+    assert_eq!(cache.get(key(1))?, Some(vec![1]));
+    cache.put(key(3), vec![3])?;
+    assert_eq!(cache.len(), 2);
+    assert_eq!(
+        cache.get(key(2))?,
+        None,
+        "least recently used entry is evicted"
+    );
+    assert_eq!(cache.get(key(1))?, Some(vec![1]));
+    assert_eq!(cache.get(key(3))?, Some(vec![3]));
+    Ok(())
+}
+
+#[test]
+fn prefix_key_covers_model_dtype_template_and_tokens() {
+    let base = PrefixKey::new("a", DType::F16, "plain", &[1, 2]);
+    assert_eq!(base, PrefixKey::new("a", DType::F16, "plain", &[1, 2]));
+    assert_ne!(base, PrefixKey::new("b", DType::F16, "plain", &[1, 2]));
+    assert_ne!(base, PrefixKey::new("a", DType::BF16, "plain", &[1, 2]));
+    assert_ne!(base, PrefixKey::new("a", DType::F16, "chat", &[1, 2]));
+    assert_ne!(base, PrefixKey::new("a", DType::F16, "plain", &[1, 3]));
+}
+
+#[test]
+fn prefix_key_covers_lora_adapters_and_scales() {
+    let base = PrefixKey::new("a", DType::F16, "plain", &[1, 2]);
+    assert_eq!(base, base.with_lora_adapters(&[]));
+    let adapted = base.with_lora_adapters(&[LoraAdapter::new("style.safetensors", 1.0)]);
+    assert_ne!(base, adapted);
+    assert_ne!(
+        adapted,
+        base.with_lora_adapters(&[LoraAdapter::new("style.safetensors", 0.5)])
+    );
+    assert_ne!(
+        adapted,
+        base.with_lora_adapters(&[LoraAdapter::new("other.safetensors", 1.0)])
+    );
+}
+
+#[test]
+fn prefix_cached_generation_matches_generate() -> Result<()> {
+    with_model("DeepseekOcrModel prefix cache test", |model| {
+        let device = model.device().clone();
+        let prompt: Vec<i64> = (0..MIN_PREFIX_TOKENS as i64 + 4).collect();
+        let input_ids = Tensor::from_vec(prompt.clone(), (1, prompt.len()), &device)?;
+        let steps = 4;
+        let expected = model
+            .generate(&input_ids, GenerateOptions::new(steps))?
+            .to_vec2::<i64>()?
+            .remove(0);
+
+        let store = LruPrefixCache::new(4);
+        for _ in 0..2 {
+            let mut options = GenerateOptions::new(steps);
+            options.prefix_cache = Some(PrefixCache {
+                store: &store,
+                model_id: "test",
+                template: "plain",
+            });
+            let generated = model.generate(&input_ids, options)?.to_vec2::<i64>()?;
+            assert_eq!(generated[0], expected);
+            assert_eq!(store.len(), 1);
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn banned_tokens_are_never_decoded() -> Result<()> {
+    with_model("DeepseekOcrModel banned token test", |model| {
+        let device = model.device().clone();
+        let input_ids = Tensor::from_vec(vec![0i64, 1, 2, 3], (1, 4), &device)?;
+        let steps = 4;
+        // Ban exactly what greedy decoding picks, so every path has to choose differently.
+        let banned = model
+            .generate(&input_ids, GenerateOptions::new(steps))?
+            .to_vec2::<i64>()?
+            .remove(0);
+        let options = || {
+            let mut options = GenerateOptions::new(steps);
+            options.banned_token_ids = &banned;
+            options
+        };
+
+        let generated = model
+            .generate(&input_ids, options())?
+            .to_vec2::<i64>()?
+            .remove(0);
+        assert!(!generated.is_empty());
+        assert!(generated.iter().all(|token| !banned.contains(token)));
+
+        let mut step_options = options();
+        step_options.logprobs = Some(0);
+        let mut state = model.prepare_generation(&input_ids, &step_options)?;
+        while !state.is_finished() {
+            let output = model.step(&mut state)?;
+            assert!(!banned.contains(&output.token));
+            assert!(output.logprob.is_some_and(f32::is_finite));
+        }
+        assert_eq!(state.generated(), generated.as_slice());
+
+        let mut batch = DecodeBatch::new();
+        let prefilled = model.prefill(&input_ids, &options())?;
+        let mut batched = vec![prefilled.first_token()];
+        batch.join(0, prefilled)?;
+        for _ in 1..steps {
+            batched.extend(batch.step(model)?.into_iter().map(|(_, token)| token));
+        }
+        assert_eq!(batched, generated);
+        Ok(())
+    })
+}
+
+#[test]
+fn batched_generation_matches_generate_per_item() -> Result<()> {
+    with_model("DeepseekOcrModel generate_batch test", |model| {
+        let device = model.device().clone();
+        let prompts = [
+            Tensor::from_vec(vec![0i64, 1, 2, 3], (1, 4), &device)?,
+            Tensor::from_vec(vec![5i64, 6], (1, 2), &device)?,
+            Tensor::from_vec(vec![7i64, 8, 9], (1, 3), &device)?,
+        ];
+        let budgets = [4, 2, 0];
+        let expected = prompts
+            .iter()
+            .zip(budgets)
+            .map(|(prompt, steps)| {
+                Ok(model
+                    .generate(prompt, GenerateOptions::new(steps))?
+                    .to_vec2::<i64>()?
+                    .remove(0))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Two rows at a time: the third item joins once the shorter one leaves.
+        let items: Vec<BatchItem<'_>> = prompts
+            .iter()
+            .zip(budgets)
+            .map(|(input_ids, steps)| BatchItem {
+                input_ids,
+                options: GenerateOptions::new(steps),
+            })
+            .collect();
+        let batched = model.generate_batch(&items, 2)?;
+        assert_eq!(batched.tokens.dims2()?, (3, 4));
+        assert_eq!(
+            batched.lengths,
+            expected.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(batched.rows()?, expected);
+        assert!(model.generate_batch(&items, 0).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn generate_with_logprobs_reports_each_token() -> Result<()> {
+    with_model("DeepseekOcrModel logprobs test", |model| {
+        let device = model.device().clone();
+        let input_ids = Tensor::from_vec(vec![0i64, 1, 2, 3], (1, 4), &device)?;
+        let steps = 4;
+        let expected = model
+            .generate(&input_ids, GenerateOptions::new(steps))?
+            .to_vec2::<i64>()?
+            .remove(0);
+
+        let chosen_only = model.generate_with_logprobs(&input_ids, GenerateOptions::new(steps))?;
+        assert_eq!(chosen_only.tokens, expected);
+        assert_eq!(chosen_only.logprobs.len(), expected.len());
+        assert!(chosen_only.top_logprobs.iter().all(Vec::is_empty));
+
+        let mut options = GenerateOptions::new(steps);
+        options.logprobs = Some(3);
+        let with_top = model.generate_with_logprobs(&input_ids, options)?;
+        assert_eq!(with_top.tokens, expected);
+        assert_eq!(with_top.logprobs, chosen_only.logprobs);
+        for ((token, logprob), top) in with_top
+            .tokens
+            .iter()
+            .zip(&with_top.logprobs)
+            .zip(&with_top.top_logprobs)
+        {
+            assert!(*logprob <= 0.0 && logprob.is_finite());
+            assert_eq!(top.len(), 3);
+            assert!(top.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+            // Greedy decoding picks the most likely token.
+            assert_eq!(top[0], (*token, *logprob));
+        }
+
+        let mut state = model.prepare_generation(&input_ids, &GenerateOptions::new(steps))?;
+        while !state.is_finished() {
+            model.step(&mut state)?;
+        }
+        assert!(state.logprobs().is_empty(), "off unless requested");
+        Ok(())
+    })
+}
+
+#[test]
+fn seeded_sampling_reproduces_generation() -> Result<()> {
+    with_model("DeepseekOcrModel seeded sampling test", |model| {
+        let device = model.device().clone();
+        let input_ids = Tensor::from_vec(vec![0i64, 1, 2, 3], (1, 4), &device)?;
+        let params = SamplingParams {
+            temperature: 1.5,
+            top_p: None,
+            seed: Some(1234),
+        };
+        let run = || -> Result<Vec<i64>> {
+            let sampler = params.sampler();
+            let mut options = GenerateOptions::new(8);
+            options.sampler = sampler.as_ref();
+            Ok(model
+                .generate(&input_ids, options)?
+                .to_vec2::<i64>()?
+                .remove(0))
+        };
+        assert_eq!(run()?, run()?);
+        Ok(())
+    })
+}
+
+#[test]
+fn step_wise_generation_matches_generate() -> Result<()> {
+    with_model("DeepseekOcrModel step test", |model| {
+        let device = model.device().clone();
+        let input_ids = Tensor::from_vec(vec![0i64, 1, 2, 3], (1, 4), &device)?;
+        let steps = 4;
+        let expected = model
+            .generate(&input_ids, GenerateOptions::new(steps))?
+            .to_vec2::<i64>()?
+            .remove(0);
+
+        let mut state = model.prepare_generation(&input_ids, &GenerateOptions::new(steps))?;
+        assert_eq!(state.prompt_len(), 4);
+        let mut tokens = Vec::new();
+        while !state.is_finished() {
+            let output = model.step(&mut state)?;
+            assert_eq!(
+                output.logprob, None,
+                "logprobs are only computed on request"
+            );
+            tokens.push(output.token);
+        }
+        assert_eq!(tokens, expected);
+        assert_eq!(state.generated(), expected.as_slice());
+        assert!(
+            model.step(&mut state).is_err(),
+            "finished state rejects steps"
+        );
+        Ok(())
+    })
+}
+
 #[test]
 fn compute_image_embeddings_produces_tokens() -> Result<()> {
     with_model("vision embedding test", |model| {
@@ -203,3 +652,103 @@ fn vision_inputs_and_precomputed_embeddings_align() -> Result<()> {
         Ok(())
     })
 }
+
+#[test]
+fn dump_config_round_trips_language_config() -> Result<()> {
+    with_model("DeepseekOcrModel dump_config test", |model| {
+        let path = std::env::temp_dir().join(format!(
+            "deepseek-ocr-dump-config-{}.json",
+            std::process::id()
+        ));
+        model.dump_config(&path)?;
+        let raw = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        let dumped: DeepseekV2Config = serde_json::from_str(&raw)?;
+        let loaded = model.language_model().config();
+        assert_eq!(dumped.hidden_size, loaded.hidden_size);
+        assert_eq!(dumped.vocab_size, loaded.vocab_size);
+        assert_eq!(dumped.num_hidden_layers, loaded.num_hidden_layers);
+        Ok(())
+    })
+}
+
+#[test]
+fn streaming_generation_reports_each_token_and_can_stop_early() -> Result<()> {
+    with_model("DeepseekOcrModel streaming test", |model| {
+        let device = model.device().clone();
+        let input_ids = Tensor::from_vec(vec![0i64, 1, 2, 3], (1, 4), &device)?;
+        let full: Vec<u32> = model
+            .generate(&input_ids, GenerateOptions::new(4))?
+            .to_vec2::<i64>()?
+            .remove(0)
+            .into_iter()
+            .map(|id| id as u32)
+            .collect();
+
+        let mut seen = Vec::new();
+        let streamed = model.generate_streaming(&input_ids, GenerateOptions::new(4), |token| {
+            seen.push(token);
+            ControlFlow::Continue(())
+        })?;
+        assert_eq!(streamed, full);
+        assert_eq!(seen, full);
+
+        let mut seen = Vec::new();
+        let stopped = model.generate_streaming(&input_ids, GenerateOptions::new(4), |token| {
+            seen.push(token);
+            ControlFlow::Break(())
+        })?;
+        assert_eq!(stopped, seen);
+        assert_eq!(stopped, full[..full.len().min(1)]);
+        Ok(())
+    })
+}
+
+/// Log-probabilities of a toy language over `a`, `b`, `c` and EOS (id 3), conditioned on the
+/// tokens so far. Greedy decoding takes `a` first, but `b` followed by EOS is more likely overall.
+fn toy_logits(history: &[i64]) -> Vec<f32> {
+    let probs: [f32; 4] = match history {
+        [] => [0.5, 0.4, 0.1, 0.0],
+        [0] => [0.4, 0.3, 0.0, 0.3],
+        [1] => [0.05, 0.05, 0.0, 0.9],
+        _ => [0.0, 0.0, 0.0, 1.0],
+    };
+    probs.iter().map(|p| p.ln()).collect()
+}
+
+fn toy_beam_search(beam: BeamSearch) -> Result<Vec<(Vec<i64>, f32)>> {
+    let hypotheses = beam.search(
+        Vec::new(),
+        toy_logits(&[]),
+        5,
+        Some(3),
+        |history: &Vec<i64>| Ok(history.clone()),
+        |history, tokens| {
+            *history = tokens.to_vec();
+            Ok(toy_logits(history))
+        },
+    )?;
+    Ok(hypotheses
+        .into_iter()
+        .map(|hypothesis| (hypothesis.tokens, hypothesis.log_prob))
+        .collect())
+}
+
+#[test]
+fn beam_search_finds_the_sequence_greedy_decoding_misses() -> Result<()> {
+    let greedy = toy_beam_search(BeamSearch::new(1))?;
+    assert_eq!(greedy.len(), 1);
+    assert_eq!(greedy[0].0, vec![0, 0]);
+
+    let beams = toy_beam_search(BeamSearch::new(2).with_num_return_sequences(2))?;
+    let tokens: Vec<_> = beams.iter().map(|(tokens, _)| tokens.clone()).collect();
+    assert_eq!(tokens, vec![vec![1], vec![0, 0]]);
+    assert!((beams[0].1 - (0.4f32 * 0.9).ln()).abs() < 1e-5);
+
+    // A strong preference for length flips the ranking towards the longer hypothesis.
+    let long = toy_beam_search(BeamSearch::new(2).with_length_penalty(3.0))?;
+    assert_eq!(long[0].0, vec![0, 0]);
+
+    assert!(toy_beam_search(BeamSearch::new(2).with_num_return_sequences(3)).is_err());
+    Ok(())
+}
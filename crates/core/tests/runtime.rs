@@ -0,0 +1,70 @@
+use anyhow::Result;
+use deepseek_ocr_core::runtime::{
+    DeviceKind, UtilizationOf, auto_device_kind, prepare_device_and_dtype_with_options,
+    system_memory,
+};
+
+#[test]
+fn cpu_plan_budgets_a_fraction_of_system_memory() -> Result<()> {
+    let plan = prepare_device_and_dtype_with_options(
+        DeviceKind::Cpu,
+        0,
+        None,
+        Some(0.5),
+        UtilizationOf::Total,
+        None,
+    )?;
+    assert!(plan.device.is_cpu());
+    assert_eq!(plan.dtype, None);
+    let Some(memory) = system_memory() else {
+        assert_eq!(plan.memory_budget, None);
+        return Ok(());
+    };
+    assert!(memory.free_bytes <= memory.total_bytes);
+    let budget = plan.memory_budget.expect("budget for queryable memory");
+    assert_eq!(budget, memory.total_bytes / 2);
+    assert_eq!(plan.kv_cache_bytes(budget / 4), Some(budget - budget / 4));
+    assert_eq!(plan.kv_cache_bytes(budget * 2), Some(0));
+
+    let unbounded = prepare_device_and_dtype_with_options(
+        DeviceKind::Cpu,
+        0,
+        None,
+        None,
+        UtilizationOf::Total,
+        None,
+    )?;
+    assert_eq!(unbounded.memory_budget, None);
+    assert_eq!(unbounded.kv_cache_bytes(0), None);
+    Ok(())
+}
+
+#[test]
+fn invalid_utilization_is_rejected() {
+    let result = prepare_device_and_dtype_with_options(
+        DeviceKind::Cpu,
+        0,
+        None,
+        Some(1.5),
+        UtilizationOf::Free,
+        None,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn auto_device_never_fails_to_initialise() -> Result<()> {
+    let plan = prepare_device_and_dtype_with_options(
+        DeviceKind::Auto,
+        0,
+        None,
+        None,
+        UtilizationOf::Free,
+        None,
+    )?;
+    if matches!(auto_device_kind(), DeviceKind::Cpu) {
+        assert!(plan.device.is_cpu());
+        assert_eq!(plan.dtype, None);
+    }
+    Ok(())
+}
@@ -6,7 +6,10 @@ use common::test_utils::{shared_language_config, shared_transformer_weights};
 use deepseek_ocr_core::{
     config::DeepseekV2Config,
     transformer::{
-        block::{TransformerBlock, lengths_to_padding_mask},
+        block::{
+            TransformerBlock, build_attention_bias, build_causal_mask, contains_nan,
+            flash_nan_fallback_count, lengths_to_padding_mask, load_balancing_loss, rerun_on_nan,
+        },
         rope::RopeCache,
     },
 };
@@ -65,6 +68,59 @@ fn lengths_to_padding_mask_builds_expected() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn attention_bias_is_causal_for_chunks_after_a_cache() -> Result<()> {
+    let device = Device::Cpu;
+    let bias = build_attention_bias(None, 1, 3, 5, 2, DType::F32, &device)?
+        .expect("multi-token chunk needs a causal bias");
+    let rows = bias.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?;
+    for (row, values) in rows.iter().enumerate() {
+        for (col, &value) in values.iter().enumerate() {
+            let visible = col <= 2 + row;
+            assert_eq!(value == 0.0, visible, "row {row} col {col}: {value}");
+        }
+    }
+    assert!(build_attention_bias(None, 1, 1, 5, 4, DType::F32, &device)?.is_none());
+    assert!(build_attention_bias(None, 1, 3, 4, 2, DType::F32, &device).is_err());
+    Ok(())
+}
+
+#[test]
+fn causal_mask_hides_future_keys_and_left_padding() -> Result<()> {
+    let device = Device::Cpu;
+    // Two rows of three new tokens after two cached positions; the first row is left-padded by 3.
+    let mask = build_causal_mask(2, 3, 2, Some(&[3, 0]), &device, DType::F32)?;
+    assert_eq!(mask.shape().dims4()?, (2, 1, 3, 5));
+    let rows = mask.squeeze(1)?.to_vec3::<f32>()?;
+    for (batch_idx, pad) in [3usize, 0].into_iter().enumerate() {
+        for (row, values) in rows[batch_idx].iter().enumerate() {
+            for (col, &value) in values.iter().enumerate() {
+                let visible = col <= 2 + row && col >= pad;
+                assert_eq!(
+                    value == 0.0,
+                    visible,
+                    "batch {batch_idx} row {row} col {col}"
+                );
+            }
+        }
+    }
+
+    // A single decode step sees every cached key unless it is padding.
+    let step = build_causal_mask(2, 1, 4, Some(&[2, 0]), &device, DType::F32)?;
+    let step = step.flatten_all()?.to_vec1::<f32>()?;
+    let visible: Vec<bool> = step.iter().map(|&value| value == 0.0).collect();
+    assert_eq!(
+        visible,
+        [false, false, true, true, true, true, true, true, true, true]
+    );
+    let unpadded = build_causal_mask(1, 1, 4, None, &device, DType::F32)?;
+    assert_eq!(unpadded.abs()?.sum_all()?.to_scalar::<f32>()?, 0.0);
+
+    assert!(build_causal_mask(2, 3, 0, Some(&[1]), &device, DType::F32).is_err());
+    assert!(build_causal_mask(1, 3, 0, Some(&[3]), &device, DType::F32).is_err());
+    Ok(())
+}
+
 #[test]
 fn transformer_block_handles_padding_mask() -> Result<()> {
     let config = shared_language_config()?;
@@ -87,3 +143,51 @@ fn transformer_block_handles_padding_mask() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn load_balancing_loss_is_one_when_balanced_and_grows_with_collapse() {
+    let n_routed = 4;
+    let uniform = vec![vec![0.25f32; n_routed]; 4];
+    let balanced: Vec<Vec<i64>> = (0..4).map(|token| vec![token]).collect();
+    let loss = load_balancing_loss(&uniform, &balanced, n_routed, 4);
+    assert!((loss - 1.0).abs() < 1e-6, "balanced loss {loss}");
+
+    let peaked = vec![vec![1.0f32, 0.0, 0.0, 0.0]; 4];
+    let collapsed = vec![vec![0i64]; 4];
+    let loss = load_balancing_loss(&peaked, &collapsed, n_routed, 2);
+    assert!(
+        (loss - n_routed as f32).abs() < 1e-6,
+        "collapsed loss {loss}"
+    );
+}
+
+#[test]
+fn contains_nan_finds_a_single_nan() -> Result<()> {
+    let device = Device::Cpu;
+    let clean = Tensor::new(&[[1.0f32, f32::INFINITY], [-2.0, 0.0]], &device)?;
+    assert!(!contains_nan(&clean)?);
+    let poisoned = Tensor::new(&[[1.0f32, 2.0], [f32::NAN, 0.0]], &device)?;
+    assert!(contains_nan(&poisoned)?);
+    assert!(contains_nan(&poisoned.to_dtype(DType::BF16)?)?);
+    Ok(())
+}
+
+#[test]
+fn nan_attention_output_is_recomputed_eagerly() -> Result<()> {
+    let device = Device::Cpu;
+    let flash = Tensor::new(&[[[f32::NAN, 1.0]]], &device)?;
+    let eager = Tensor::new(&[[[0.5f32, 1.0]]], &device)?;
+    let before = flash_nan_fallback_count();
+
+    let (out, tag) = rerun_on_nan((flash, "flash"), || Ok((eager.clone(), "eager")))?;
+    assert_eq!(tag, "eager");
+    assert_eq!(out.to_vec3::<f32>()?, eager.to_vec3::<f32>()?);
+    assert!(flash_nan_fallback_count() > before);
+
+    let clean = Tensor::new(&[[[0.25f32, 1.0]]], &device)?;
+    let (_, tag) = rerun_on_nan((clean, "flash"), || -> Result<_> {
+        panic!("clean outputs must not be recomputed")
+    })?;
+    assert_eq!(tag, "flash");
+    Ok(())
+}
@@ -1,6 +1,11 @@
+mod common;
+
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
-use deepseek_ocr_core::transformer::cache::{DynamicCache, KvCacheChunk, LayerKvCache};
+use common::test_utils::tiny_language_model;
+use deepseek_ocr_core::transformer::cache::{
+    CacheEviction, CacheStats, DynamicCache, KvCacheChunk, LayerKvCache,
+};
 
 fn make_chunk(
     device: &Device,
@@ -99,3 +104,294 @@ fn prompt_guard_runs_reset_hook() -> Result<()> {
     assert!(flag.get());
     Ok(())
 }
+
+#[test]
+fn dynamic_cache_stats_count_positions_bytes_and_clears() -> Result<()> {
+    let device = Device::Cpu;
+    let mut cache = DynamicCache::with_num_layers(2);
+    assert_eq!(cache.stats(), CacheStats::default());
+
+    let prefill = make_chunk(&device, 1, 2, 3, 4)?;
+    cache.append(0, prefill.clone())?;
+    cache.append(1, prefill)?;
+    let stats = cache.stats();
+    assert_eq!(stats.seq_len, 3);
+    assert_eq!(stats.populated_layers, 2);
+    // Two layers of keys and values, 1 * 2 * 3 * 4 f32 each.
+    assert_eq!(stats.bytes, 2 * 2 * 24 * 4);
+    assert_eq!(stats.appended_positions, 3);
+
+    cache.clear();
+    cache.clear();
+    cache.append(0, make_chunk(&device, 1, 2, 1, 4)?)?;
+    let stats = cache.stats();
+    assert_eq!(stats.seq_len, 1);
+    assert_eq!(stats.populated_layers, 1);
+    assert_eq!(stats.appended_positions, 4);
+    assert_eq!(stats.clears, 1);
+    Ok(())
+}
+
+#[test]
+fn dynamic_cache_snapshot_round_trips_cached_positions() -> Result<()> {
+    let device = Device::Cpu;
+    let mut cache = DynamicCache::with_num_layers(3);
+    let key_t = Tensor::arange(0f32, 24., &device)?.reshape((1, 2, 4, 3))?;
+    let value = Tensor::arange(100f32, 124., &device)?.reshape((1, 2, 3, 4))?;
+    cache.append(0, KvCacheChunk::new(key_t.clone(), value.clone())?)?;
+    cache.append(2, KvCacheChunk::new(key_t, value)?)?;
+    cache.append(0, make_chunk(&device, 1, 2, 1, 4)?)?;
+    cache.append(2, make_chunk(&device, 1, 2, 1, 4)?)?;
+
+    let snapshot = cache.to_snapshot()?;
+    let restored = DynamicCache::from_snapshot(&snapshot, DType::F16, &device)?;
+    assert_eq!(restored.num_layers(), 3);
+    assert_eq!(restored.seq_len(), Some(4));
+    assert!(restored.get(1).is_none());
+    let original = cache.get(2).expect("layer 2");
+    let copy = restored.get(2).expect("layer 2 restored");
+    assert_eq!(copy.value_view()?.dtype(), DType::F16);
+    assert_eq!(
+        copy.key_view()?
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?,
+        original.key_view()?.flatten_all()?.to_vec1::<f32>()?
+    );
+    assert_eq!(
+        copy.value_view()?
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?,
+        original.value_view()?.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    assert!(
+        DynamicCache::from_snapshot(&snapshot[..snapshot.len() - 1], DType::F32, &device).is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn clearing_after_a_forward_releases_every_layer() -> Result<()> {
+    let model = tiny_language_model(None)?;
+    let mut cache = DynamicCache::with_num_layers(model.transformer_weights().layers.len());
+    assert_eq!(cache.memory_bytes(), 0);
+
+    let input_ids = Tensor::new(&[[3i64, 1, 4, 1, 5]], &Device::Cpu)?;
+    model.forward(Some(&input_ids), None, None, None, Some(&mut cache), true)?;
+    assert_eq!(cache.seq_len(), Some(5));
+    assert!(cache.memory_bytes() > 0);
+
+    cache.clear();
+    assert_eq!(cache.seq_len(), None);
+    assert_eq!(cache.memory_bytes(), 0);
+    assert!(cache.layers().iter().all(|entry| entry.is_none()));
+
+    // The cleared cache serves the next document from position zero.
+    model.forward(Some(&input_ids), None, None, None, Some(&mut cache), true)?;
+    assert_eq!(cache.seq_len(), Some(5));
+    Ok(())
+}
+
+#[test]
+fn attention_sink_eviction_keeps_the_sink_and_the_recent_window() -> Result<()> {
+    let device = Device::Cpu;
+    let mut cache = DynamicCache::with_num_layers(1)
+        .with_max_seq_len(4, CacheEviction::AttentionSink { sink: 1 });
+    // One head of width one whose value at each position is the position itself.
+    let positions = Tensor::arange(0f32, 6., &device)?;
+    let key_t = positions.reshape((1, 1, 1, 6))?;
+    let value = positions.reshape((1, 1, 6, 1))?;
+    cache.append(0, KvCacheChunk::new(key_t, value)?)?;
+
+    // The three-position window after the sink is cut back to its newest half in one go.
+    assert_eq!(cache.enforce_max_seq_len()?, 3);
+    assert_eq!(cache.seq_len(), Some(3));
+    assert_eq!(cache.next_position(), 6);
+    assert_eq!(cache.stats().evicted_positions, 3);
+    let entry = cache.get(0).expect("layer 0 cached");
+    assert_eq!(
+        entry.value_view()?.flatten_all()?.to_vec1::<f32>()?,
+        vec![0.0, 4.0, 5.0]
+    );
+    assert_eq!(
+        entry.key_view()?.flatten_all()?.to_vec1::<f32>()?,
+        vec![0.0, 4.0, 5.0]
+    );
+    // The next position fits under the bound without another eviction.
+    let next = Tensor::new(&[6f32], &device)?;
+    cache.append(
+        0,
+        KvCacheChunk::new(next.reshape((1, 1, 1, 1))?, next.reshape((1, 1, 1, 1))?)?,
+    )?;
+    assert_eq!(cache.enforce_max_seq_len()?, 0);
+    assert_eq!(cache.seq_len(), Some(4));
+    assert!(cache.to_snapshot().is_err());
+
+    cache.clear();
+    assert_eq!(cache.next_position(), 0);
+    Ok(())
+}
+
+#[test]
+fn compact_stride_sets_how_far_below_the_bound_evictions_go() -> Result<()> {
+    let device = Device::Cpu;
+    let append = |cache: &mut DynamicCache, start: usize, len: usize| -> Result<()> {
+        let positions = Tensor::arange(start as f32, (start + len) as f32, &device)?;
+        cache.append(
+            0,
+            KvCacheChunk::new(
+                positions.reshape((1, 1, 1, len))?,
+                positions.reshape((1, 1, len, 1))?,
+            )?,
+        )
+    };
+    let mut cache = DynamicCache::with_num_layers(1)
+        .with_max_seq_len(8, CacheEviction::AttentionSink { sink: 2 })
+        .with_compact_stride(2);
+    assert_eq!(cache.compact_stride(), Some(2));
+    append(&mut cache, 0, 9)?;
+    // Two positions below the bound rather than half of the six-position window.
+    assert_eq!(cache.enforce_max_seq_len()?, 3);
+    assert_eq!(cache.seq_len(), Some(6));
+    assert_eq!(
+        cache
+            .get(0)
+            .expect("layer 0 cached")
+            .value_view()?
+            .flatten_all()?
+            .to_vec1::<f32>()?,
+        vec![0.0, 1.0, 5.0, 6.0, 7.0, 8.0]
+    );
+    // The freed positions fill up before the next eviction.
+    append(&mut cache, 9, 2)?;
+    assert_eq!(cache.enforce_max_seq_len()?, 0);
+    append(&mut cache, 11, 1)?;
+    assert_eq!(cache.enforce_max_seq_len()?, 3);
+    assert_eq!(cache.next_position(), 12);
+    // Clearing keeps the stride along with the bound.
+    cache.clear();
+    assert_eq!(cache.compact_stride(), Some(2));
+
+    let mut too_wide = DynamicCache::with_num_layers(1)
+        .with_max_seq_len(8, CacheEviction::AttentionSink { sink: 2 })
+        .with_compact_stride(6);
+    append(&mut too_wide, 0, 9)?;
+    let err = too_wide.enforce_max_seq_len().unwrap_err();
+    assert!(err.to_string().contains("compact stride 6"), "{err:#}");
+    Ok(())
+}
+
+/// Decode throughput of a bounded cache at long context for a few compact strides. Ignored by
+/// default; run with
+/// `cargo test --release -p deepseek-ocr-core --test transformer_cache -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn long_context_compaction_throughput() -> Result<()> {
+    const LAYERS: usize = 4;
+    const HEADS: usize = 8;
+    const HEAD_DIM: usize = 64;
+    const MAX_SEQ_LEN: usize = 2048;
+    const STEPS: usize = 1024;
+    let device = Device::Cpu;
+    let step = make_chunk(&device, 1, HEADS, 1, HEAD_DIM)?;
+    let mut rebuilds_by_stride = Vec::new();
+    for stride in [1, 16, 128, MAX_SEQ_LEN / 2] {
+        let mut cache = DynamicCache::with_num_layers(LAYERS)
+            .with_max_seq_len(MAX_SEQ_LEN, CacheEviction::DropOldest)
+            .with_compact_stride(stride);
+        let prompt = make_chunk(&device, 1, HEADS, MAX_SEQ_LEN, HEAD_DIM)?;
+        for layer in 0..LAYERS {
+            cache.append(layer, prompt.clone())?;
+        }
+        let start = std::time::Instant::now();
+        let mut rebuilds = 0;
+        for _ in 0..STEPS {
+            for layer in 0..LAYERS {
+                cache.append(layer, step.clone())?;
+            }
+            if cache.enforce_max_seq_len()? > 0 {
+                rebuilds += 1;
+            }
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "compact stride {stride:>5}: {:>8.0} tokens/s, {rebuilds} rebuilds, {} evicted",
+            STEPS as f64 / elapsed.as_secs_f64(),
+            cache.stats().evicted_positions
+        );
+        rebuilds_by_stride.push(rebuilds);
+    }
+    assert!(rebuilds_by_stride.windows(2).all(|pair| pair[0] > pair[1]));
+    Ok(())
+}
+
+#[test]
+fn bounded_cache_keeps_counting_positions_past_evictions() -> Result<()> {
+    let model = tiny_language_model(None)?;
+    let layers = model.transformer_weights().layers.len();
+    let mut cache =
+        DynamicCache::with_num_layers(layers).with_max_seq_len(4, CacheEviction::DropOldest);
+    let device = Device::Cpu;
+    model.forward(
+        Some(&Tensor::new(&[[7i64, 8, 9]], &device)?),
+        None,
+        None,
+        None,
+        Some(&mut cache),
+        true,
+    )?;
+    for token in [10i64, 11, 12] {
+        let ids = Tensor::new(&[[token]], &device)?;
+        model.forward(Some(&ids), None, None, None, Some(&mut cache), true)?;
+    }
+    // Going over the bound at the fifth position evicted three, leaving two plus the sixth.
+    assert_eq!(cache.seq_len(), Some(3));
+    assert_eq!(cache.next_position(), 6);
+
+    let step = |positions: Option<&Tensor>| -> Result<Vec<f32>> {
+        let mut cache = cache.clone();
+        let ids = Tensor::new(&[[13i64]], &device)?;
+        let out = model.forward(Some(&ids), None, None, positions, Some(&mut cache), true)?;
+        assert_eq!(cache.seq_len(), Some(4));
+        Ok(out.logits.flatten_all()?.to_vec1::<f32>()?)
+    };
+    let implicit = step(None)?;
+    assert_eq!(implicit, step(Some(&Tensor::new(&[[6i64]], &device)?))?);
+    assert_ne!(
+        implicit,
+        step(Some(&Tensor::new(&[[3i64]], &device)?))?,
+        "positions must not restart from the cached length"
+    );
+    Ok(())
+}
+
+#[test]
+fn forks_share_the_prefix_and_diverge_independently() -> Result<()> {
+    let device = Device::Cpu;
+    let chunk = |values: &[f32]| -> Result<KvCacheChunk> {
+        let len = values.len();
+        let tensor = Tensor::new(values, &device)?;
+        KvCacheChunk::new(
+            tensor.reshape((1, 1, 1, len))?,
+            tensor.reshape((1, 1, len, 1))?,
+        )
+    };
+    let mut cache = DynamicCache::with_num_layers(1);
+    cache.append(0, chunk(&[1.0, 2.0, 3.0])?)?;
+    let mut fork = cache.fork()?;
+    // The fork reuses the prefix without growth headroom.
+    assert_eq!(fork.memory_bytes(), 2 * 3 * 4);
+
+    cache.append(0, chunk(&[4.0])?)?;
+    fork.append(0, chunk(&[9.0])?)?;
+    let values = |cache: &DynamicCache| -> Result<Vec<f32>> {
+        let entry = cache.get(0).expect("layer 0 cached");
+        Ok(entry.value_view()?.flatten_all()?.to_vec1::<f32>()?)
+    };
+    assert_eq!(values(&cache)?, vec![1.0, 2.0, 3.0, 4.0]);
+    assert_eq!(values(&fork)?, vec![1.0, 2.0, 3.0, 9.0]);
+    assert_eq!(fork.seq_len(), Some(4));
+    Ok(())
+}
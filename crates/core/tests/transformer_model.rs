@@ -3,7 +3,9 @@ mod common;
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
 use common::test_utils::with_shared_language_model;
-use deepseek_ocr_core::transformer::{cache::DynamicCache, model::DeepseekLanguageModel};
+use deepseek_ocr_core::transformer::{
+    cache::DynamicCache, decoder::next_token_entropy, model::DeepseekLanguageModel,
+};
 
 fn with_language_model<F>(label: &str, f: F) -> Result<()>
 where
@@ -56,3 +58,53 @@ fn language_model_decode_appends_cache() -> Result<()> {
         Ok(())
     })
 }
+
+#[test]
+fn chunked_prefill_matches_single_pass() -> Result<()> {
+    with_language_model("chunked prefill test", |model| {
+        let device = Device::Cpu;
+        let layers = model.transformer_weights().layers.len();
+        let ids: Vec<i64> = (0..7).map(|i| 100 + 37 * i).collect();
+        let input_ids = Tensor::from_vec(ids, (1, 7), &device)?;
+        let embeds = model.embed_tokens(&input_ids)?;
+
+        let mut cache = DynamicCache::with_num_layers(layers);
+        let full = model.forward(Some(&input_ids), None, None, None, Some(&mut cache), true)?;
+        let mut chunked_cache = DynamicCache::with_num_layers(layers);
+        let chunked = model.forward_chunked(&embeds, None, None, &mut chunked_cache, 3)?;
+        assert_eq!(chunked_cache.seq_len(), Some(7));
+        // Only the final position reaches the head; earlier chunks live on in the cache alone.
+        assert_eq!(chunked.logits.dim(1)?, 1);
+        assert_eq!(chunked.hidden_states.dim(1)?, 1);
+
+        let full_logits = full.logits.narrow(1, 6, 1)?.to_dtype(DType::F32)?;
+        let chunked_logits = chunked.logits.to_dtype(DType::F32)?;
+        let diff = (&full_logits - &chunked_logits)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-3, "chunked logits differ by {diff}");
+        assert_eq!(
+            full_logits.argmax(2)?.to_vec2::<u32>()?,
+            chunked_logits.argmax(2)?.to_vec2::<u32>()?
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn next_token_entropy_reads_the_last_position_of_each_row() -> Result<()> {
+    let vocab = 8;
+    let mut values = vec![0.0f32; 2 * 2 * vocab];
+    // Row 0 ends uniform; row 1 ends all but certain. The first position must be ignored.
+    values[0] = 50.0;
+    values[2 * vocab + vocab + 3] = 50.0;
+    let logits = Tensor::from_vec(values, (2, 2, vocab), &Device::Cpu)?;
+    let entropy = next_token_entropy(&logits)?;
+    assert!(
+        (entropy[0] - (vocab as f32).ln()).abs() < 1e-5,
+        "{entropy:?}"
+    );
+    assert!(entropy[1] < 1e-5, "{entropy:?}");
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use deepseek_ocr_core::transformer::sampling::{
+    LogitsSampler, RepetitionPenalty, SamplingParams, repeated_ngram_tokens,
+};
+
+fn logits(rows: &[[f32; 4]]) -> Result<Tensor> {
+    let flat: Vec<f32> = rows.iter().flatten().copied().collect();
+    Ok(Tensor::from_vec(flat, (rows.len(), 4), &Device::Cpu)?)
+}
+
+#[test]
+fn greedy_sampler_takes_argmax_of_last_position() -> Result<()> {
+    let logits = logits(&[[9.0, 0.0, 0.0, 0.0], [0.0, 1.0, 3.0, 2.0]])?;
+    assert_eq!(LogitsSampler::greedy().sample(&logits)?, 2);
+    // Top-k of one leaves nothing to chance at any temperature.
+    let sampler = LogitsSampler::new(2.0).with_top_k(1).with_seed(7);
+    assert_eq!(sampler.sample(&logits)?, 2);
+    Ok(())
+}
+
+#[test]
+fn seeded_sampler_is_reproducible() -> Result<()> {
+    let logits = logits(&[[1.0, 1.0, 1.0, 1.0]])?;
+    let draw = |sampler: &LogitsSampler| -> Result<Vec<u32>> {
+        (0..32).map(|_| sampler.sample(&logits)).collect()
+    };
+    let sampler = LogitsSampler::new(1.0).with_seed(42);
+    let copy = sampler.clone();
+    let first = draw(&sampler)?;
+    assert_eq!(first, draw(&LogitsSampler::new(1.0).with_seed(42))?);
+    assert_eq!(first, draw(&copy)?);
+    assert!(first.iter().any(|&token| token != first[0]));
+    Ok(())
+}
+
+#[test]
+fn seeded_sampling_params_build_identical_samplers() -> Result<()> {
+    assert!(SamplingParams::default().sampler().is_none());
+    let logits = logits(&[[1.0, 1.0, 1.0, 1.0]])?;
+    let params = SamplingParams {
+        temperature: 1.0,
+        top_p: None,
+        seed: Some(9),
+    };
+    let draw = || -> Result<Vec<u32>> {
+        let sampler = params.sampler().expect("temperature above zero samples");
+        (0..32).map(|_| sampler.sample(&logits)).collect()
+    };
+    assert_eq!(draw()?, draw()?);
+    Ok(())
+}
+
+#[test]
+fn filters_exclude_unlikely_and_banned_tokens() -> Result<()> {
+    let logits = logits(&[[f32::NEG_INFINITY, 5.0, 4.9, -10.0]])?;
+    let nucleus = LogitsSampler::new(1.0).with_top_p(0.5).with_seed(1);
+    let top_two = LogitsSampler::new(1.0).with_top_k(2).with_seed(1);
+    for _ in 0..64 {
+        assert_eq!(nucleus.sample(&logits)?, 1);
+        assert!(matches!(top_two.sample(&logits)?, 1 | 2));
+    }
+    Ok(())
+}
+
+#[test]
+fn repetition_penalty_lowers_repeated_token_logits() -> Result<()> {
+    let logits = Tensor::new(&[4.0f32, -1.0, 3.0, 0.5], &Device::Cpu)?;
+    let generated = [0, 1, 0, 1, 0, 1];
+    let penalized = RepetitionPenalty::new(2.0)?
+        .apply(&logits, &generated)?
+        .to_vec1::<f32>()?;
+    assert_eq!(penalized, vec![2.0, -2.0, 3.0, 0.5]);
+    // The looping token no longer wins the argmax.
+    let greedy = LogitsSampler::greedy();
+    assert_eq!(greedy.sample(&logits)?, 0);
+    assert_eq!(
+        greedy.sample(&Tensor::new(penalized.as_slice(), &Device::Cpu)?)?,
+        2
+    );
+    Ok(())
+}
+
+#[test]
+fn repetition_penalty_must_be_positive_and_finite() {
+    for penalty in [0.0, -1.5, f32::NAN, f32::INFINITY] {
+        assert!(
+            RepetitionPenalty::new(penalty).is_err(),
+            "penalty {penalty} was accepted"
+        );
+    }
+    assert!(RepetitionPenalty::new(0.5).is_ok());
+}
+
+#[test]
+fn no_repeat_ngram_bans_completions_of_seen_ngrams() {
+    let generated = [7, 8, 9, 7, 8, 4, 7];
+    assert_eq!(repeated_ngram_tokens(&generated, 2), vec![8]);
+    assert_eq!(repeated_ngram_tokens(&generated[..5], 3), vec![9]);
+    assert!(repeated_ngram_tokens(&generated, 4).is_empty());
+    assert!(repeated_ngram_tokens(&generated, 0).is_empty());
+}
@@ -1,8 +1,18 @@
 mod common;
 
-use anyhow::Result;
-use common::test_utils::{shared_language_config, shared_transformer_weights};
-use deepseek_ocr_core::transformer::weights::MlpWeights;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use candle_core::{D, DType, Device, Module, Tensor};
+use candle_nn::VarBuilder;
+use common::test_utils::{
+    shared_language_config, shared_transformer_weights, tiny_language_weights,
+};
+use deepseek_ocr_core::transformer::{
+    lora::LoraAdapter,
+    model::DeepseekLanguageModel,
+    weights::{LinearWeights, MlpWeights, WeightQuant},
+};
 
 #[test]
 fn transformer_weights_load_from_safetensor() -> Result<()> {
@@ -41,3 +51,190 @@ fn transformer_weights_load_from_safetensor() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn int8_quantized_linear_tracks_dense_projection() -> Result<()> {
+    let device = Device::Cpu;
+    let (out_dim, in_dim) = (48, 128);
+    let weight = Tensor::randn(0f32, 0.05, (out_dim, in_dim), &device)?;
+    let input = Tensor::randn(0f32, 1.0, (4, in_dim), &device)?;
+    let expected = input.matmul(&weight.t()?)?;
+
+    let mut linear = LinearWeights {
+        weight,
+        bias: None,
+        qmatmul: None,
+        lora: Vec::new(),
+    };
+    let saved = linear.quantize(WeightQuant::Int8)?;
+    // Q8_0 stores 32 int8 values plus one f16 scale per block.
+    let quantized_bytes = out_dim * (in_dim / 32) * 34;
+    assert_eq!(
+        saved,
+        out_dim * in_dim * DType::F32.size_in_bytes() - quantized_bytes
+    );
+    assert_eq!(linear.weight.shape().dims2()?, (out_dim, in_dim));
+    assert_eq!(
+        linear.quantize(WeightQuant::Int8)?,
+        0,
+        "second pass is a no-op"
+    );
+
+    let actual = linear
+        .qmatmul
+        .as_ref()
+        .context("quantized kernel missing")?
+        .forward(&input)?;
+    let err = (&actual - &expected)?.sqr()?.sum(D::Minus1)?.sqrt()?;
+    let norm = expected.sqr()?.sum(D::Minus1)?.sqrt()?;
+    let relative = (err / norm)?.max(0)?.to_scalar::<f32>()?;
+    assert!(relative < 0.02, "relative error too large: {relative}");
+    Ok(())
+}
+
+#[test]
+fn quantize_skips_layers_not_aligned_to_blocks() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 0.05, (8, 40), &device)?;
+    let mut linear = LinearWeights {
+        weight: weight.clone(),
+        bias: None,
+        qmatmul: None,
+        lora: Vec::new(),
+    };
+    assert_eq!(linear.quantize(WeightQuant::Int8)?, 0);
+    assert!(linear.qmatmul.is_none());
+    let diff = (&linear.weight - &weight)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert_eq!(diff, 0.0);
+    Ok(())
+}
+
+#[test]
+fn int4_quantized_decoder_tracks_dense_logits() -> Result<()> {
+    let device = Device::Cpu;
+    let (cfg, varmap) = tiny_language_weights()?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let dense = DeepseekLanguageModel::load(Arc::clone(&cfg), &vb)?;
+    let model = DeepseekLanguageModel::load_with_quantization(cfg, &vb, Some(WeightQuant::Int4))?;
+    for layer in &model.transformer_weights().layers {
+        let attention = &layer.attention;
+        for proj in [
+            &attention.q_proj,
+            &attention.k_proj,
+            &attention.v_proj,
+            &attention.o_proj,
+        ] {
+            assert!(proj.qmatmul.is_some(), "attention projection left dense");
+        }
+    }
+
+    let input_ids = Tensor::new(&[[1i64, 5, 9, 2]], &device)?;
+    let logits = |model: &DeepseekLanguageModel| -> Result<Tensor> {
+        Ok(model
+            .forward(Some(&input_ids), None, None, None, None, false)?
+            .logits)
+    };
+    let expected = logits(&dense)?;
+    let actual = logits(&model)?;
+    assert_eq!(actual.shape().dims3()?, (1, 4, 96));
+    let err = (&actual - &expected)?.sqr()?.sum_all()?.sqrt()?;
+    let norm = expected.sqr()?.sum_all()?.sqrt()?;
+    let relative = (err / norm)?.to_scalar::<f32>()?;
+    // Q4_0 lands around 0.15 on this random decoder; a broken kernel is off by far more, and a
+    // decoder that silently stayed dense matches exactly.
+    assert!(
+        relative > 0.0 && relative < 0.3,
+        "relative error vs dense logits: {relative}"
+    );
+    Ok(())
+}
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    Ok((a - b)?.abs()?.max_all()?.to_scalar::<f32>()?)
+}
+
+fn write_adapter(name: &str, tensors: &[(&str, &Tensor)]) -> Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!("deepseek-ocr-lora-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("adapter_model.safetensors");
+    let tensors: HashMap<String, Tensor> = tensors
+        .iter()
+        .map(|(key, tensor)| (format!("base_model.model.model.{key}"), (*tensor).clone()))
+        .collect();
+    candle_core::safetensors::save(&tensors, &path)?;
+    Ok(path)
+}
+
+#[test]
+fn zero_scaled_lora_adapter_keeps_base_logits() -> Result<()> {
+    let device = Device::Cpu;
+    let (cfg, varmap) = tiny_language_weights()?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let a = Tensor::randn(0f32, 0.2, (4, 64), &device)?;
+    let b = Tensor::randn(0f32, 0.2, (64, 4), &device)?;
+    let path = write_adapter(
+        "zero",
+        &[
+            ("layers.0.self_attn.q_proj.lora_A.weight", &a),
+            ("layers.0.self_attn.q_proj.lora_B.weight", &b),
+        ],
+    )?;
+
+    let base = DeepseekLanguageModel::load(Arc::clone(&cfg), &vb)?;
+    let adapted =
+        DeepseekLanguageModel::load_with_adapters(cfg, &vb, None, &[LoraAdapter::new(&path, 0.0)])?;
+    let input_ids = Tensor::new(&[[3i64, 17, 42, 5]], &device)?;
+    let logits = |model: &DeepseekLanguageModel| -> Result<Tensor> {
+        Ok(model
+            .forward(Some(&input_ids), None, None, None, None, false)?
+            .logits)
+    };
+    let base_logits = logits(&base)?;
+    assert_eq!(max_abs_diff(&logits(&adapted)?, &base_logits)?, 0.0);
+
+    adapted.set_lora_scale(0, 1.0)?;
+    assert_eq!(adapted.lora_scales(), [1.0]);
+    let enabled = logits(&adapted)?;
+    assert!(max_abs_diff(&enabled, &base_logits)? > 1e-3);
+    adapted.set_lora_scale(0, 0.0)?;
+    assert_eq!(max_abs_diff(&logits(&adapted)?, &base_logits)?, 0.0);
+    assert!(adapted.set_lora_scale(1, 1.0).is_err());
+
+    // The enabled adapter behaves like the merged weight `W + B A`.
+    let name = "model.layers.0.self_attn.q_proj.weight";
+    let q_proj = varmap.data().lock().unwrap()[name].clone();
+    q_proj.set(&(q_proj.as_tensor() + b.matmul(&a)?)?)?;
+    let merged = logits(&base)?;
+    assert!(max_abs_diff(&enabled, &merged)? < 1e-4);
+    std::fs::remove_dir_all(path.parent().unwrap())?;
+    Ok(())
+}
+
+#[test]
+fn lora_adapter_with_mismatched_shape_is_rejected() -> Result<()> {
+    let device = Device::Cpu;
+    let (cfg, varmap) = tiny_language_weights()?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let a = Tensor::zeros((4, 32), DType::F32, &device)?;
+    let b = Tensor::zeros((128, 4), DType::F32, &device)?;
+    let path = write_adapter(
+        "shape",
+        &[
+            ("layers.1.mlp.down_proj.lora_A.weight", &a),
+            ("layers.1.mlp.down_proj.lora_B.weight", &b),
+        ],
+    )?;
+    let err =
+        DeepseekLanguageModel::load_with_adapters(cfg, &vb, None, &[LoraAdapter::new(&path, 1.0)])
+            .err()
+            .context("mismatched adapter loaded")?;
+    assert!(
+        format!("{err:#}").contains("layers.1.mlp.down_proj"),
+        "{err:#}"
+    );
+    std::fs::remove_dir_all(path.parent().unwrap())?;
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use deepseek_ocr_core::vision::{Binarize, BinarizeMethod, Preprocessor, otsu_threshold};
+use image::{DynamicImage, GrayImage, Luma};
+
+const WIDTH: u32 = 600;
+const HEIGHT: u32 = 200;
+
+fn is_ink(x: u32, y: u32) -> bool {
+    (y % 25) < 3 && (20..WIDTH - 20).contains(&x) && x % 9 < 6
+}
+
+/// Faint text on paper whose brightness drifts from `left` to `right`; ink is 35 levels darker
+/// than the paper around it.
+fn faint_scan(left: f32, right: f32) -> DynamicImage {
+    DynamicImage::ImageLuma8(GrayImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let paper = left + (right - left) * x as f32 / WIDTH as f32;
+        let value = if is_ink(x, y) { paper - 35.0 } else { paper };
+        Luma([value as u8])
+    }))
+}
+
+/// Difference between the mean paper and mean ink luma.
+fn separation(image: &DynamicImage) -> f32 {
+    let luma = image.to_luma8();
+    let (mut ink, mut paper) = ((0.0, 0), (0.0, 0));
+    for (x, y, pixel) in luma.enumerate_pixels() {
+        let bucket = if is_ink(x, y) { &mut ink } else { &mut paper };
+        bucket.0 += pixel.0[0] as f32;
+        bucket.1 += 1;
+    }
+    paper.0 / paper.1 as f32 - ink.0 / ink.1 as f32
+}
+
+/// Fraction of pixels that ended up black exactly where the fixture has ink.
+fn accuracy(image: &DynamicImage) -> f32 {
+    let luma = image.to_luma8();
+    let correct = luma
+        .enumerate_pixels()
+        .filter(|(x, y, pixel)| (pixel.0[0] == 0) == is_ink(*x, *y))
+        .count();
+    correct as f32 / (WIDTH * HEIGHT) as f32
+}
+
+#[test]
+fn otsu_separates_evenly_lit_low_contrast_scan() {
+    let scan = faint_scan(190.0, 190.0);
+    let threshold = otsu_threshold(&scan.to_luma8());
+    assert!((155..190).contains(&threshold), "threshold {threshold}");
+
+    let binary = Binarize {
+        method: BinarizeMethod::Otsu,
+    }
+    .process(scan.clone())
+    .unwrap();
+    assert!(separation(&binary) > 3.0 * separation(&scan));
+    assert_eq!(accuracy(&binary), 1.0);
+}
+
+#[test]
+fn adaptive_handles_uneven_lighting() {
+    let scan = faint_scan(110.0, 230.0);
+    let adaptive = Binarize {
+        method: BinarizeMethod::Adaptive,
+    }
+    .process(scan.clone())
+    .unwrap();
+    let otsu = Binarize {
+        method: BinarizeMethod::Otsu,
+    }
+    .process(scan.clone())
+    .unwrap();
+    assert!(separation(&adaptive) > 3.0 * separation(&scan));
+    assert!(
+        accuracy(&adaptive) > 0.98,
+        "accuracy {}",
+        accuracy(&adaptive)
+    );
+    assert!(accuracy(&adaptive) > accuracy(&otsu));
+}
@@ -0,0 +1,64 @@
+use deepseek_ocr_core::vision::{
+    BuiltinPreprocessor, PreprocessOptions, PreprocessPipeline, deskew, estimate_skew,
+};
+use image::{DynamicImage, GrayImage, Luma};
+
+/// White page with dashed "text lines" sloping down to the right by `degrees`.
+fn skewed_page(degrees: f32) -> DynamicImage {
+    let slope = degrees.to_radians().tan();
+    let image = GrayImage::from_fn(600, 400, |x, y| {
+        let offset = y as f32 - x as f32 * slope;
+        let in_line = (40.0..360.0).contains(&offset) && (offset as i32 % 30) < 4;
+        let in_text = (40..560).contains(&x) && x % 7 != 0;
+        if in_line && in_text {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    });
+    DynamicImage::ImageLuma8(image)
+}
+
+#[test]
+fn estimates_skew_in_both_directions() {
+    for degrees in [-3.0f32, 0.0, 2.0] {
+        let estimate = estimate_skew(&skewed_page(degrees), 5.0);
+        assert!(
+            (estimate - degrees).abs() < 0.2,
+            "expected {degrees}, got {estimate}"
+        );
+    }
+}
+
+#[test]
+fn skew_beyond_max_angle_is_not_corrected() {
+    let estimate = estimate_skew(&skewed_page(4.0), 1.0);
+    assert!(estimate.abs() <= 1.0);
+    assert_eq!(estimate_skew(&skewed_page(4.0), 0.0), 0.0);
+}
+
+#[test]
+fn deskewed_page_is_level() {
+    let (straight, angle) = deskew(skewed_page(2.5), 5.0);
+    assert!((angle - 2.5).abs() < 0.2, "detected {angle}");
+    assert!(estimate_skew(&straight, 5.0).abs() < 0.2);
+}
+
+#[test]
+fn pipeline_reports_corrected_angle() {
+    let pipeline = PreprocessPipeline::from_builtins_with(
+        &[BuiltinPreprocessor::Deskew],
+        &PreprocessOptions {
+            deskew_max_angle: 5.0,
+            ..PreprocessOptions::default()
+        },
+    );
+    let (_, stats) = pipeline.apply_with_stats(skewed_page(-2.0)).unwrap();
+    let angle = stats.deskew_degrees.expect("deskew reports its angle");
+    assert!((angle + 2.0).abs() < 0.2, "detected {angle}");
+
+    let (_, stats) = PreprocessPipeline::new()
+        .apply_with_stats(skewed_page(-2.0))
+        .unwrap();
+    assert_eq!(stats.deskew_degrees, None);
+}
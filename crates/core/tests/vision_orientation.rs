@@ -0,0 +1,101 @@
+use anyhow::Result;
+use deepseek_ocr_core::vision::load_image_from_memory;
+use image::{
+    ExtendedColorType, GenericImageView, ImageEncoder, Rgb, RgbImage, codecs::jpeg::JpegEncoder,
+};
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 16;
+
+/// 32x16 black image with a white top-left quadrant, so every EXIF transform is distinguishable.
+fn marker_image() -> RgbImage {
+    RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        if x < WIDTH / 2 && y < HEIGHT / 2 {
+            Rgb([255, 255, 255])
+        } else {
+            Rgb([0, 0, 0])
+        }
+    })
+}
+
+/// Minimal little-endian TIFF block holding a single orientation (0x0112) entry.
+fn exif_with_orientation(value: u16) -> Vec<u8> {
+    let mut exif = Vec::new();
+    exif.extend_from_slice(b"II");
+    exif.extend_from_slice(&42u16.to_le_bytes());
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&0x0112u16.to_le_bytes());
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&value.to_le_bytes());
+    exif.extend_from_slice(&0u16.to_le_bytes());
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif
+}
+
+fn jpeg_fixture(orientation: u16) -> Result<Vec<u8>> {
+    let image = marker_image();
+    let mut bytes = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut bytes, 100);
+    encoder.set_exif_metadata(exif_with_orientation(orientation))?;
+    encoder.write_image(image.as_raw(), WIDTH, HEIGHT, ExtendedColorType::Rgb8)?;
+    Ok(bytes)
+}
+
+/// Returns which quadrant (column, row) of the decoded image holds the white marker.
+fn marker_quadrant(image: &image::DynamicImage) -> (u32, u32) {
+    let (w, h) = image.dimensions();
+    let rgb = image.to_rgb8();
+    let mut best = (0, 0);
+    let mut best_value = 0u32;
+    for qy in 0..2 {
+        for qx in 0..2 {
+            let x = qx * w / 2 + w / 4;
+            let y = qy * h / 2 + h / 4;
+            let value = rgb.get_pixel(x, y).0.iter().map(|&c| c as u32).sum();
+            if value > best_value {
+                best_value = value;
+                best = (qx, qy);
+            }
+        }
+    }
+    best
+}
+
+#[test]
+fn exif_orientation_is_applied_for_every_value() -> Result<()> {
+    // (exif value, expected dimensions, expected marker quadrant)
+    let cases = [
+        (1u16, (WIDTH, HEIGHT), (0, 0)),
+        (2, (WIDTH, HEIGHT), (1, 0)),
+        (3, (WIDTH, HEIGHT), (1, 1)),
+        (4, (WIDTH, HEIGHT), (0, 1)),
+        (5, (HEIGHT, WIDTH), (0, 0)),
+        (6, (HEIGHT, WIDTH), (1, 0)),
+        (7, (HEIGHT, WIDTH), (1, 1)),
+        (8, (HEIGHT, WIDTH), (0, 1)),
+    ];
+    for (value, dims, quadrant) in cases {
+        let bytes = jpeg_fixture(value)?;
+        let image = load_image_from_memory(&bytes, true)?;
+        assert_eq!(image.dimensions(), dims, "orientation {value} dimensions");
+        assert_eq!(
+            marker_quadrant(&image),
+            quadrant,
+            "orientation {value} marker"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn exif_orientation_can_be_disabled() -> Result<()> {
+    for value in 1u16..=8 {
+        let bytes = jpeg_fixture(value)?;
+        let image = load_image_from_memory(&bytes, false)?;
+        assert_eq!(image.dimensions(), (WIDTH, HEIGHT));
+        assert_eq!(marker_quadrant(&image), (0, 0));
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use deepseek_ocr_core::vision::{PageBreakOptions, detect_page_breaks, detect_page_breaks_with};
+use image::{DynamicImage, GenericImageView, GrayImage, Luma};
+
+const WIDTH: u32 = 200;
+
+/// White canvas with black text-like bars covering the given `(top, bottom)` row ranges.
+fn stacked_pages(height: u32, inked: &[(u32, u32)]) -> DynamicImage {
+    let image = GrayImage::from_fn(WIDTH, height, |x, y| {
+        let in_band = inked.iter().any(|&(top, bottom)| y >= top && y < bottom);
+        if in_band && (20..180).contains(&x) && y % 4 != 0 {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    });
+    DynamicImage::ImageLuma8(image)
+}
+
+#[test]
+fn splits_at_wide_whitespace_gaps() {
+    // Three "pages" separated by 100px gaps, with small paragraph gaps inside each page.
+    let image = stacked_pages(1100, &[(20, 150), (170, 300), (400, 700), (800, 1080)]);
+    let slices = detect_page_breaks(&image);
+    let bounds: Vec<(u32, u32)> = slices.iter().map(|s| (s.top, s.top + s.height)).collect();
+    assert_eq!(bounds, vec![(0, 350), (350, 750), (750, 1100)]);
+    for slice in &slices {
+        assert_eq!(slice.image.dimensions(), (WIDTH, slice.height));
+    }
+}
+
+#[test]
+fn short_sections_merge_into_neighbours() {
+    // The 30px band between the two pages is too short to stand alone and joins the first page.
+    let image = stacked_pages(820, &[(20, 250), (350, 380), (480, 800)]);
+    let options = PageBreakOptions {
+        min_gap_height: 64,
+        threshold: 0.01,
+        min_section_height: 200,
+    };
+    let slices = detect_page_breaks_with(&image, &options);
+    let bounds: Vec<(u32, u32)> = slices.iter().map(|s| (s.top, s.top + s.height)).collect();
+    assert_eq!(bounds, vec![(0, 430), (430, 820)]);
+}
+
+#[test]
+fn image_without_gaps_stays_whole() {
+    let image = stacked_pages(400, &[(10, 390)]);
+    let slices = detect_page_breaks(&image);
+    assert_eq!(slices.len(), 1);
+    assert_eq!((slices[0].top, slices[0].height), (0, 400));
+}
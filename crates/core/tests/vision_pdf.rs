@@ -0,0 +1,54 @@
+#![cfg(feature = "pdf")]
+
+use deepseek_ocr_core::vision::{PdfRenderOptions, render_pdf};
+
+/// One blank 1x1 inch page under the standard security handler (RC4, revision 2) with the user
+/// password `secret`, so it cannot be opened without one.
+const ENCRYPTED_PDF: &[u8] = include_bytes!("fixtures/encrypted.pdf");
+
+/// A single blank 1x1 inch page.
+const BLANK_PDF: &[u8] = b"%PDF-1.4
+1 0 obj << /Type /Catalog /Pages 2 0 R >> endobj
+2 0 obj << /Type /Pages /Kids [3 0 R] /Count 1 >> endobj
+3 0 obj << /Type /Page /Parent 2 0 R /MediaBox [0 0 72 72] >> endobj
+trailer << /Size 4 /Root 1 0 R >>
+%%EOF
+";
+
+/// Whether the pdfium shared library can be loaded; tests that render skip when it is absent.
+fn pdfium_available() -> bool {
+    match render_pdf(BLANK_PDF, &PdfRenderOptions::default()) {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("skipping: {err:#}");
+            false
+        }
+    }
+}
+
+#[test]
+fn non_positive_dpi_is_rejected_before_loading_pdfium() {
+    for dpi in [0.0, -72.0, f32::NAN] {
+        let err = render_pdf(BLANK_PDF, &PdfRenderOptions::new(dpi)).unwrap_err();
+        assert!(err.to_string().contains("DPI must be positive"), "{err:#}");
+    }
+}
+
+#[test]
+fn pages_render_at_the_requested_dpi() {
+    if !pdfium_available() {
+        return;
+    }
+    let pages = render_pdf(BLANK_PDF, &PdfRenderOptions::new(144.0)).unwrap();
+    assert_eq!(pages.len(), 1);
+    assert_eq!((pages[0].width(), pages[0].height()), (144, 144));
+}
+
+#[test]
+fn password_protected_pdf_reports_encryption() {
+    if !pdfium_available() {
+        return;
+    }
+    let err = render_pdf(ENCRYPTED_PDF, &PdfRenderOptions::default()).unwrap_err();
+    assert!(err.to_string().contains("PDF is encrypted"), "{err:#}");
+}
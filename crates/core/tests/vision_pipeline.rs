@@ -0,0 +1,90 @@
+use anyhow::{Result, bail};
+use deepseek_ocr_core::vision::{
+    BuiltinPreprocessor, ContrastNormalize, Grayscale, PreprocessPipeline, Preprocessor,
+};
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+
+struct Invert;
+
+impl Preprocessor for Invert {
+    fn name(&self) -> &str {
+        "invert"
+    }
+
+    fn process(&self, mut image: DynamicImage) -> Result<DynamicImage> {
+        image.invert();
+        Ok(image)
+    }
+}
+
+struct Fail;
+
+impl Preprocessor for Fail {
+    fn name(&self) -> &str {
+        "fail"
+    }
+
+    fn process(&self, _image: DynamicImage) -> Result<DynamicImage> {
+        bail!("boom")
+    }
+}
+
+fn solid(color: [u8; 3]) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb(color)))
+}
+
+#[test]
+fn empty_pipeline_leaves_image_untouched() {
+    let image = solid([10, 200, 30]);
+    let pipeline = PreprocessPipeline::new();
+    assert!(pipeline.is_empty());
+    let output = pipeline.apply(image.clone()).unwrap();
+    assert_eq!(output.to_rgb8(), image.to_rgb8());
+}
+
+#[test]
+fn custom_steps_run_in_order() {
+    let pipeline = PreprocessPipeline::new().with(Grayscale).with(Invert);
+    assert_eq!(pipeline.len(), 2);
+    let output = pipeline.apply(solid([255, 255, 255])).unwrap();
+    assert_eq!(output.get_pixel(0, 0).0, [0, 0, 0, 255]);
+}
+
+#[test]
+fn failing_step_is_named_in_the_error() {
+    let pipeline = PreprocessPipeline::new().with(Grayscale).with(Fail);
+    let err = pipeline.apply(solid([0, 0, 0])).unwrap_err();
+    assert!(format!("{err:#}").contains("preprocessor `fail` failed"));
+}
+
+#[test]
+fn grayscale_equalises_channels() {
+    let output = Grayscale.process(solid([200, 40, 90])).unwrap().to_rgb8();
+    let [r, g, b] = output.get_pixel(0, 0).0;
+    assert!(r == g && g == b);
+}
+
+#[test]
+fn contrast_normalize_stretches_to_full_range() {
+    // Faded scan: values only span 100..=150.
+    let image = RgbImage::from_fn(50, 1, |x, _| {
+        let v = 100 + x as u8;
+        Rgb([v, v, v])
+    });
+    let step = ContrastNormalize { clip_fraction: 0.0 };
+    let output = step
+        .process(DynamicImage::ImageRgb8(image))
+        .unwrap()
+        .to_rgb8();
+    assert_eq!(output.get_pixel(0, 0).0, [0, 0, 0]);
+    assert_eq!(output.get_pixel(49, 0).0, [255, 255, 255]);
+}
+
+#[test]
+fn builtins_build_in_order() {
+    let pipeline = PreprocessPipeline::from_builtins(&[
+        BuiltinPreprocessor::Grayscale,
+        BuiltinPreprocessor::Contrast,
+    ]);
+    assert_eq!(format!("{pipeline:?}"), r#"["grayscale", "contrast"]"#);
+}
@@ -0,0 +1,142 @@
+use deepseek_ocr_core::{
+    model::{ImageSizeBounds, TileRect},
+    vision::{dynamic_preprocess_with_strips, select_tile_grid},
+};
+use image::{DynamicImage, GenericImageView, RgbImage};
+
+const IMAGE_SIZE: u32 = 640;
+
+#[test]
+fn receipt_without_strip_tiling_uses_tallest_grid() {
+    assert_eq!(select_tile_grid(400, 8000, 2, 9, IMAGE_SIZE, None), (1, 9));
+}
+
+#[test]
+fn extreme_ratios_tile_into_strips_along_the_long_axis() {
+    // 1:20 receipt: one crop per width's length of height, so each covers a square of the
+    // receipt.
+    assert_eq!(
+        select_tile_grid(400, 8000, 2, 9, IMAGE_SIZE, Some(8.0)),
+        (1, 20)
+    );
+    // 12:1 banner becomes a single row of 12.
+    assert_eq!(
+        select_tile_grid(6000, 500, 2, 9, IMAGE_SIZE, Some(8.0)),
+        (12, 1)
+    );
+    // A 1:50 strip is capped so token counts stay bounded.
+    assert_eq!(
+        select_tile_grid(100, 5000, 2, 9, IMAGE_SIZE, Some(8.0)),
+        (1, 20)
+    );
+}
+
+#[test]
+fn ratios_below_threshold_keep_grid_selection() {
+    for (width, height) in [(1000, 3000), (3000, 2000), (640, 640)] {
+        assert_eq!(
+            select_tile_grid(width, height, 2, 9, IMAGE_SIZE, Some(8.0)),
+            select_tile_grid(width, height, 2, 9, IMAGE_SIZE, None),
+            "{width}x{height}"
+        );
+    }
+}
+
+#[test]
+fn strip_preprocess_emits_square_tiles() {
+    let image = DynamicImage::ImageRgb8(RgbImage::new(64, 640));
+    let result = dynamic_preprocess_with_strips(&image, 2, 9, 64, false, Some(5.0));
+    assert_eq!(result.ratio, (1, 10));
+    assert_eq!(result.tiles.len(), 10);
+    assert!(
+        result
+            .tiles
+            .iter()
+            .all(|tile| tile.dimensions() == (64, 64))
+    );
+}
+
+#[test]
+fn image_size_bounds_recommend_the_grid_resolution() {
+    let bounds = ImageSizeBounds::new(16, 1024, IMAGE_SIZE, true, None);
+    assert_eq!(bounds.max_long_side, 9 * IMAGE_SIZE);
+    // A 2:3 page maps to a 2x3 grid, so it needs 1280x1920 to keep every crop at full detail.
+    assert_eq!(bounds.tile_grid(2000, 3000), Some((2, 3)));
+    assert_eq!(bounds.recommended_size(2000, 3000), (1280, 1920));
+    assert_eq!(bounds.recommended_size(600, 800), (600, 800));
+
+    let global_only = ImageSizeBounds::new(16, 1024, IMAGE_SIZE, false, None);
+    assert_eq!(global_only.tile_grid(3000, 4000), None);
+    assert_eq!(global_only.recommended_size(3000, 4000), (768, 1024));
+    assert_eq!(global_only.max_long_side, 1024);
+}
+
+#[test]
+fn tile_layout_reports_each_crop_source_rectangle() {
+    let bounds = ImageSizeBounds::new(16, 1024, IMAGE_SIZE, true, None);
+    let layout = bounds.tile_layout(2000, 3000);
+    assert_eq!((layout.columns, layout.rows), (2, 3));
+    assert_eq!(layout.tiles.len(), 6);
+    assert_eq!(
+        layout.tiles[0],
+        TileRect {
+            x: 0,
+            y: 0,
+            width: 1000,
+            height: 1000
+        }
+    );
+    assert_eq!(
+        layout.tiles[5],
+        TileRect {
+            x: 1000,
+            y: 2000,
+            width: 1000,
+            height: 1000
+        }
+    );
+    // The 2:3 page is letterboxed into the middle of the square global view.
+    assert_eq!(
+        layout.global_content,
+        Some(TileRect {
+            x: 170,
+            y: 0,
+            width: 683,
+            height: 1024
+        })
+    );
+
+    // Uneven sizes still cover every source pixel exactly once.
+    let odd = bounds.tile_layout(1001, 500);
+    let covered: u32 = odd.tiles.iter().map(|tile| tile.width * tile.height).sum();
+    assert_eq!(covered, 1001 * 500);
+
+    let global_only = ImageSizeBounds::new(16, 1024, IMAGE_SIZE, false, None);
+    let layout = global_only.tile_layout(2000, 3000);
+    assert_eq!((layout.columns, layout.rows), (0, 0));
+    assert!(layout.tiles.is_empty());
+}
+
+#[test]
+fn tile_budget_picks_the_grid_closest_to_the_aspect_ratio() {
+    // 16:1 spreadsheet: the default budget squeezes it into 9 columns, a larger one matches it.
+    assert_eq!(select_tile_grid(1600, 100, 2, 9, IMAGE_SIZE, None), (9, 1));
+    assert_eq!(
+        select_tile_grid(1600, 100, 2, 16, IMAGE_SIZE, None),
+        (16, 1)
+    );
+    // 3:2 page fits a 3x2 grid either way.
+    assert_eq!(select_tile_grid(3000, 2000, 2, 9, IMAGE_SIZE, None), (3, 2));
+    assert_eq!(
+        select_tile_grid(3000, 2000, 2, 16, IMAGE_SIZE, None),
+        (3, 2)
+    );
+    // 1:3 page with the smallest budget gets a single column.
+    assert_eq!(select_tile_grid(1000, 3000, 2, 2, IMAGE_SIZE, None), (1, 2));
+
+    let bounds = ImageSizeBounds::new(16, 1024, IMAGE_SIZE, true, None).with_max_tiles(16);
+    assert_eq!(bounds.tile_grid(1600, 100), Some((16, 1)));
+    assert_eq!(bounds.max_long_side, 16 * IMAGE_SIZE);
+    let global_only = ImageSizeBounds::new(16, 1024, IMAGE_SIZE, false, None).with_max_tiles(16);
+    assert_eq!(global_only.tile_grid(1600, 100), None);
+}
@@ -0,0 +1,88 @@
+use std::{
+    fs::File,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor, safetensors};
+use deepseek_ocr_core::model::{WeightKeyRemap, shared_mmaped_safetensors, weights_var_builder};
+
+#[test]
+fn mappings_are_shared_until_the_file_changes() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("deepseek-ocr-mmap-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("weights.safetensors");
+    let tensor = Tensor::arange(0f32, 4.0, &Device::Cpu)?;
+    safetensors::save(&[("w", tensor)].into_iter().collect(), &path)?;
+
+    let first = shared_mmaped_safetensors(&path)?;
+    // A different spelling of the same file resolves to the same mapping.
+    let second = shared_mmaped_safetensors(&dir.join(".").join("weights.safetensors"))?;
+    assert!(Arc::ptr_eq(&first, &second));
+
+    File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+    let third = shared_mmaped_safetensors(&path)?;
+    assert!(!Arc::ptr_eq(&first, &third));
+    assert_eq!(third.load("w", &Device::Cpu)?.dims(), &[4]);
+
+    drop((first, second, third));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn sharded_checkpoints_load_through_their_index() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("deepseek-ocr-shards-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    for (shard, name) in [("a.safetensors", "first"), ("b.safetensors", "second")] {
+        let tensor = Tensor::ones(2, DType::F32, &Device::Cpu)?;
+        safetensors::save(&[(name, tensor)].into_iter().collect(), dir.join(shard))?;
+    }
+    let index = dir.join("model.safetensors.index.json");
+    let write_index = |weight_map: &str| {
+        std::fs::write(
+            &index,
+            format!(r#"{{"metadata": {{}}, "weight_map": {weight_map}}}"#),
+        )
+    };
+
+    write_index(r#"{"first": "a.safetensors", "second": "b.safetensors"}"#)?;
+    let vb = weights_var_builder(&index, DType::F32, &Device::Cpu)?;
+    assert_eq!(vb.get(2, "first")?.to_vec1::<f32>()?, vec![1.0, 1.0]);
+    assert_eq!(vb.get(2, "second")?.dims(), &[2]);
+    drop(vb);
+
+    write_index(r#"{"first": "a.safetensors", "third": "c.safetensors"}"#)?;
+    let Err(err) = shared_mmaped_safetensors(&index) else {
+        panic!("a missing shard must be reported");
+    };
+    assert!(format!("{err:#}").contains("c.safetensors"), "{err:#}");
+
+    write_index(r#"{"first": "a.safetensors", "third": "b.safetensors"}"#)?;
+    let Err(err) = shared_mmaped_safetensors(&index) else {
+        panic!("a tensor absent from its shard must be reported");
+    };
+    assert!(format!("{err:#}").contains("`third`"), "{err:#}");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn weight_key_remap_parses_from_to() -> Result<()> {
+    let remap: WeightKeyRemap = "model.layers.=model.language_model.layers.".parse()?;
+    assert_eq!(remap.from, "model.layers.");
+    assert_eq!(remap.to, "model.language_model.layers.");
+    assert_eq!(
+        remap.to_string(),
+        "model.layers.=model.language_model.layers."
+    );
+    let strip: WeightKeyRemap = "backbone.=".parse()?;
+    assert_eq!(strip.to, "");
+    assert!("no-separator".parse::<WeightKeyRemap>().is_err());
+    Ok(())
+}
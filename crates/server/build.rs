@@ -0,0 +1,39 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_grpc();
+}
+
+/// Generate the `Ocr` service from Rust definitions so building needs no `protoc`; the wire
+/// contract lives in `proto/deepseek_ocr.proto`.
+#[cfg(feature = "grpc")]
+fn compile_grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    println!("cargo:rerun-if-changed=build.rs");
+    let method = |name: &str, route: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type("super::RecognizeRequest")
+            .codec_path("tonic_prost::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Ocr")
+        .package("deepseek_ocr.v1")
+        .method(
+            method("recognize", "Recognize")
+                .output_type("super::Document")
+                .build(),
+        )
+        .method(
+            method("recognize_stream", "RecognizeStream")
+                .output_type("super::RecognizeChunk")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new()
+        .build_client(false)
+        .build_transport(false)
+        .compile(&[service]);
+}
@@ -0,0 +1,80 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use rocket::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{error::ApiError, models::QueueMetrics};
+
+/// Seconds a client rejected by a full queue is asked to wait before retrying.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Caps how many generation requests run at once so they do not all contend for the model lock
+/// and grow device memory together.
+///
+/// Up to `max_in_flight` requests (the server's `max_num_seqs`) hold a slot from image
+/// preprocessing to their last token. Later requests wait in FIFO order; once `max_queued` are
+/// waiting, new ones are rejected with 503 and a `Retry-After` hint instead.
+pub struct AdmissionQueue {
+    max_in_flight: usize,
+    max_queued: Option<usize>,
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+impl AdmissionQueue {
+    pub fn new(max_in_flight: usize, max_queued: Option<usize>) -> Arc<Self> {
+        let max_in_flight = max_in_flight.max(1);
+        Arc::new(Self {
+            max_in_flight,
+            max_queued,
+            slots: Arc::new(Semaphore::new(max_in_flight)),
+            queued: AtomicUsize::new(0),
+        })
+    }
+
+    /// Wait for a generation slot; it is released when the permit is dropped.
+    pub async fn admit(&self) -> Result<OwnedSemaphorePermit, ApiError> {
+        if let Ok(permit) = Arc::clone(&self.slots).try_acquire_owned() {
+            return Ok(permit);
+        }
+        let reserved = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                match self.max_queued {
+                    Some(max) if queued >= max => None,
+                    _ => Some(queued + 1),
+                }
+            });
+        if let Err(queued) = reserved {
+            return Err(ApiError::Overloaded {
+                message: format!("{queued} requests are already queued; retry shortly"),
+                retry_after_secs: RETRY_AFTER_SECS,
+            });
+        }
+        // Leaves the queue on drop, including when the client goes away while waiting.
+        let _waiting = QueuedGuard(&self.queued);
+        Arc::clone(&self.slots)
+            .acquire_owned()
+            .await
+            .map_err(|_| ApiError::Unavailable("admission queue is closed".into()))
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            in_flight: self.max_in_flight - self.slots.available_permits(),
+            queued: self.queued.load(Ordering::SeqCst),
+            max_in_flight: self.max_in_flight,
+            max_queued: self.max_queued,
+        }
+    }
+}
+
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
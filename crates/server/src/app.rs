@@ -1,72 +1,144 @@
-use std::sync::{Arc, Mutex};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::{Context, Result};
-use deepseek_ocr_config::{AppConfig, LocalFileSystem};
+use anyhow::{Context, Result, ensure};
+use candle_core::{DType, Device};
+use deepseek_ocr_config::{AppConfig, ConfigOverrides, LocalFileSystem, ModelFileSystem};
 use deepseek_ocr_core::{
-    model::DeepseekOcrModel,
-    runtime::{default_dtype_for_device, prepare_device_and_dtype_with_options},
+    config::{DeepseekV2Config, load_ocr_config},
+    model::{DeepseekOcrModel, LoadOptions, LruPrefixCache},
+    runtime::{
+        DevicePlan, Precision, UtilizationOf, default_dtype_for_device,
+        prepare_device_and_dtype_with_options, seed_device,
+    },
 };
 use rocket::{Config, data::ToByteUnit};
 use tokenizers::Tokenizer;
-use tracing::info;
+use tracing::{error, info};
 
 use crate::{
+    admission::AdmissionQueue,
     args::Args,
+    breaker::CircuitBreaker,
+    request_id::RequestIdFairing,
     resources::{ensure_config_file, ensure_tokenizer_file, prepare_weights_path},
     routes,
-    state::AppState,
+    scheduler::{DecodeScheduler, SchedulerPrefixCache},
+    state::{AppState, LoadFn, ModelSlot},
+    stream::FlushPolicy,
 };
 
 pub async fn run(args: Args) -> Result<()> {
-    let fs = LocalFileSystem::new("deepseek-ocr");
-    let (mut app_config, descriptor) = AppConfig::load_or_init(&fs, args.config.as_deref())?;
+    let config_fs = LocalFileSystem::new("deepseek-ocr");
+    let (mut app_config, descriptor) = AppConfig::load_or_init(&config_fs, args.config.as_deref())?;
+    app_config += ConfigOverrides::from_env()?;
     app_config += &args;
+    let fs = app_config.model_file_system(&config_fs)?;
     app_config.normalise(&fs)?;
-    let resources = app_config.active_model_resources(&fs)?;
+    #[cfg(not(feature = "grpc"))]
+    ensure!(
+        app_config.server.grpc_port.is_none(),
+        "--grpc-port requires a server built with `--features grpc`"
+    );
 
     info!(
         "Using configuration {} (active model `{}`)",
-        descriptor.location.display_with(&fs)?,
+        descriptor.location.display_with(&config_fs)?,
         app_config.models.active
     );
 
-    let config_path = ensure_config_file(&fs, &resources.config)?;
-    let tokenizer_path = ensure_tokenizer_file(&fs, &resources.tokenizer)?;
-    let weights_path = prepare_weights_path(&fs, &resources.weights)?;
-
-    // Read GPU configuration options
-    let gpu_memory_utilization = app_config.inference.gpu_memory_utilization;
     let max_num_seqs = app_config.inference.max_num_seqs;
-
-    let (device, maybe_dtype) = prepare_device_and_dtype_with_options(
-        app_config.inference.device,
-        app_config.inference.precision,
-        gpu_memory_utilization,
-        max_num_seqs,
+    let (loaded, degraded) = load_with_fallback(
+        &app_config.models.active,
+        app_config.models.fallback.as_deref(),
+        |model_id| load_model(&fs, &app_config, model_id),
     )?;
-    let dtype = maybe_dtype.unwrap_or_else(|| default_dtype_for_device(&device));
+    let LoadedModel {
+        model,
+        tokenizer,
+        config_path,
+        weights_path,
+        device,
+        dtype,
+        load_options,
+    } = loaded;
 
-    let model = DeepseekOcrModel::load(Some(&config_path), Some(&weights_path), device, dtype)
-        .context("failed to load DeepSeek-OCR model")?;
-    let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|err| {
-        anyhow::anyhow!(
-            "failed to load tokenizer from {}: {err}",
-            tokenizer_path.display()
+    let loader: LoadFn = Box::new(move || {
+        DeepseekOcrModel::load_with_options(
+            Some(&config_path),
+            Some(&weights_path),
+            device.clone(),
+            dtype,
+            &load_options,
         )
-    })?;
+    });
+    let model = ModelSlot::new(model, loader);
+    let prefix_cache =
+        app_config
+            .inference
+            .prefix_cache_entries
+            .map(|entries| SchedulerPrefixCache {
+                store: Arc::new(LruPrefixCache::new(entries)),
+                model_id: app_config.server.model_id.clone(),
+            });
+    let scheduler =
+        DecodeScheduler::spawn(Arc::clone(&model), max_num_seqs.unwrap_or(1), prefix_cache)?;
+    let breaker = CircuitBreaker::new(
+        app_config.server.breaker_threshold,
+        Duration::from_secs(app_config.server.breaker_window_secs),
+        Arc::clone(&model),
+        app_config.server.breaker_reload,
+    );
+
+    let admission = AdmissionQueue::new(
+        max_num_seqs.unwrap_or(1),
+        app_config.server.max_queued_requests,
+    );
 
     let state = AppState::new(
-        Arc::new(Mutex::new(model)),
+        model,
+        scheduler,
+        breaker,
+        admission,
         Arc::new(tokenizer),
+        degraded,
         app_config.inference.base_size,
         app_config.inference.image_size,
         app_config.inference.crop_mode,
+        app_config.inference.strip_aspect_threshold,
+        app_config.inference.preprocess_pipeline(),
         app_config.inference.max_new_tokens,
+        app_config.inference.max_new_tokens_ceiling,
+        app_config.inference.apply_exif_orientation,
+        app_config.inference.structure_aware_stop,
+        app_config.inference.detect_empty_output,
+        app_config.inference.partial_utf8,
+        app_config.inference.raw_output,
+        app_config.inference.ban_image_tokens,
+        app_config.inference.stop_criteria(),
+        app_config.inference.sampling(),
+        app_config.inference.beam_search(),
+        app_config.inference.repetition_penalty()?,
+        app_config.inference.no_repeat_ngram_size(),
+        FlushPolicy::new(
+            app_config.server.stream_flush_tokens,
+            app_config.server.stream_flush_interval_ms,
+        ),
         app_config.server.model_id.clone(),
     );
 
     let model_id = state.model_id.clone();
 
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_port) = app_config.server.grpc_port {
+        let service = crate::grpc::OcrService::from_app(&state);
+        let host = app_config.server.host.clone();
+        rocket::tokio::spawn(async move {
+            if let Err(err) = crate::grpc::serve(service, &host, grpc_port).await {
+                error!(error = %format!("{err:#}"), "gRPC server stopped");
+            }
+        });
+    }
+
     let figment = Config::figment()
         .merge(("port", app_config.server.port))
         .merge(("address", app_config.server.host.clone()))
@@ -84,6 +156,7 @@ pub async fn run(args: Args) -> Result<()> {
 
     rocket::custom(figment)
         .manage(state)
+        .attach(RequestIdFairing)
         .mount("/v1", routes::v1_routes())
         .launch()
         .await
@@ -91,3 +164,365 @@ pub async fn run(args: Args) -> Result<()> {
 
     Ok(())
 }
+
+struct LoadedModel {
+    model: DeepseekOcrModel,
+    tokenizer: Tokenizer,
+    config_path: PathBuf,
+    weights_path: PathBuf,
+    device: Device,
+    dtype: DType,
+    /// What the model was loaded with, reused when the circuit breaker reloads it.
+    load_options: LoadOptions,
+}
+
+/// Load the active model, or the fallback model when that fails and one is configured. Returns
+/// the loaded model and whether it is the fallback.
+fn load_with_fallback<T>(
+    active: &str,
+    fallback: Option<&str>,
+    mut load: impl FnMut(&str) -> Result<T>,
+) -> Result<(T, bool)> {
+    let err = match load(active) {
+        Ok(loaded) => return Ok((loaded, false)),
+        Err(err) => err,
+    };
+    let Some(fallback) = fallback else {
+        return Err(err);
+    };
+    error!(
+        error = %format!("{err:#}"),
+        "DEGRADED: model `{active}` failed to load; serving fallback model `{fallback}`"
+    );
+    let loaded = load(fallback).with_context(|| {
+        format!("fallback model `{fallback}` also failed to load (model `{active}`: {err:#})")
+    })?;
+    Ok((loaded, true))
+}
+
+/// Load options for `model_id`. The checkpoint-specific ones (`[model_config_overrides]`,
+/// `weight_key_remap` and `lora_adapters`) are written for the active model, so a fallback,
+/// typically a different checkpoint, loads without them.
+fn load_options(app_config: &AppConfig, model_id: &str) -> LoadOptions {
+    let inference = &app_config.inference;
+    let mut options = LoadOptions {
+        quantize: inference.quantize,
+        preprocess_device: inference.preprocess_device,
+        debug_crops_dir: inference.debug_crops_dir.clone(),
+        aux_loss: inference.aux_loss,
+        flash_nan_check: inference.flash_nan_check,
+        early_exit: inference.early_exit(),
+        prefill_chunk_size: inference.prefill_chunk_size,
+        kv_max_seq_len: inference.kv_max_seq_len,
+        kv_eviction: inference.kv_eviction,
+        kv_compact_stride: inference.kv_compact_stride,
+        max_tiles: Some(inference.max_tiles),
+        normalization: inference.normalization,
+        ..LoadOptions::default()
+    };
+    if model_id == app_config.models.active {
+        options.language_overrides = app_config.model_config_overrides.clone();
+        options.weight_key_remap = inference.weight_key_remap.clone();
+        options.lora_adapters = inference.lora_adapters.clone();
+    }
+    options
+}
+
+/// Resolve (downloading if needed) and load one model entry's config, weights and tokenizer onto
+/// the entry's device.
+fn load_model(fs: &ModelFileSystem, app_config: &AppConfig, model_id: &str) -> Result<LoadedModel> {
+    let resources = app_config.model_resources(fs, model_id)?;
+    let load_options = load_options(app_config, model_id);
+    let settings = app_config.model_inference_settings(model_id)?;
+    let plan = prepare_device_and_dtype_with_options(
+        settings.device,
+        settings.device_index,
+        settings.precision,
+        settings.gpu_memory_utilization,
+        settings.gpu_memory_utilization_of,
+        settings.max_num_seqs,
+    )
+    .with_context(|| {
+        format!(
+            "device {:?} #{} for model `{model_id}` is not available",
+            settings.device, settings.device_index
+        )
+    })?;
+    if let Some(seed) = settings.seed {
+        seed_device(&plan.device, seed)?;
+    }
+    let dtype = plan
+        .dtype
+        .unwrap_or_else(|| default_dtype_for_device(&plan.device));
+    info!(
+        "Loading model `{model_id}` on {:?} ({dtype:?})",
+        settings.device
+    );
+
+    let config_path = ensure_config_file(fs, &resources.config)?;
+    if let Some(utilization) = settings.gpu_memory_utilization {
+        let mut language = load_ocr_config(Some(&config_path))?.resolved_language_config()?;
+        if !load_options.language_overrides.is_empty() {
+            language.apply_overrides(&load_options.language_overrides)?;
+        }
+        check_memory_budget(
+            &plan,
+            &language,
+            dtype,
+            utilization,
+            settings.gpu_memory_utilization_of,
+            settings.max_num_seqs.unwrap_or(1),
+            &load_options,
+        )
+        .with_context(|| format!("model `{model_id}` does not fit the GPU memory budget"))?;
+    }
+    let tokenizer_path = ensure_tokenizer_file(fs, &resources)?;
+    let weights_path = prepare_weights_path(fs, &resources.weights)?;
+    let model = DeepseekOcrModel::load_with_options(
+        Some(&config_path),
+        Some(&weights_path),
+        plan.device.clone(),
+        dtype,
+        &load_options,
+    )
+    .context("failed to load DeepSeek-OCR model")?;
+    let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|err| {
+        anyhow::anyhow!(
+            "failed to load tokenizer from {}: {err}",
+            tokenizer_path.display()
+        )
+    })?;
+    Ok(LoadedModel {
+        model,
+        tokenizer,
+        config_path,
+        weights_path,
+        device: plan.device,
+        dtype,
+        load_options,
+    })
+}
+
+/// Refuse to load when the decoder's estimated footprint exceeds `utilization` of the device's
+/// total or free memory, rather than running out of memory halfway through the load. The
+/// estimate counts the weights as `load_options` quantizes them and sizes the KV cache at its
+/// `kv_max_seq_len` bound when that is below `max_position_embeddings`.
+fn check_memory_budget(
+    plan: &DevicePlan,
+    language: &DeepseekV2Config,
+    dtype: DType,
+    utilization: f32,
+    of: UtilizationOf,
+    max_num_seqs: usize,
+    load_options: &LoadOptions,
+) -> Result<()> {
+    const GIB: f64 = (1u64 << 30) as f64;
+    let (Some(memory), Some(budget)) = (plan.memory, plan.memory_budget) else {
+        info!(
+            "Device memory is not queryable on {:?}; gpu_memory_utilization not enforced",
+            plan.device
+        );
+        return Ok(());
+    };
+    info!(
+        "Device memory: {:.2} GiB total, {:.2} GiB free; budget {:.2} GiB ({:.0}% of {of:?})",
+        memory.total_bytes as f64 / GIB,
+        memory.free_bytes as f64 / GIB,
+        budget as f64 / GIB,
+        utilization * 100.0
+    );
+
+    let precision = match dtype {
+        DType::F16 => Precision::F16,
+        DType::BF16 => Precision::Bf16,
+        _ => Precision::F32,
+    };
+    let max_cache_len = load_options
+        .kv_max_seq_len
+        .map_or(language.max_position_embeddings, |bound| {
+            bound.min(language.max_position_embeddings)
+        });
+    let estimate = language.estimated_memory_quantized(
+        precision,
+        max_cache_len,
+        max_num_seqs,
+        load_options.quantize,
+    );
+    ensure!(
+        estimate.total_bytes() <= budget,
+        "the decoder needs an estimated {:.2} GiB but the budget is {:.2} GiB ({:.0}% of {of:?} memory; {:.2} GiB total, {:.2} GiB free). Lower max_num_seqs or kv_max_seq_len, set quantize, or raise gpu_memory_utilization",
+        estimate.total_bytes() as f64 / GIB,
+        budget as f64 / GIB,
+        utilization * 100.0,
+        memory.total_bytes as f64 / GIB,
+        memory.free_bytes as f64 / GIB
+    );
+    if let Some(kv_cache_bytes) =
+        plan.kv_cache_bytes(estimate.weights_bytes + estimate.activation_bytes)
+    {
+        info!(
+            "KV cache budget {:.2} GiB; a full cache for {max_num_seqs} sequence(s) needs {:.2} GiB",
+            kv_cache_bytes as f64 / GIB,
+            estimate.kv_cache_bytes as f64 / GIB
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use deepseek_ocr_core::{runtime::DeviceMemory, transformer::weights::WeightQuant};
+
+    use super::*;
+
+    fn language() -> DeepseekV2Config {
+        serde_json::from_value(serde_json::json!({
+            "vocab_size": 64,
+            "hidden_size": 64,
+            "intermediate_size": 128,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 2,
+            "max_position_embeddings": 4096,
+        }))
+        .expect("valid language config")
+    }
+
+    /// A device whose whole memory is the budget.
+    fn plan(budget: u64) -> DevicePlan {
+        DevicePlan {
+            device: Device::Cpu,
+            dtype: None,
+            memory: Some(DeviceMemory {
+                total_bytes: budget,
+                free_bytes: budget,
+            }),
+            memory_budget: Some(budget),
+        }
+    }
+
+    fn check(budget: u64, load_options: &LoadOptions) -> Result<()> {
+        check_memory_budget(
+            &plan(budget),
+            &language(),
+            DType::F16,
+            1.0,
+            UtilizationOf::Total,
+            1,
+            load_options,
+        )
+    }
+
+    #[test]
+    fn memory_budget_counts_quantized_weights_and_the_kv_bound() {
+        let bounded = LoadOptions {
+            quantize: Some(WeightQuant::Int4),
+            kv_max_seq_len: Some(256),
+            ..LoadOptions::default()
+        };
+        let budget = language()
+            .estimated_memory_quantized(Precision::F16, 256, 1, Some(WeightQuant::Int4))
+            .total_bytes();
+        assert!(check(budget, &bounded).is_ok());
+        assert!(check(budget, &LoadOptions::default()).is_err());
+        // Either setting alone still needs more than the budget.
+        let quantized = LoadOptions {
+            kv_max_seq_len: None,
+            ..bounded.clone()
+        };
+        assert!(check(budget, &quantized).is_err());
+        let dense = LoadOptions {
+            quantize: None,
+            ..bounded.clone()
+        };
+        assert!(check(budget, &dense).is_err());
+        // A bound above the context length changes nothing.
+        let loose = LoadOptions {
+            kv_max_seq_len: Some(1 << 20),
+            ..bounded
+        };
+        assert!(check(budget, &loose).is_err());
+    }
+
+    #[test]
+    fn active_model_that_loads_is_not_degraded() {
+        let mut attempts = Vec::new();
+        let (loaded, degraded) = load_with_fallback("large", Some("small"), |id| {
+            attempts.push(id.to_string());
+            Ok(id.to_string())
+        })
+        .unwrap();
+        assert_eq!((loaded.as_str(), degraded), ("large", false));
+        assert_eq!(attempts, ["large"]);
+    }
+
+    #[test]
+    fn fallback_serves_degraded_when_the_active_model_fails() {
+        let (loaded, degraded) = load_with_fallback("large", Some("small"), |id| {
+            anyhow::ensure!(id != "large", "out of memory");
+            Ok(id.to_string())
+        })
+        .unwrap();
+        assert_eq!((loaded.as_str(), degraded), ("small", true));
+    }
+
+    #[test]
+    fn load_error_stands_without_a_working_fallback() {
+        let err = load_with_fallback("large", None, |_| -> Result<()> {
+            anyhow::bail!("out of memory")
+        })
+        .unwrap_err();
+        assert_eq!(format!("{err:#}"), "out of memory");
+
+        let err = load_with_fallback("large", Some("small"), |id| -> Result<()> {
+            anyhow::bail!("no weights for `{id}`")
+        })
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "fallback model `small` also failed to load (model `large`: no weights for `large`): \
+             no weights for `small`"
+        );
+    }
+
+    #[test]
+    fn fallback_loads_without_the_active_checkpoint_options() {
+        let mut app_config = AppConfig::default();
+        app_config.models.active = "large".into();
+        app_config.inference.weight_key_remap =
+            vec!["model.=model.language_model.".parse().unwrap()];
+        app_config.inference.quantize = Some(WeightQuant::Int8);
+
+        let active = load_options(&app_config, "large");
+        assert_eq!(
+            active.weight_key_remap,
+            app_config.inference.weight_key_remap
+        );
+        let fallback = load_options(&app_config, "small");
+        assert!(fallback.weight_key_remap.is_empty());
+        assert!(fallback.lora_adapters.is_empty());
+        assert_eq!(
+            fallback.quantize,
+            Some(WeightQuant::Int8),
+            "runtime options still apply"
+        );
+    }
+
+    #[test]
+    fn memory_budget_is_skipped_when_memory_is_unknown() {
+        let plan = DevicePlan {
+            memory: None,
+            memory_budget: None,
+            ..plan(0)
+        };
+        let result = check_memory_budget(
+            &plan,
+            &language(),
+            DType::F16,
+            0.5,
+            UtilizationOf::Free,
+            1,
+            &LoadOptions::default(),
+        );
+        assert!(result.is_ok());
+    }
+}
@@ -2,7 +2,13 @@ use std::path::PathBuf;
 
 use clap::Parser;
 use deepseek_ocr_config::{AppConfig, ConfigOverride, ConfigOverrides};
-use deepseek_ocr_core::runtime::{DeviceKind, Precision};
+use deepseek_ocr_core::{
+    inference::{MaxNewTokens, PartialUtf8},
+    model::{Normalization, WeightKeyRemap},
+    runtime::{DeviceKind, Precision, PreprocessDevice, UtilizationOf},
+    transformer::{cache::CacheEviction, lora::LoraAdapter, weights::WeightQuant},
+    vision::{BinarizeMethod, BuiltinPreprocessor},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "DeepSeek-OCR API Server", long_about = None)]
@@ -15,6 +21,23 @@ pub struct Args {
     #[arg(long, value_name = "ID", help_heading = "Application")]
     pub model: Option<String>,
 
+    /// Merge a named `[profiles.<name>]` preset over the inference settings.
+    #[arg(long, value_name = "NAME", help_heading = "Application")]
+    pub profile: Option<String>,
+
+    /// Fail at startup when the configuration file has settings no field recognises, instead of
+    /// ignoring them with a warning.
+    #[arg(long, help_heading = "Application")]
+    pub strict_config: bool,
+
+    /// Model entry to serve instead if the selected model fails to load.
+    #[arg(long, value_name = "ID", help_heading = "Application")]
+    pub fallback_model: Option<String>,
+
+    /// Download model files from this base URL into a local mirror (builds with `--features http`).
+    #[arg(long, value_name = "URL", help_heading = "Application")]
+    pub remote_url: Option<String>,
+
     /// Override the model configuration JSON path.
     #[arg(long, value_name = "PATH", help_heading = "Application")]
     pub model_config: Option<PathBuf>,
@@ -31,10 +54,18 @@ pub struct Args {
     #[arg(long, help_heading = "Inference")]
     pub device: Option<DeviceKind>,
 
+    /// GPU ordinal for CUDA/Metal on multi-GPU machines (default 0).
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub device_index: Option<usize>,
+
     /// Numeric precision override (cpu=f32 default, metal/cuda=f16).
     #[arg(long, help_heading = "Inference")]
     pub dtype: Option<Precision>,
 
+    /// Quantize decoder linear layers as they load (int8 or int4).
+    #[arg(long, help_heading = "Inference")]
+    pub quantize: Option<WeightQuant>,
+
     /// Global view resolution.
     #[arg(long, help_heading = "Inference")]
     pub base_size: Option<u32>,
@@ -47,15 +78,176 @@ pub struct Args {
     #[arg(long, help_heading = "Inference")]
     pub crop_mode: Option<bool>,
 
-    /// Default max tokens budget per request.
+    /// Tile images at least this elongated (long/short side) as a strip of square crops.
+    #[arg(long, value_name = "RATIO", help_heading = "Inference")]
+    pub strip_aspect_threshold: Option<f32>,
+
+    /// Most crops per image in crop mode; the grid closest to the image's aspect ratio within this
+    /// budget is used (defaults to 9).
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub max_tiles: Option<u32>,
+
+    /// Per-channel RGB mean for pixel normalisation (defaults to 0.5,0.5,0.5).
+    #[arg(
+        long,
+        value_name = "R,G,B",
+        value_parser = Normalization::parse_channels,
+        help_heading = "Inference"
+    )]
+    pub normalization_mean: Option<[f32; 3]>,
+
+    /// Per-channel RGB standard deviation for pixel normalisation (defaults to 0.5,0.5,0.5).
+    #[arg(
+        long,
+        value_name = "R,G,B",
+        value_parser = Normalization::parse_channels,
+        help_heading = "Inference"
+    )]
+    pub normalization_std: Option<[f32; 3]>,
+
+    /// Built-in preprocessors to run on each image before tiling, in order (e.g. deskew,contrast).
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "STEPS",
+        help_heading = "Inference"
+    )]
+    pub preprocess: Option<Vec<BuiltinPreprocessor>>,
+
+    /// Largest skew in degrees the `deskew` preprocessor corrects (defaults to 5).
+    #[arg(long, value_name = "DEGREES", help_heading = "Inference")]
+    pub deskew_max_angle: Option<f32>,
+
+    /// Threshold selection for the `binarize` preprocessor: otsu (global) or adaptive (local).
+    #[arg(long, help_heading = "Inference")]
+    pub binarize_method: Option<BinarizeMethod>,
+
+    /// Build image tensors on the compute device or on the CPU with one transfer afterwards.
     #[arg(long, help_heading = "Inference")]
-    pub max_new_tokens: Option<usize>,
+    pub preprocess_device: Option<PreprocessDevice>,
+
+    /// Write the global view and each crop, as the vision encoders see them, under this directory.
+    #[arg(long, value_name = "DIR", help_heading = "Inference")]
+    pub debug_crops_dir: Option<PathBuf>,
+
+    /// Compute the MoE load-balancing loss on every forward and report it (true/false).
+    #[arg(long, help_heading = "Inference")]
+    pub aux_loss: Option<bool>,
+
+    /// Re-run a layer with eager attention when flash attention emits NaNs (true/false).
+    #[arg(long, help_heading = "Inference")]
+    pub flash_nan_check: Option<bool>,
+
+    /// Experimental: let decode steps exit after this many layers when the next token is certain.
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub early_exit_layer: Option<usize>,
+
+    /// Next-token entropy (nats) below which `--early-exit-layer` exits.
+    #[arg(long, value_name = "NATS", help_heading = "Inference")]
+    pub early_exit_entropy: Option<f32>,
+
+    /// Prefill prompts in blocks of this many tokens to cap peak memory on long prompts.
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub prefill_chunk_size: Option<usize>,
+
+    /// Reuse the KV cache of up to N distinct prompt prefixes (text before the first image).
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub prefix_cache_entries: Option<usize>,
+
+    /// Cap each sequence's KV cache at N positions, evicting older ones beyond it (bounds memory on very long pages).
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub kv_max_seq_len: Option<usize>,
+
+    /// Positions a capped KV cache keeps: `drop-oldest`, or `sink:N` to also keep the first N.
+    #[arg(long, value_name = "POLICY", help_heading = "Inference")]
+    pub kv_eviction: Option<CacheEviction>,
+
+    /// Positions a capped KV cache leaves free below the cap after each eviction (default: half the window), trading recent context for fewer rebuilds.
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub kv_compact_stride: Option<usize>,
+
+    /// Rewrite a weight-name prefix before lookup, for checkpoints with non-standard tensor names (repeatable).
+    #[arg(long, value_name = "FROM=TO", help_heading = "Inference")]
+    pub weight_key_remap: Option<Vec<WeightKeyRemap>>,
+
+    /// Apply a LoRA adapter (PEFT safetensors) over the decoder, scaled by SCALE (default 1.0) (repeatable).
+    #[arg(long, value_name = "PATH[=SCALE]", help_heading = "Inference")]
+    pub lora_adapter: Option<Vec<LoraAdapter>>,
+
+    /// Default max tokens budget per request, or `auto` to size it from the image token count.
+    #[arg(long, value_name = "N|auto", help_heading = "Inference")]
+    pub max_new_tokens: Option<MaxNewTokens>,
+
+    /// Largest budget `--max-new-tokens auto` may pick.
+    #[arg(long, help_heading = "Inference")]
+    pub max_new_tokens_ceiling: Option<usize>,
+
+    /// Default sampling temperature for requests that do not set one; 0 decodes greedily.
+    #[arg(long, value_name = "T", help_heading = "Inference")]
+    pub temperature: Option<f64>,
+
+    /// Default nucleus sampling cutoff in (0, 1], used when the temperature is above 0.
+    #[arg(long, value_name = "P", help_heading = "Inference")]
+    pub top_p: Option<f64>,
+
+    /// Seed the device RNG and the sampler of requests that do not set a seed.
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub seed: Option<u64>,
+
+    /// Decode every request with beam search over N hypotheses; such requests cannot stream or
+    /// sample.
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub num_beams: Option<usize>,
+
+    /// Beam length penalty; above 1 favours longer outputs.
+    #[arg(long, value_name = "F", help_heading = "Inference")]
+    pub length_penalty: Option<f32>,
+
+    /// Penalise tokens a request already generated; above 1 discourages loops.
+    #[arg(long, value_name = "F", help_heading = "Inference")]
+    pub repetition_penalty: Option<f32>,
+
+    /// Never let a request repeat an n-gram of N tokens; 0 disables.
+    #[arg(long, value_name = "N", help_heading = "Inference")]
+    pub no_repeat_ngram_size: Option<usize>,
+
+    /// Apply EXIF orientation metadata to uploaded images.
+    #[arg(long, help_heading = "Inference")]
+    pub apply_exif_orientation: Option<bool>,
+
+    /// Trim budget-truncated Markdown back to the last complete table row/list item.
+    #[arg(long, help_heading = "Inference")]
+    pub structure_aware_stop: Option<bool>,
+
+    /// Return an empty string for output that is only whitespace or placeholder tokens, e.g. blank pages.
+    #[arg(long, help_heading = "Inference")]
+    pub detect_empty_output: Option<bool>,
+
+    /// Never decode the image placeholder token; disable for fine-tunes that emit it (true/false, defaults to true).
+    #[arg(long, help_heading = "Inference")]
+    pub ban_image_tokens: Option<bool>,
+
+    /// Stop decoding once the output contains this string, which is cut from the result; repeat for several.
+    #[arg(long = "stop", value_name = "STRING", help_heading = "Inference")]
+    pub stop_sequences: Option<Vec<String>>,
+
+    /// Also return the decoded output with special tokens and grounding markup kept, for debugging (true/false).
+    #[arg(long, help_heading = "Inference")]
+    pub raw_output: Option<bool>,
+
+    /// Handle a multibyte character cut by the token budget: drop it or decode a few extra tokens to complete it.
+    #[arg(long, help_heading = "Inference")]
+    pub partial_utf8: Option<PartialUtf8>,
 
     /// GPU memory fraction to use for model weights / KV cache (0.0 - 1.0)
     #[arg(long, help_heading = "Inference")]
     pub gpu_memory_utilization: Option<f32>,
 
-    /// Maximum number of concurrent sequences/batches the server will accept
+    /// Apply --gpu-memory-utilization to free device memory at load time (default) or to total memory.
+    #[arg(long, help_heading = "Inference")]
+    pub gpu_memory_utilization_of: Option<UtilizationOf>,
+
+    /// Maximum number of concurrent sequences decoded together in one batched step (default 1)
     #[arg(long, help_heading = "Inference")]
     pub max_num_seqs: Option<usize>,
 
@@ -67,9 +259,38 @@ pub struct Args {
     #[arg(long, help_heading = "Application")]
     pub port: Option<u16>,
 
+    /// TCP port for the gRPC service (requires the `grpc` feature; off by default).
+    #[arg(long, help_heading = "Application")]
+    pub grpc_port: Option<u16>,
+
     /// Model identifier returned by /models.
     #[arg(long, help_heading = "Application")]
     pub model_id: Option<String>,
+
+    /// Consecutive inference failures that mark the server unready (disabled by default).
+    #[arg(long, value_name = "N", help_heading = "Application")]
+    pub breaker_threshold: Option<u32>,
+
+    /// Window in seconds for counting failures and for keeping the breaker open (defaults to 60).
+    #[arg(long, value_name = "SECS", help_heading = "Application")]
+    pub breaker_window_secs: Option<u64>,
+
+    /// Reload the model from disk when the breaker trips (true/false).
+    #[arg(long, help_heading = "Application")]
+    pub breaker_reload: Option<bool>,
+
+    /// Requests allowed to wait for a generation slot before new ones get 503 (unbounded by
+    /// default).
+    #[arg(long, value_name = "N", help_heading = "Application")]
+    pub max_queued_requests: Option<usize>,
+
+    /// Tokens coalesced into one streamed event (defaults to 1, an event per decode step).
+    #[arg(long, value_name = "N", help_heading = "Application")]
+    pub stream_flush_tokens: Option<usize>,
+
+    /// Flush buffered streamed tokens after this many milliseconds, even below the token count.
+    #[arg(long, value_name = "MS", help_heading = "Application")]
+    pub stream_flush_interval_ms: Option<u64>,
 }
 
 impl From<&Args> for ConfigOverrides {
@@ -77,20 +298,71 @@ impl From<&Args> for ConfigOverrides {
         let mut overrides = ConfigOverrides::default();
         overrides.config_path = args.config.clone();
         overrides.model_id = args.model.clone();
+        overrides.profile = args.profile.clone();
+        if args.strict_config {
+            overrides.strict_config = Some(true);
+        }
+        overrides.fallback_model = args.fallback_model.clone();
+        overrides.remote_url = args.remote_url.clone();
         overrides.model_config = args.model_config.clone();
         overrides.tokenizer = args.tokenizer.clone();
         overrides.weights = args.weights.clone();
         overrides.inference.device = args.device;
+        overrides.inference.device_index = args.device_index;
         overrides.inference.precision = args.dtype;
+        overrides.inference.quantize = args.quantize;
         overrides.inference.base_size = args.base_size;
         overrides.inference.image_size = args.image_size;
         overrides.inference.crop_mode = args.crop_mode;
+        overrides.inference.strip_aspect_threshold = args.strip_aspect_threshold;
+        overrides.inference.max_tiles = args.max_tiles;
+        overrides.inference.normalization_mean = args.normalization_mean;
+        overrides.inference.normalization_std = args.normalization_std;
+        overrides.inference.preprocess = args.preprocess.clone();
+        overrides.inference.deskew_max_angle = args.deskew_max_angle;
+        overrides.inference.binarize_method = args.binarize_method;
+        overrides.inference.preprocess_device = args.preprocess_device;
+        overrides.inference.debug_crops_dir = args.debug_crops_dir.clone();
+        overrides.inference.aux_loss = args.aux_loss;
+        overrides.inference.flash_nan_check = args.flash_nan_check;
+        overrides.inference.early_exit_layer = args.early_exit_layer;
+        overrides.inference.early_exit_entropy = args.early_exit_entropy;
+        overrides.inference.prefill_chunk_size = args.prefill_chunk_size;
+        overrides.inference.prefix_cache_entries = args.prefix_cache_entries;
+        overrides.inference.kv_max_seq_len = args.kv_max_seq_len;
+        overrides.inference.kv_eviction = args.kv_eviction;
+        overrides.inference.kv_compact_stride = args.kv_compact_stride;
+        overrides.inference.weight_key_remap = args.weight_key_remap.clone();
+        overrides.inference.lora_adapters = args.lora_adapter.clone();
         overrides.inference.max_new_tokens = args.max_new_tokens;
+        overrides.inference.max_new_tokens_ceiling = args.max_new_tokens_ceiling;
+        overrides.inference.temperature = args.temperature;
+        overrides.inference.top_p = args.top_p;
+        overrides.inference.seed = args.seed;
+        overrides.inference.num_beams = args.num_beams;
+        overrides.inference.length_penalty = args.length_penalty;
+        overrides.inference.repetition_penalty = args.repetition_penalty;
+        overrides.inference.no_repeat_ngram_size = args.no_repeat_ngram_size;
+        overrides.inference.apply_exif_orientation = args.apply_exif_orientation;
+        overrides.inference.structure_aware_stop = args.structure_aware_stop;
+        overrides.inference.detect_empty_output = args.detect_empty_output;
+        overrides.inference.ban_image_tokens = args.ban_image_tokens;
+        overrides.inference.stop_sequences = args.stop_sequences.clone();
+        overrides.inference.raw_output = args.raw_output;
+        overrides.inference.partial_utf8 = args.partial_utf8;
         overrides.inference.gpu_memory_utilization = args.gpu_memory_utilization;
+        overrides.inference.gpu_memory_utilization_of = args.gpu_memory_utilization_of;
         overrides.inference.max_num_seqs = args.max_num_seqs;
         overrides.server.host = args.host.clone();
         overrides.server.port = args.port;
+        overrides.server.grpc_port = args.grpc_port;
         overrides.server.model_id = args.model_id.clone();
+        overrides.server.breaker_threshold = args.breaker_threshold;
+        overrides.server.breaker_window_secs = args.breaker_window_secs;
+        overrides.server.breaker_reload = args.breaker_reload;
+        overrides.server.max_queued_requests = args.max_queued_requests;
+        overrides.server.stream_flush_tokens = args.stream_flush_tokens;
+        overrides.server.stream_flush_interval_ms = args.stream_flush_interval_ms;
         overrides
     }
 }
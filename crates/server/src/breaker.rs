@@ -0,0 +1,243 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{error, info, warn};
+
+use crate::{error::ApiError, state::SharedModel};
+
+/// Stops the server from taking work once inference keeps failing (e.g. the GPU went away).
+///
+/// After `threshold` consecutive internal failures within `window`, the breaker opens: `/readyz`
+/// reports unready and generation endpoints answer 503. It stays open for `window` (or until a
+/// reload finishes), then lets requests through again; the first failure after that reopens it
+/// immediately, while a success closes it.
+pub struct CircuitBreaker {
+    threshold: Option<u32>,
+    window: Duration,
+    model: SharedModel,
+    /// Reload the model from disk when the breaker trips.
+    reload: bool,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    streak_start: Option<Instant>,
+    opened_at: Option<Instant>,
+    half_open: bool,
+    reloading: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        threshold: Option<u32>,
+        window: Duration,
+        model: SharedModel,
+        reload: bool,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            threshold: threshold.filter(|&n| n > 0),
+            window,
+            model,
+            reload,
+            state: Mutex::new(BreakerState::default()),
+        })
+    }
+
+    /// Whether new work is currently being rejected.
+    pub fn is_open(&self) -> bool {
+        let state = self.lock();
+        state.reloading
+            || state
+                .opened_at
+                .is_some_and(|opened| opened.elapsed() < self.window)
+    }
+
+    /// Reject the request while the breaker is open.
+    pub fn check(&self) -> Result<(), ApiError> {
+        if self.threshold.is_none() {
+            return Ok(());
+        }
+        let mut state = self.lock();
+        if let Some(opened) = state.opened_at {
+            if state.reloading || opened.elapsed() < self.window {
+                return Err(ApiError::Unavailable(
+                    "model is failing repeatedly; not accepting requests".into(),
+                ));
+            }
+            state.opened_at = None;
+            state.half_open = true;
+        }
+        Ok(())
+    }
+
+    /// Count the outcome of one inference. Only internal errors count as failures; bad requests
+    /// say nothing about the model's health.
+    pub fn record<T>(self: &Arc<Self>, result: &Result<T, ApiError>) {
+        let Some(threshold) = self.threshold else {
+            return;
+        };
+        let mut state = self.lock();
+        match result {
+            Ok(_) => {
+                state.failures = 0;
+                state.streak_start = None;
+                state.half_open = false;
+            }
+            Err(ApiError::Internal(_)) => {
+                let now = Instant::now();
+                match state.streak_start {
+                    Some(start) if now.duration_since(start) <= self.window => state.failures += 1,
+                    _ => {
+                        state.streak_start = Some(now);
+                        state.failures = 1;
+                    }
+                }
+                if state.opened_at.is_none() && (state.half_open || state.failures >= threshold) {
+                    self.trip(&mut state);
+                }
+            }
+            Err(_) => {}
+        }
+    }
+
+    fn trip(self: &Arc<Self>, state: &mut BreakerState) {
+        error!(
+            failures = state.failures,
+            "Inference failing repeatedly; marking server unready"
+        );
+        state.opened_at = Some(Instant::now());
+        state.failures = 0;
+        state.streak_start = None;
+        state.half_open = false;
+        if self.reload && !state.reloading {
+            state.reloading = true;
+            let breaker = Arc::clone(self);
+            thread::spawn(move || breaker.reload_model());
+        }
+    }
+
+    fn reload_model(&self) {
+        warn!("Reloading model after repeated failures");
+        let outcome = self.model.reload();
+        let mut state = self.lock();
+        state.reloading = false;
+        match outcome {
+            Ok(()) => {
+                state.opened_at = None;
+                info!("Model reloaded; accepting requests again");
+            }
+            Err(err) => {
+                error!(error = %format!("{err:#}"), "Model reload failed");
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BreakerState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+
+    use super::*;
+    use crate::state::ModelSlot;
+
+    const WINDOW: Duration = Duration::from_millis(200);
+
+    fn breaker(threshold: Option<u32>, reload: bool) -> Arc<CircuitBreaker> {
+        let model = ModelSlot::unloaded(Box::new(|| bail!("no weights in tests")));
+        CircuitBreaker::new(threshold, WINDOW, model, reload)
+    }
+
+    fn failure() -> Result<(), ApiError> {
+        Err(ApiError::Internal("decode failed".into()))
+    }
+
+    #[test]
+    fn trips_after_threshold_consecutive_internal_failures() {
+        let breaker = breaker(Some(3), false);
+        breaker.record(&failure());
+        breaker.record(&failure());
+        // Bad requests say nothing about the model and leave the streak alone.
+        breaker.record(&Err::<(), _>(ApiError::BadRequest("bad image".into())));
+        assert!(!breaker.is_open());
+        assert!(breaker.check().is_ok());
+
+        breaker.record(&failure());
+        assert!(breaker.is_open());
+        assert!(matches!(breaker.check(), Err(ApiError::Unavailable(_))));
+    }
+
+    #[test]
+    fn success_resets_the_streak() {
+        let breaker = breaker(Some(2), false);
+        breaker.record(&failure());
+        breaker.record(&Ok(()));
+        breaker.record(&failure());
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn failures_further_apart_than_the_window_start_a_new_streak() {
+        let breaker = breaker(Some(2), false);
+        breaker.record(&failure());
+        thread::sleep(WINDOW + Duration::from_millis(50));
+        breaker.record(&failure());
+        assert!(!breaker.is_open());
+        breaker.record(&failure());
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn after_the_window_one_trial_request_decides() {
+        let breaker = breaker(Some(1), false);
+        breaker.record(&failure());
+        assert!(breaker.check().is_err());
+        thread::sleep(WINDOW + Duration::from_millis(50));
+        assert!(!breaker.is_open());
+
+        // A failed trial reopens the breaker at once.
+        breaker.check().expect("trial request goes through");
+        breaker.record(&failure());
+        assert!(breaker.is_open());
+
+        // A successful one closes it.
+        thread::sleep(WINDOW + Duration::from_millis(50));
+        breaker.check().expect("trial request goes through");
+        breaker.record(&Ok(()));
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn disabled_breaker_never_opens() {
+        let breaker = breaker(None, true);
+        for _ in 0..10 {
+            breaker.record(&failure());
+        }
+        assert!(!breaker.is_open());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn failed_reload_leaves_the_breaker_open_for_the_window() {
+        let breaker = breaker(Some(1), true);
+        breaker.record(&failure());
+        assert!(breaker.is_open());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while breaker.lock().reloading {
+            assert!(Instant::now() < deadline, "reload never finished");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(breaker.lock().opened_at.is_some());
+        assert!(!breaker.model.is_loaded());
+    }
+}
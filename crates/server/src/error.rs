@@ -13,6 +13,14 @@ pub enum ApiError {
     BadRequest(String),
     #[error("{0}")]
     Internal(String),
+    #[error("{0}")]
+    Unavailable(String),
+    /// Too many requests are waiting; answered with 503 and a `Retry-After` header.
+    #[error("{message}")]
+    Overloaded {
+        message: String,
+        retry_after_secs: u64,
+    },
 }
 
 impl From<Error> for ApiError {
@@ -38,6 +46,14 @@ impl<'r> Responder<'r, 'static> for ApiError {
         let (status, error_type) = match self {
             ApiError::BadRequest(_) => (Status::BadRequest, "invalid_request_error"),
             ApiError::Internal(_) => (Status::InternalServerError, "internal_error"),
+            ApiError::Unavailable(_) => (Status::ServiceUnavailable, "service_unavailable"),
+            ApiError::Overloaded { .. } => (Status::ServiceUnavailable, "overloaded"),
+        };
+        let retry_after = match &self {
+            ApiError::Overloaded {
+                retry_after_secs, ..
+            } => Some(*retry_after_secs),
+            _ => None,
         };
         let body = ErrorBody {
             error: ErrorDetail {
@@ -45,6 +61,10 @@ impl<'r> Responder<'r, 'static> for ApiError {
                 r#type: error_type.to_string(),
             },
         };
-        Custom(status, Json(body)).respond_to(request)
+        let mut response = Custom(status, Json(body)).respond_to(request)?;
+        if let Some(secs) = retry_after {
+            response.set_raw_header("Retry-After", secs.to_string());
+        }
+        Ok(response)
     }
 }
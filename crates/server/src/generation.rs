@@ -1,23 +1,30 @@
 use std::{convert::TryFrom, sync::Arc};
 
 use base64::Engine;
-use candle_core::{DType, Tensor};
+use candle_core::{DType, Device, Tensor};
 use deepseek_ocr_core::{
     inference::{
-        build_prompt_tokens, compute_image_embeddings, normalize_text, prepare_vision_inputs,
+        FinishReason, MaxNewTokens, PartialUtf8, StopCriteria, build_prompt_tokens,
+        build_prompt_tokens_for_embeddings, compute_image_embeddings, decode_without_partial_utf8,
+        finish_trimmed_output, image_token_ids, normalize_text, prepare_vision_inputs_with_stats,
+        tail_is_partial_utf8, trim_to_structural_boundary,
     },
-    model::{DeepseekOcrModel, GenerateOptions, OwnedVisionInput},
+    model::{BeamSearch, DeepseekOcrModel, GenerateOptions, OwnedVisionInput},
+    transformer::sampling::{LogitsSampler, RepetitionPenalty},
+    vision::{PreprocessPipeline, PreprocessStats, load_image_from_memory},
 };
 use image::DynamicImage;
 use reqwest::blocking::Client;
 use rocket::tokio;
 use tokenizers::Tokenizer;
-use tracing::info;
+use tracing::{Span, info, info_span, warn};
 
 use crate::{
     error::ApiError,
     models::{ApiMessage, ImagePayload, MessageContent, MessagePart},
-    state::{GenerationInputs, SharedModel},
+    request_id::RequestId,
+    scheduler::{DecodeJob, DecodeScheduler, ExtendFn, ProgressFn, StopFn},
+    state::{GenerationInputs, ModelGuard, SharedModel},
     stream::{StreamContext, StreamController},
 };
 
@@ -25,33 +32,66 @@ use crate::{
 pub struct GenerationResult {
     pub text: String,
     pub prompt_tokens: usize,
+    /// Prompt token ids fed to the model, image placeholders included.
+    pub prompt_token_ids: Vec<i64>,
     pub response_tokens: usize,
+    pub finish_reason: FinishReason,
+    /// Skew corrected by the deskew preprocessor, per image it ran on, in degrees.
+    pub deskew_degrees: Vec<f32>,
+    /// Decoded output with special tokens kept, when `--raw-output` is on.
+    pub raw_output: Option<String>,
 }
 
 pub async fn generate_async(
     inputs: GenerationInputs,
     prompt: String,
     images: Vec<DynamicImage>,
-    max_new_tokens: usize,
+    max_new_tokens: MaxNewTokens,
     stream: Option<StreamContext>,
+    request_id: RequestId,
 ) -> Result<GenerationResult, ApiError> {
+    let inputs = match inputs.admit().await {
+        Ok(inputs) => inputs,
+        Err(err) => {
+            if let Some(ctx) = stream {
+                ctx.send_error(&err.to_string());
+            }
+            return Err(err);
+        }
+    };
     let stream_for_block = stream.clone();
+    let breaker = Arc::clone(&inputs.breaker);
     let join_result = tokio::task::spawn_blocking(move || {
+        let _span = info_span!("generate", request_id = %request_id).entered();
         generate_blocking(
             &inputs.model,
+            &inputs.scheduler,
             Arc::clone(&inputs.tokenizer),
             prompt,
             images,
             inputs.base_size,
             inputs.image_size,
             inputs.crop_mode,
+            inputs.strip_aspect_threshold,
+            &inputs.preprocess,
             max_new_tokens,
+            inputs.max_new_tokens_ceiling,
+            inputs.structure_aware_stop,
+            inputs.detect_empty_output,
+            inputs.partial_utf8,
+            inputs.raw_output,
+            inputs.ban_image_tokens,
+            &inputs.stop,
+            inputs.sampling.sampler(),
+            inputs.beam_search,
+            inputs.repetition_penalty,
+            inputs.no_repeat_ngram_size,
             stream_for_block,
         )
     })
     .await;
 
-    match join_result {
+    let result = match join_result {
         Ok(Ok(result)) => Ok(result),
         Ok(Err(err)) => {
             if let Some(ctx) = stream {
@@ -66,27 +106,153 @@ pub async fn generate_async(
             }
             Err(api_err)
         }
+    };
+    breaker.record(&result);
+    result
+}
+
+/// Decode against vision embeddings computed by another service. `payload` is a safetensors
+/// buffer holding `image_0..image_{n-1}`, one `[tokens, hidden]` tensor per `<image>` slot.
+pub async fn generate_from_embeddings_async(
+    inputs: GenerationInputs,
+    prompt: String,
+    payload: Vec<u8>,
+    max_new_tokens: MaxNewTokens,
+    request_id: RequestId,
+) -> Result<GenerationResult, ApiError> {
+    let inputs = inputs.admit().await?;
+    let breaker = Arc::clone(&inputs.breaker);
+    let result = tokio::task::spawn_blocking(move || {
+        let _span = info_span!("generate", request_id = %request_id).entered();
+        let guard = inputs.model.lock()?;
+        let embeddings = decode_embeddings(&guard, &payload, prompt.matches("<image>").count())?;
+        let (input_ids_vec, mask_vec) =
+            build_prompt_tokens_for_embeddings(&inputs.tokenizer, &prompt, &embeddings).map_err(
+                |err| ApiError::BadRequest(format!("prompt formatting failed: {err:#}")),
+            )?;
+        decode_prompt(
+            guard,
+            &inputs.scheduler,
+            &inputs.tokenizer,
+            None,
+            input_ids_vec,
+            mask_vec,
+            embeddings,
+            max_new_tokens,
+            inputs.max_new_tokens_ceiling,
+            inputs.structure_aware_stop,
+            inputs.detect_empty_output,
+            inputs.partial_utf8,
+            inputs.raw_output,
+            inputs.ban_image_tokens,
+            &inputs.stop,
+            inputs.sampling.sampler(),
+            inputs.beam_search,
+            inputs.repetition_penalty,
+            inputs.no_repeat_ngram_size,
+        )
+    })
+    .await
+    .map_err(|err| ApiError::Internal(format!("generation task failed: {err}")))
+    .and_then(|result| result);
+    breaker.record(&result);
+    result
+}
+
+fn decode_embeddings(
+    model: &DeepseekOcrModel,
+    payload: &[u8],
+    image_slots: usize,
+) -> Result<Vec<Tensor>, ApiError> {
+    let hidden = model.language_model().config().hidden_size;
+    read_embeddings(payload, image_slots, hidden, model.device())?
+        .into_iter()
+        .map(|tensor| {
+            tensor
+                .to_dtype(model.dtype())
+                .map_err(|err| ApiError::Internal(format!("embedding cast failed: {err}")))
+        })
+        .collect()
+}
+
+/// Split `payload` into its `image_{idx}` tensors, rejecting a payload that does not match the
+/// prompt's `<image>` slots or the model's `hidden` size as a bad request.
+fn read_embeddings(
+    payload: &[u8],
+    image_slots: usize,
+    hidden: usize,
+    device: &Device,
+) -> Result<Vec<Tensor>, ApiError> {
+    let mut tensors = candle_core::safetensors::load_buffer(payload, device)
+        .map_err(|err| ApiError::BadRequest(format!("invalid safetensors payload: {err}")))?;
+    if tensors.len() != image_slots {
+        return Err(ApiError::BadRequest(format!(
+            "payload holds {} tensors but the prompt has {image_slots} <image> slots",
+            tensors.len()
+        )));
     }
+    (0..image_slots)
+        .map(|idx| {
+            let name = format!("image_{idx}");
+            let tensor = tensors
+                .remove(&name)
+                .ok_or_else(|| ApiError::BadRequest(format!("payload is missing `{name}`")))?;
+            let (rows, cols) = tensor.dims2().map_err(|_| {
+                ApiError::BadRequest(format!(
+                    "`{name}` must have shape [tokens, {hidden}], got {:?}",
+                    tensor.dims()
+                ))
+            })?;
+            if rows == 0 || cols != hidden {
+                return Err(ApiError::BadRequest(format!(
+                    "`{name}` must have shape [tokens, {hidden}], got [{rows}, {cols}]"
+                )));
+            }
+            Ok(tensor)
+        })
+        .collect()
 }
 
+#[allow(clippy::too_many_arguments)]
 fn generate_blocking(
     model: &SharedModel,
+    scheduler: &DecodeScheduler,
     tokenizer: Arc<Tokenizer>,
     prompt: String,
     images: Vec<DynamicImage>,
     base_size: u32,
     image_size: u32,
     crop_mode: bool,
-    max_new_tokens: usize,
+    strip_aspect_threshold: Option<f32>,
+    preprocess: &PreprocessPipeline,
+    max_new_tokens: MaxNewTokens,
+    max_new_tokens_ceiling: usize,
+    structure_aware_stop: bool,
+    detect_empty_output: bool,
+    partial_utf8: PartialUtf8,
+    raw_output: bool,
+    ban_image_tokens: bool,
+    stop: &StopCriteria,
+    sampler: Option<LogitsSampler>,
+    beam_search: Option<BeamSearch>,
+    repetition_penalty: Option<RepetitionPenalty>,
+    no_repeat_ngram_size: Option<usize>,
     stream: Option<StreamContext>,
 ) -> Result<GenerationResult, ApiError> {
-    let guard = model
-        .lock()
-        .map_err(|_| ApiError::Internal("model lock poisoned".into()))?;
+    let guard = model.lock()?;
     let tokenizer_ref = tokenizer.as_ref();
-    let stream_controller = stream.map(|ctx| StreamController::new(Arc::clone(&tokenizer), ctx));
-    let owned_inputs = prepare_inputs(&*guard, &images, base_size, image_size, crop_mode)?;
-    let embeddings = compute_image_embeddings(&*guard, &owned_inputs)
+    let stream_controller =
+        stream.map(|ctx| StreamController::new(Arc::clone(&tokenizer), ctx, stop));
+    let (owned_inputs, preprocess_stats) = prepare_inputs(
+        &guard,
+        &images,
+        base_size,
+        image_size,
+        crop_mode,
+        strip_aspect_threshold,
+        preprocess,
+    )?;
+    let embeddings = compute_image_embeddings(&guard, &owned_inputs)
         .map_err(|err| ApiError::Internal(format!("image embedding failed: {err:#}")))?;
     let (input_ids_vec, mask_vec) = build_prompt_tokens(
         tokenizer_ref,
@@ -99,55 +265,234 @@ fn generate_blocking(
     )
     .map_err(|err| ApiError::BadRequest(format!("prompt formatting failed: {err:#}")))?;
 
+    let mut result = decode_prompt(
+        guard,
+        scheduler,
+        &tokenizer,
+        stream_controller,
+        input_ids_vec,
+        mask_vec,
+        embeddings,
+        max_new_tokens,
+        max_new_tokens_ceiling,
+        structure_aware_stop,
+        detect_empty_output,
+        partial_utf8,
+        raw_output,
+        ban_image_tokens,
+        stop,
+        sampler,
+        beam_search,
+        repetition_penalty,
+        no_repeat_ngram_size,
+    )?;
+    result.deskew_degrees = preprocess_stats
+        .iter()
+        .filter_map(|stats| stats.deskew_degrees)
+        .collect();
+    Ok(result)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn decode_prompt(
+    guard: ModelGuard<'_>,
+    scheduler: &DecodeScheduler,
+    tokenizer: &Arc<Tokenizer>,
+    stream_controller: Option<StreamController>,
+    input_ids_vec: Vec<i64>,
+    mask_vec: Vec<u8>,
+    embeddings: Vec<Tensor>,
+    max_new_tokens: MaxNewTokens,
+    max_new_tokens_ceiling: usize,
+    structure_aware_stop: bool,
+    detect_empty_output: bool,
+    partial_utf8: PartialUtf8,
+    raw_output: bool,
+    ban_image_tokens: bool,
+    stop: &StopCriteria,
+    sampler: Option<LogitsSampler>,
+    beam_search: Option<BeamSearch>,
+    repetition_penalty: Option<RepetitionPenalty>,
+    no_repeat_ngram_size: Option<usize>,
+) -> Result<GenerationResult, ApiError> {
+    let prompt_token_ids = input_ids_vec.clone();
     let input_len = input_ids_vec.len();
-    let token_device = guard.device();
+    let language_config = guard.language_model().config();
+    let eos_token_id = language_config.eos_token_id;
+    let max_new_tokens = max_new_tokens.resolve(
+        input_len,
+        mask_vec.iter().filter(|&&flag| flag != 0).count(),
+        language_config.max_position_embeddings,
+        max_new_tokens_ceiling,
+    );
+    let banned_token_ids = if ban_image_tokens {
+        image_token_ids(tokenizer)
+    } else {
+        Vec::new()
+    };
+    if let Some(beam) = beam_search {
+        let generated_tokens = decode_beams(
+            guard,
+            input_ids_vec,
+            mask_vec,
+            &embeddings,
+            max_new_tokens,
+            eos_token_id,
+            &banned_token_ids,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            &beam,
+        )
+        .map_err(|err| ApiError::Internal(format!("generation failed: {err:#}")))?;
+        return finish_generation(
+            tokenizer,
+            None,
+            generated_tokens,
+            prompt_token_ids,
+            max_new_tokens,
+            structure_aware_stop,
+            detect_empty_output,
+            raw_output,
+            stop,
+        );
+    }
+    // The scheduler thread takes the model lock itself between batched decode steps.
+    drop(guard);
+
+    let extend_while: Option<ExtendFn> = (partial_utf8 == PartialUtf8::Complete).then(|| {
+        let tokenizer = Arc::clone(tokenizer);
+        Box::new(move |ids: &[i64]| {
+            tail_is_partial_utf8(ids, |tail| tokenizer.decode(tail, true).unwrap_or_default())
+        }) as ExtendFn
+    });
+
+    let stop_matches: Option<StopFn> = (!stop.is_empty()).then(|| {
+        let tokenizer = Arc::clone(tokenizer);
+        let stop = stop.clone();
+        Box::new(move |ids: &[i64]| {
+            stop.matches(ids, |ids| tokenizer.decode(ids, false).unwrap_or_default())
+        }) as StopFn
+    });
+    // A streaming client that hung up ends its sequence at the next token.
+    let disconnected = stream_controller
+        .as_ref()
+        .map(StreamController::disconnected);
+    let stop_when: Option<StopFn> = match (stop_matches, disconnected) {
+        (stop_matches, None) => stop_matches,
+        (None, Some(disconnected)) => Some(Box::new(move |_: &[i64]| disconnected())),
+        (Some(stop_matches), Some(disconnected)) => Some(Box::new(move |ids: &[i64]| {
+            disconnected() || stop_matches(ids)
+        })),
+    };
 
-    let input_ids = Tensor::from_vec(input_ids_vec.clone(), (1, input_len), token_device)
-        .map_err(|err| ApiError::Internal(format!("input tensor failed: {err}")))?
-        .to_dtype(DType::I64)
-        .map_err(|err| ApiError::Internal(format!("tensor cast failed: {err}")))?;
+    let progress: Option<ProgressFn> = stream_controller.as_ref().map(|controller| {
+        controller.send_initial();
+        Box::new(controller.callback()) as ProgressFn
+    });
 
-    let mask_tensor = Tensor::from_vec(mask_vec.clone(), (1, mask_vec.len()), token_device)
-        .map_err(|err| ApiError::Internal(format!("mask tensor failed: {err}")))?
-        .to_dtype(DType::U8)
-        .map_err(|err| ApiError::Internal(format!("mask cast failed: {err}")))?;
+    let generated_tokens = scheduler
+        .submit(DecodeJob {
+            input_ids: input_ids_vec,
+            images_seq_mask: mask_vec,
+            embeddings,
+            max_new_tokens,
+            eos_token_id,
+            banned_token_ids,
+            sampler,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            stop_when,
+            extend_while,
+            progress,
+            span: Span::current(),
+        })
+        .map_err(|err| ApiError::Internal(format!("generation failed: {err:#}")))?;
+    finish_generation(
+        tokenizer,
+        stream_controller.as_ref(),
+        generated_tokens,
+        prompt_token_ids,
+        max_new_tokens,
+        structure_aware_stop,
+        detect_empty_output,
+        raw_output,
+        stop,
+    )
+}
 
+/// Decode with beam search on this thread. Every hypothesis forks its own KV cache, which the
+/// batching scheduler does not do, so the model stays locked until the search ends.
+#[allow(clippy::too_many_arguments)]
+fn decode_beams(
+    model: ModelGuard<'_>,
+    input_ids: Vec<i64>,
+    images_seq_mask: Vec<u8>,
+    embeddings: &[Tensor],
+    max_new_tokens: usize,
+    eos_token_id: Option<i64>,
+    banned_token_ids: &[i64],
+    repetition_penalty: Option<RepetitionPenalty>,
+    no_repeat_ngram_size: Option<usize>,
+    beam: &BeamSearch,
+) -> anyhow::Result<Vec<i64>> {
+    let device = model.device();
+    let len = input_ids.len();
+    let input_ids = Tensor::from_vec(input_ids, (1, len), device)?.to_dtype(DType::I64)?;
+    let mask = Tensor::from_vec(images_seq_mask, (1, len), device)?.to_dtype(DType::U8)?;
     let mut options = GenerateOptions::new(max_new_tokens);
-    options.images_seq_mask = Some(&mask_tensor);
+    options.images_seq_mask = Some(&mask);
+    options.eos_token_id = eos_token_id;
+    options.banned_token_ids = banned_token_ids;
+    options.repetition_penalty = repetition_penalty;
+    options.no_repeat_ngram_size = no_repeat_ngram_size;
     if !embeddings.is_empty() {
-        options.image_embeddings = Some(embeddings.as_slice());
+        options.image_embeddings = Some(embeddings);
     }
-    options.eos_token_id = guard.language_model().config().eos_token_id;
+    let best = model
+        .generate_beams(&input_ids, &options, beam)?
+        .into_iter()
+        .next();
+    Ok(best.map_or_else(Vec::new, |best| best.tokens))
+}
 
-    let mut _progress_guard: Option<Box<dyn Fn(usize, &[i64]) + Send + Sync>> = None;
-    if let Some(controller) = &stream_controller {
-        controller.send_initial();
-        let callback = controller.callback();
-        _progress_guard = Some(Box::new(callback));
-        if let Some(cb) = _progress_guard.as_ref() {
-            options.progress_callback = Some(&**cb);
-        }
+/// Turn the generated token ids into the response text, finishing the stream if there is one.
+#[allow(clippy::too_many_arguments)]
+fn finish_generation(
+    tokenizer: &Tokenizer,
+    stream_controller: Option<&StreamController>,
+    generated_tokens: Vec<i64>,
+    prompt_token_ids: Vec<i64>,
+    max_new_tokens: usize,
+    structure_aware_stop: bool,
+    detect_empty_output: bool,
+    raw_output: bool,
+    stop: &StopCriteria,
+) -> Result<GenerationResult, ApiError> {
+    let input_len = prompt_token_ids.len();
+    let generated_tokens = stop.strip_tokens(&generated_tokens).to_vec();
+    let generated_ids: Vec<u32> = generated_tokens
+        .iter()
+        .filter_map(|&id| u32::try_from(id).ok())
+        .collect();
+    let decoded = decode_without_partial_utf8(&generated_ids, |ids| {
+        tokenizer.decode(ids, true).unwrap_or_default()
+    });
+    let raw_output =
+        raw_output.then(|| tokenizer.decode(&generated_ids, false).unwrap_or_default());
+    let decoded = stop.truncate(&decoded).to_string();
+    let (decoded, truncated) = if structure_aware_stop && generated_tokens.len() >= max_new_tokens {
+        trim_to_structural_boundary(&decoded)
+    } else {
+        (decoded, false)
+    };
+    if truncated {
+        warn!("[generate] token budget hit mid-structure; trimmed to last complete block");
+    }
+    let (normalized, finish_reason) =
+        finish_trimmed_output(normalize_text(&decoded), detect_empty_output, truncated);
+    if finish_reason == FinishReason::Empty {
+        info!("[generate] output is blank; returning empty text");
     }
-
-    let generated = guard
-        .generate(&input_ids, options)
-        .map_err(|err| ApiError::Internal(format!("generation failed: {err:#}")))?;
-    let generated_tokens = generated
-        .to_vec2::<i64>()
-        .map_err(|err| ApiError::Internal(format!("token decode failed: {err:#}")))?
-        .into_iter()
-        .next()
-        .unwrap_or_default();
-    let decoded = tokenizer_ref
-        .decode(
-            &generated_tokens
-                .iter()
-                .filter_map(|&id| u32::try_from(id).ok())
-                .collect::<Vec<_>>(),
-            true,
-        )
-        .unwrap_or_default();
-    let normalized = normalize_text(&decoded);
 
     info!(
         "[generate] decoded_raw=\"{}\" normalized=\"{}\"",
@@ -163,17 +508,30 @@ fn generate_blocking(
             .collect::<String>()
     );
 
-    drop(guard);
-
-    if let Some(controller) = &stream_controller {
+    if let Some(controller) = stream_controller {
+        if controller.is_disconnected() {
+            info!(
+                "[generate] client disconnected; stopped after {} tokens",
+                generated_tokens.len()
+            );
+        }
         controller.flush_remaining(&generated_tokens);
-        controller.finalize(&normalized, input_len, generated_tokens.len());
+        controller.finalize(
+            &normalized,
+            &prompt_token_ids,
+            generated_tokens.len(),
+            finish_reason,
+        );
     }
 
     Ok(GenerationResult {
         text: normalized,
         prompt_tokens: input_len,
+        prompt_token_ids,
         response_tokens: generated_tokens.len(),
+        finish_reason,
+        deskew_degrees: Vec::new(),
+        raw_output,
     })
 }
 
@@ -183,12 +541,25 @@ fn prepare_inputs(
     base_size: u32,
     image_size: u32,
     crop_mode: bool,
-) -> Result<Vec<OwnedVisionInput>, ApiError> {
-    prepare_vision_inputs(model, images, base_size, image_size, crop_mode)
-        .map_err(|err| ApiError::Internal(format!("vision input failed: {err:#}")))
+    strip_aspect_threshold: Option<f32>,
+    preprocess: &PreprocessPipeline,
+) -> Result<(Vec<OwnedVisionInput>, Vec<PreprocessStats>), ApiError> {
+    prepare_vision_inputs_with_stats(
+        model,
+        images,
+        base_size,
+        image_size,
+        crop_mode,
+        strip_aspect_threshold,
+        preprocess,
+    )
+    .map_err(|err| ApiError::Internal(format!("vision input failed: {err:#}")))
 }
 
-pub fn convert_messages(messages: &[ApiMessage]) -> Result<(String, Vec<DynamicImage>), ApiError> {
+pub fn convert_messages(
+    messages: &[ApiMessage],
+    apply_exif_orientation: bool,
+) -> Result<(String, Vec<DynamicImage>), ApiError> {
     let latest_user_idx = messages
         .iter()
         .rposition(|message| message.role.eq_ignore_ascii_case("user"))
@@ -202,7 +573,7 @@ pub fn convert_messages(messages: &[ApiMessage]) -> Result<(String, Vec<DynamicI
     // OCR模型不是为对话训练的，所以只保留一轮的prompt，留多轮连正常输出都产生不了
     for message in &messages[..latest_user_idx] {
         if message.role.eq_ignore_ascii_case("system") {
-            let (text, mut msg_images) = flatten_content(&message.content)?;
+            let (text, mut msg_images) = flatten_content(&message.content, apply_exif_orientation)?;
             if !text.is_empty() {
                 sections.push(text);
             }
@@ -210,7 +581,8 @@ pub fn convert_messages(messages: &[ApiMessage]) -> Result<(String, Vec<DynamicI
         }
     }
 
-    let (user_text, mut user_images) = flatten_content(&messages[latest_user_idx].content)?;
+    let (user_text, mut user_images) =
+        flatten_content(&messages[latest_user_idx].content, apply_exif_orientation)?;
     if !user_text.is_empty() {
         sections.push(user_text);
     }
@@ -222,19 +594,26 @@ pub fn convert_messages(messages: &[ApiMessage]) -> Result<(String, Vec<DynamicI
         ));
     }
 
+    Ok((wrap_user_prompt(&sections.join("\n\n")), all_images))
+}
+
+/// Wrap the user turn in the single-round chat markers the OCR model was trained on.
+pub fn wrap_user_prompt(body: &str) -> String {
     let mut prompt = String::from("<|User|>\n");
-    let body = sections.join("\n\n");
     if !body.is_empty() {
-        prompt.push_str(&body);
+        prompt.push_str(body);
         if !body.ends_with('\n') {
             prompt.push('\n');
         }
     }
     prompt.push_str("<|Assistant|>\n");
-    Ok((prompt, all_images))
+    prompt
 }
 
-fn flatten_content(content: &MessageContent) -> Result<(String, Vec<DynamicImage>), ApiError> {
+fn flatten_content(
+    content: &MessageContent,
+    apply_exif_orientation: bool,
+) -> Result<(String, Vec<DynamicImage>), ApiError> {
     match content {
         MessageContent::Text(text) => Ok((text.trim().to_owned(), Vec::new())),
         MessageContent::Parts(parts) => {
@@ -244,7 +623,7 @@ fn flatten_content(content: &MessageContent) -> Result<(String, Vec<DynamicImage
                 match part {
                     MessagePart::ImageUrl { image_url } | MessagePart::InputImage { image_url } => {
                         buffer.push_str("<image>");
-                        images.push(load_image(image_url)?);
+                        images.push(load_image(image_url, apply_exif_orientation)?);
                     }
                     MessagePart::Text { text } | MessagePart::InputText { text } => {
                         if !buffer.is_empty() {
@@ -259,20 +638,20 @@ fn flatten_content(content: &MessageContent) -> Result<(String, Vec<DynamicImage
     }
 }
 
-fn load_image(spec: &ImagePayload) -> Result<DynamicImage, ApiError> {
+fn load_image(spec: &ImagePayload, apply_exif_orientation: bool) -> Result<DynamicImage, ApiError> {
     let url = spec.url();
     if let Some(rest) = url.strip_prefix("data:") {
-        return load_data_url(rest);
+        return load_data_url(rest, apply_exif_orientation);
     }
     if url.starts_with("http://") || url.starts_with("https://") {
-        return fetch_remote_image(url);
+        return fetch_remote_image(url, apply_exif_orientation);
     }
     Err(ApiError::BadRequest(
         "only data: URIs or http(s) image URLs are supported".into(),
     ))
 }
 
-fn load_data_url(data: &str) -> Result<DynamicImage, ApiError> {
+fn load_data_url(data: &str, apply_exif_orientation: bool) -> Result<DynamicImage, ApiError> {
     let (meta, payload) = data
         .split_once(',')
         .ok_or_else(|| ApiError::BadRequest("invalid data URL".into()))?;
@@ -284,11 +663,11 @@ fn load_data_url(data: &str) -> Result<DynamicImage, ApiError> {
     let decoded = base64::engine::general_purpose::STANDARD
         .decode(payload)
         .map_err(|err| ApiError::BadRequest(format!("invalid base64 image payload: {err}")))?;
-    image::load_from_memory(&decoded)
-        .map_err(|err| ApiError::BadRequest(format!("failed to decode inline image: {err}")))
+    load_image_from_memory(&decoded, apply_exif_orientation)
+        .map_err(|err| ApiError::BadRequest(format!("failed to decode inline image: {err:#}")))
 }
 
-fn fetch_remote_image(url: &str) -> Result<DynamicImage, ApiError> {
+fn fetch_remote_image(url: &str, apply_exif_orientation: bool) -> Result<DynamicImage, ApiError> {
     let client = Client::new();
     let response = client
         .get(url)
@@ -299,6 +678,76 @@ fn fetch_remote_image(url: &str) -> Result<DynamicImage, ApiError> {
     let bytes = response
         .bytes()
         .map_err(|err| ApiError::BadRequest(format!("failed to read image body: {err}")))?;
-    image::load_from_memory(&bytes)
-        .map_err(|err| ApiError::BadRequest(format!("failed to decode remote image: {err}")))
+    load_image_from_memory(&bytes, apply_exif_orientation)
+        .map_err(|err| ApiError::BadRequest(format!("failed to decode remote image: {err:#}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    const HIDDEN: usize = 8;
+
+    fn payload(tensors: Vec<(&str, Tensor)>) -> Vec<u8> {
+        static PAYLOADS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "deepseek-ocr-embeddings-{}-{}.safetensors",
+            std::process::id(),
+            PAYLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tensors: HashMap<&str, Tensor> = tensors.into_iter().collect();
+        candle_core::safetensors::save(&tensors, &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    fn zeros(shape: &[usize]) -> Tensor {
+        Tensor::zeros(shape, DType::F32, &Device::Cpu).unwrap()
+    }
+
+    fn rejection(payload: &[u8], image_slots: usize) -> String {
+        match read_embeddings(payload, image_slots, HIDDEN, &Device::Cpu) {
+            Err(ApiError::BadRequest(message)) => message,
+            Err(err) => panic!("expected a bad request, got {err:?}"),
+            Ok(_) => panic!("payload was accepted"),
+        }
+    }
+
+    #[test]
+    fn embeddings_matching_the_slots_and_hidden_size_are_read_in_order() {
+        let bytes = payload(vec![
+            ("image_0", zeros(&[3, HIDDEN])),
+            ("image_1", zeros(&[5, HIDDEN])),
+        ]);
+        let tensors = read_embeddings(&bytes, 2, HIDDEN, &Device::Cpu).unwrap();
+        let shapes: Vec<_> = tensors.iter().map(|t| t.dims().to_vec()).collect();
+        assert_eq!(shapes, [vec![3, HIDDEN], vec![5, HIDDEN]]);
+    }
+
+    #[test]
+    fn mismatched_embeddings_are_bad_requests() {
+        let wrong_hidden = payload(vec![("image_0", zeros(&[3, HIDDEN + 1]))]);
+        assert!(rejection(&wrong_hidden, 1).contains("got [3, 9]"));
+
+        let wrong_rank = payload(vec![("image_0", zeros(&[1, 3, HIDDEN]))]);
+        assert!(rejection(&wrong_rank, 1).contains("must have shape [tokens, 8]"));
+
+        let empty = payload(vec![("image_0", zeros(&[0, HIDDEN]))]);
+        assert!(rejection(&empty, 1).contains("got [0, 8]"));
+
+        let one = payload(vec![("image_0", zeros(&[3, HIDDEN]))]);
+        assert!(rejection(&one, 2).contains("2 <image> slots"));
+
+        let misnamed = payload(vec![("image_1", zeros(&[3, HIDDEN]))]);
+        assert!(rejection(&misnamed, 1).contains("missing `image_0`"));
+
+        assert!(rejection(b"not safetensors", 1).contains("invalid safetensors payload"));
+    }
 }
@@ -0,0 +1,396 @@
+//! gRPC front end mirroring `POST /v1/documents`, for deployments that only speak gRPC.
+//!
+//! Requests go through the same circuit breaker, decode scheduler and generation path as the
+//! HTTP routes. Message types follow `proto/deepseek_ocr.proto` field for field.
+
+use std::{net::SocketAddr, pin::Pin};
+
+use anyhow::{Context, Result};
+use deepseek_ocr_core::{
+    document::{self, DocumentResult, SamplingUsage, TableFormat},
+    inference::MaxNewTokens,
+    vision::load_image_from_memory,
+};
+use image::DynamicImage;
+use rocket::tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt, wrappers::UnboundedReceiverStream};
+use tonic::{Request, Response, Status, transport::Server};
+use tracing::info;
+
+use crate::{
+    error::ApiError,
+    generation::{generate_async, wrap_user_prompt},
+    models::{SamplingRequest, StreamFormat},
+    request_id::{REQUEST_ID_HEADER, RequestId},
+    state::{AppState, GenerationInputs},
+    stream::{Delta, FlushPolicy, StreamContext, StreamKind, StreamSender},
+};
+
+include!(concat!(env!("OUT_DIR"), "/deepseek_ocr.v1.Ocr.rs"));
+
+use ocr_server::{Ocr, OcrServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RecognizeRequest {
+    #[prost(string, tag = "1")]
+    pub model: String,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub images: Vec<Vec<u8>>,
+    #[prost(string, tag = "3")]
+    pub prompt: String,
+    #[prost(uint32, optional, tag = "4")]
+    pub max_tokens: Option<u32>,
+    #[prost(double, optional, tag = "5")]
+    pub temperature: Option<f64>,
+    #[prost(double, optional, tag = "6")]
+    pub top_p: Option<f64>,
+    #[prost(uint64, optional, tag = "7")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RecognizeChunk {
+    #[prost(oneof = "Chunk", tags = "1, 2")]
+    pub chunk: Option<Chunk>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Chunk {
+    #[prost(string, tag = "1")]
+    Text(String),
+    #[prost(message, tag = "2")]
+    Document(Document),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Document {
+    #[prost(uint32, tag = "1")]
+    pub schema_version: u32,
+    #[prost(string, tag = "2")]
+    pub text: String,
+    #[prost(message, repeated, tag = "3")]
+    pub regions: Vec<Region>,
+    #[prost(message, repeated, tag = "4")]
+    pub tables: Vec<Table>,
+    #[prost(message, optional, tag = "5")]
+    pub usage: Option<Usage>,
+    #[prost(string, tag = "6")]
+    pub finish_reason: String,
+    #[prost(string, optional, tag = "7")]
+    pub raw_output: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Region {
+    #[prost(string, tag = "1")]
+    pub label: String,
+    #[prost(message, repeated, tag = "2")]
+    pub boxes: Vec<BoundingBox>,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct BoundingBox {
+    #[prost(uint32, tag = "1")]
+    pub x1: u32,
+    #[prost(uint32, tag = "2")]
+    pub y1: u32,
+    #[prost(uint32, tag = "3")]
+    pub x2: u32,
+    #[prost(uint32, tag = "4")]
+    pub y2: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Table {
+    #[prost(string, tag = "1")]
+    pub format: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Usage {
+    #[prost(uint64, tag = "1")]
+    pub prompt_tokens: u64,
+    #[prost(uint64, tag = "2")]
+    pub completion_tokens: u64,
+    #[prost(uint64, tag = "3")]
+    pub total_tokens: u64,
+    #[prost(float, repeated, tag = "4")]
+    pub deskew_degrees: Vec<f32>,
+    #[prost(message, optional, tag = "5")]
+    pub sampling: Option<Sampling>,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Sampling {
+    #[prost(double, tag = "1")]
+    pub temperature: f64,
+    #[prost(double, optional, tag = "2")]
+    pub top_p: Option<f64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub seed: Option<u64>,
+}
+
+impl From<DocumentResult> for Document {
+    fn from(result: DocumentResult) -> Self {
+        Self {
+            schema_version: result.schema_version,
+            text: result.text,
+            regions: result
+                .regions
+                .into_iter()
+                .map(|region| Region {
+                    label: region.label,
+                    boxes: region
+                        .boxes
+                        .into_iter()
+                        .map(|[x1, y1, x2, y2]| BoundingBox { x1, y1, x2, y2 })
+                        .collect(),
+                })
+                .collect(),
+            tables: result
+                .tables
+                .into_iter()
+                .map(|table| Table {
+                    format: match table.format {
+                        TableFormat::Markdown => "markdown",
+                        TableFormat::Html => "html",
+                    }
+                    .into(),
+                    content: table.content,
+                })
+                .collect(),
+            usage: Some(Usage {
+                prompt_tokens: result.usage.prompt_tokens as u64,
+                completion_tokens: result.usage.completion_tokens as u64,
+                total_tokens: result.usage.total_tokens as u64,
+                deskew_degrees: result.usage.deskew_degrees,
+                sampling: result.usage.sampling.map(|sampling| Sampling {
+                    temperature: sampling.temperature,
+                    top_p: sampling.top_p,
+                    seed: sampling.seed,
+                }),
+            }),
+            finish_reason: result.finish_reason.as_str().into(),
+            raw_output: result.raw_output,
+        }
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(err: ApiError) -> Self {
+        match err {
+            ApiError::BadRequest(message) => Status::invalid_argument(message),
+            ApiError::Internal(message) => Status::internal(message),
+            ApiError::Unavailable(message) | ApiError::Overloaded { message, .. } => {
+                Status::unavailable(message)
+            }
+        }
+    }
+}
+
+/// Everything a gRPC call needs from [`AppState`], captured before Rocket takes ownership of it.
+pub struct OcrService {
+    inputs: GenerationInputs,
+    model_id: String,
+    max_new_tokens: MaxNewTokens,
+    apply_exif_orientation: bool,
+    stream_flush: FlushPolicy,
+}
+
+impl OcrService {
+    pub fn from_app(state: &AppState) -> Self {
+        Self {
+            inputs: GenerationInputs::from_app(state),
+            model_id: state.model_id.clone(),
+            max_new_tokens: state.max_new_tokens,
+            apply_exif_orientation: state.apply_exif_orientation,
+            stream_flush: state.stream_flush,
+        }
+    }
+
+    /// Check admission and turn a request into what to generate with.
+    fn prepare(&self, request: Request<RecognizeRequest>) -> Result<Prepared, ApiError> {
+        let request_id = RequestId::from_client(
+            request
+                .metadata()
+                .get(REQUEST_ID_HEADER.to_ascii_lowercase().as_str())
+                .and_then(|value| value.to_str().ok()),
+        );
+        let request = request.into_inner();
+        if !request.model.is_empty() && request.model != self.model_id {
+            return Err(ApiError::BadRequest(format!(
+                "requested model `{}` is not available",
+                request.model
+            )));
+        }
+        self.inputs.breaker.check()?;
+        let mut inputs = self.inputs.clone();
+        inputs.apply_sampling(&SamplingRequest {
+            temperature: request.temperature,
+            top_p: request.top_p,
+            seed: request.seed,
+        })?;
+        let images = request
+            .images
+            .iter()
+            .enumerate()
+            .map(|(idx, bytes)| {
+                load_image_from_memory(bytes, self.apply_exif_orientation).map_err(|err| {
+                    ApiError::BadRequest(format!("failed to decode image {idx}: {err:#}"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let prompt = request.prompt.trim();
+        let markers = prompt.matches("<image>").count();
+        let body = if markers == 0 {
+            let mut body = "<image>\n".repeat(images.len());
+            body.push_str(prompt);
+            body
+        } else if markers == images.len() {
+            prompt.to_owned()
+        } else {
+            return Err(ApiError::BadRequest(format!(
+                "prompt has {markers} <image> markers but {} images were sent",
+                images.len()
+            )));
+        };
+        if body.trim().is_empty() {
+            return Err(ApiError::BadRequest(
+                "request must include a prompt or images".into(),
+            ));
+        }
+        let max_tokens = request
+            .max_tokens
+            .map(|tokens| MaxNewTokens::Fixed(tokens as usize))
+            .unwrap_or(self.max_new_tokens);
+        Ok(Prepared {
+            request_id,
+            inputs,
+            prompt: wrap_user_prompt(body.trim()),
+            images,
+            max_tokens,
+        })
+    }
+}
+
+/// A validated request: its id, the inputs with its sampling fields applied, and the prompt,
+/// images and budget to generate with.
+struct Prepared {
+    request_id: RequestId,
+    inputs: GenerationInputs,
+    prompt: String,
+    images: Vec<DynamicImage>,
+    max_tokens: MaxNewTokens,
+}
+
+type ChunkStream = Pin<Box<dyn Stream<Item = Result<RecognizeChunk, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Ocr for OcrService {
+    async fn recognize(
+        &self,
+        request: Request<RecognizeRequest>,
+    ) -> Result<Response<Document>, Status> {
+        let Prepared {
+            request_id,
+            inputs,
+            prompt,
+            images,
+            max_tokens,
+        } = self.prepare(request)?;
+        inputs.check_beam_search(false)?;
+        let sampling = SamplingUsage::from(inputs.sampling);
+        let generation =
+            generate_async(inputs, prompt, images, max_tokens, None, request_id).await?;
+        let mut result = DocumentResult::from_output(
+            &generation.text,
+            generation.prompt_tokens,
+            generation.response_tokens,
+            generation.finish_reason,
+        );
+        result.usage.deskew_degrees = generation.deskew_degrees;
+        result.usage.sampling = Some(sampling);
+        result.raw_output = generation.raw_output;
+        Ok(Response::new(result.into()))
+    }
+
+    type RecognizeStreamStream = ChunkStream;
+
+    async fn recognize_stream(
+        &self,
+        request: Request<RecognizeRequest>,
+    ) -> Result<Response<Self::RecognizeStreamStream>, Status> {
+        let Prepared {
+            request_id,
+            inputs,
+            prompt,
+            images,
+            max_tokens,
+        } = self.prepare(request)?;
+        inputs.check_beam_search(true)?;
+        let sampling = SamplingUsage::from(inputs.sampling);
+        let (delta_sender, delta_rx) = mpsc::unbounded_channel();
+        let (result_sender, result_rx) = mpsc::unbounded_channel();
+        let context = StreamContext {
+            sender: StreamSender::Deltas(delta_sender),
+            format: StreamFormat::Text,
+            kind: StreamKind::Grpc,
+            flush: self.stream_flush,
+        };
+        let inputs = inputs.admit().await?;
+        rocket::tokio::spawn(async move {
+            let generation = generate_async(
+                inputs,
+                prompt,
+                images,
+                max_tokens,
+                Some(context),
+                request_id,
+            )
+            .await;
+            let chunk = generation.map_err(Status::from).map(|generation| {
+                let mut result = DocumentResult::from_output(
+                    &generation.text,
+                    generation.prompt_tokens,
+                    generation.response_tokens,
+                    generation.finish_reason,
+                );
+                result.usage.deskew_degrees = generation.deskew_degrees;
+                result.usage.sampling = Some(sampling);
+                result.raw_output = generation.raw_output;
+                RecognizeChunk {
+                    chunk: Some(Chunk::Document(result.into())),
+                }
+            });
+            let _ = result_sender.send(chunk);
+        });
+        // The delta sender is dropped with the stream context once generation returns, so the
+        // final document always follows the last delta.
+        let deltas = UnboundedReceiverStream::new(delta_rx).filter_map(|delta| match delta {
+            Delta::Text(text) => Some(Ok(RecognizeChunk {
+                chunk: Some(Chunk::Text(text)),
+            })),
+            Delta::TokenIds(_) => None,
+        });
+        let stream = deltas.chain(UnboundedReceiverStream::new(result_rx));
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Serve the gRPC API on `host:port` until the process exits.
+pub async fn serve(service: OcrService, host: &str, port: u16) -> Result<()> {
+    let addr: SocketAddr = format!("{host}:{port}")
+        .parse()
+        .with_context(|| format!("invalid gRPC bind address {host}:{port}"))?;
+    info!(
+        "gRPC service ready on {addr} (schema v{})",
+        document::DOCUMENT_SCHEMA_VERSION
+    );
+    Server::builder()
+        .add_service(OcrServer::new(service))
+        .serve(addr)
+        .await
+        .context("gRPC server failed")
+}
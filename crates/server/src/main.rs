@@ -3,14 +3,20 @@
 #[macro_use]
 extern crate rocket;
 
+mod admission;
 mod app;
 mod args;
+mod breaker;
 mod error;
 mod generation;
+#[cfg(feature = "grpc")]
+mod grpc;
 mod logging;
 mod models;
+mod request_id;
 mod resources;
 mod routes;
+mod scheduler;
 mod state;
 mod stream;
 
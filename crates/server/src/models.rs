@@ -1,5 +1,8 @@
+use deepseek_ocr_core::{document::SamplingUsage, transformer::sampling::SamplingParams};
 use serde::{Deserialize, Serialize};
 
+use crate::error::ApiError;
+
 #[derive(Debug, Serialize)]
 pub struct ResponsesResponse {
     pub id: String,
@@ -15,6 +18,15 @@ pub struct Usage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,
+    /// Skew in degrees corrected on each input image when the deskew preprocessor is enabled.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deskew_degrees: Vec<f32>,
+    /// Sampling settings the response was decoded with, request fields and defaults combined.
+    pub sampling: SamplingUsage,
+    /// Exact prompt token ids, image placeholders included, when the request set
+    /// `return_prompt_token_ids`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<i64>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -62,6 +74,78 @@ pub struct ModelsResponse {
     pub data: Vec<ModelInfo>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct UnloadResponse {
+    /// False when the model was already unloaded.
+    pub unloaded: bool,
+    /// Growth in free device memory across the unload, on devices that report it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub freed_bytes: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsResponse {
+    pub kv_cache: KvCacheMetrics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moe_aux_loss: Option<AuxLossMetrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub early_exit: Option<EarlyExitMetrics>,
+    /// Attention forwards re-run eagerly since startup because flash attention emitted NaNs;
+    /// stays 0 unless `--flash-nan-check` is on.
+    pub flash_nan_fallback_count: u64,
+    pub queue: QueueMetrics,
+}
+
+/// Generation requests admitted or waiting for a slot.
+#[derive(Debug, Serialize)]
+pub struct QueueMetrics {
+    /// Requests holding a slot, from preprocessing to their last token.
+    pub in_flight: usize,
+    /// Requests waiting for a slot.
+    pub queued: usize,
+    /// Slots, i.e. `max_num_seqs`.
+    pub max_in_flight: usize,
+    /// Waiting requests beyond which new ones get 503; `None` is unbounded.
+    pub max_queued: Option<usize>,
+}
+
+/// Decode steps since startup with `--early-exit-layer` on.
+#[derive(Debug, Serialize)]
+pub struct EarlyExitMetrics {
+    pub steps: u64,
+    /// Steps that skipped the MLPs of the layers after the exit layer.
+    pub exits: u64,
+    /// Average number of layers whose MLP ran per step.
+    pub mean_layers: f32,
+}
+
+/// MoE load-balancing loss over the decoder forwards since startup (1.0 is perfectly balanced
+/// before `aux_loss_alpha` scaling).
+#[derive(Debug, Serialize)]
+pub struct AuxLossMetrics {
+    pub forwards: u64,
+    /// Loss of the most recent forward, summed over MoE layers and scaled by `aux_loss_alpha`.
+    pub last: f32,
+    pub mean: f32,
+}
+
+/// KV cache figures for the scheduler's shared decode batch.
+#[derive(Debug, Serialize)]
+pub struct KvCacheMetrics {
+    /// Sequences currently decoding in the batch.
+    pub active_sequences: usize,
+    /// Padded positions cached per sequence row.
+    pub seq_len: usize,
+    /// Bytes allocated for keys and values, growth headroom included.
+    pub bytes: usize,
+    /// Largest `bytes` seen since startup.
+    pub peak_bytes: usize,
+    /// Positions cached over the server's lifetime, prompts and generated tokens alike.
+    pub cached_positions: u64,
+    /// Sequences that finished decoding since startup.
+    pub completed_sequences: u64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ModelInfo {
     pub id: String,
@@ -79,8 +163,80 @@ pub struct ResponsesRequest {
     pub max_output_tokens: Option<usize>,
     #[serde(default)]
     pub max_tokens: Option<usize>,
+    #[serde(flatten)]
+    pub sampling: SamplingRequest,
     #[serde(default)]
     pub stream: Option<bool>,
+    #[serde(default)]
+    pub stream_format: StreamFormat,
+    /// Report the prompt token ids in `usage`, or the final event's usage when streaming.
+    #[serde(default)]
+    pub return_prompt_token_ids: bool,
+}
+
+/// OpenAI sampling fields accepted by every generation endpoint. Omitted ones take the
+/// server's `inference.temperature`, `inference.top_p` and `inference.seed`.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct SamplingRequest {
+    /// Sampling temperature; `0` decodes greedily.
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    /// Nucleus sampling cutoff, applied when `temperature` is above zero.
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    /// Seeds the sampler so repeated sampled requests return the same text.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+impl SamplingRequest {
+    /// Combine the request's fields with the configured `defaults`, request values winning.
+    pub fn resolve(&self, defaults: SamplingParams) -> Result<SamplingParams, ApiError> {
+        let temperature = self.temperature.unwrap_or(defaults.temperature);
+        if !temperature.is_finite() || temperature < 0.0 {
+            return Err(ApiError::BadRequest(format!(
+                "`temperature` must be a non-negative number, got {temperature}"
+            )));
+        }
+        if let Some(top_p) = self.top_p.filter(|top_p| !(*top_p > 0.0 && *top_p <= 1.0)) {
+            return Err(ApiError::BadRequest(format!(
+                "`top_p` must be in (0, 1], got {top_p}"
+            )));
+        }
+        Ok(SamplingParams {
+            temperature,
+            top_p: self.top_p.or(defaults.top_p),
+            seed: self.seed.or(defaults.seed),
+        })
+    }
+}
+
+/// Payload carried by streamed deltas.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFormat {
+    /// Decoded text chunks.
+    #[default]
+    Text,
+    /// Raw generated token ids, for clients that detokenize themselves.
+    TokenIds,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingResponsesRequest {
+    pub model: String,
+    /// User prompt containing one `<image>` marker per precomputed embedding.
+    pub prompt: String,
+    /// Base64-encoded safetensors buffer with `image_0..image_{n-1}` tensors of shape
+    /// `[tokens, hidden_size]`.
+    pub embeddings: String,
+    #[serde(default)]
+    pub max_output_tokens: Option<usize>,
+    #[serde(flatten)]
+    pub sampling: SamplingRequest,
+    /// Report the prompt token ids in `usage`.
+    #[serde(default)]
+    pub return_prompt_token_ids: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -90,8 +246,30 @@ pub struct ChatCompletionRequest {
     pub messages: Vec<ApiMessage>,
     #[serde(default)]
     pub max_tokens: Option<usize>,
+    #[serde(flatten)]
+    pub sampling: SamplingRequest,
     #[serde(default)]
     pub stream: Option<bool>,
+    #[serde(default)]
+    pub stream_format: StreamFormat,
+    /// Report the prompt token ids in `usage`, or the final event's usage when streaming.
+    #[serde(default)]
+    pub return_prompt_token_ids: bool,
+}
+
+/// Body of `/v1/documents`: chat-style messages, answered with a `DocumentResult`.
+#[derive(Debug, Deserialize)]
+pub struct DocumentRequest {
+    pub model: String,
+    #[serde(default)]
+    pub messages: Vec<ApiMessage>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(flatten)]
+    pub sampling: SamplingRequest,
+    /// Report the prompt token ids in `usage`.
+    #[serde(default)]
+    pub return_prompt_token_ids: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -138,3 +316,110 @@ impl ImagePayload {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: SamplingParams = SamplingParams {
+        temperature: 0.7,
+        top_p: Some(0.9),
+        seed: Some(7),
+    };
+
+    #[test]
+    fn request_sampling_fields_win_over_the_configured_defaults() {
+        let request: ResponsesRequest = serde_json::from_value(serde_json::json!({
+            "model": "deepseek-ocr",
+            "temperature": 0.2,
+            "top_p": 0.5,
+            "seed": 42,
+        }))
+        .expect("valid responses request");
+        let params = request.sampling.resolve(DEFAULTS).expect("valid sampling");
+        assert_eq!(
+            params,
+            SamplingParams {
+                temperature: 0.2,
+                top_p: Some(0.5),
+                seed: Some(42),
+            }
+        );
+
+        let request: DocumentRequest = serde_json::from_value(serde_json::json!({
+            "model": "deepseek-ocr",
+            "seed": 3,
+        }))
+        .expect("valid document request");
+        let params = request.sampling.resolve(DEFAULTS).expect("valid sampling");
+        assert_eq!(
+            params,
+            SamplingParams {
+                seed: Some(3),
+                ..DEFAULTS
+            }
+        );
+        assert_eq!(
+            SamplingRequest::default().resolve(DEFAULTS).ok(),
+            Some(DEFAULTS)
+        );
+    }
+
+    #[test]
+    fn prompt_token_ids_are_returned_only_on_request() {
+        let request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "deepseek-ocr",
+        }))
+        .expect("valid chat request");
+        assert!(!request.return_prompt_token_ids);
+
+        let request: ResponsesRequest = serde_json::from_value(serde_json::json!({
+            "model": "deepseek-ocr",
+            "return_prompt_token_ids": true,
+        }))
+        .expect("valid responses request");
+        assert!(request.return_prompt_token_ids);
+    }
+
+    #[test]
+    fn out_of_range_sampling_fields_are_rejected() {
+        for request in [
+            SamplingRequest {
+                temperature: Some(-1.0),
+                ..SamplingRequest::default()
+            },
+            SamplingRequest {
+                temperature: Some(f64::NAN),
+                ..SamplingRequest::default()
+            },
+            SamplingRequest {
+                top_p: Some(0.0),
+                ..SamplingRequest::default()
+            },
+            SamplingRequest {
+                top_p: Some(1.5),
+                ..SamplingRequest::default()
+            },
+        ] {
+            assert!(matches!(
+                request.resolve(DEFAULTS),
+                Err(ApiError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn usage_reports_only_the_sampling_settings_that_apply() {
+        let sampled = serde_json::to_value(SamplingUsage::from(DEFAULTS)).expect("serializes");
+        assert_eq!(
+            sampled,
+            serde_json::json!({ "temperature": 0.7, "top_p": 0.9, "seed": 7 })
+        );
+        let greedy = SamplingParams {
+            temperature: 0.0,
+            ..DEFAULTS
+        };
+        let greedy = serde_json::to_value(SamplingUsage::from(greedy)).expect("serializes");
+        assert_eq!(greedy, serde_json::json!({ "temperature": 0.0 }));
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+
+use rocket::{
+    Data, Request, Response,
+    fairing::{Fairing, Info, Kind},
+    request::{FromRequest, Outcome},
+};
+use tracing::debug;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Correlation id for a single HTTP request, taken from `X-Request-Id` when the client supplies a
+/// usable one and generated otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Accept client ids made of ASCII alphanumerics, `-`, `_`, `.` and `:` (at most 128 bytes)
+    /// so they are safe to echo into headers and logs; anything else gets a fresh id.
+    pub fn from_client(value: Option<&str>) -> Self {
+        value
+            .map(str::trim)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+            .filter(|id| {
+                id.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+            })
+            .map(|id| Self(id.to_owned()))
+            .unwrap_or_else(Self::generate)
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn cached(req: &Request<'_>) -> RequestId {
+    req.local_cache(|| RequestId::from_client(req.headers().get_one(REQUEST_ID_HEADER)))
+        .clone()
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(cached(req))
+    }
+}
+
+/// Resolves the request id up front and echoes it on every response, including errors.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = cached(req);
+        debug!(request_id = %id, method = %req.method(), uri = %req.uri(), "Request received");
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        res.set_raw_header(REQUEST_ID_HEADER, cached(req).0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{http::Header, local::blocking::Client};
+
+    use super::*;
+
+    #[rocket::get("/id")]
+    fn echo(id: RequestId) -> String {
+        id.to_string()
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::build()
+            .attach(RequestIdFairing)
+            .mount("/", rocket::routes![echo]);
+        Client::tracked(rocket).expect("valid rocket instance")
+    }
+
+    /// The id on the response header and the one the handler saw.
+    fn ids(header: Option<&str>) -> (String, String) {
+        let client = client();
+        let mut request = client.get("/id");
+        if let Some(value) = header {
+            request.add_header(Header::new(REQUEST_ID_HEADER, value.to_owned()));
+        }
+        let response = request.dispatch();
+        let echoed = response
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .expect("response carries a request id")
+            .to_owned();
+        (echoed, response.into_string().unwrap_or_default())
+    }
+
+    #[test]
+    fn client_request_id_is_echoed() {
+        let (echoed, seen) = ids(Some("trace-42:a.b_c"));
+        assert_eq!(echoed, "trace-42:a.b_c");
+        assert_eq!(seen, echoed);
+        assert_eq!(ids(Some("  padded  ")).0, "padded");
+    }
+
+    #[test]
+    fn missing_request_id_is_generated() {
+        let (echoed, seen) = ids(None);
+        assert!(Uuid::parse_str(&echoed).is_ok(), "{echoed}");
+        assert_eq!(seen, echoed);
+        assert_ne!(ids(None).0, echoed);
+    }
+
+    #[test]
+    fn unusable_request_ids_are_replaced() {
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for value in ["", "   ", "has space", "caf\u{e9}", too_long.as_str()] {
+            let (echoed, seen) = ids(Some(value));
+            assert!(Uuid::parse_str(&echoed).is_ok(), "{value:?} -> {echoed}");
+            assert_eq!(seen, echoed);
+        }
+    }
+
+    #[test]
+    fn error_responses_carry_the_request_id() {
+        let client = client();
+        let response = client
+            .get("/missing")
+            .header(Header::new(REQUEST_ID_HEADER, "lost-1"))
+            .dispatch();
+        assert_eq!(response.status(), rocket::http::Status::NotFound);
+        assert_eq!(
+            response.headers().get_one(REQUEST_ID_HEADER),
+            Some("lost-1")
+        );
+    }
+}
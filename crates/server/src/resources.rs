@@ -1,25 +1,42 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use deepseek_ocr_assets as assets;
-use deepseek_ocr_config::{LocalFileSystem, ResourceLocation, VirtualFileSystem};
+use deepseek_ocr_config::{ModelFileSystem, ModelResources, ResourceLocation, VirtualFileSystem};
+use tracing::info;
 
-pub fn ensure_config_file(fs: &LocalFileSystem, location: &ResourceLocation) -> Result<PathBuf> {
+pub fn ensure_config_file(fs: &ModelFileSystem, location: &ResourceLocation) -> Result<PathBuf> {
     ensure_resource(fs, location, |path| assets::ensure_config_at(path))
 }
 
-pub fn ensure_tokenizer_file(fs: &LocalFileSystem, location: &ResourceLocation) -> Result<PathBuf> {
-    ensure_resource(fs, location, |path| assets::ensure_tokenizer_at(path))
+/// Resolve the tokenizer: the configured path, else `tokenizer.json` beside the weights, else the
+/// managed default (downloaded when missing).
+pub fn ensure_tokenizer_file(fs: &ModelFileSystem, resources: &ModelResources) -> Result<PathBuf> {
+    let location = &resources.tokenizer;
+    let path = ensure_resource(fs, location, |path| assets::ensure_tokenizer_at(path))
+        .with_context(|| {
+            format!(
+                "no tokenizer found at {}: set `tokenizer` for the model or place tokenizer.json \
+                 next to the weights",
+                location.display_with(fs).unwrap_or_default()
+            )
+        })?;
+    info!(
+        "Using tokenizer {} ({})",
+        path.display(),
+        resources.tokenizer_source.describe()
+    );
+    Ok(path)
 }
 
-pub fn prepare_weights_path(fs: &LocalFileSystem, location: &ResourceLocation) -> Result<PathBuf> {
+pub fn prepare_weights_path(fs: &ModelFileSystem, location: &ResourceLocation) -> Result<PathBuf> {
     ensure_resource(fs, location, |path| {
         assets::resolve_weights_with_default(None, path)
     })
 }
 
 fn ensure_resource<F>(
-    fs: &LocalFileSystem,
+    fs: &ModelFileSystem,
     location: &ResourceLocation,
     ensure_fn: F,
 ) -> Result<PathBuf>
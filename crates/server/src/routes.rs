@@ -1,19 +1,36 @@
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
 
-use rocket::{Either, Route, State, serde::json::Json, tokio::sync::mpsc};
+use base64::Engine;
+use deepseek_ocr_core::{
+    document::{DocumentResult, SamplingUsage},
+    inference::MaxNewTokens,
+    transformer::block::flash_nan_fallback_count,
+};
+use rocket::{
+    Either, Route, State,
+    http::Status,
+    response::status::Custom,
+    serde::json::Json,
+    tokio::{self, sync::mpsc},
+};
 use tracing::debug;
 use uuid::Uuid;
 
 use crate::{
     error::ApiError,
-    generation::{convert_messages, generate_async},
+    generation::{
+        GenerationResult, convert_messages, generate_async, generate_from_embeddings_async,
+        wrap_user_prompt,
+    },
     models::{
-        ChatChoice, ChatCompletionRequest, ChatCompletionResponse, ChatMessageResponse, ModelInfo,
-        ModelsResponse, ResponseContent, ResponseOutput, ResponsesRequest, ResponsesResponse,
+        ChatChoice, ChatCompletionRequest, ChatCompletionResponse, ChatMessageResponse,
+        DocumentRequest, EmbeddingResponsesRequest, MetricsResponse, ModelInfo, ModelsResponse,
+        ResponseContent, ResponseOutput, ResponsesRequest, ResponsesResponse, UnloadResponse,
         Usage,
     },
+    request_id::RequestId,
     state::{AppState, GenerationInputs},
-    stream::{BoxEventStream, StreamContext, StreamKind, into_event_stream},
+    stream::{BoxEventStream, StreamContext, StreamKind, StreamSender, into_event_stream},
 };
 
 #[get("/health")]
@@ -21,6 +38,33 @@ pub fn health() -> &'static str {
     "ok"
 }
 
+/// Readiness probe: 503 while the circuit breaker is rejecting work or the model is unloaded,
+/// `degraded` while the fallback model is serving.
+#[get("/readyz")]
+pub fn readyz(state: &State<AppState>) -> Custom<&'static str> {
+    if state.breaker.is_open() {
+        Custom(Status::ServiceUnavailable, "unready")
+    } else if !state.model.is_loaded() {
+        Custom(Status::ServiceUnavailable, "unloaded")
+    } else if state.degraded {
+        Custom(Status::Ok, "degraded")
+    } else {
+        Custom(Status::Ok, "ready")
+    }
+}
+
+/// KV cache figures for tuning cache size and `--max-num-seqs`.
+#[get("/metrics")]
+pub fn metrics(state: &State<AppState>) -> Json<MetricsResponse> {
+    Json(MetricsResponse {
+        kv_cache: state.scheduler.cache_metrics(),
+        moe_aux_loss: state.scheduler.aux_loss_metrics(),
+        early_exit: state.scheduler.early_exit_metrics(),
+        flash_nan_fallback_count: flash_nan_fallback_count(),
+        queue: state.admission.metrics(),
+    })
+}
+
 #[get("/models")]
 pub fn list_models(state: &State<AppState>) -> Json<ModelsResponse> {
     let now = current_timestamp();
@@ -35,33 +79,64 @@ pub fn list_models(state: &State<AppState>) -> Json<ModelsResponse> {
     })
 }
 
+/// Drop the model to give its device memory back, e.g. while a desktop app sharing the GPU is
+/// idle. The next generation request loads it again.
+#[post("/models/unload")]
+pub async fn unload_model(state: &State<AppState>) -> Result<Json<UnloadResponse>, ApiError> {
+    let active = state.scheduler.cache_metrics().active_sequences;
+    if active > 0 {
+        return Err(ApiError::Unavailable(format!(
+            "{active} sequences are still decoding; retry once the server is idle"
+        )));
+    }
+    let model = Arc::clone(&state.model);
+    let unloaded = tokio::task::spawn_blocking(move || model.unload())
+        .await
+        .map_err(|err| ApiError::Internal(format!("unload task failed: {err}")))??;
+    Ok(Json(UnloadResponse {
+        unloaded: unloaded.is_some(),
+        freed_bytes: unloaded.and_then(|unloaded| unloaded.freed_bytes),
+    }))
+}
+
 #[post("/responses", format = "json", data = "<req>")]
 pub async fn responses_endpoint(
     state: &State<AppState>,
+    request_id: RequestId,
     req: Json<ResponsesRequest>,
 ) -> Result<Either<Json<ResponsesResponse>, BoxEventStream>, ApiError> {
     ensure_model(&req.model, &state.model_id)?;
-    let gen_inputs = GenerationInputs::from_app(state.inner());
-    let (prompt, images) = convert_messages(&req.input)?;
+    state.breaker.check()?;
+    let mut gen_inputs = GenerationInputs::from_app(state.inner());
+    gen_inputs.apply_sampling(&req.sampling)?;
+    gen_inputs.check_beam_search(req.stream.unwrap_or(false))?;
+    let sampling = SamplingUsage::from(gen_inputs.sampling);
+    let (prompt, images) = convert_messages(&req.input, state.apply_exif_orientation)?;
     let max_tokens = req
         .max_output_tokens
         .or(req.max_tokens)
+        .map(MaxNewTokens::Fixed)
         .unwrap_or(state.max_new_tokens);
     if req.stream.unwrap_or(false) {
-        let stream_inputs = gen_inputs.clone();
+        // Admit before answering so a full queue is a 503 rather than an error event.
+        let stream_inputs = gen_inputs.admit().await?;
         let created = current_timestamp();
         let response_id = format!("resp-{}", Uuid::new_v4());
         let output_id = format!("msg-{}", Uuid::new_v4());
         let (sender, rx) = mpsc::unbounded_channel();
         let stream = into_event_stream(rx);
         let context = StreamContext {
-            sender,
+            sender: StreamSender::Events(sender),
+            format: req.stream_format,
             kind: StreamKind::Responses {
                 response_id: response_id.clone(),
                 output_id: output_id.clone(),
                 model: state.model_id.clone(),
                 created,
+                sampling,
+                return_prompt_token_ids: req.return_prompt_token_ids,
             },
+            flush: state.stream_flush,
         };
         let task_context = context.clone();
         rocket::tokio::spawn(async move {
@@ -71,59 +146,88 @@ pub async fn responses_endpoint(
                 images,
                 max_tokens,
                 Some(task_context),
+                request_id,
             )
             .await;
         });
         return Ok(Either::Right(stream));
     }
-    let generation = generate_async(gen_inputs, prompt, images, max_tokens, None).await?;
-    let created = current_timestamp();
-    let response = ResponsesResponse {
-        id: format!("resp-{}", Uuid::new_v4()),
-        object: "response".into(),
-        created,
-        model: req.model.clone(),
-        output: vec![ResponseOutput {
-            id: format!("msg-{}", Uuid::new_v4()),
-            r#type: "message".into(),
-            role: "assistant".into(),
-            content: vec![ResponseContent {
-                r#type: "output_text".into(),
-                text: generation.text.clone(),
-            }],
-        }],
-        usage: Usage {
-            prompt_tokens: generation.prompt_tokens,
-            completion_tokens: generation.response_tokens,
-            total_tokens: generation.prompt_tokens + generation.response_tokens,
-        },
-    };
-    Ok(Either::Left(Json(response)))
+    let generation =
+        generate_async(gen_inputs, prompt, images, max_tokens, None, request_id).await?;
+    Ok(Either::Left(Json(responses_body(
+        &req.model,
+        generation,
+        sampling,
+        req.return_prompt_token_ids,
+    ))))
+}
+
+#[post("/responses/embeddings", format = "json", data = "<req>")]
+pub async fn embedding_responses_endpoint(
+    state: &State<AppState>,
+    request_id: RequestId,
+    req: Json<EmbeddingResponsesRequest>,
+) -> Result<Json<ResponsesResponse>, ApiError> {
+    ensure_model(&req.model, &state.model_id)?;
+    state.breaker.check()?;
+    let payload = base64::engine::general_purpose::STANDARD
+        .decode(req.embeddings.trim())
+        .map_err(|err| ApiError::BadRequest(format!("invalid base64 embeddings payload: {err}")))?;
+    let prompt = wrap_user_prompt(req.prompt.trim());
+    let max_tokens = req
+        .max_output_tokens
+        .map(MaxNewTokens::Fixed)
+        .unwrap_or(state.max_new_tokens);
+    let mut gen_inputs = GenerationInputs::from_app(state.inner());
+    gen_inputs.apply_sampling(&req.sampling)?;
+    gen_inputs.check_beam_search(false)?;
+    let sampling = SamplingUsage::from(gen_inputs.sampling);
+    let generation =
+        generate_from_embeddings_async(gen_inputs, prompt, payload, max_tokens, request_id).await?;
+    Ok(Json(responses_body(
+        &req.model,
+        generation,
+        sampling,
+        req.return_prompt_token_ids,
+    )))
 }
 
 #[post("/chat/completions", format = "json", data = "<req>")]
 pub async fn chat_completions_endpoint(
     state: &State<AppState>,
+    request_id: RequestId,
     req: Json<ChatCompletionRequest>,
 ) -> Result<Either<Json<ChatCompletionResponse>, BoxEventStream>, ApiError> {
     ensure_model(&req.model, &state.model_id)?;
-    let gen_inputs = GenerationInputs::from_app(state.inner());
-    let (prompt, images) = convert_messages(&req.messages)?;
-    debug!(prompt = %prompt, "Prepared chat prompt");
-    let max_tokens = req.max_tokens.unwrap_or(state.max_new_tokens);
+    state.breaker.check()?;
+    let mut gen_inputs = GenerationInputs::from_app(state.inner());
+    gen_inputs.apply_sampling(&req.sampling)?;
+    gen_inputs.check_beam_search(req.stream.unwrap_or(false))?;
+    let sampling = SamplingUsage::from(gen_inputs.sampling);
+    let (prompt, images) = convert_messages(&req.messages, state.apply_exif_orientation)?;
+    debug!(request_id = %request_id, prompt = %prompt, "Prepared chat prompt");
+    let max_tokens = req
+        .max_tokens
+        .map(MaxNewTokens::Fixed)
+        .unwrap_or(state.max_new_tokens);
     if req.stream.unwrap_or(false) {
-        let stream_inputs = gen_inputs.clone();
+        // Admit before answering so a full queue is a 503 rather than an error event.
+        let stream_inputs = gen_inputs.admit().await?;
         let created = current_timestamp();
         let completion_id = format!("chatcmpl-{}", Uuid::new_v4());
         let (sender, rx) = mpsc::unbounded_channel();
         let stream = into_event_stream(rx);
         let context = StreamContext {
-            sender,
+            sender: StreamSender::Events(sender),
+            format: req.stream_format,
             kind: StreamKind::Chat {
                 completion_id: completion_id.clone(),
                 model: state.model_id.clone(),
                 created,
+                sampling,
+                return_prompt_token_ids: req.return_prompt_token_ids,
             },
+            flush: state.stream_flush,
         };
         let task_context = context.clone();
         rocket::tokio::spawn(async move {
@@ -133,12 +237,14 @@ pub async fn chat_completions_endpoint(
                 images,
                 max_tokens,
                 Some(task_context),
+                request_id,
             )
             .await;
         });
         return Ok(Either::Right(stream));
     }
-    let generation = generate_async(gen_inputs, prompt, images, max_tokens, None).await?;
+    let mut generation =
+        generate_async(gen_inputs, prompt, images, max_tokens, None, request_id).await?;
     let created = current_timestamp();
     let response = ChatCompletionResponse {
         id: format!("chatcmpl-{}", Uuid::new_v4()),
@@ -151,26 +257,105 @@ pub async fn chat_completions_endpoint(
                 role: "assistant".into(),
                 content: generation.text.clone(),
             },
-            finish_reason: "stop".into(),
+            finish_reason: generation.finish_reason.as_str().into(),
         }],
-        usage: Usage {
-            prompt_tokens: generation.prompt_tokens,
-            completion_tokens: generation.response_tokens,
-            total_tokens: generation.prompt_tokens + generation.response_tokens,
-        },
+        usage: usage(&mut generation, sampling, req.return_prompt_token_ids),
     };
     Ok(Either::Left(Json(response)))
 }
 
+/// Non-streaming OCR returning the versioned [`DocumentResult`] envelope instead of an
+/// OpenAI-shaped body.
+#[post("/documents", format = "json", data = "<req>")]
+pub async fn documents_endpoint(
+    state: &State<AppState>,
+    request_id: RequestId,
+    req: Json<DocumentRequest>,
+) -> Result<Json<DocumentResult>, ApiError> {
+    ensure_model(&req.model, &state.model_id)?;
+    state.breaker.check()?;
+    let (prompt, images) = convert_messages(&req.messages, state.apply_exif_orientation)?;
+    let max_tokens = req
+        .max_tokens
+        .map(MaxNewTokens::Fixed)
+        .unwrap_or(state.max_new_tokens);
+    let mut gen_inputs = GenerationInputs::from_app(state.inner());
+    gen_inputs.apply_sampling(&req.sampling)?;
+    gen_inputs.check_beam_search(false)?;
+    let sampling = SamplingUsage::from(gen_inputs.sampling);
+    let generation =
+        generate_async(gen_inputs, prompt, images, max_tokens, None, request_id).await?;
+    let mut document = DocumentResult::from_output(
+        &generation.text,
+        generation.prompt_tokens,
+        generation.response_tokens,
+        generation.finish_reason,
+    );
+    document.usage.deskew_degrees = generation.deskew_degrees;
+    document.usage.sampling = Some(sampling);
+    document.raw_output = generation.raw_output;
+    document.usage.prompt_token_ids = req
+        .return_prompt_token_ids
+        .then_some(generation.prompt_token_ids);
+    Ok(Json(document))
+}
+
 pub fn v1_routes() -> Vec<Route> {
     routes![
         health,
+        readyz,
+        metrics,
         list_models,
+        unload_model,
         responses_endpoint,
-        chat_completions_endpoint
+        embedding_responses_endpoint,
+        chat_completions_endpoint,
+        documents_endpoint
     ]
 }
 
+fn responses_body(
+    model: &str,
+    mut generation: GenerationResult,
+    sampling: SamplingUsage,
+    return_prompt_token_ids: bool,
+) -> ResponsesResponse {
+    let usage = usage(&mut generation, sampling, return_prompt_token_ids);
+    ResponsesResponse {
+        id: format!("resp-{}", Uuid::new_v4()),
+        object: "response".into(),
+        created: current_timestamp(),
+        model: model.to_owned(),
+        output: vec![ResponseOutput {
+            id: format!("msg-{}", Uuid::new_v4()),
+            r#type: "message".into(),
+            role: "assistant".into(),
+            content: vec![ResponseContent {
+                r#type: "output_text".into(),
+                text: generation.text,
+            }],
+        }],
+        usage,
+    }
+}
+
+/// Token counts of `generation`, with its prompt token ids when `return_prompt_token_ids`.
+fn usage(
+    generation: &mut GenerationResult,
+    sampling: SamplingUsage,
+    return_prompt_token_ids: bool,
+) -> Usage {
+    Usage {
+        prompt_tokens: generation.prompt_tokens,
+        completion_tokens: generation.response_tokens,
+        total_tokens: generation.prompt_tokens + generation.response_tokens,
+        deskew_degrees: std::mem::take(&mut generation.deskew_degrees),
+        sampling,
+        prompt_token_ids: return_prompt_token_ids
+            .then(|| std::mem::take(&mut generation.prompt_token_ids)),
+    }
+}
+
 fn ensure_model(requested: &str, available: &str) -> Result<(), ApiError> {
     if requested == available {
         Ok(())
@@ -187,3 +372,35 @@ fn current_timestamp() -> i64 {
         .map(|dur| dur.as_secs() as i64)
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use deepseek_ocr_core::{inference::FinishReason, transformer::sampling::SamplingParams};
+
+    use super::*;
+
+    fn generation() -> GenerationResult {
+        GenerationResult {
+            text: "text".into(),
+            prompt_tokens: 3,
+            prompt_token_ids: vec![5, 6, 7],
+            response_tokens: 2,
+            finish_reason: FinishReason::Stop,
+            deskew_degrees: Vec::new(),
+            raw_output: None,
+        }
+    }
+
+    #[test]
+    fn usage_carries_prompt_token_ids_only_on_request() {
+        let sampling = SamplingUsage::from(SamplingParams::default());
+        let usage = serde_json::to_value(super::usage(&mut generation(), sampling, false))
+            .expect("serializes");
+        assert_eq!(usage["total_tokens"], 5);
+        assert!(usage.get("prompt_token_ids").is_none(), "{usage}");
+
+        let body = responses_body("deepseek-ocr", generation(), sampling, true);
+        assert_eq!(body.usage.prompt_token_ids, Some(vec![5, 6, 7]));
+        assert_eq!(body.usage.prompt_tokens, 3);
+    }
+}
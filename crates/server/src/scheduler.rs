@@ -0,0 +1,402 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
+    },
+    thread,
+};
+
+use anyhow::{Context, Result, anyhow};
+use candle_core::{DType, Tensor};
+use deepseek_ocr_core::{
+    model::{
+        DecodeBatch, DeepseekOcrModel, GenerateOptions, MAX_BUDGET_EXTENSION, PrefilledSequence,
+        PrefixCache, PrefixCacheStore,
+    },
+    transformer::{
+        model::{AuxLossStats, EarlyExitStats},
+        sampling::{LogitsSampler, RepetitionPenalty},
+    },
+};
+use tracing::{Span, error, info};
+
+use crate::{
+    models::{AuxLossMetrics, EarlyExitMetrics, KvCacheMetrics},
+    state::SharedModel,
+};
+
+pub type ExtendFn = Box<dyn Fn(&[i64]) -> bool + Send>;
+pub type StopFn = Box<dyn Fn(&[i64]) -> bool + Send>;
+pub type ProgressFn = Box<dyn Fn(usize, &[i64]) + Send>;
+
+/// One prompt waiting to be decoded by the scheduler thread.
+pub struct DecodeJob {
+    pub input_ids: Vec<i64>,
+    pub images_seq_mask: Vec<u8>,
+    pub embeddings: Vec<Tensor>,
+    pub max_new_tokens: usize,
+    pub eos_token_id: Option<i64>,
+    /// Token ids never sampled (see [`GenerateOptions::banned_token_ids`]).
+    pub banned_token_ids: Vec<i64>,
+    /// Samples instead of decoding greedily (see [`GenerateOptions::sampler`]).
+    pub sampler: Option<LogitsSampler>,
+    /// See [`GenerateOptions::repetition_penalty`].
+    pub repetition_penalty: Option<RepetitionPenalty>,
+    /// See [`GenerateOptions::no_repeat_ngram_size`].
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Ends the sequence once it returns true (see [`GenerateOptions::stop_when`]).
+    pub stop_when: Option<StopFn>,
+    pub extend_while: Option<ExtendFn>,
+    pub progress: Option<ProgressFn>,
+    pub span: Span,
+}
+
+struct Submission {
+    job: DecodeJob,
+    reply: Sender<Result<Vec<i64>>>,
+}
+
+struct ActiveSequence {
+    job: DecodeJob,
+    reply: Sender<Result<Vec<i64>>>,
+    generated: Vec<i64>,
+}
+
+impl ActiveSequence {
+    /// Record a sampled token; returns true once the sequence is finished.
+    fn accept(&mut self, token: i64) -> bool {
+        if self.job.eos_token_id == Some(token) {
+            return true;
+        }
+        self.generated.push(token);
+        if let Some(progress) = &self.job.progress {
+            progress(self.generated.len(), &self.generated);
+        }
+        if self
+            .job
+            .stop_when
+            .as_ref()
+            .is_some_and(|stop| stop(&self.generated))
+        {
+            return true;
+        }
+        let max_steps = self.job.max_new_tokens
+            + self
+                .job
+                .extend_while
+                .as_ref()
+                .map_or(0, |_| MAX_BUDGET_EXTENSION);
+        self.generated.len() >= self.job.max_new_tokens
+            && !(self.generated.len() < max_steps
+                && self
+                    .job
+                    .extend_while
+                    .as_ref()
+                    .is_some_and(|extend| extend(&self.generated)))
+    }
+
+    fn finish(self, result: Result<()>) {
+        let _ = self.reply.send(result.map(|_| self.generated));
+    }
+}
+
+/// Groups the decode steps of concurrent requests into one batched forward pass.
+///
+/// Requests join the batch as soon as their prompt is prefilled and leave when they hit EOS or
+/// their token budget, so short requests are not held back by long ones. At most
+/// `max_num_seqs` sequences decode together; with a limit of 1 requests run one at a time
+/// exactly as before.
+#[derive(Clone)]
+pub struct DecodeScheduler {
+    sender: Sender<Submission>,
+    metrics: Arc<CacheMetrics>,
+}
+
+/// Where the scheduler keeps prompt-prefix KV caches, keyed under the served model id.
+pub struct SchedulerPrefixCache {
+    pub store: Arc<dyn PrefixCacheStore>,
+    pub model_id: String,
+}
+
+/// The server always wraps prompts with [`crate::generation::wrap_user_prompt`], so every prefix
+/// is keyed under the same template.
+const PROMPT_TEMPLATE: &str = "chat";
+
+/// Running KV cache counters (plus MoE aux-loss and early-exit figures when enabled), written by
+/// the scheduler thread after every change to the batch.
+#[derive(Default)]
+struct CacheMetrics {
+    active_sequences: AtomicUsize,
+    seq_len: AtomicUsize,
+    bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    cached_positions: AtomicU64,
+    completed_sequences: AtomicU64,
+    /// Latest MoE aux-loss figures, when the model was loaded with `--aux-loss`.
+    aux_loss: Mutex<Option<AuxLossStats>>,
+    /// Latest early-exit figures, when the model was loaded with `--early-exit-layer`.
+    early_exit: Mutex<Option<EarlyExitStats>>,
+}
+
+impl CacheMetrics {
+    fn observe(&self, batch: &DecodeBatch) {
+        let stats = batch.cache().stats();
+        self.active_sequences.store(batch.len(), Ordering::Relaxed);
+        self.seq_len.store(stats.seq_len, Ordering::Relaxed);
+        self.bytes.store(stats.bytes, Ordering::Relaxed);
+        self.peak_bytes.fetch_max(stats.bytes, Ordering::Relaxed);
+    }
+
+    fn cached(&self, positions: usize) {
+        self.cached_positions
+            .fetch_add(positions as u64, Ordering::Relaxed);
+    }
+
+    fn completed(&self) {
+        self.completed_sequences.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn observe_model(&self, model: &DeepseekOcrModel) {
+        let language = model.language_model();
+        if let (Some(stats), Ok(mut slot)) = (language.aux_loss_stats(), self.aux_loss.lock()) {
+            *slot = Some(stats);
+        }
+        if let (Some(stats), Ok(mut slot)) = (language.early_exit_stats(), self.early_exit.lock()) {
+            *slot = Some(stats);
+        }
+    }
+
+    fn early_exit(&self) -> Option<EarlyExitMetrics> {
+        let stats = (*self.early_exit.lock().ok()?)?;
+        Some(EarlyExitMetrics {
+            steps: stats.steps,
+            exits: stats.exits,
+            mean_layers: stats.mean_layers,
+        })
+    }
+
+    fn aux_loss(&self) -> Option<AuxLossMetrics> {
+        let stats = (*self.aux_loss.lock().ok()?)?;
+        Some(AuxLossMetrics {
+            forwards: stats.forwards,
+            last: stats.last,
+            mean: stats.mean,
+        })
+    }
+
+    fn snapshot(&self) -> KvCacheMetrics {
+        KvCacheMetrics {
+            active_sequences: self.active_sequences.load(Ordering::Relaxed),
+            seq_len: self.seq_len.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+            cached_positions: self.cached_positions.load(Ordering::Relaxed),
+            completed_sequences: self.completed_sequences.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl DecodeScheduler {
+    pub fn spawn(
+        model: SharedModel,
+        max_num_seqs: usize,
+        prefix_cache: Option<SchedulerPrefixCache>,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let max_num_seqs = max_num_seqs.max(1);
+        let metrics = Arc::new(CacheMetrics::default());
+        let thread_metrics = Arc::clone(&metrics);
+        thread::Builder::new()
+            .name("decode-scheduler".into())
+            .spawn(move || {
+                run(
+                    model,
+                    receiver,
+                    max_num_seqs,
+                    prefix_cache.as_ref(),
+                    &thread_metrics,
+                )
+            })
+            .context("failed to spawn decode scheduler thread")?;
+        info!("Decode scheduler batching up to {max_num_seqs} sequences");
+        Ok(Self { sender, metrics })
+    }
+
+    /// Current and cumulative KV cache figures for the decode batch.
+    pub fn cache_metrics(&self) -> KvCacheMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// MoE load-balancing loss figures; `None` unless `--aux-loss` is on and the model has MoE
+    /// layers.
+    pub fn aux_loss_metrics(&self) -> Option<AuxLossMetrics> {
+        self.metrics.aux_loss()
+    }
+
+    /// Decode depth figures; `None` unless `--early-exit-layer` is set.
+    pub fn early_exit_metrics(&self) -> Option<EarlyExitMetrics> {
+        self.metrics.early_exit()
+    }
+
+    /// Queue a job and block until its generated token ids are available.
+    pub fn submit(&self, job: DecodeJob) -> Result<Vec<i64>> {
+        let (reply, response) = mpsc::channel();
+        self.sender
+            .send(Submission { job, reply })
+            .map_err(|_| anyhow!("decode scheduler has shut down"))?;
+        response
+            .recv()
+            .map_err(|_| anyhow!("decode scheduler dropped the request"))?
+    }
+}
+
+fn run(
+    model: SharedModel,
+    receiver: Receiver<Submission>,
+    max_num_seqs: usize,
+    prefix_cache: Option<&SchedulerPrefixCache>,
+    metrics: &CacheMetrics,
+) {
+    let mut batch = DecodeBatch::new();
+    let mut active: Vec<(u64, ActiveSequence)> = Vec::new();
+    let mut next_id = 0u64;
+    loop {
+        metrics.observe(&batch);
+        let mut pending = Vec::new();
+        if active.is_empty() {
+            match receiver.recv() {
+                Ok(submission) => pending.push(submission),
+                Err(_) => return,
+            }
+        }
+        while active.len() + pending.len() < max_num_seqs {
+            match receiver.try_recv() {
+                Ok(submission) => pending.push(submission),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) if active.is_empty() && pending.is_empty() => {
+                    return;
+                }
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        // Reloads the model if it was unloaded while the scheduler was idle.
+        let guard = match model.lock() {
+            Ok(guard) => guard,
+            Err(err) => {
+                for submission in pending {
+                    let _ = submission.reply.send(Err(anyhow!("{err}")));
+                }
+                for (_, sequence) in active.drain(..) {
+                    sequence.finish(Err(anyhow!("{err}")));
+                }
+                batch = DecodeBatch::new();
+                continue;
+            }
+        };
+
+        for Submission { job, reply } in pending {
+            let id = next_id;
+            next_id += 1;
+            let mut sequence = ActiveSequence {
+                job,
+                reply,
+                generated: Vec::new(),
+            };
+            if sequence.job.max_new_tokens == 0 {
+                sequence.finish(Ok(()));
+                continue;
+            }
+            let prefilled = {
+                let _span = sequence.job.span.clone().entered();
+                prefill(&guard, &sequence.job, prefix_cache)
+            };
+            let joined = prefilled.and_then(|prefilled| {
+                let first = prefilled.first_token();
+                let prompt_len = prefilled.seq_len();
+                batch.join(id, prefilled)?;
+                metrics.cached(prompt_len);
+                Ok(first)
+            });
+            match joined {
+                Ok(first) => {
+                    if sequence.accept(first) {
+                        sequence.finish(Ok(()));
+                        metrics.completed();
+                        if let Err(err) = batch.leave(id) {
+                            error!("failed to release finished sequence: {err:#}");
+                        }
+                    } else {
+                        active.push((id, sequence));
+                    }
+                }
+                Err(err) => sequence.finish(Err(err)),
+            }
+        }
+
+        if batch.is_empty() {
+            continue;
+        }
+        let step = batch.step(&guard);
+        metrics.observe_model(&guard);
+        drop(guard);
+        match step {
+            Ok(tokens) => {
+                metrics.cached(tokens.len());
+                for (id, token) in tokens {
+                    let Some(position) = active.iter().position(|(active_id, _)| *active_id == id)
+                    else {
+                        continue;
+                    };
+                    if active[position].1.accept(token) {
+                        let (_, sequence) = active.remove(position);
+                        sequence.finish(Ok(()));
+                        metrics.completed();
+                        if let Err(err) = batch.leave(id) {
+                            error!("failed to release finished sequence: {err:#}");
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                error!("batched decode step failed: {err:#}");
+                for (_, sequence) in active.drain(..) {
+                    sequence.finish(Err(anyhow!("generation failed: {err:#}")));
+                }
+                batch = DecodeBatch::new();
+            }
+        }
+    }
+}
+
+fn prefill(
+    model: &DeepseekOcrModel,
+    job: &DecodeJob,
+    prefix_cache: Option<&SchedulerPrefixCache>,
+) -> Result<PrefilledSequence> {
+    let device = model.device();
+    let input_ids = Tensor::from_vec(job.input_ids.clone(), (1, job.input_ids.len()), device)?
+        .to_dtype(DType::I64)?;
+    let mask = Tensor::from_vec(
+        job.images_seq_mask.clone(),
+        (1, job.images_seq_mask.len()),
+        device,
+    )?
+    .to_dtype(DType::U8)?;
+    let mut options = GenerateOptions::new(job.max_new_tokens);
+    options.images_seq_mask = Some(&mask);
+    options.banned_token_ids = &job.banned_token_ids;
+    options.sampler = job.sampler.as_ref();
+    options.repetition_penalty = job.repetition_penalty;
+    options.no_repeat_ngram_size = job.no_repeat_ngram_size;
+    if !job.embeddings.is_empty() {
+        options.image_embeddings = Some(&job.embeddings);
+    }
+    options.prefix_cache = prefix_cache.map(|cache| PrefixCache {
+        store: cache.store.as_ref(),
+        model_id: &cache.model_id,
+        template: PROMPT_TEMPLATE,
+    });
+    model.prefill(&input_ids, &options)
+}
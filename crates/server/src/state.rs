@@ -1,18 +1,173 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    ops::Deref,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
+use anyhow::Result;
+use rocket::tokio::sync::OwnedSemaphorePermit;
 use tokenizers::Tokenizer;
+use tracing::{info, warn};
 
-use deepseek_ocr_core::model::DeepseekOcrModel;
+use deepseek_ocr_core::{
+    inference::{MaxNewTokens, PartialUtf8, StopCriteria},
+    model::{BeamSearch, DeepseekOcrModel},
+    runtime::device_memory,
+    transformer::sampling::{RepetitionPenalty, SamplingParams},
+    vision::PreprocessPipeline,
+};
 
-pub type SharedModel = Arc<Mutex<DeepseekOcrModel>>;
+use crate::{
+    admission::AdmissionQueue, breaker::CircuitBreaker, error::ApiError, models::SamplingRequest,
+    scheduler::DecodeScheduler, stream::FlushPolicy,
+};
+
+pub type SharedModel = Arc<ModelSlot>;
+
+/// Loads a fresh copy of the served model from disk.
+pub type LoadFn = Box<dyn Fn() -> Result<DeepseekOcrModel> + Send + Sync>;
+
+/// The served model, or nothing after `POST /v1/models/unload`. Whoever locks it next loads it
+/// again, so an unloaded server comes back on its next request.
+pub struct ModelSlot {
+    model: Mutex<Option<DeepseekOcrModel>>,
+    loaded: AtomicBool,
+    loader: LoadFn,
+}
+
+/// Locked access to a loaded model.
+pub struct ModelGuard<'a>(MutexGuard<'a, Option<DeepseekOcrModel>>);
+
+impl Deref for ModelGuard<'_> {
+    type Target = DeepseekOcrModel;
+
+    fn deref(&self) -> &DeepseekOcrModel {
+        self.0.as_ref().expect("model guard holds a loaded model")
+    }
+}
+
+/// What [`ModelSlot::unload`] released.
+pub struct Unloaded {
+    /// Growth in free device memory across the unload; `None` where memory is not queryable.
+    pub freed_bytes: Option<u64>,
+}
+
+impl ModelSlot {
+    pub fn new(model: DeepseekOcrModel, loader: LoadFn) -> Arc<Self> {
+        Arc::new(Self {
+            model: Mutex::new(Some(model)),
+            loaded: AtomicBool::new(true),
+            loader,
+        })
+    }
+
+    /// A slot with nothing loaded yet, for tests that never run the model.
+    #[cfg(test)]
+    pub fn unloaded(loader: LoadFn) -> Arc<Self> {
+        Arc::new(Self {
+            model: Mutex::new(None),
+            loaded: AtomicBool::new(false),
+            loader,
+        })
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Acquire)
+    }
+
+    /// Lock the model, loading it first if it was unloaded.
+    pub fn lock(&self) -> Result<ModelGuard<'_>, ApiError> {
+        let mut guard = self
+            .model
+            .lock()
+            .map_err(|_| ApiError::Internal("model lock poisoned".into()))?;
+        if guard.is_none() {
+            info!("Loading model unloaded earlier");
+            let model = (self.loader)()
+                .map_err(|err| ApiError::Internal(format!("failed to reload model: {err:#}")))?;
+            *guard = Some(model);
+            self.loaded.store(true, Ordering::Release);
+        }
+        Ok(ModelGuard(guard))
+    }
+
+    /// Replace the model with a fresh copy from disk, e.g. after the circuit breaker tripped. The
+    /// current copy is released first, so the two never have to fit in memory together; if the
+    /// load fails, the slot is left unloaded and the next [`Self::lock`] tries again.
+    pub fn reload(&self) -> Result<()> {
+        let mut guard = self.model.lock().unwrap_or_else(|poisoned| {
+            self.model.clear_poison();
+            poisoned.into_inner()
+        });
+        if let Some(model) = guard.take() {
+            self.loaded.store(false, Ordering::Release);
+            model.unload()?;
+        }
+        *guard = Some((self.loader)()?);
+        self.loaded.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Drop the model and free its device memory. Waits for the decode step in flight, if any.
+    /// Returns `None` when no model was loaded.
+    pub fn unload(&self) -> Result<Option<Unloaded>, ApiError> {
+        let mut guard = self
+            .model
+            .lock()
+            .map_err(|_| ApiError::Internal("model lock poisoned".into()))?;
+        let Some(model) = guard.take() else {
+            return Ok(None);
+        };
+        self.loaded.store(false, Ordering::Release);
+        let device = model.device().clone();
+        let before = device_memory(&device);
+        model.unload()?;
+        let freed_bytes = before
+            .zip(device_memory(&device))
+            .map(|(before, after)| after.free_bytes.saturating_sub(before.free_bytes));
+        match freed_bytes {
+            Some(0) => warn!("Model unloaded but free device memory did not grow"),
+            Some(bytes) => info!(
+                "Model unloaded; {:.2} GiB of device memory reclaimed",
+                bytes as f64 / (1u64 << 30) as f64
+            ),
+            None => info!("Model unloaded"),
+        }
+        Ok(Some(Unloaded { freed_bytes }))
+    }
+}
 
 pub struct AppState {
     pub model: SharedModel,
+    pub scheduler: DecodeScheduler,
+    pub breaker: Arc<CircuitBreaker>,
+    pub admission: Arc<AdmissionQueue>,
     pub tokenizer: Arc<Tokenizer>,
+    /// The configured model failed to load and the fallback model is serving instead.
+    pub degraded: bool,
     pub base_size: u32,
     pub image_size: u32,
     pub crop_mode: bool,
-    pub max_new_tokens: usize,
+    pub strip_aspect_threshold: Option<f32>,
+    pub preprocess: PreprocessPipeline,
+    pub max_new_tokens: MaxNewTokens,
+    pub max_new_tokens_ceiling: usize,
+    pub apply_exif_orientation: bool,
+    pub structure_aware_stop: bool,
+    pub detect_empty_output: bool,
+    pub partial_utf8: PartialUtf8,
+    pub raw_output: bool,
+    pub ban_image_tokens: bool,
+    pub stop: StopCriteria,
+    /// Sampling defaults for requests that do not choose their own.
+    pub sampling: SamplingParams,
+    /// Beam search every request decodes with, when `num_beams` is above one.
+    pub beam_search: Option<BeamSearch>,
+    pub repetition_penalty: Option<RepetitionPenalty>,
+    pub no_repeat_ngram_size: Option<usize>,
+    pub stream_flush: FlushPolicy,
     pub model_id: String,
 }
 
@@ -20,20 +175,58 @@ impl AppState {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         model: SharedModel,
+        scheduler: DecodeScheduler,
+        breaker: Arc<CircuitBreaker>,
+        admission: Arc<AdmissionQueue>,
         tokenizer: Arc<Tokenizer>,
+        degraded: bool,
         base_size: u32,
         image_size: u32,
         crop_mode: bool,
-        max_new_tokens: usize,
+        strip_aspect_threshold: Option<f32>,
+        preprocess: PreprocessPipeline,
+        max_new_tokens: MaxNewTokens,
+        max_new_tokens_ceiling: usize,
+        apply_exif_orientation: bool,
+        structure_aware_stop: bool,
+        detect_empty_output: bool,
+        partial_utf8: PartialUtf8,
+        raw_output: bool,
+        ban_image_tokens: bool,
+        stop: StopCriteria,
+        sampling: SamplingParams,
+        beam_search: Option<BeamSearch>,
+        repetition_penalty: Option<RepetitionPenalty>,
+        no_repeat_ngram_size: Option<usize>,
+        stream_flush: FlushPolicy,
         model_id: String,
     ) -> Self {
         Self {
             model,
+            scheduler,
+            breaker,
+            admission,
             tokenizer,
+            degraded,
             base_size,
             image_size,
             crop_mode,
+            strip_aspect_threshold,
+            preprocess,
             max_new_tokens,
+            max_new_tokens_ceiling,
+            apply_exif_orientation,
+            structure_aware_stop,
+            detect_empty_output,
+            partial_utf8,
+            raw_output,
+            ban_image_tokens,
+            stop,
+            sampling,
+            beam_search,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            stream_flush,
             model_id,
         }
     }
@@ -42,20 +235,95 @@ impl AppState {
 #[derive(Clone)]
 pub struct GenerationInputs {
     pub model: SharedModel,
+    pub scheduler: DecodeScheduler,
+    pub breaker: Arc<CircuitBreaker>,
+    pub admission: Arc<AdmissionQueue>,
+    /// Generation slot held by these inputs once admitted, shared by their clones.
+    pub permit: Option<Arc<OwnedSemaphorePermit>>,
     pub tokenizer: Arc<Tokenizer>,
     pub base_size: u32,
     pub image_size: u32,
     pub crop_mode: bool,
+    pub strip_aspect_threshold: Option<f32>,
+    pub preprocess: PreprocessPipeline,
+    pub max_new_tokens_ceiling: usize,
+    pub structure_aware_stop: bool,
+    pub detect_empty_output: bool,
+    pub partial_utf8: PartialUtf8,
+    pub raw_output: bool,
+    pub ban_image_tokens: bool,
+    pub stop: StopCriteria,
+    /// Per-request sampling settings, the configured defaults unless
+    /// [`Self::apply_sampling`] overrides them; a zero temperature decodes greedily.
+    pub sampling: SamplingParams,
+    /// Decodes with beam search on the request thread instead of through the scheduler.
+    pub beam_search: Option<BeamSearch>,
+    pub repetition_penalty: Option<RepetitionPenalty>,
+    pub no_repeat_ngram_size: Option<usize>,
 }
 
 impl GenerationInputs {
     pub fn from_app(state: &AppState) -> Self {
         Self {
             model: Arc::clone(&state.model),
+            scheduler: state.scheduler.clone(),
+            breaker: Arc::clone(&state.breaker),
+            admission: Arc::clone(&state.admission),
+            permit: None,
             tokenizer: Arc::clone(&state.tokenizer),
             base_size: state.base_size,
             image_size: state.image_size,
             crop_mode: state.crop_mode,
+            strip_aspect_threshold: state.strip_aspect_threshold,
+            preprocess: state.preprocess.clone(),
+            max_new_tokens_ceiling: state.max_new_tokens_ceiling,
+            structure_aware_stop: state.structure_aware_stop,
+            detect_empty_output: state.detect_empty_output,
+            partial_utf8: state.partial_utf8,
+            raw_output: state.raw_output,
+            ban_image_tokens: state.ban_image_tokens,
+            stop: state.stop.clone(),
+            sampling: state.sampling,
+            beam_search: state.beam_search,
+            repetition_penalty: state.repetition_penalty,
+            no_repeat_ngram_size: state.no_repeat_ngram_size,
+        }
+    }
+
+    /// Take the request's sampling fields over the configured defaults.
+    pub fn apply_sampling(&mut self, request: &SamplingRequest) -> Result<(), ApiError> {
+        self.sampling = request.resolve(self.sampling)?;
+        Ok(())
+    }
+
+    /// Reject a request that would stream or sample while beam search is on: beam search only
+    /// picks its output once every hypothesis has finished, and it never samples.
+    pub fn check_beam_search(&self, stream: bool) -> Result<(), ApiError> {
+        let Some(beam) = self.beam_search else {
+            return Ok(());
+        };
+        if stream {
+            return Err(ApiError::BadRequest(format!(
+                "streaming is not available while the server decodes with beam search \
+                 ({} beams)",
+                beam.num_beams
+            )));
+        }
+        if self.sampling.temperature > 0.0 {
+            return Err(ApiError::BadRequest(format!(
+                "`temperature` must be 0 while the server decodes with beam search ({} beams)",
+                beam.num_beams
+            )));
+        }
+        Ok(())
+    }
+
+    /// Wait for a generation slot (see [`AdmissionQueue`]); a no-op once admitted. The slot is
+    /// released when the last clone of these inputs is dropped.
+    pub async fn admit(mut self) -> Result<Self, ApiError> {
+        if self.permit.is_none() {
+            self.permit = Some(Arc::new(self.admission.admit().await?));
         }
+        Ok(self)
     }
 }
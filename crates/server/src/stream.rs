@@ -2,8 +2,13 @@ use std::{
     convert::TryFrom,
     pin::Pin,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+use deepseek_ocr_core::{
+    document::SamplingUsage,
+    inference::{FinishReason, StopCriteria, StreamingDetokenizer},
+};
 use rocket::{
     response::stream::{Event, EventStream},
     tokio::sync::mpsc,
@@ -12,6 +17,8 @@ use serde_json::json;
 use tokenizers::Tokenizer;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
+use crate::models::StreamFormat;
+
 pub type BoxEventStream =
     EventStream<Pin<Box<dyn rocket::futures::stream::Stream<Item = Event> + Send>>>;
 
@@ -24,24 +31,89 @@ pub fn into_event_stream(rx: mpsc::UnboundedReceiver<Event>) -> BoxEventStream {
 
 #[derive(Clone)]
 pub struct StreamContext {
-    pub sender: mpsc::UnboundedSender<Event>,
+    pub sender: StreamSender,
+    pub format: StreamFormat,
     pub kind: StreamKind,
+    pub flush: FlushPolicy,
+}
+
+/// When buffered deltas are sent: once `tokens` tokens are waiting, or once `interval` has passed
+/// since the last event, whichever comes first. The interval is checked as tokens arrive, so a
+/// stalled decoder does not produce empty events. Whatever is buffered at the end of the stream is
+/// always sent.
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    pub tokens: usize,
+    pub interval: Option<Duration>,
+}
+
+impl FlushPolicy {
+    pub fn new(tokens: usize, interval_ms: Option<u64>) -> Self {
+        Self {
+            tokens: tokens.max(1),
+            interval: interval_ms.map(Duration::from_millis),
+        }
+    }
+
+    fn is_due(&self, pending_tokens: usize, last_flush: Instant) -> bool {
+        pending_tokens >= self.tokens
+            || self
+                .interval
+                .is_some_and(|interval| last_flush.elapsed() >= interval)
+    }
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self::new(1, None)
+    }
+}
+
+/// Where a stream's output goes: SSE events for the HTTP API, or bare deltas for transports that
+/// frame their own messages.
+#[derive(Clone)]
+pub enum StreamSender {
+    Events(mpsc::UnboundedSender<Event>),
+    #[cfg(feature = "grpc")]
+    Deltas(mpsc::UnboundedSender<Delta>),
+}
+
+impl StreamSender {
+    fn send(&self, event: Event) {
+        match self {
+            StreamSender::Events(sender) => {
+                let _ = sender.send(event);
+            }
+            #[cfg(feature = "grpc")]
+            StreamSender::Deltas(_) => {}
+        }
+    }
+
+    /// True once the receiving end is gone, i.e. the client disconnected.
+    fn is_closed(&self) -> bool {
+        match self {
+            StreamSender::Events(sender) => sender.is_closed(),
+            #[cfg(feature = "grpc")]
+            StreamSender::Deltas(sender) => sender.is_closed(),
+        }
+    }
 }
 
 impl StreamContext {
     pub fn send_error(&self, message: &str) {
         match &self.kind {
             StreamKind::Responses { .. } => {
-                let _ = self.sender.send(Event::json(&json!({
+                self.sender.send(Event::json(&json!({
                     "type": "response.error",
                     "error": { "message": message },
                 })));
-                let _ = self.sender.send(Event::data("[DONE]"));
+                self.sender.send(Event::data("[DONE]"));
             }
             StreamKind::Chat {
                 completion_id,
                 model,
                 created,
+                ..
             } => {
                 let payload = json!({
                     "id": completion_id,
@@ -55,9 +127,11 @@ impl StreamContext {
                     }],
                     "error": { "message": message },
                 });
-                let _ = self.sender.send(Event::json(&payload));
-                let _ = self.sender.send(Event::data("[DONE]"));
+                self.sender.send(Event::json(&payload));
+                self.sender.send(Event::data("[DONE]"));
             }
+            #[cfg(feature = "grpc")]
+            StreamKind::Grpc => {}
         }
     }
 }
@@ -69,26 +143,148 @@ pub enum StreamKind {
         output_id: String,
         model: String,
         created: i64,
+        /// Reported in the final event's usage.
+        sampling: SamplingUsage,
+        /// Also report the prompt token ids in the final event's usage.
+        return_prompt_token_ids: bool,
     },
     Chat {
         completion_id: String,
         model: String,
         created: i64,
+        /// Reported in the final event's usage.
+        sampling: SamplingUsage,
+        /// Also report the prompt token ids in the final event's usage.
+        return_prompt_token_ids: bool,
     },
+    /// Only the deltas are forwarded; the caller sends the final result itself.
+    #[cfg(feature = "grpc")]
+    Grpc,
+}
+
+pub enum Delta {
+    Text(String),
+    TokenIds(Vec<i64>),
 }
 
+impl Delta {
+    fn is_empty(&self) -> bool {
+        match self {
+            Delta::Text(text) => text.is_empty(),
+            Delta::TokenIds(ids) => ids.is_empty(),
+        }
+    }
+
+    fn empty(format: StreamFormat) -> Self {
+        match format {
+            StreamFormat::Text => Delta::Text(String::new()),
+            StreamFormat::TokenIds => Delta::TokenIds(Vec::new()),
+        }
+    }
+
+    /// Extend this delta with `other`. A delta of the other kind cannot be merged, so it takes
+    /// this one's place and the delta it displaced is returned to be sent first.
+    fn append(&mut self, other: Delta) -> Option<Delta> {
+        match (self, other) {
+            (Delta::Text(text), Delta::Text(more)) => {
+                text.push_str(&more);
+                None
+            }
+            (Delta::TokenIds(ids), Delta::TokenIds(more)) => {
+                ids.extend(more);
+                None
+            }
+            (this, other) => Some(std::mem::replace(this, other)),
+        }
+    }
+}
+
+type DecodeFn = Box<dyn Fn(&[u32]) -> String + Send + Sync>;
+
 struct StreamControllerInner {
-    sender: mpsc::UnboundedSender<Event>,
-    tokenizer: Arc<Tokenizer>,
+    sender: StreamSender,
+    decode: DecodeFn,
+    stop: StopCriteria,
+    format: StreamFormat,
     kind: StreamKind,
+    flush: FlushPolicy,
     runtime: Mutex<StreamRuntime>,
 }
 
-#[derive(Default)]
 struct StreamRuntime {
     last_count: usize,
+    detokenizer: StreamingDetokenizer,
     role_sent: bool,
     finished: bool,
+    /// Deltas held back by the flush policy, and how many tokens they cover.
+    pending: Option<Delta>,
+    pending_tokens: usize,
+    last_flush: Instant,
+    /// Decoded text that may be the start of a stop string, withheld until it is ruled out.
+    held: String,
+    /// Set once a stop string appeared; nothing after it is streamed.
+    stopped: bool,
+}
+
+impl Default for StreamRuntime {
+    fn default() -> Self {
+        Self {
+            last_count: 0,
+            detokenizer: StreamingDetokenizer::default(),
+            role_sent: false,
+            finished: false,
+            pending: None,
+            pending_tokens: 0,
+            last_flush: Instant::now(),
+            held: String::new(),
+            stopped: false,
+        }
+    }
+}
+
+impl StreamRuntime {
+    /// Hold `delta` back until the flush policy is due. Returns a buffered delta that `delta`
+    /// could not be merged into, which must be sent before anything else.
+    fn buffer(&mut self, delta: Delta, format: StreamFormat, tokens: usize) -> Option<Delta> {
+        let displaced = self
+            .pending
+            .get_or_insert_with(|| Delta::empty(format))
+            .append(delta);
+        self.pending_tokens += tokens;
+        displaced.filter(|delta| !delta.is_empty())
+    }
+
+    /// The part of `text` that is safe to stream: everything before a stop string, minus a tail
+    /// that could still become one. With `finished`, a tail that never completed is released.
+    fn release_text(&mut self, text: &str, stop: &StopCriteria, finished: bool) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.held.push_str(text);
+        let end = stop.truncate(&self.held).len();
+        if end < self.held.len() {
+            self.stopped = true;
+            self.held.truncate(end);
+            return std::mem::take(&mut self.held);
+        }
+        if finished {
+            return std::mem::take(&mut self.held);
+        }
+        let keep = stop.partial_match_len(&self.held);
+        self.held.drain(..self.held.len() - keep).collect()
+    }
+
+    fn take_pending(&mut self) -> Option<Delta> {
+        self.pending_tokens = 0;
+        self.last_flush = Instant::now();
+        self.pending.take()
+    }
+}
+
+fn token_ids(ids: &[i64]) -> Vec<u32> {
+    ids.iter()
+        .filter_map(|&id| u32::try_from(id).ok())
+        .collect()
 }
 
 pub struct StreamController {
@@ -96,12 +292,25 @@ pub struct StreamController {
 }
 
 impl StreamController {
-    pub fn new(tokenizer: Arc<Tokenizer>, context: StreamContext) -> Self {
+    /// Stream the output of `context`, withholding the stop strings in `stop` the same way the
+    /// final text drops them.
+    pub fn new(tokenizer: Arc<Tokenizer>, context: StreamContext, stop: &StopCriteria) -> Self {
+        Self::with_decoder(
+            Box::new(move |ids: &[u32]| tokenizer.decode(ids, true).unwrap_or_default()),
+            context,
+            stop,
+        )
+    }
+
+    fn with_decoder(decode: DecodeFn, context: StreamContext, stop: &StopCriteria) -> Self {
         StreamController {
             inner: Arc::new(StreamControllerInner {
                 sender: context.sender,
-                tokenizer,
+                decode,
+                stop: stop.clone(),
+                format: context.format,
                 kind: context.kind,
+                flush: context.flush,
                 runtime: Mutex::new(StreamRuntime::default()),
             }),
         }
@@ -115,9 +324,31 @@ impl StreamController {
         self.inner.flush_remaining(tokens);
     }
 
-    pub fn finalize(&self, normalized: &str, prompt_tokens: usize, completion_tokens: usize) {
-        self.inner
-            .finalize(normalized, prompt_tokens, completion_tokens);
+    pub fn finalize(
+        &self,
+        normalized: &str,
+        prompt_token_ids: &[i64],
+        completion_tokens: usize,
+        finish_reason: FinishReason,
+    ) {
+        self.inner.finalize(
+            normalized,
+            prompt_token_ids,
+            completion_tokens,
+            finish_reason,
+        );
+    }
+
+    /// Whether the client stopped listening.
+    pub fn is_disconnected(&self) -> bool {
+        self.inner.sender.is_closed()
+    }
+
+    /// [`Self::is_disconnected`] as a check to run between decode steps, so an abandoned request
+    /// frees its batch slot instead of decoding to the budget.
+    pub fn disconnected(&self) -> impl Fn() -> bool + Send + Sync + 'static {
+        let inner = Arc::clone(&self.inner);
+        move || inner.sender.is_closed()
     }
 
     pub fn callback(&self) -> impl Fn(usize, &[i64]) + Send + Sync + 'static {
@@ -137,7 +368,7 @@ impl StreamControllerInner {
                 created,
                 ..
             } => {
-                let _ = self.sender.send(Event::json(&json!({
+                self.sender.send(Event::json(&json!({
                     "type": "response.created",
                     "response": {
                         "id": response_id,
@@ -151,6 +382,7 @@ impl StreamControllerInner {
                 completion_id,
                 model,
                 created,
+                ..
             } => {
                 let payload = json!({
                     "id": completion_id,
@@ -165,38 +397,35 @@ impl StreamControllerInner {
                         "finish_reason": serde_json::Value::Null,
                     }],
                 });
-                let _ = self.sender.send(Event::json(&payload));
+                self.sender.send(Event::json(&payload));
                 if let Ok(mut state) = self.runtime.lock() {
                     state.role_sent = true;
                 }
             }
+            #[cfg(feature = "grpc")]
+            StreamKind::Grpc => {}
         }
     }
 
-    fn decode_tokens(&self, ids: &[i64]) -> Option<String> {
-        let tokens: Vec<u32> = ids
-            .iter()
-            .filter_map(|&id| u32::try_from(id).ok())
-            .collect();
-        if tokens.is_empty() {
-            return None;
-        }
-        self.tokenizer
-            .decode(&tokens, true)
-            .ok()
-            .filter(|s| !s.is_empty())
+    fn decode_text(&self, ids: &[u32]) -> String {
+        (self.decode)(ids)
     }
 
-    fn emit_delta(&self, text: String, include_role: bool) {
+    fn emit_delta(&self, delta: Delta, include_role: bool) {
         match &self.kind {
             StreamKind::Responses {
                 response_id,
                 output_id,
                 model,
                 created,
+                ..
             } => {
+                let (event_type, delta) = match delta {
+                    Delta::Text(text) => ("response.output_text.delta", json!(text)),
+                    Delta::TokenIds(ids) => ("response.output_token_ids.delta", json!(ids)),
+                };
                 let payload = json!({
-                    "type": "response.output_text.delta",
+                    "type": event_type,
                     "response": {
                         "id": response_id,
                         "object": "response",
@@ -205,20 +434,22 @@ impl StreamControllerInner {
                     },
                     "output_id": output_id,
                     "output_index": 0,
-                    "delta": text,
+                    "delta": delta,
                 });
-                let _ = self.sender.send(Event::json(&payload));
+                self.sender.send(Event::json(&payload));
             }
             StreamKind::Chat {
                 completion_id,
                 model,
                 created,
+                ..
             } => {
-                let mut delta = json!({ "content": text });
-                if include_role {
-                    if let serde_json::Value::Object(obj) = &mut delta {
-                        obj.insert("role".into(), serde_json::Value::String("assistant".into()));
-                    }
+                let mut delta = match delta {
+                    Delta::Text(text) => json!({ "content": text }),
+                    Delta::TokenIds(ids) => json!({ "token_ids": ids }),
+                };
+                if let Some(obj) = delta.as_object_mut().filter(|_| include_role) {
+                    obj.insert("role".into(), serde_json::Value::String("assistant".into()));
                 }
                 let payload = json!({
                     "id": completion_id,
@@ -231,60 +462,112 @@ impl StreamControllerInner {
                         "finish_reason": serde_json::Value::Null,
                     }],
                 });
-                let _ = self.sender.send(Event::json(&payload));
+                self.sender.send(Event::json(&payload));
+            }
+            #[cfg(feature = "grpc")]
+            StreamKind::Grpc => {
+                if let StreamSender::Deltas(sender) = &self.sender {
+                    let _ = sender.send(delta);
+                }
             }
         }
     }
 
     fn handle_progress(&self, count: usize, ids: &[i64]) {
-        let (start, include_role) = {
+        let (deltas, include_role) = {
             let mut state = self.runtime.lock().expect("stream state lock poisoned");
             if count <= state.last_count {
                 return;
             }
-            let start = state.last_count;
+            let new_ids = &ids[state.last_count..count];
+            let delta = match self.format {
+                StreamFormat::TokenIds => Delta::TokenIds(new_ids.to_vec()),
+                // The detokenizer holds back a split multibyte character until the rest of its
+                // bytes arrive, and a possible stop string is held back until it is ruled out.
+                StreamFormat::Text => {
+                    let text = state
+                        .detokenizer
+                        .push(&token_ids(new_ids), |ids| self.decode_text(ids));
+                    Delta::Text(state.release_text(&text, &self.stop, false))
+                }
+            };
+            let tokens = count - state.last_count;
             state.last_count = count;
-            let include_role = matches!(self.kind, StreamKind::Chat { .. }) && !state.role_sent;
-            if include_role {
-                state.role_sent = true;
+            let mut deltas: Vec<Delta> = state
+                .buffer(delta, self.format, tokens)
+                .into_iter()
+                .collect();
+            if self.flush.is_due(state.pending_tokens, state.last_flush) {
+                deltas.extend(state.take_pending());
             }
-            (start, include_role)
+            self.take_nonempty(deltas, &mut state)
         };
-
-        if let Some(text) = self.decode_tokens(&ids[start..count]) {
-            if !text.is_empty() {
-                self.emit_delta(text, include_role);
-            }
-        }
+        self.emit_deltas(deltas, include_role);
     }
 
     fn flush_remaining(&self, ids: &[i64]) {
-        let len = ids.len();
-        if len == 0 {
-            return;
-        }
-        let (start, include_role) = {
+        let (deltas, include_role) = {
             let mut state = self.runtime.lock().expect("stream state lock poisoned");
-            if len <= state.last_count {
-                return;
-            }
-            let start = state.last_count;
-            state.last_count = len;
-            let include_role = matches!(self.kind, StreamKind::Chat { .. }) && !state.role_sent;
-            if include_role {
-                state.role_sent = true;
-            }
-            (start, include_role)
+            let start = state.last_count.min(ids.len());
+            let new_ids = &ids[start..];
+            let delta = match self.format {
+                // Raw ids are forwarded as generated; a trailing partial character is the client's
+                // call.
+                StreamFormat::TokenIds => Delta::TokenIds(new_ids.to_vec()),
+                StreamFormat::Text => {
+                    let decode = |ids: &[u32]| self.decode_text(ids);
+                    let mut text = state.detokenizer.push(&token_ids(new_ids), decode);
+                    text.push_str(&state.detokenizer.finish(decode));
+                    Delta::Text(state.release_text(&text, &self.stop, true))
+                }
+            };
+            state.last_count = ids.len().max(state.last_count);
+            // Whatever the flush policy held back goes out now, ahead of the final event.
+            let mut deltas: Vec<Delta> = state
+                .buffer(delta, self.format, new_ids.len())
+                .into_iter()
+                .collect();
+            deltas.extend(state.take_pending());
+            self.take_nonempty(deltas, &mut state)
         };
+        self.emit_deltas(deltas, include_role);
+    }
 
-        if let Some(text) = self.decode_tokens(&ids[start..len]) {
-            if !text.is_empty() {
-                self.emit_delta(text, include_role);
-            }
+    /// Drop empty deltas and work out whether the first one left must carry the role.
+    fn take_nonempty(
+        &self,
+        mut deltas: Vec<Delta>,
+        state: &mut StreamRuntime,
+    ) -> (Vec<Delta>, bool) {
+        deltas.retain(|delta| !delta.is_empty());
+        if deltas.is_empty() {
+            return (deltas, false);
         }
+        let include_role = self.take_role(state);
+        (deltas, include_role)
     }
 
-    fn finalize(&self, normalized: &str, prompt_tokens: usize, completion_tokens: usize) {
+    fn emit_deltas(&self, deltas: Vec<Delta>, include_role: bool) {
+        for (index, delta) in deltas.into_iter().enumerate() {
+            self.emit_delta(delta, include_role && index == 0);
+        }
+    }
+
+    /// Whether the next emitted chunk must carry the assistant role (first chat chunk only).
+    fn take_role(&self, state: &mut StreamRuntime) -> bool {
+        let include_role = matches!(self.kind, StreamKind::Chat { .. }) && !state.role_sent;
+        state.role_sent = true;
+        include_role
+    }
+
+    fn finalize(
+        &self,
+        normalized: &str,
+        prompt_token_ids: &[i64],
+        completion_tokens: usize,
+        finish_reason: FinishReason,
+    ) {
+        let prompt_tokens = prompt_token_ids.len();
         {
             let mut state = self.runtime.lock().expect("stream state lock poisoned");
             if state.finished {
@@ -299,8 +582,19 @@ impl StreamControllerInner {
                 output_id,
                 model,
                 created,
+                sampling,
+                return_prompt_token_ids,
             } => {
                 let total_tokens = prompt_tokens + completion_tokens;
+                let mut usage = json!({
+                    "input_tokens": prompt_tokens,
+                    "output_tokens": completion_tokens,
+                    "total_tokens": total_tokens,
+                    "sampling": sampling,
+                });
+                if *return_prompt_token_ids {
+                    usage["prompt_token_ids"] = json!(prompt_token_ids);
+                }
                 let payload = json!({
                     "type": "response.completed",
                     "response": {
@@ -317,21 +611,28 @@ impl StreamControllerInner {
                                 "text": normalized,
                             }],
                         }],
-                        "usage": {
-                            "input_tokens": prompt_tokens,
-                            "output_tokens": completion_tokens,
-                            "total_tokens": total_tokens,
-                        },
+                        "usage": usage,
                     }
                 });
-                let _ = self.sender.send(Event::json(&payload));
-                let _ = self.sender.send(Event::data("[DONE]"));
+                self.sender.send(Event::json(&payload));
+                self.sender.send(Event::data("[DONE]"));
             }
             StreamKind::Chat {
                 completion_id,
                 model,
                 created,
+                sampling,
+                return_prompt_token_ids,
             } => {
+                let mut usage = json!({
+                    "prompt_tokens": prompt_tokens,
+                    "completion_tokens": completion_tokens,
+                    "total_tokens": prompt_tokens + completion_tokens,
+                    "sampling": sampling,
+                });
+                if *return_prompt_token_ids {
+                    usage["prompt_token_ids"] = json!(prompt_token_ids);
+                }
                 let payload = json!({
                     "id": completion_id,
                     "object": "chat.completion.chunk",
@@ -340,17 +641,203 @@ impl StreamControllerInner {
                     "choices": [{
                         "index": 0,
                         "delta": serde_json::Value::Object(serde_json::Map::new()),
-                        "finish_reason": "stop",
+                        "finish_reason": finish_reason.as_str(),
                     }],
-                    "usage": {
-                        "prompt_tokens": prompt_tokens,
-                        "completion_tokens": completion_tokens,
-                        "total_tokens": prompt_tokens + completion_tokens,
-                    }
+                    "usage": usage,
                 });
-                let _ = self.sender.send(Event::json(&payload));
-                let _ = self.sender.send(Event::data("[DONE]"));
+                self.sender.send(Event::json(&payload));
+                self.sender.send(Event::data("[DONE]"));
             }
+            #[cfg(feature = "grpc")]
+            StreamKind::Grpc => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use deepseek_ocr_core::transformer::sampling::SamplingParams;
+
+    use super::*;
+
+    /// Each id decodes to one letter, so a stop string spans as many tokens as it has letters.
+    fn letters(ids: &[u32]) -> String {
+        ids.iter().map(|&id| char::from(b'A' + id as u8)).collect()
+    }
+
+    /// Stream `generated` one token per step and return the events the client receives.
+    fn stream(generated: &str, stop: &StopCriteria) -> Vec<Event> {
+        stream_with(generated, stop, FlushPolicy::default(), |_| {})
+    }
+
+    /// [`stream`] under `flush`, calling `before_step` with each step's token count first.
+    fn stream_with(
+        generated: &str,
+        stop: &StopCriteria,
+        flush: FlushPolicy,
+        mut before_step: impl FnMut(usize),
+    ) -> Vec<Event> {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let controller = StreamController::with_decoder(
+            Box::new(letters),
+            StreamContext {
+                sender: StreamSender::Events(sender),
+                format: StreamFormat::Text,
+                kind: StreamKind::Chat {
+                    completion_id: "chatcmpl-test".into(),
+                    model: "test".into(),
+                    created: 0,
+                    sampling: SamplingParams::default().into(),
+                    return_prompt_token_ids: false,
+                },
+                flush,
+            },
+            stop,
+        );
+        let generated: Vec<i64> = generated.bytes().map(|b| i64::from(b - b'A')).collect();
+        let callback = controller.callback();
+        for count in 1..=generated.len() {
+            before_step(count);
+            callback(count, &generated[..count]);
+        }
+        controller.flush_remaining(&generated);
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn chunk(content: &str, role: bool) -> Event {
+        let mut delta = json!({ "content": content });
+        if role {
+            delta["role"] = json!("assistant");
+        }
+        Event::json(&json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "test",
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": serde_json::Value::Null,
+            }],
+        }))
+    }
+
+    #[test]
+    fn stop_string_spanning_tokens_is_not_streamed() {
+        let stop = StopCriteria::new().with_strings(["END"]);
+        // "E" and "N" are held back as they arrive, then dropped once "D" completes the match.
+        assert_eq!(
+            stream("HIEND", &stop),
+            vec![chunk("H", true), chunk("I", false)]
+        );
+    }
+
+    #[test]
+    fn held_back_text_is_released_when_the_stop_string_is_ruled_out() {
+        let stop = StopCriteria::new().with_strings(["END"]);
+        assert_eq!(
+            stream("HENX", &stop),
+            vec![chunk("H", true), chunk("ENX", false)]
+        );
+        // A prefix still pending when generation ends is part of the output.
+        assert_eq!(
+            stream("HEN", &stop),
+            vec![chunk("H", true), chunk("EN", false)]
+        );
+    }
+
+    #[test]
+    fn deltas_are_sent_once_enough_tokens_are_buffered() {
+        let flush = FlushPolicy::new(3, None);
+        assert_eq!(
+            stream_with("ABCDEF", &StopCriteria::new(), flush, |_| {}),
+            vec![chunk("ABC", true), chunk("DEF", false)]
+        );
+    }
+
+    #[test]
+    fn deltas_are_sent_once_the_interval_has_passed() {
+        let flush = FlushPolicy::new(100, Some(200));
+        // Only the third token arrives after the interval, so it goes out with the two before it.
+        let events = stream_with("ABCD", &StopCriteria::new(), flush, |count| {
+            if count == 3 {
+                thread::sleep(Duration::from_millis(250));
+            }
+        });
+        assert_eq!(events, vec![chunk("ABC", true), chunk("D", false)]);
+    }
+
+    #[test]
+    fn final_flush_sends_what_the_policy_held_back() {
+        let flush = FlushPolicy::new(3, None);
+        assert_eq!(
+            stream_with("ABCDE", &StopCriteria::new(), flush, |_| {}),
+            vec![chunk("ABC", true), chunk("DE", false)]
+        );
+        // Nothing reached the threshold, so the final flush sends everything.
+        let flush = FlushPolicy::new(100, Some(60_000));
+        assert_eq!(
+            stream_with("AB", &StopCriteria::new(), flush, |_| {}),
+            vec![chunk("AB", true)]
+        );
+    }
+
+    #[test]
+    fn appending_a_different_kind_of_delta_returns_the_buffered_one() {
+        let mut delta = Delta::Text("AB".into());
+        assert!(delta.append(Delta::Text("C".into())).is_none());
+        let displaced = delta.append(Delta::TokenIds(vec![7]));
+        assert!(matches!(displaced, Some(Delta::Text(text)) if text == "ABC"));
+        assert!(matches!(delta, Delta::TokenIds(ids) if ids == [7]));
+    }
+
+    #[test]
+    fn final_event_reports_prompt_token_ids_on_request() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let sampling = SamplingUsage::from(SamplingParams::default());
+        let controller = StreamController::with_decoder(
+            Box::new(letters),
+            StreamContext {
+                sender: StreamSender::Events(sender),
+                format: StreamFormat::Text,
+                kind: StreamKind::Chat {
+                    completion_id: "chatcmpl-test".into(),
+                    model: "test".into(),
+                    created: 0,
+                    sampling,
+                    return_prompt_token_ids: true,
+                },
+                flush: FlushPolicy::default(),
+            },
+            &StopCriteria::new(),
+        );
+        controller.finalize("AB", &[5, 6, 7], 2, FinishReason::Stop);
+        assert_eq!(
+            receiver.try_recv().ok(),
+            Some(Event::json(&json!({
+                "id": "chatcmpl-test",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "test",
+                "choices": [{
+                    "index": 0,
+                    "delta": {},
+                    "finish_reason": "stop",
+                }],
+                "usage": {
+                    "prompt_tokens": 3,
+                    "completion_tokens": 2,
+                    "total_tokens": 5,
+                    "sampling": sampling,
+                    "prompt_token_ids": [5, 6, 7],
+                },
+            })))
+        );
+    }
+}